@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 HOST 8888 HOST 8888 --discovery 27015 --discovery 239.255.0.1:1900
@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080,127.0.0.1:8090 HOST 8888 HOST 8888 --multipath all
@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 HOST 8888 HOST 8888 --public 9999/udp --public 25565/tcp
@@ -0,0 +1,3 @@
+clear
+FINGERPRINT=$(openssl x509 -in rubicon-quic.pem -outform der | sha256sum | cut -d" " -f1)
+cargo run --release connect "quic://127.0.0.1:8081#sha256=$FINGERPRINT" HOST 8888 HOST 8888
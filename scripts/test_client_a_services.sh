@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 HOST 8888 HOST 8888 -s minecraft=25565/tcp -s voice=9987/udp
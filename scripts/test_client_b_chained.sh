@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8092 JOINER 22000 HOST 9999 --relay-only
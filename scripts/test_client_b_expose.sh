@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 PEER 22100 HOST 9999 -e 9998/udp -e 9990
@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 JOINER 22000 HOST 9999 -f 22001:9998/udp -f 22002:9999/tcp
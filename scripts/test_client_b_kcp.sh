@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080 JOINER 22000 HOST 9999 --tcp-transport kcp
\ No newline at end of file
@@ -0,0 +1,2 @@
+clear
+cargo run --release connect 127.0.0.1:8080,127.0.0.1:8090 JOINER 22000 HOST 9999 --select-relay
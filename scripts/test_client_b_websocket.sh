@@ -0,0 +1,2 @@
+clear
+cargo run --release connect ws://127.0.0.1:8082 JOINER 22000 HOST 9999
@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8092 --federation-secret secret --relay-name edge --upstream 127.0.0.1:8091
@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8091 --federation-secret secret --relay-name middle --upstream 127.0.0.1:8080
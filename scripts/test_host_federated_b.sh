@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8090 --federation-secret secret --relay-name us --peer 127.0.0.1:8080
@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8080 --replication-port 8100 --federation-secret s3cret
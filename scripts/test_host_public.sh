@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8080 --public-ports
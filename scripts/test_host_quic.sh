@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8080 --quic-port 8081
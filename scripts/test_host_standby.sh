@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8081 --standby-of 127.0.0.1:8100 --federation-secret s3cret
@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8080 --stun-port 3478
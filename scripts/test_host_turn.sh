@@ -0,0 +1,2 @@
+clear
+cargo run --release host 8080 --turn-port 3478 --turn-user alice:secret --turn-private-peers
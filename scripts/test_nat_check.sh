@@ -0,0 +1,2 @@
+clear
+cargo run --release nat-check 127.0.0.1:8080
@@ -0,0 +1,2 @@
+clear
+cargo run --release nat-sim 1080 --behaviour port-restricted --mapping-lifetime 30
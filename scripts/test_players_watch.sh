@@ -0,0 +1,2 @@
+clear
+cargo run --release players 127.0.0.1:8080 --watch
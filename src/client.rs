@@ -1,14 +1,33 @@
 use std::{
     collections::HashMap,
-    net::{TcpStream, UdpSocket},
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::Duration,
 };
 
 use crate::{
-    common::{ToConnections, DISABLE_NAGLE_ALGORITHM},
-    connections::Connections,
-    packet::{DataPacket, DataPacketLike},
+    commands::{Exposure, Forward, Service, SocketType},
+    common::{ToConnections, UdpDestination, DISABLE_NAGLE_ALGORITHM, MAX_QUEUE_SIZE},
+    connections::{Connections, PublicPlayerData},
+    discovery::{BroadcastPacket, LanDiscovery},
+    failover::{resolve, Relays},
+    loopback::{connect_from, VirtualIps},
+    multipath::Multipath,
+    packet::{
+        CatalogPacket, DataPacket, DataPacketLike, GreetingPacket, HostMigrationPacket, Packet,
+        HOP_LIMIT,
+    },
+    punch::DirectPaths,
+    reliable::ReliableChannel,
+    socket::SocketWrapper,
 };
 
+/// How long we wait for the players we forward to to publish the services we connect to
+pub const SERVICE_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait for a host to answer our request for the services players published
+pub const CATALOG_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct ClientLocalConnection {
     pub player_name: String,
     pub port: u16,
@@ -27,21 +46,91 @@ pub struct ClientState {
 
     /// A hashset mapping identifiers to their local connections
     pub local_redirection_table: HashMap<String, ClientLocalConnection>,
+
+    /// Every local port forwarded to the other player, the one on `player_port` coming first
+    pub forwards: Vec<Forward>,
+    /// Udp sockets of forwarded ports, other than `player_port` (which belongs to the udp thread)
+    pub forward_udp_sockets: HashMap<u16, UdpSocket>,
+    /// Forwarded port each local tcp connection came through, by the port of the connection
+    pub tcp_forwards: HashMap<u16, u16>,
+    /// Forwarded port each local program sent udp data to, by the port of the program
+    pub udp_forwards: HashMap<u16, u16>,
+    /// Local ports other players may connect to. None when every port is (we're the host of the session).
+    pub exposures: Option<Vec<Exposure>>,
+    /// Services other players published, by their name
+    pub catalog: HashMap<String, Vec<Service>>,
+    /// Other players on the server, as the server announces them
+    pub players: HashMap<String, PublicPlayerData>,
+
+    /// Link to the relay server
+    pub relay: SocketWrapper,
+    /// Relays we may move to when the one we're linked to stops answering
+    pub relays: Relays,
+    /// What we greet relays with, again whenever we move to another one
+    pub greeting: GreetingPacket,
+    /// Address of the udp socket of the relay, shared with the udp thread.
+    /// None when udp data travels over the relay link instead.
+    pub relay_udp_address: Arc<Mutex<Option<String>>>,
+    /// Udp data to send out of the socket bound on `player_port`, along with its destination
+    pub relay_packet_sender: Sender<(UdpDestination, Vec<u8>)>,
+    pub relay_queue_size: Arc<Mutex<u64>>,
+
+    /// Direct udp paths to other players. None when udp data always goes through the relay.
+    pub direct_paths: Option<DirectPaths>,
+    /// Sessions to every relay of the list, udp data going over several of them.
+    /// None when it only goes through the one we're linked to.
+    pub multipath: Option<Multipath>,
+    /// Relays discovery traffic of LAN games. None when we don't relay any.
+    pub discovery: Option<LanDiscovery>,
+    /// Addresses our local programs see the players talking to them at.
+    /// None when they all come from the same address.
+    pub virtual_ips: Option<VirtualIps>,
+
+    /// Channel exchanging tcp data with the relay over udp, shared with the udp thread.
+    /// None when tcp data goes through the tcp stream.
+    pub reliable_channel: Option<Arc<Mutex<ReliableChannel>>>,
 }
 impl ClientState {
     pub fn new(
-        player_name: String,
-        player_port: u16,
-        other_player_name: String,
+        greeting: GreetingPacket,
         other_player_port: u16,
+        relay: SocketWrapper,
+        relays: Relays,
+        relay_udp_address: Option<String>,
+        relay_packet_sender: Sender<(UdpDestination, Vec<u8>)>,
     ) -> Self {
+        let player_port = greeting.local_port;
         Self {
             connections: Connections::new(),
-            player_name,
+            player_name: greeting.player_name.clone(),
             player_port,
-            other_player_name,
+            other_player_name: greeting.other_player_name.clone(),
             other_player_port,
             local_redirection_table: Default::default(),
+            forwards: vec![Forward {
+                local_port: player_port,
+                player_name: None,
+                remote_port: other_player_port,
+                service: None,
+                socket_type: None,
+            }],
+            forward_udp_sockets: Default::default(),
+            tcp_forwards: Default::default(),
+            udp_forwards: Default::default(),
+            exposures: None,
+            catalog: Default::default(),
+            players: Default::default(),
+            relay,
+            relays,
+            greeting,
+            relay_udp_address: Arc::new(Mutex::new(relay_udp_address)),
+            relay_packet_sender,
+            relay_queue_size: Default::default(),
+            direct_paths: None,
+            multipath: None,
+            discovery: None,
+            virtual_ips: None,
+            reliable_channel: None,
         }
     }
 
@@ -49,9 +138,575 @@ impl ClientState {
         self.connections.print();
     }
 
-    /// Check if the client is a game host (if so, player name == other player name)
-    pub fn is_host(&self) -> bool {
-        self.player_name == self.other_player_name
+    /// The forward of a local port, falling back to the one on `player_port`
+    pub fn get_forward(&self, local_port: u16) -> &Forward {
+        self.forwards
+            .iter()
+            .find(|forward| forward.local_port == local_port)
+            .unwrap_or(&self.forwards[0])
+    }
+
+    /// Name of the player a forward sends its data to
+    pub fn get_forward_target(&self, forward: &Forward) -> String {
+        forward
+            .player_name
+            .clone()
+            .unwrap_or(self.other_player_name.clone())
+    }
+
+    /// The address local programs see a player at, when players get addresses of their own
+    pub fn virtual_ip(&mut self, player_name: &str) -> Option<Ipv4Addr> {
+        self.virtual_ips
+            .as_mut()
+            .map(|virtual_ips| virtual_ips.get(player_name))
+    }
+
+    /// Whether udp data answers a local program that sent through one of our forwards,
+    /// rather than coming from a player reaching our local programs
+    fn is_forwarded_udp_reply(&self, data: &DataPacket) -> bool {
+        data.reply && self.udp_forwards.contains_key(&data.receiver_port)
+    }
+
+    /// Stores the services a player published, and points the forwards to them at their ports
+    pub fn receive_catalog(&mut self, catalog: CatalogPacket) {
+        println!(
+            "Player {} publishes {} services",
+            catalog.player_name,
+            catalog.services.len()
+        );
+        for service in catalog.services.iter() {
+            println!("- {}", service);
+        }
+        for index in 0..self.forwards.len() {
+            let forward = &self.forwards[index];
+            let Some(service_name) = forward.service.as_ref() else {
+                continue;
+            };
+            if self.get_forward_target(forward) != catalog.player_name {
+                continue;
+            }
+            if let Some(service) = catalog
+                .services
+                .iter()
+                .find(|service| &service.name == service_name)
+            {
+                if forward.remote_port != service.port {
+                    println!(
+                        "Forwarding {} to {}/{} on port {}",
+                        forward.local_port, catalog.player_name, service.name, service.port
+                    );
+                    self.forwards[index].remote_port = service.port;
+                }
+            } else {
+                println!(
+                    "Player {} doesn't publish {} (anymore?)",
+                    catalog.player_name, service_name
+                );
+            }
+        }
+        // The first port is also handled by the udp thread, which doesn't know about forwards
+        self.other_player_port = self.forwards[0].remote_port;
+        self.catalog.insert(catalog.player_name, catalog.services);
+    }
+
+    /// Retargets everything we sent to a host that left to its replacement, which may be us.
+    /// Connections to the old host are closed, for the game to notice and reconnect through the same listeners.
+    pub fn migrate_host(&mut self, migration: HostMigrationPacket) {
+        let (old_host_name, new_host_name) = (
+            migration.old_host_name.as_str(),
+            migration.new_host_name.as_str(),
+        );
+        println!(
+            "Host {} left, migrating to {}",
+            old_host_name, new_host_name
+        );
+        // Local connections that went to the old host
+        let stale_ports: Vec<u16> = self
+            .tcp_forwards
+            .iter()
+            .filter(|(_, local_port)| {
+                self.get_forward_target(self.get_forward(**local_port)) == old_host_name
+            })
+            .map(|(source_port, _)| *source_port)
+            .collect();
+        {
+            let mut connections = self.connections.data.lock().unwrap();
+            for source_port in stale_ports {
+                println!("Closing local connection {} to the old host", source_port);
+                connections.remove(&source_port);
+                self.tcp_forwards.remove(&source_port);
+            }
+        }
+        // Connections the old host made to us
+        self.local_redirection_table
+            .retain(|_, local_connection| local_connection.player_name != old_host_name);
+        // Before the forwards to the old host point to the new one
+        if new_host_name == self.player_name {
+            self.take_over_exposures(old_host_name, migration.exposed_ports);
+        }
+
+        if self.other_player_name == old_host_name {
+            self.other_player_name = new_host_name.to_string();
+            // Relays we greet from now on should know who our host is
+            self.greeting.other_player_name = new_host_name.to_string();
+        }
+        for forward in self.forwards.iter_mut() {
+            if forward.player_name.as_deref() == Some(old_host_name) {
+                forward.player_name = Some(new_host_name.to_string());
+            }
+        }
+    }
+
+    /// Exposes what the host we replace exposed, or the ports we forwarded to it when it exposed every port,
+    /// and tells the relay about it
+    fn take_over_exposures(&mut self, old_host_name: &str, exposed_ports: Option<Vec<u16>>) {
+        let taken_over = match exposed_ports {
+            Some(ports) => ports
+                .into_iter()
+                .map(|port| Exposure {
+                    port,
+                    socket_type: None,
+                })
+                .collect(),
+            None => self
+                .forwards
+                .iter()
+                .filter(|forward| self.get_forward_target(forward) == old_host_name)
+                .map(|forward| Exposure {
+                    port: forward.remote_port,
+                    socket_type: forward.socket_type,
+                })
+                .collect::<Vec<_>>(),
+        };
+        let Some(exposures) = self.exposures.as_mut() else {
+            return;
+        };
+        for exposure in taken_over {
+            if !exposures.contains(&exposure) {
+                println!("We're the host now, exposing {}", exposure.port);
+                exposures.push(exposure);
+            }
+        }
+        self.greeting.exposed_ports =
+            Some(exposures.iter().map(|exposure| exposure.port).collect());
+        self.greeting.other_player_name = self.player_name.clone();
+        if let Err(e) = self
+            .relay
+            .send_packet(&Packet::Greeting(self.greeting.clone()))
+        {
+            println!("Failed to tell the relay what we expose now: {}", e);
+        }
+    }
+
+    /// Moves our session to the next relay that answers, once the one we're linked to stopped echoing our heartbeats.
+    /// We greet it as the same player, so the others keep reaching us by name through it (or a relay it federates with).
+    /// Local listeners and connections are kept, data that was on its way through the old relay is lost.
+    /// When none of the relays answers, we go through them again until one does.
+    pub fn fail_over(&mut self) {
+        println!(
+            "Relay {} stopped answering, failing over",
+            self.relays.current_address()
+        );
+        // With sessions to other relays already, go on with the best of them
+        if let Some((address, relay)) = self.multipath.as_mut().and_then(Multipath::take_best_link)
+        {
+            if let Some(index) = self
+                .relays
+                .addresses
+                .iter()
+                .position(|other| other == &address)
+            {
+                println!("Going on with relay {}", address);
+                self.relays.current = index;
+                self.relays.receive_echo();
+                if self.switch_relay(relay) {
+                    return;
+                }
+            }
+        }
+        self.relays.back_off();
+        loop {
+            for _ in 0..self.relays.addresses.len() {
+                let Some(relay) = self.relays.link_next() else {
+                    break;
+                };
+                if self.switch_relay(relay) {
+                    return;
+                }
+            }
+            self.relays.wait_to_retry();
+        }
+    }
+
+    /// Moves to the relay minimising the combined latency of the players of our session, once we know theirs.
+    /// The session is the player we send our data to (ourselves for hosts) and every player sending theirs to it.
+    /// They all know the latencies of each other, so they all pick the same relay.
+    pub fn agree_on_relay(&mut self) {
+        if self.greeting.relay_latencies.is_empty() {
+            return;
+        }
+        let host_name = &self.other_player_name;
+        let others: Vec<&Vec<(String, u64)>> = self
+            .players
+            .values()
+            .filter(|player| &player.name == host_name || &player.other_player_name == host_name)
+            .filter(|player| player.name != self.player_name && !player.relay_latencies.is_empty())
+            .map(|player| &player.relay_latencies)
+            .collect();
+        if others.is_empty() {
+            return;
+        }
+        // Players may know a relay by different names or ips, it's the same relay when they resolve alike
+        let resolved: HashMap<&String, Vec<SocketAddr>> = self
+            .greeting
+            .relay_latencies
+            .iter()
+            .chain(others.iter().flat_map(|latencies| latencies.iter()))
+            .map(|(address, _)| (address, resolve(address)))
+            .collect();
+        let same_relay = |address: &String, other: &String| {
+            address == other
+                || resolved[address]
+                    .iter()
+                    .any(|resolved_address| resolved[other].contains(resolved_address))
+        };
+        // Relays every one of us reaches, ties going to the lowest address for everyone to agree
+        let Some((combined_latency, _, address)) = self
+            .greeting
+            .relay_latencies
+            .iter()
+            .filter_map(|(address, latency)| {
+                let others_latency = others
+                    .iter()
+                    .map(|latencies| {
+                        latencies
+                            .iter()
+                            .find(|(other, _)| same_relay(address, other))
+                            .map(|(_, latency)| *latency)
+                    })
+                    .sum::<Option<u64>>()?;
+                Some((
+                    latency + others_latency,
+                    resolved[address].iter().min().copied(),
+                    address.clone(),
+                ))
+            })
+            .min()
+        else {
+            println!("No relay is reachable by every player of the session, staying here");
+            return;
+        };
+        if &address == self.relays.current_address() {
+            return;
+        }
+        let Some(index) = self
+            .relays
+            .addresses
+            .iter()
+            .position(|other| other == &address)
+        else {
+            return;
+        };
+        println!(
+            "Moving to relay {}, with a combined latency of {:.2}ms for the {} players of the session",
+            address,
+            combined_latency as f64 / 1000.,
+            others.len() + 1
+        );
+        let previous = self.relays.current;
+        match self.relays.link_to(index) {
+            Ok(relay) => {
+                if !self.switch_relay(relay) {
+                    self.relays.current = previous;
+                }
+            }
+            Err(e) => println!("Relay {} is unreachable: {}", address, e),
+        }
+    }
+
+    /// Greets a relay we just linked to, making it the one we talk to.
+    /// Returns false when it can't carry our session, leaving us on the old one.
+    fn switch_relay(&mut self, relay: SocketWrapper) -> bool {
+        let relay_udp_address = (!relay.carries_udp() && self.relays.reaches_udp)
+            .then(|| self.relays.current_address().clone());
+        if self.reliable_channel.is_some() && relay_udp_address.is_none() {
+            println!("Can't send tcp data over reliable udp to this relay, skipping it");
+            return false;
+        }
+        if relay_udp_address.is_none() && self.direct_paths.is_some() {
+            println!("Direct udp paths need a udp socket reaching the relay, staying on the relay");
+            self.direct_paths = None;
+            self.greeting.udp_candidates = None;
+        }
+        self.greeting.udp_over_link = relay_udp_address.is_none();
+        if let Err(e) = relay.send_packet(&Packet::Greeting(self.greeting.clone())) {
+            println!("Failed to greet the relay: {}", e);
+            return false;
+        }
+        relay.set_nonblocking(true).unwrap();
+        self.relay = relay;
+        *self.relay_udp_address.lock().unwrap() = relay_udp_address;
+        // The new relay knows nothing about the reliable channel we had with the old one
+        if let Some(channel) = self.reliable_channel.as_ref() {
+            *channel.lock().unwrap() = ReliableChannel::new(self.player_name.clone());
+        }
+        // It tells us who's there again
+        self.players.clear();
+        true
+    }
+
+    /// Whether the ports of every service we forward to are known
+    pub fn has_resolved_services(&self) -> bool {
+        self.forwards
+            .iter()
+            .all(|forward| forward.service.is_none() || forward.remote_port != 0)
+    }
+
+    /// Whether other players may connect to a local port
+    pub fn is_exposed(&self, port: u16, socket_type: SocketType) -> bool {
+        self.exposures.as_ref().is_none_or(|exposures| {
+            exposures
+                .iter()
+                .any(|exposure| exposure.port == port && exposure.carries(socket_type))
+        })
+    }
+
+    /// The forward a local tcp connection came through, by the port of the connection
+    pub fn get_tcp_forward(&self, source_port: u16) -> &Forward {
+        self.get_forward(
+            self.tcp_forwards
+                .get(&source_port)
+                .copied()
+                .unwrap_or(self.player_port),
+        )
+    }
+
+    /// Sends a data packet carrying tcp traffic to the relay.
+    /// Goes through the reliable udp channel when there's one, through the relay link otherwise.
+    pub fn send_tcp_data(&self, packet: DataPacket) -> std::io::Result<()> {
+        let packet = Packet::Data(packet);
+        if let Some(channel) = self.reliable_channel.as_ref() {
+            channel
+                .lock()
+                .unwrap()
+                .send(&bincode::serialize(&packet).unwrap());
+            Ok(())
+        } else {
+            self.relay.send_packet(&packet)
+        }
+    }
+
+    /// Sends a data packet carrying udp traffic to the relay, or straight to its player over a direct path.
+    /// Goes through the relay link when it carries udp, through the udp socket otherwise.
+    /// Sending over several relays, it goes to every one of them or to the best one.
+    pub fn send_udp_data(&mut self, mut packet: DataPacket) {
+        if let Some(address) = self
+            .direct_paths
+            .as_ref()
+            .and_then(|direct_paths| direct_paths.get_address(&packet.receiver_name))
+        {
+            self.send_udp_to(
+                address.to_string(),
+                bincode::serialize(&Packet::Data(packet)).unwrap(),
+            );
+            return;
+        }
+        if let Some(multipath) = self.multipath.as_mut() {
+            packet.sequence = Some(multipath.next_sequence());
+            let data = bincode::serialize(&Packet::Data(packet)).unwrap();
+            for address in multipath.destinations() {
+                self.send_udp_to(address.to_string(), data.clone());
+            }
+            return;
+        }
+        let relay_udp_address = self.relay_udp_address.lock().unwrap().clone();
+        let Some(relay_udp_address) = relay_udp_address else {
+            if let Err(e) = self.relay.send_packet(&Packet::Data(packet)) {
+                println!("Failed to send udp data over the relay link: {}", e);
+            }
+            return;
+        };
+        self.send_udp_to(
+            relay_udp_address,
+            bincode::serialize(&Packet::Data(packet)).unwrap(),
+        );
+    }
+
+    /// Sends data out of the udp socket bound on `player_port`, to the relay or another player
+    pub fn send_udp_to(&self, address: String, data: Vec<u8>) {
+        if *self.relay_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
+            self.relay_packet_sender
+                .send((UdpDestination::Remote(address), data))
+                .unwrap();
+            *self.relay_queue_size.lock().unwrap() += 1;
+        } else {
+            println!("Queue over capacity! Dropping the udp thingy...");
+        }
+    }
+
+    /// Whether udp data is the first copy of it to reach us, from players sending it over several relays.
+    /// Counts it for the path it came over, when it came over udp.
+    pub fn is_first_copy(&mut self, packet: &DataPacket, udp_address: Option<SocketAddr>) -> bool {
+        self.multipath.as_mut().is_none_or(|multipath| {
+            multipath.receive_data(&packet.sender_name, packet.sequence, udp_address)
+        })
+    }
+
+    /// Passes udp data the relay sent us to the local program it's meant for.
+    pub fn receive_udp_data(&mut self, data_packet: DataPacket) {
+        if !self.is_forwarded_udp_reply(&data_packet) {
+            // Someone is talking to one of our local programs, or answering its discovery traffic
+            if !self.is_exposed(data_packet.receiver_port, SocketType::Udp)
+                && !self
+                    .discovery
+                    .as_ref()
+                    .is_some_and(|discovery| discovery.is_answered(data_packet.receiver_port))
+            {
+                println!(
+                    "Dropping udp data from {} for port {}, which isn't exposed",
+                    data_packet.sender_name, data_packet.receiver_port
+                );
+                return;
+            }
+            // We need to ensure we have the udp socket existing
+            self.ensure_udp_socket_on_redirection_table(&data_packet);
+
+            data_packet.print("RELATING A DATA PACKET TO A LOCAL CONNECTION: ");
+
+            let player_identifier = data_packet.get_original_player_identifier();
+            if let Some(local_client_connection) =
+                self.local_redirection_table.get(&player_identifier)
+            {
+                // println!("RELAYING TO: 127.0.0.1:{}", data_packet.receiver_port);
+                let _ = local_client_connection
+                    .udp_socket
+                    .as_ref()
+                    .unwrap()
+                    .send_to(
+                        &data_packet.data,
+                        format!("127.0.0.1:{}", data_packet.receiver_port),
+                    );
+            } else {
+                panic!("redirection table DOESNT contain an entry for {player_identifier}");
+            }
+        } else if let Some(udp_socket) = self
+            .udp_forwards
+            .get(&data_packet.receiver_port)
+            .and_then(|local_port| self.forward_udp_sockets.get(local_port))
+        {
+            // Answer from the same port the local program sent its data to
+            let _ = udp_socket.send_to(
+                &data_packet.data,
+                format!("127.0.0.1:{}", data_packet.receiver_port),
+            );
+        } else if *self.relay_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
+            data_packet.print("RECEIVED FOR RELAY ");
+
+            self.relay_packet_sender
+                .send((
+                    UdpDestination::Local(format!("127.0.0.1:{}", data_packet.receiver_port)),
+                    data_packet.data,
+                ))
+                .unwrap();
+            *self.relay_queue_size.lock().unwrap() += 1;
+        } else {
+            println!("DROPPING A UDP PACKET! Queue over capacity...");
+        }
+    }
+
+    /// Passes the discovery traffic local programs sent on to the relay, for the players of our room.
+    /// What we sent out ourselves is left out.
+    pub fn relay_discovery(&mut self, buffer: &mut [u8]) {
+        let Some(discovery) = self.discovery.as_mut() else {
+            return;
+        };
+        let broadcasts = discovery.capture(buffer, |port| {
+            port == self.player_port
+                || self.forward_udp_sockets.contains_key(&port)
+                || self.local_redirection_table.values().any(|connection| {
+                    connection
+                        .udp_socket
+                        .as_ref()
+                        .and_then(|socket| socket.local_addr().ok())
+                        .is_some_and(|address| address.port() == port)
+                })
+        });
+        for broadcast in broadcasts {
+            if let Err(e) = self.relay.send_packet(&Packet::Broadcast(broadcast)) {
+                println!("Failed to relay discovery traffic: {}", e);
+            }
+        }
+    }
+
+    /// Sends discovery traffic another player captured out on our LAN, from a socket of its own.
+    /// Local programs answering it reach the program that sent it, like when it sends us udp data from that port.
+    pub fn receive_broadcast(&mut self, broadcast: BroadcastPacket) {
+        if !self
+            .discovery
+            .as_ref()
+            .is_some_and(|discovery| discovery.is_relayed(&broadcast))
+        {
+            println!(
+                "Dropping discovery traffic from {} for port {}, which we don't relay",
+                broadcast.sender_name, broadcast.port
+            );
+            return;
+        }
+        let data = DataPacket {
+            socket_type: SocketType::Udp,
+            sender_name: broadcast.sender_name.clone(),
+            sender_port: 0,
+            receiver_name: self.player_name.clone(),
+            receiver_port: broadcast.port,
+            data: vec![],
+            source_port: broadcast.source_port,
+            hop_limit: HOP_LIMIT,
+            sequence: None,
+            reply: false,
+        };
+        // Sent out on the LAN, which loopback addresses don't reach
+        if !self
+            .local_redirection_table
+            .contains_key(&data.get_original_player_identifier())
+        {
+            let local_connection =
+                Self::get_local_udp_socket_for_redirection_table(Ipv4Addr::UNSPECIFIED);
+            self.local_redirection_table.insert(
+                data.get_original_player_identifier(),
+                self.get_local_connection_for_redirection_table_from_udp(&data, local_connection),
+            );
+        }
+        if let (Some(discovery), Some(socket)) = (
+            self.discovery.as_mut(),
+            self.local_redirection_table
+                .get(&data.get_original_player_identifier())
+                .and_then(|connection| connection.udp_socket.as_ref()),
+        ) {
+            discovery.emit(socket, &broadcast);
+        }
+    }
+
+    /// Whether the relay stopped acknowledging what we send over the reliable udp channel
+    pub fn is_reliable_channel_dead(&self) -> bool {
+        self.reliable_channel
+            .as_ref()
+            .is_some_and(|channel| channel.lock().unwrap().is_dead())
+    }
+
+    /// Returns the packets the relay sent us over the reliable udp channel.
+    pub fn receive_reliable_packets(&self) -> Vec<Packet> {
+        let mut packets = vec![];
+        if let Some(channel) = self.reliable_channel.as_ref() {
+            let mut channel = channel.lock().unwrap();
+            while let Some(message) = channel.receive() {
+                if let Ok(packet) = bincode::deserialize::<Packet>(&message) {
+                    packets.push(packet);
+                } else {
+                    println!("Failed to decode a packet received over reliable udp!");
+                }
+            }
+        }
+        packets
     }
 
     pub fn ensure_udp_socket_on_redirection_table(&mut self, data: &DataPacket) {
@@ -65,7 +720,10 @@ impl ClientState {
             }
         } else {
             // There's no local connection
-            let local_connection = Self::get_local_udp_socket_for_redirection_table();
+            let local_ip = self
+                .virtual_ip(&data.sender_name)
+                .unwrap_or(Ipv4Addr::UNSPECIFIED);
+            let local_connection = Self::get_local_udp_socket_for_redirection_table(local_ip);
             self.local_redirection_table.insert(
                 data.get_original_player_identifier(),
                 self.get_local_connection_for_redirection_table_from_udp(data, local_connection),
@@ -83,9 +741,16 @@ impl ClientState {
                 // There's no tcp stream but the local connection exists
                 // Communication probably started with udp?
             }
-        } else if let Some(local_connection) =
-            Self::get_local_tcp_socket_for_redirection_table(data)
-        {
+        } else if !self.is_exposed(data.get_receiver_port(), SocketType::Tcp) {
+            println!(
+                "Refusing a tcp connection from {} to port {}, which isn't exposed",
+                data.get_sender_name(),
+                data.get_receiver_port()
+            );
+        } else if let Some(local_connection) = Self::get_local_tcp_socket_for_redirection_table(
+            data,
+            self.virtual_ip(&data.get_sender_name()),
+        ) {
             println!(
                 "Bound new tcp stream: {} -> {}",
                 local_connection.local_addr().unwrap(),
@@ -99,29 +764,55 @@ impl ClientState {
         }
     }
 
-    fn get_local_udp_socket_for_redirection_table() -> UdpSocket {
+    fn get_local_udp_socket_for_redirection_table(mut local_ip: Ipv4Addr) -> UdpSocket {
         let mut starting_port = 40_000;
         let mut fails = 0;
         loop {
-            let udp_socket_addr = format!("0.0.0.0:{}", starting_port);
-            if let Ok(udp_socket) = UdpSocket::bind(udp_socket_addr.clone()) {
-                udp_socket.set_nonblocking(true).unwrap();
-                return udp_socket;
-            } else {
-                starting_port += 1;
-                fails += 1;
-                if fails >= 10_000 {
-                    panic!("FAILED TO FIND A VALID PORT FOR UDP COMMUNICATION!");
+            let udp_socket_addr = SocketAddr::from((local_ip, starting_port));
+            match UdpSocket::bind(udp_socket_addr) {
+                Ok(udp_socket) => {
+                    udp_socket.set_nonblocking(true).unwrap();
+                    return udp_socket;
+                }
+                Err(e) if e.kind() == ErrorKind::AddrNotAvailable => {
+                    println!(
+                        "Can't bind {}, is 127.0.0.0/8 routed to loopback? Binding every address instead",
+                        local_ip
+                    );
+                    local_ip = Ipv4Addr::UNSPECIFIED;
+                    continue;
+                }
+                Err(_) => {
+                    starting_port += 1;
+                    fails += 1;
+                    if fails >= 10_000 {
+                        panic!("FAILED TO FIND A VALID PORT FOR UDP COMMUNICATION!");
+                    }
                 }
             }
         }
     }
 
+    /// Connects to the local port data is meant for, from the virtual ip of its player when it has one
     fn get_local_tcp_socket_for_redirection_table<D: DataPacketLike>(
         data: &D,
+        local_ip: Option<Ipv4Addr>,
     ) -> Option<TcpStream> {
-        let tcp_socket_addr = format!("127.0.0.1:{}", data.get_receiver_port());
-        if let Ok(tcp_socket) = TcpStream::connect(tcp_socket_addr.clone()) {
+        let tcp_socket_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, data.get_receiver_port()));
+        let tcp_socket = match local_ip {
+            Some(local_ip) => match connect_from(local_ip, data.get_receiver_port()) {
+                Err(e) if e.kind() == ErrorKind::AddrNotAvailable => {
+                    println!(
+                        "Can't connect from {}, is 127.0.0.0/8 routed to loopback? Connecting from 127.0.0.1 instead",
+                        local_ip
+                    );
+                    TcpStream::connect(tcp_socket_addr)
+                }
+                result => result,
+            },
+            None => TcpStream::connect(tcp_socket_addr),
+        };
+        if let Ok(tcp_socket) = tcp_socket {
             tcp_socket.set_nodelay(DISABLE_NAGLE_ALGORITHM).unwrap();
             tcp_socket.set_nonblocking(true).unwrap();
             return Some(tcp_socket);
@@ -1,6 +1,13 @@
-use clap::{command, Parser, Subcommand, ValueEnum};
+use std::{collections::HashSet, net::Ipv4Addr, str::FromStr};
+
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    proxy::{Proxy, ProxyKind},
+    socket::{QUIC_SCHEME, WEBSOCKET_SCHEME},
+};
+
 #[derive(Debug, Parser)]
 #[command(name = "rubicon")]
 #[command(about = "A software router for network packets", long_about = None)]
@@ -8,15 +15,303 @@ pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
 }
+impl Args {
+    /// Parses the command line, exiting with a usage error on arguments that don't make sense together
+    /// in ways clap can't tell by itself.
+    pub fn parse_valid() -> Self {
+        let args = Self::parse();
+        if let Err(message) = args.command.check() {
+            Self::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+        args
+    }
+}
 
-#[derive(Serialize, Deserialize, ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Default)]
 pub enum SocketType {
     Udp,
+    #[default]
     Tcp,
 }
-impl Default for SocketType {
-    fn default() -> Self {
-        Self::Tcp
+
+/// How data of tcp connections travels between a client and the relay server.
+#[derive(Serialize, Deserialize, ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum TcpTransport {
+    /// Use the tcp stream connected to the relay.
+    #[default]
+    Tcp,
+    /// Use the udp socket, with a reliable ordered protocol in the style of KCP.
+    /// Avoids head-of-line stalls of tcp on long, lossy paths.
+    Kcp,
+}
+
+/// How the server picks a new host for the players of a host that left.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum MigrationPolicy {
+    /// The player of the room that greeted the server first.
+    #[default]
+    FirstJoined,
+    /// The player of the room with the lowest round trip time to the server.
+    LowestRtt,
+}
+
+/// How udp traffic goes over the relays a player keeps sessions to at once.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MultipathMode {
+    /// Every datagram goes over every relay, the receiver dropping the copies.
+    All,
+    /// Datagrams go over the relay answering fastest, moving to another one as soon as it stops answering.
+    Best,
+}
+
+/// Which datagrams a simulated NAT lets in, and how it maps outgoing ones to outside ports.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum NatBehaviour {
+    /// One outside port per inside socket, anyone may send to it.
+    FullCone,
+    /// One outside port per inside socket, only addresses it sent to may send to it.
+    AddressRestricted,
+    /// One outside port per inside socket, only address and port pairs it sent to may send to it.
+    #[default]
+    PortRestricted,
+    /// One outside port per destination, only that destination may send to it.
+    Symmetric,
+}
+
+/// Credentials of a TURN user, given as `username:password`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TurnUser {
+    pub username: String,
+    pub password: String,
+}
+impl FromStr for TurnUser {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Self {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(format!(
+                "{spec} is not a valid TURN user, expected username:password"
+            )),
+        }
+    }
+}
+
+/// An inclusive range of ports, given as `first-last`, ie. `40000-40999`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (first, last) = spec.split_once('-').unwrap_or((spec, spec));
+        let parse = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| format!("{port} is not a valid port"))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first == 0 || first > last {
+            return Err(format!("{spec} is not a valid port range"));
+        }
+        Ok(Self { first, last })
+    }
+}
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+/// A local port forwarded to a port on the machine of another player.
+/// Given as `local_port[:[player_name:]remote_port][/tcp|/udp]`, ie. `22000:9999/tcp` or `22001:ALICE:9987/udp`,
+/// or as `local_port:player_name/service[/tcp|/udp]` to forward to a service the player published, ie. `22002:ALICE/voice`.
+/// The player defaults to the other player of the session, the remote port defaults to the local one,
+/// and both protocols are forwarded unless one is picked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Forward {
+    pub local_port: u16,
+    /// None for the other player of the session
+    pub player_name: Option<String>,
+    pub remote_port: u16,
+    /// Service of the player to forward to, in place of a port.
+    /// The remote port is found in the catalog of the player once the server sends it.
+    pub service: Option<String>,
+    /// None when both tcp and udp are forwarded
+    pub socket_type: Option<SocketType>,
+}
+impl Forward {
+    pub fn carries(&self, socket_type: SocketType) -> bool {
+        self.socket_type.is_none() || self.socket_type == Some(socket_type)
+    }
+}
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (target, socket_type) = split_socket_type(spec)?;
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| format!("{port} is not a valid port"))
+        };
+        let (local_port, player_name, remote_port, service) =
+            match target.split(':').collect::<Vec<_>>()[..] {
+                [port] => (parse_port(port)?, None, parse_port(port)?, None),
+                [local_port, remote] => match remote.split_once('/') {
+                    Some((player_name, service)) => (
+                        parse_port(local_port)?,
+                        Some(player_name.to_string()),
+                        0,
+                        Some(service.to_string()),
+                    ),
+                    None => (parse_port(local_port)?, None, parse_port(remote)?, None),
+                },
+                [local_port, player_name, remote_port] => (
+                    parse_port(local_port)?,
+                    Some(player_name.to_string()),
+                    parse_port(remote_port)?,
+                    None,
+                ),
+                _ => return Err(format!("{spec} is not a valid forward")),
+            };
+        Ok(Self {
+            local_port,
+            player_name,
+            remote_port,
+            service,
+            socket_type,
+        })
+    }
+}
+
+/// Splits a trailing `/tcp` or `/udp` off a spec
+fn split_socket_type(spec: &str) -> Result<(&str, Option<SocketType>), String> {
+    match spec.rsplit_once('/') {
+        Some((rest, socket_type)) if socket_type.parse::<u16>().is_err() => {
+            match SocketType::from_str(socket_type, true) {
+                Ok(socket_type) => Ok((rest, Some(socket_type))),
+                // Not a protocol, the name of a service
+                Err(_) if rest.contains(':') && !rest.contains('/') => Ok((spec, None)),
+                Err(_) => Err(format!(
+                    "unknown protocol {socket_type}, expected tcp or udp"
+                )),
+            }
+        }
+        Some(_) => Err(format!("{spec} is not valid")),
+        None => Ok((spec, None)),
+    }
+}
+
+/// A local port other players may connect to through the relay.
+/// Given as `port[/tcp|/udp]`, ie. `9987/udp`. Both protocols are exposed unless one is picked.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Exposure {
+    pub port: u16,
+    /// None when both tcp and udp are exposed
+    pub socket_type: Option<SocketType>,
+}
+impl Exposure {
+    pub fn carries(&self, socket_type: SocketType) -> bool {
+        self.socket_type.is_none() || self.socket_type == Some(socket_type)
+    }
+}
+impl FromStr for Exposure {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (port, socket_type) = split_socket_type(spec)?;
+        Ok(Self {
+            port: port
+                .parse::<u16>()
+                .map_err(|_| format!("{port} is not a valid port"))?,
+            socket_type,
+        })
+    }
+}
+
+/// Udp discovery traffic of LAN games to relay to the players of our room.
+/// Given as `port` for broadcasts to that port, or `group:port` for a multicast group, ie. `239.255.0.1:1900`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Discovery {
+    /// None for broadcasts
+    pub group: Option<Ipv4Addr>,
+    pub port: u16,
+}
+impl FromStr for Discovery {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (group, port) = match spec.rsplit_once(':') {
+            Some((group, port)) => {
+                let group = group
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| format!("{group} is not a valid multicast group"))?;
+                if !group.is_multicast() {
+                    return Err(format!("{group} is not a multicast address"));
+                }
+                (Some(group), port)
+            }
+            None => (None, spec),
+        };
+        Ok(Self {
+            group,
+            port: port
+                .parse::<u16>()
+                .map_err(|_| format!("{port} is not a valid port"))?,
+        })
+    }
+}
+impl std::fmt::Display for Discovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.group {
+            Some(group) => write!(f, "multicast {}:{}", group, self.port),
+            None => write!(f, "broadcast {}", self.port),
+        }
+    }
+}
+
+/// A named local service players can connect to without knowing its port.
+/// Given as `name=port[/tcp|/udp]`, ie. `minecraft=25565/tcp` or `voice=9987/udp`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Service {
+    pub name: String,
+    pub port: u16,
+    /// None when the service speaks both tcp and udp
+    pub socket_type: Option<SocketType>,
+}
+impl FromStr for Service {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let Some((name, exposure)) = spec.split_once('=') else {
+            return Err(format!("{spec} is not a valid service, expected name=port"));
+        };
+        if name.is_empty() || name.contains(['/', ':']) {
+            return Err(format!("{name} is not a valid service name"));
+        }
+        let exposure = Exposure::from_str(exposure)?;
+        Ok(Self {
+            name: name.to_string(),
+            port: exposure.port,
+            socket_type: exposure.socket_type,
+        })
+    }
+}
+impl std::fmt::Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.name, self.port)?;
+        match self.socket_type {
+            Some(SocketType::Tcp) => write!(f, "/tcp"),
+            Some(SocketType::Udp) => write!(f, "/udp"),
+            None => Ok(()),
+        }
     }
 }
 
@@ -27,9 +322,78 @@ pub enum Commands {
     Host {
         /// The outgoing port for clients to connect to
         port: u16,
+        /// Also accept quic connections on this (udp) port.
+        /// Clients can then connect using `quic://address:port#sha256=<fingerprint>` as the server address,
+        /// the fingerprint of the certificate being printed on start.
+        #[arg(long)]
+        quic_port: Option<u16>,
+        /// File the certificate of the quic listener is kept in, created on first use
+        #[arg(long, default_value = "rubicon-quic.pem")]
+        quic_certificate: String,
+        /// Also accept websocket upgrades on this (tcp) port, for players behind firewalls letting only http out.
+        /// Clients can then connect using `ws://address:port` as the server address.
+        #[arg(long)]
+        websocket_port: Option<u16>,
+        /// Also serve a single session over stdin and stdout, exiting once it ends.
+        /// Lets clients reach the host through `--proxy-command "ssh relay rubicon host 8080 --stdio"`.
+        /// Everything else is printed to stderr.
+        #[arg(long)]
+        stdio: bool,
+        /// How to pick a new host when a host leaves, among the players connected to it that allow host migration.
+        #[arg(long, value_enum, default_value_t)]
+        migration_policy: MigrationPolicy,
+        /// Give players ports of this machine forwarding to the local ports they make public with `--public`,
+        /// for people that don't run rubicon. A player may have 8 of them, and all players 256.
+        #[arg(long)]
+        public_ports: bool,
+        /// Also answer STUN binding requests on this (udp) port, next to the udp socket of the relay.
+        /// With a second port, `nat-check` can tell which kind of NAT players are behind.
+        #[arg(long)]
+        stun_port: Option<u16>,
+        /// Also serve TURN (udp) on this port, for clients that aren't players - ie. WebRTC ones.
+        #[arg(long, requires = "turn_users")]
+        turn_port: Option<u16>,
+        /// A user allowed to allocate TURN relays, as `username:password` (repeatable)
+        #[arg(long = "turn-user")]
+        turn_users: Vec<TurnUser>,
+        /// Realm of the TURN long-term credentials
+        #[arg(long, default_value = "rubicon")]
+        turn_realm: String,
+        /// Let TURN clients relay to loopback, private and link-local addresses, which they can't by default.
+        /// Only for relays on a LAN - otherwise anyone with a TURN user reaches the network of the relay.
+        #[arg(long, requires = "turn_port")]
+        turn_private_peers: bool,
+        /// Federate with the relay at this address (repeatable), so players on either relay can reach each other.
+        /// The other relay needs the same `--federation-secret`.
+        #[arg(long = "peer", requires = "federation_secret")]
+        peers: Vec<String>,
+        /// Secret relays prove they know to federate with this one, or to replicate with it
+        #[arg(long)]
+        federation_secret: Option<String>,
+        /// Our name among the relays we federate with, `relay-<port>` by default
+        #[arg(long, requires = "federation_secret")]
+        relay_name: Option<String>,
+        /// Act as a downstream of the relay at this address, passing it the data of receivers we don't know.
+        /// It learns the players reachable through us, so relays may be chained over several hops,
+        /// and tells us about the players of the rest of the chain, their presence and services included.
+        /// Players of relays federating with it through `--peer` aren't reachable from the chain.
+        /// The upstream needs the same `--federation-secret`.
+        #[arg(long, requires = "federation_secret")]
+        upstream: Option<String>,
+        /// Port a standby relay may follow our state on, to take over when we go down.
+        /// The standby needs the same `--federation-secret`.
+        #[arg(long, requires = "federation_secret")]
+        replication_port: Option<u16>,
+        /// Stay passive, following the state of the primary relay at this replication address,
+        /// and take over when it goes down. Start it after the primary.
+        /// Once the primary is back, its players are handed back to it and we stand by again.
+        #[arg(long, requires = "federation_secret")]
+        standby_of: Option<String>,
     },
 
     /// Connects to a given host and routes data it receives from other programs into it.
+    /// Prefix the address of the host with `quic://` to talk to it over quic (pinning the certificate
+    /// the host prints with `#sha256=<fingerprint>`), or `ws://` to use websockets.
     /// Example use:
     /// `rubicon connect 636.35.0.24:7777 Player 7000 Host 8000`
     /// This will use a server hosted at 636.35.0.24:7777,
@@ -39,7 +403,9 @@ pub enum Commands {
     /// Prefer 127.0.0.1, to let udp traffic through.
     #[command(arg_required_else_help = true)]
     Connect {
-        /// Adress of the host
+        /// Adress of the host (`quic://` prefixed for quic, a `ws://` url for websockets).
+        /// Several relays may be given, separated by commas: we link to the first one that answers,
+        /// and move to the next one when it stops echoing our heartbeats.
         server_address: String,
         /// Name of the player, used as an identifier
         player_name: String,
@@ -47,27 +413,95 @@ pub enum Commands {
         /// rubicon may bind tcp/udp sockets on this port so make sure it's free.
         player_port: u16,
         /// Name of the other player to connect to.
+        /// Give it as `player_name/service` to connect to a service the player published, ie. `Host/minecraft`.
         other_player_name: String,
-        /// The port on their machine to route the traffic to.
-        other_player_port: u16,
+        /// The port on their machine to route the traffic to. Not needed when connecting to a service.
+        other_player_port: Option<u16>,
+        /// More local ports to forward over the same session, as `local_port[:[player_name:]remote_port][/tcp|/udp]`.
+        /// Each of them can target a different player. Can be given many times.
+        #[arg(short, long = "forward")]
+        forwards: Vec<Forward>,
+        /// Local ports other players may connect to through the relay, as `port[/tcp|/udp]`.
+        /// Can be given many times. Connecting to ourselves (same player names) exposes every port,
+        /// otherwise only the ports given here are.
+        #[arg(short, long = "expose")]
+        exposures: Vec<Exposure>,
+        /// Local services to publish to the other players, as `name=port[/tcp|/udp]`, ie. `minecraft=25565/tcp`.
+        /// Their ports are exposed. Can be given many times.
+        #[arg(short, long = "service")]
+        services: Vec<Service>,
+        /// Local ports to make public on the relay machine, as `port[/tcp|/udp]`, for people that don't run rubicon.
+        /// The relay forwards a port of its own to each of them for as long as we're connected.
+        /// Their ports are exposed. Can be given many times.
+        #[arg(long = "public")]
+        public_ports: Vec<Exposure>,
+        /// Relay the discovery datagrams LAN games broadcast on a port, or send to a multicast group as `group:port`,
+        /// to the players of our room, sending theirs out on our LAN. They need to relay them too.
+        /// Answers come back like any other udp traffic, so the ports of the games answering must be exposed.
+        /// Start rubicon after the games: we share the ports we capture, but a game binding one afterwards
+        /// without sharing it would fail to. Can be given many times.
+        #[arg(long = "discovery")]
+        discoveries: Vec<Discovery>,
+        /// Give every player talking to our local programs an address of its own in 127.0.0.0/8, derived from its name,
+        /// for games telling players apart by address. Needs the whole range to be routed to loopback, as on linux.
+        #[arg(long)]
+        virtual_ips: bool,
+        /// Follow the other player being replaced by a new host if it leaves, which may be us.
+        /// Only for games supporting host migration - our listeners are kept up for the game to reconnect.
+        #[arg(long)]
+        host_migration: bool,
+        /// Always send udp traffic through the relay, rather than straight to the other players when we can reach them
+        #[arg(long)]
+        relay_only: bool,
+        /// Probe every relay over a link and over its udp socket before linking to one, linking to the one answering fastest.
+        /// The link of `quic://` and `ws://` relays carries their udp, so only it is probed. Relays that don't answer are tried last.
+        /// Players of a session doing so move together to the relay that's the fastest for all of them.
+        #[arg(long, conflicts_with_all = ["proxy", "proxy_command"])]
+        select_relay: bool,
+        /// Keep sessions to every relay at once, sending udp traffic over all of them or over the best one.
+        /// The players we send to need sessions to the same relays, ie. by using `--multipath` too.
+        #[arg(long, value_enum, conflicts_with_all = ["select_relay", "proxy", "proxy_command"])]
+        multipath: Option<MultipathMode>,
+        /// How to carry tcp traffic to the relay.
+        #[arg(long, value_enum, default_value_t)]
+        tcp_transport: TcpTransport,
+        /// Reach the host through a proxy - `http://[user:password@]host:port` for HTTP CONNECT,
+        /// or `socks5://[user:password@]host:port`.
+        /// SOCKS5 proxies carry udp traffic too when they support UDP ASSOCIATE,
+        /// otherwise udp traffic goes over the tcp link.
+        #[arg(long, conflicts_with = "proxy_command")]
+        proxy: Option<Proxy>,
+        /// Reach the host through the stdin and stdout of a command, ie. `ssh -W %h:%p jumphost`.
+        /// `%h` and `%p` are replaced with the host and port of the server address.
+        /// Udp traffic goes through the command as well.
+        #[arg(long)]
+        proxy_command: Option<String>,
+        /// More relays to fail over between, listed one per line in a file, after those of the server address.
+        /// Empty lines and lines starting with `#` are skipped.
+        #[arg(long)]
+        relays_file: Option<String>,
     },
 
+    /// Connects to a given host, forwarding every given port to the same port of the other player.
+    /// All ports share a single session with the host.
     #[command(arg_required_else_help = true)]
     MultiConnect {
-        /// Adress of the host
+        /// Adress of the host, or several relays to fail over between like for connect
         server_address: String,
         /// Name of the player, used as an identifier
         player_name: String,
         /// Name of the other player to connect to.
         other_player_name: String,
-        /// Local port to be used as a connection point for the incoming packets.
-        /// rubicon may bind tcp/udp sockets on this port so make sure it's free.
+        /// Local ports to be used as connection points for the incoming packets.
+        /// rubicon may bind tcp/udp sockets on these ports so make sure they're free.
+        #[arg(required = true, num_args = 1..)]
         player_ports: Vec<u16>,
     },
 
+    /// Like multi-connect, for a whole range of ports.
     #[command(arg_required_else_help = true)]
     MassConnect {
-        /// Adress of the host
+        /// Adress of the host, or several relays to fail over between like for connect
         server_address: String,
         /// Name of the player, used as an identifier
         player_name: String,
@@ -79,6 +513,57 @@ pub enum Commands {
         upper_port_inclusive: u16,
     },
 
+    /// Lists the services players published on a host.
+    #[command(arg_required_else_help = true)]
+    Services {
+        /// Adress of the host (`quic://` prefixed for quic, a `ws://` url for websockets)
+        server_address: String,
+        /// Only list the services of this player
+        player_name: Option<String>,
+    },
+
+    /// Lists the players on a host.
+    #[command(arg_required_else_help = true)]
+    Players {
+        /// Adress of the host (`quic://` prefixed for quic, a `ws://` url for websockets)
+        server_address: String,
+        /// Keep printing players as they join, leave or update what they publish
+        #[arg(short, long)]
+        watch: bool,
+    },
+
+    /// Checks which kind of NAT we're behind, using the STUN responder of a host,
+    /// and whether other players are likely to reach us directly.
+    #[command(arg_required_else_help = true)]
+    NatCheck {
+        /// Address of the udp socket of the host (the port it's hosting on)
+        server_address: String,
+        /// Check through a SOCKS5 proxy, `socks5://[user:password@]host:port`
+        #[arg(long)]
+        proxy: Option<Proxy>,
+    },
+
+    /// Simulates a NAT box, to try direct paths between players on a single machine.
+    /// Players go through it with `--proxy socks5://127.0.0.1:<port>`: their udp leaves from
+    /// the outside ports, and comes back in only if the behaviour lets it.
+    #[command(arg_required_else_help = true)]
+    NatSim {
+        /// The (tcp) port players reach the NAT at
+        port: u16,
+        #[arg(long, value_enum, default_value_t)]
+        behaviour: NatBehaviour,
+        /// Seconds a mapping stays open without outgoing datagrams
+        #[arg(long, default_value_t = 30)]
+        mapping_lifetime: u64,
+        /// Ports of the players behind the NAT (the ports they connect on), as `first-last`.
+        /// Udp from other ports isn't translated. Every port but the outside ones by default.
+        #[arg(long)]
+        inside_ports: Option<PortRange>,
+        /// Ports mappings are opened on, as `first-last`
+        #[arg(long, default_value = "40000-40999")]
+        outside_ports: PortRange,
+    },
+
     /// Pings a tcp socket at a given address from a given port.
     #[command(arg_required_else_help = true)]
     Ping {
@@ -96,3 +581,413 @@ pub enum Commands {
     #[command(arg_required_else_help = true)]
     Command { address: String, command: String },
 }
+impl Commands {
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Commands::Connect {
+                server_address,
+                player_port,
+                other_player_name,
+                other_player_port,
+                forwards,
+                tcp_transport,
+                proxy_command,
+                multipath,
+                relays_file,
+                ..
+            } => {
+                if relays_file.is_none() && !lists_relays(server_address) {
+                    return Err(format!("no relay to connect to in {server_address:?}"));
+                }
+                if let (Some(_), Some(relay)) = (multipath, relay_carrying_udp(server_address)) {
+                    return Err(format!(
+                        "--multipath sends udp straight to the relays, which {relay} isn't reached with"
+                    ));
+                }
+                // Reliable udp needs a udp socket reaching the relay, which links carrying udp themselves go without
+                if *tcp_transport == TcpTransport::Kcp {
+                    if proxy_command.is_some() {
+                        return Err(
+                            "--tcp-transport kcp can't be used with --proxy-command, which carries udp itself"
+                                .to_string(),
+                        );
+                    }
+                    if let Some(relay) = relay_carrying_udp(server_address) {
+                        return Err(format!(
+                            "--tcp-transport kcp can't be used with {relay}, whose link carries udp itself"
+                        ));
+                    }
+                }
+                // The other player is given with either a port or one of its services
+                match (other_player_name.split_once('/'), other_player_port) {
+                    (Some((name, service)), Some(_)) => {
+                        return Err(format!(
+                        "connecting to the {service} service of {name}, there's no need for a port"
+                    ))
+                    }
+                    (None, None) => {
+                        return Err(format!(
+                            "a port or a service of {other_player_name} is needed"
+                        ))
+                    }
+                    _ => {}
+                }
+                forwarded_once(
+                    std::iter::once(*player_port)
+                        .chain(forwards.iter().map(|forward| forward.local_port)),
+                )
+            }
+            Commands::NatCheck {
+                proxy: Some(proxy), ..
+            } if proxy.kind != ProxyKind::Socks5 => {
+                Err("nat-check sends udp, only SOCKS5 proxies carry it".to_string())
+            }
+            Commands::MultiConnect { server_address, .. }
+            | Commands::MassConnect { server_address, .. }
+                if !lists_relays(server_address) =>
+            {
+                Err(format!("no relay to connect to in {server_address:?}"))
+            }
+            Commands::MultiConnect { player_ports, .. } => {
+                forwarded_once(player_ports.iter().copied())
+            }
+            Commands::MassConnect {
+                lower_port_inclusive,
+                upper_port_inclusive,
+                ..
+            } if lower_port_inclusive > upper_port_inclusive => Err(format!(
+                "{}-{} is not a valid port range",
+                lower_port_inclusive, upper_port_inclusive
+            )),
+            Commands::NatSim {
+                inside_ports: Some(inside_ports),
+                outside_ports,
+                ..
+            } if inside_ports.first <= outside_ports.last
+                && outside_ports.first <= inside_ports.last =>
+            {
+                Err("inside and outside ports of the NAT overlap".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether a list of relays separated by commas has any
+fn lists_relays(server_address: &str) -> bool {
+    server_address
+        .split(',')
+        .any(|address| !address.trim().is_empty())
+}
+
+/// The first `quic://` or `ws://` relay of a list, whose link carries udp data rather than a udp socket
+fn relay_carrying_udp(server_address: &str) -> Option<&str> {
+    server_address
+        .split(',')
+        .map(str::trim)
+        .find(|address| address.starts_with(QUIC_SCHEME) || address.starts_with(WEBSOCKET_SCHEME))
+}
+
+/// Fails on a local port forwarded twice, as there's no telling where its traffic should go
+fn forwarded_once(ports: impl Iterator<Item = u16>) -> Result<(), String> {
+    let mut forwarded = HashSet::new();
+    for port in ports {
+        if !forwarded.insert(port) {
+            return Err(format!("port {} is forwarded more than once", port));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Commands, String> {
+        let args = Args::try_parse_from(args.split_whitespace()).map_err(|e| e.to_string())?;
+        args.command.check()?;
+        Ok(args.command)
+    }
+
+    #[test]
+    fn parses_forwards() {
+        assert_eq!(
+            Forward::from_str("22000").unwrap(),
+            Forward {
+                local_port: 22000,
+                player_name: None,
+                remote_port: 22000,
+                service: None,
+                socket_type: None,
+            }
+        );
+        assert_eq!(
+            Forward::from_str("22000:9999/tcp").unwrap(),
+            Forward {
+                local_port: 22000,
+                player_name: None,
+                remote_port: 9999,
+                service: None,
+                socket_type: Some(SocketType::Tcp),
+            }
+        );
+        assert_eq!(
+            Forward::from_str("22001:ALICE:9987/udp").unwrap(),
+            Forward {
+                local_port: 22001,
+                player_name: Some("ALICE".to_string()),
+                remote_port: 9987,
+                service: None,
+                socket_type: Some(SocketType::Udp),
+            }
+        );
+        assert_eq!(
+            Forward::from_str("22002:ALICE/voice").unwrap(),
+            Forward {
+                local_port: 22002,
+                player_name: Some("ALICE".to_string()),
+                remote_port: 0,
+                service: Some("voice".to_string()),
+                socket_type: None,
+            }
+        );
+        let forward = Forward::from_str("22002:ALICE/voice/udp").unwrap();
+        assert_eq!(forward.service, Some("voice".to_string()));
+        assert_eq!(forward.socket_type, Some(SocketType::Udp));
+        assert!(forward.carries(SocketType::Udp) && !forward.carries(SocketType::Tcp));
+    }
+
+    #[test]
+    fn refuses_invalid_forwards() {
+        for spec in [
+            "",
+            "port",
+            "70000",
+            "22000/sctp",
+            "22000:ALICE:9987:1",
+            "22000/voice",
+        ] {
+            assert!(Forward::from_str(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn parses_exposures() {
+        let both = Exposure::from_str("9987").unwrap();
+        assert_eq!(
+            both,
+            Exposure {
+                port: 9987,
+                socket_type: None,
+            }
+        );
+        assert!(both.carries(SocketType::Tcp) && both.carries(SocketType::Udp));
+
+        let udp = Exposure::from_str("9987/udp").unwrap();
+        assert_eq!(udp.socket_type, Some(SocketType::Udp));
+        assert!(udp.carries(SocketType::Udp) && !udp.carries(SocketType::Tcp));
+        assert_eq!(
+            Exposure::from_str("25565/TCP").unwrap().socket_type,
+            Some(SocketType::Tcp)
+        );
+
+        for spec in ["", "port", "70000", "9987/sctp", "9987:1", "9987/udp/tcp"] {
+            assert!(Exposure::from_str(spec).is_err(), "{spec}");
+        }
+        assert!(
+            parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 -e 9987/udp -e 25565")
+                .is_ok()
+        );
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 -e 9987/quic").is_err());
+    }
+
+    #[test]
+    fn parses_discoveries() {
+        let broadcast = Discovery::from_str("27015").unwrap();
+        assert_eq!(
+            broadcast,
+            Discovery {
+                group: None,
+                port: 27015,
+            }
+        );
+        assert_eq!(broadcast.to_string(), "broadcast 27015");
+        let multicast = Discovery::from_str("239.255.0.1:1900").unwrap();
+        assert_eq!(
+            multicast,
+            Discovery {
+                group: Some(Ipv4Addr::new(239, 255, 0, 1)),
+                port: 1900,
+            }
+        );
+        assert_eq!(multicast.to_string(), "multicast 239.255.0.1:1900");
+
+        for spec in [
+            "",
+            "port",
+            "70000",
+            "192.168.1.255:27015",
+            "239.255.0.1",
+            "239.255.0.1:",
+            "group:1900",
+            "239.255.0.1:1900/udp",
+        ] {
+            assert!(Discovery::from_str(spec).is_err(), "{spec}");
+        }
+        assert!(parse_args(
+            "rubicon connect 127.0.0.1:8080 A 22000 B 9999 --discovery 27015 --discovery 239.255.0.1:1900"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn parses_turn_users() {
+        assert_eq!(
+            TurnUser::from_str("webrtc:hunter2").unwrap(),
+            TurnUser {
+                username: "webrtc".to_string(),
+                password: "hunter2".to_string(),
+            }
+        );
+        // Passwords may hold colons, and be empty
+        assert_eq!(
+            TurnUser::from_str("webrtc:a:b").unwrap().password,
+            "a:b".to_string()
+        );
+        assert_eq!(TurnUser::from_str("webrtc:").unwrap().password, "");
+        for spec in ["", "webrtc", ":hunter2"] {
+            assert!(TurnUser::from_str(spec).is_err(), "{spec}");
+        }
+        assert!(
+            parse_args("rubicon host 8080 --turn-port 3478 --turn-user a:b --turn-user c:d")
+                .is_ok()
+        );
+        // Serving TURN takes users
+        assert!(parse_args("rubicon host 8080 --turn-port 3478").is_err());
+    }
+
+    #[test]
+    fn parses_services() {
+        assert_eq!(
+            Service::from_str("minecraft=25565/tcp").unwrap(),
+            Service {
+                name: "minecraft".to_string(),
+                port: 25565,
+                socket_type: Some(SocketType::Tcp),
+            }
+        );
+        let voice = Service::from_str("voice=9987").unwrap();
+        assert_eq!(voice.socket_type, None);
+        assert_eq!(voice.to_string(), "voice -> 9987");
+        assert_eq!(
+            Service::from_str("voice=9987/udp").unwrap().to_string(),
+            "voice -> 9987/udp"
+        );
+
+        for spec in [
+            "",
+            "minecraft",
+            "=25565",
+            "mine/craft=25565",
+            "mine:craft=25565",
+            "minecraft=port",
+            "minecraft=25565/sctp",
+        ] {
+            assert!(Service::from_str(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn needs_a_port_or_a_service() {
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999").is_ok());
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B/minecraft").is_ok());
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B").is_err());
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B/minecraft 25565").is_err());
+    }
+
+    #[test]
+    fn sends_tcp_over_udp_only_to_relays_reached_over_udp() {
+        assert!(
+            parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 --tcp-transport kcp").is_ok()
+        );
+        assert!(parse_args(
+            "rubicon connect 127.0.0.1:8080,quic://127.0.0.1:8081 A 22000 B 9999 --tcp-transport kcp"
+        )
+        .is_err());
+        assert!(parse_args(
+            "rubicon connect ws://127.0.0.1:8080/rubicon A 22000 B 9999 --tcp-transport kcp"
+        )
+        .is_err());
+        assert!(parse_args(
+            "rubicon connect 127.0.0.1:8080 A 22000 B 9999 --tcp-transport kcp --proxy-command cat"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sends_over_several_relays_only_reached_over_udp() {
+        assert!(parse_args(
+            "rubicon connect 127.0.0.1:8080,127.0.0.1:8090 A 22000 B 9999 --multipath all"
+        )
+        .is_ok());
+        assert!(parse_args(
+            "rubicon connect 127.0.0.1:8080,quic://127.0.0.1:8090 A 22000 B 9999 --multipath all"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn needs_a_relay() {
+        assert!(parse_args("rubicon connect , A 22000 B 9999").is_err());
+        assert!(parse_args("rubicon connect , A 22000 B 9999 --relays-file relays.txt").is_ok());
+        assert!(parse_args("rubicon multi-connect ,, A B 22000").is_err());
+        assert!(parse_args("rubicon mass-connect , A B 22000 22001").is_err());
+    }
+
+    #[test]
+    fn checks_nats_only_through_proxies_carrying_udp() {
+        assert!(
+            parse_args("rubicon nat-check 127.0.0.1:8080 --proxy socks5://127.0.0.1:1080").is_ok()
+        );
+        assert!(
+            parse_args("rubicon nat-check 127.0.0.1:8080 --proxy http://127.0.0.1:3128").is_err()
+        );
+    }
+
+    #[test]
+    fn keeps_nat_ports_apart() {
+        assert!(parse_args("rubicon nat-sim 1080").is_ok());
+        assert!(parse_args(
+            "rubicon nat-sim 1080 --inside-ports 22000-22999 --outside-ports 40000-40999"
+        )
+        .is_ok());
+        assert!(parse_args("rubicon nat-sim 1080 --inside-ports 30000-40000").is_err());
+        assert!(parse_args("rubicon nat-sim 1080 --outside-ports 39000-39999").is_ok());
+        assert!(parse_args(
+            "rubicon nat-sim 1080 --inside-ports 22000-22999 --outside-ports 22500-23500"
+        )
+        .is_err());
+        assert!(parse_args("rubicon nat-sim 1080 --outside-ports 40999-40000").is_err());
+    }
+
+    #[test]
+    fn refuses_ports_forwarded_twice() {
+        assert!(
+            parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 -f 22001 -f 22002").is_ok()
+        );
+        assert!(
+            parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 -f 22001 -f 22001:9000")
+                .is_err()
+        );
+        assert!(parse_args("rubicon connect 127.0.0.1:8080 A 22000 B 9999 -f 22000:9000").is_err());
+        assert!(parse_args("rubicon multi-connect 127.0.0.1:8080 A B 7000 7001 7000").is_err());
+    }
+
+    #[test]
+    fn needs_ports_to_connect_on() {
+        assert!(parse_args("rubicon multi-connect 127.0.0.1:8080 A B 7000 7001").is_ok());
+        assert!(parse_args("rubicon multi-connect 127.0.0.1:8080 A B").is_err());
+        assert!(parse_args("rubicon mass-connect 127.0.0.1:8080 A B 7000 7000").is_ok());
+        assert!(parse_args("rubicon mass-connect 127.0.0.1:8080 A B 7001 7000").is_err());
+    }
+}
@@ -4,9 +4,14 @@ use std::{
     time::{Duration, Instant},
 };
 
+use ring::rand::{SecureRandom, SystemRandom};
+
 use crate::{
     connections::{Connections, PlayerData},
+    framing::MAX_FRAME_SIZE,
     packet::Packet,
+    proxy::UdpAssociation,
+    reliable::ReliableChannel,
     socket::SocketWrapper,
 };
 
@@ -16,17 +21,38 @@ pub const BUFFER_SIZE: usize = 1024 * 64;
 pub const MAX_QUEUE_SIZE: u64 = 1024;
 pub const HEARTBEATS_PER_SECOND: f64 = 4.;
 
+/// Where the udp thread of a client sends a datagram
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UdpDestination {
+    /// A local program
+    Local(String),
+    /// The relay, or another player - through the proxy when there's one
+    Remote(String),
+}
+
+/// Random bytes from the generator of the system, for whatever must not be guessed
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("The system has no random number generator");
+    bytes
+}
+
 pub trait ToConnections {
     fn to_connections(&mut self) -> &mut Connections;
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_udp_traffic(
-    mut udp_address_and_server_relay: (String, std::sync::mpsc::Sender<(u16, Vec<u8>)>),
-    relay_packets_receiver: std::sync::mpsc::Receiver<(String, Vec<u8>)>,
+    mut udp_address_and_server_relay: (String, std::sync::mpsc::Sender<(SocketAddr, Vec<u8>)>),
+    relay_packets_receiver: std::sync::mpsc::Receiver<(UdpDestination, Vec<u8>)>,
     udp_queue_size: Arc<Mutex<u64>>,
     relay_queue_size: Arc<Mutex<u64>>,
-    server_address: String,
+    server_address: Arc<Mutex<Option<String>>>,
     player_name: String,
+    reliable_channel: Option<Arc<Mutex<ReliableChannel>>>,
+    udp_association: Option<UdpAssociation>,
 ) {
     std::thread::spawn(move || {
         if let Err(e) = thread_priority::set_current_thread_priority(
@@ -46,29 +72,48 @@ pub fn handle_udp_traffic(
         udp.set_nonblocking(true).unwrap();
         let mut buffer = [0u8; BUFFER_SIZE];
 
+        // Datagrams for the relay and other players may have to go through a SOCKS5 proxy
+        let send_to_relay = |data: &[u8], server_address: &String| {
+            if let Some(association) = udp_association.as_ref() {
+                udp.send_to(
+                    &association.wrap(server_address, data)?,
+                    association.relay_address(),
+                )
+            } else {
+                udp.send_to(data, server_address)
+            }
+        };
+
         // We must send a packet to the server. This is VERY important as it'll let us avoid issues with the NAT.
 
         let udp_queue_size = udp_queue_size.clone();
         let relay_queue_size = relay_queue_size.clone();
 
         // Send the initial heartbeat. Important for communication!
-        let _ = udp.send_to(
-            &bincode::serialize(&Packet::Heartbeat(player_name.clone())).unwrap(),
-            &server_address,
-        );
+        // Relays reached over links carrying udp don't need any.
+        if let Some(server_address) = server_address.lock().unwrap().as_ref() {
+            let _ = send_to_relay(
+                &bincode::serialize(&Packet::Heartbeat(player_name.clone())).unwrap(),
+                server_address,
+            );
+        }
 
         let mut last_heartbeat = Instant::now();
         loop {
             let begin = Instant::now();
             let mut had_one = false;
+            // The relay may change when the client fails over to another one
+            let server_address = server_address.lock().unwrap().clone();
 
             // Keep the connection going and send the heartbeat again
-            if last_heartbeat.elapsed().as_secs_f64() > 1. / HEARTBEATS_PER_SECOND {
-                last_heartbeat = Instant::now();
-                let _ = udp.send_to(
-                    &bincode::serialize(&Packet::Heartbeat(player_name.clone())).unwrap(),
-                    &server_address,
-                );
+            if let Some(server_address) = server_address.as_ref() {
+                if last_heartbeat.elapsed().as_secs_f64() > 1. / HEARTBEATS_PER_SECOND {
+                    last_heartbeat = Instant::now();
+                    let _ = send_to_relay(
+                        &bincode::serialize(&Packet::Heartbeat(player_name.clone())).unwrap(),
+                        server_address,
+                    );
+                }
             }
             {
                 // Loop until we empty the queue
@@ -76,23 +121,39 @@ pub fn handle_udp_traffic(
                 if let Ok((size, addr)) = udp.recv_from(&mut buffer) {
                     had_one = true;
 
-                    if *udp_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
-                        // println!("Received udp traffic of size {} from {}", size, addr);
-                        // Welp, gotta send it next!
-                        // But... where to?
-                        // This could be a server setup ;-;
-                        // println!("RECEIVED UDP :: {} @ {}", addr, size);
-                        server_relay
-                            .send((addr.port(), buffer[..size].to_vec()))
-                            .unwrap();
-                        *udp_queue_size.lock().unwrap() += 1;
-
-                        // println!("RECEIVED UDP PACKETS: {}", received_packets);
+                    let datagram = match udp_association.as_ref() {
+                        // Relayed by the proxy, strip its header
+                        Some(association) if addr == association.relay_address() => {
+                            association.unwrap(&buffer[..size])
+                        }
+                        _ => Some((addr, &buffer[..size])),
+                    };
+
+                    if let Some((addr, data)) = datagram {
+                        if let (Some(channel), Ok(Packet::Reliable(segment))) = (
+                            reliable_channel.as_ref(),
+                            bincode::deserialize::<Packet>(data),
+                        ) {
+                            // Tcp data sent to us by the relay over udp
+                            channel.lock().unwrap().input(segment);
+                        } else if *udp_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
+                            // println!("Received udp traffic of size {} from {}", size, addr);
+                            // Welp, gotta send it next!
+                            // But... where to?
+                            // This could be a server setup ;-;
+                            // println!("RECEIVED UDP :: {} @ {}", addr, size);
+                            server_relay.send((addr, data.to_vec())).unwrap();
+                            *udp_queue_size.lock().unwrap() += 1;
+
+                            // println!("RECEIVED UDP PACKETS: {}", received_packets);
+                        } else {
+                            println!(
+                                "QUEUE OVER CAPACITY {}! DROPPING A UDP PACKET...",
+                                *udp_queue_size.lock().unwrap()
+                            );
+                        }
                     } else {
-                        println!(
-                            "QUEUE OVER CAPACITY {}! DROPPING A UDP PACKET...",
-                            *udp_queue_size.lock().unwrap()
-                        );
+                        println!("Dropping a datagram the proxy relayed with a malformed header");
                     }
                 } else {
                     // break;
@@ -105,7 +166,11 @@ pub fn handle_udp_traffic(
 
                     had_one = true;
                     // println!("RELAYING UDP DATA TO :: -> {} @ {}", addr, data.len());
-                    if let Err(e) = udp.send_to(&data, addr) {
+                    let result = match addr {
+                        UdpDestination::Remote(addr) => send_to_relay(&data, &addr),
+                        UdpDestination::Local(addr) => udp.send_to(&data, addr),
+                    };
+                    if let Err(e) = result {
                         println!("ERROR WHEN SENDING A UDP PACKET: {}", e);
                     }
                     // println!("SENT UDP PACKETS: {}", sent_packets);
@@ -113,6 +178,18 @@ pub fn handle_udp_traffic(
                     // break;
                 }
                 // }
+
+                if let (Some(channel), Some(server_address)) =
+                    (reliable_channel.as_ref(), server_address.as_ref())
+                {
+                    for segment in channel.lock().unwrap().flush() {
+                        had_one = true;
+                        let _ = send_to_relay(
+                            &bincode::serialize(&Packet::Reliable(segment)).unwrap(),
+                            server_address,
+                        );
+                    }
+                }
             }
 
             let remaining_millis =
@@ -125,15 +202,22 @@ pub fn handle_udp_traffic(
 }
 
 pub fn accept_connections(
-    tcp_listener: &TcpListener,
+    tcp_listeners: &[TcpListener],
     connections: Connections,
     connection_sender: Option<Sender<SocketAddr>>,
+    relay_links: bool,
 ) -> ! {
+    if tcp_listeners.len() > 1 {
+        // Can't wait on a single one of them
+        for tcp_listener in tcp_listeners {
+            tcp_listener.set_nonblocking(true).unwrap();
+        }
+    }
     loop {
         let begin = Instant::now();
 
         let mut had_one = false;
-        {
+        for tcp_listener in tcp_listeners {
             let stream = tcp_listener.accept();
             if let Ok((tcp_stream, peer)) = stream {
                 had_one = true;
@@ -143,16 +227,12 @@ pub fn accept_connections(
                 let mut connections = connections.data.lock().unwrap();
                 // Here is where we add new connections!
                 // We detect them by receiving tcp packets.
-                connections.insert(
-                    peer.port(),
-                    PlayerData {
-                        name: "<missing>".to_string(),
-                        address: peer.clone(),
-                        stream: SocketWrapper::from_tcp_socket(tcp_stream),
-                        local_port: None,
-                        last_known_udp_port: 0,
-                    },
-                );
+                let stream = if relay_links {
+                    SocketWrapper::from_relay_tcp_socket(tcp_stream)
+                } else {
+                    SocketWrapper::from_tcp_socket(tcp_stream)
+                };
+                connections.insert_link(peer.port(), PlayerData::new(peer, stream));
                 if let Some(sender) = connection_sender.as_ref() {
                     sender.send(peer).unwrap();
                 }
@@ -168,7 +248,7 @@ pub fn accept_connections(
 
 pub fn handle_connections<
     T: ToConnections + Send + 'static,
-    F: FnMut(&mut T, &mut [u8], &mut bool) -> () + Send + 'static,
+    F: FnMut(&mut T, &mut [u8], &mut bool) + Send + 'static,
 >(
     mut connections: T,
     mut closure: F,
@@ -180,7 +260,8 @@ pub fn handle_connections<
             panic!("{:?}", e);
         }
 
-        let mut buffer = [0u8; BUFFER_SIZE];
+        // Links may carry packets wrapping a whole buffer of data read from local programs
+        let mut buffer = vec![0u8; MAX_FRAME_SIZE];
         loop {
             let begin = Instant::now();
 
@@ -5,11 +5,23 @@ use std::{
     },
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::{common::ToConnections, packet::GreetingPacket, socket::SocketWrapper};
+use crate::{
+    commands::{Service, TcpTransport},
+    common::ToConnections,
+    federation::RemotePlayer,
+    packet::GreetingPacket,
+    pipe::STDIO_SESSION_PORT,
+    reliable::ReliableChannel,
+    replication::{new_token, Ghost, ReplicatedPlayer},
+    socket::SocketWrapper,
+    tunnel::{PublicPort, Visitor},
+    turn::TurnAllocation,
+};
 
 #[derive(Debug)]
 pub struct PlayerData {
@@ -20,17 +32,161 @@ pub struct PlayerData {
     /// Port the player uses itself, useful for sending udp packets to it!
     pub local_port: Option<u16>,
     pub last_known_udp_port: u16,
+    /// Present when the player wants tcp data to be sent over udp
+    pub reliable_channel: Option<ReliableChannel>,
+    /// Set for players whose udp data has to go over their link (ie. behind proxies that can't carry udp)
+    pub udp_over_link: bool,
+    /// Local ports the player forwards over its session
+    pub forwarded_ports: Vec<u16>,
+    /// Local ports other players may connect to, None when every port is
+    pub exposed_ports: Option<Vec<u16>>,
+    /// Services the player published
+    pub services: Vec<Service>,
+    /// Set for players that want to be told who joins and leaves
+    pub presence: bool,
+    /// Set for players only watching the server, which aren't announced to others
+    pub observer: bool,
+    /// The player this one sends its data to, itself for hosts
+    pub other_player_name: String,
+    /// Set for players following host migration
+    pub host_migration: bool,
+    /// When the player first greeted us
+    pub greeted_at: Option<Instant>,
+    /// Round trip time of the link of the player, once measured
+    pub rtt: Option<Duration>,
+    /// Local addresses of the udp socket of the player, for direct paths to other players
+    pub udp_candidates: Option<Vec<SocketAddr>>,
+    /// Bytes of data relayed to the player
+    pub relayed_bytes: u64,
+    /// Set for links to relays we federate with, with their name, once they proved they know the secret
+    pub peer_relay: Option<String>,
+    /// Set for links we dialed to federate with another relay
+    pub dialed_relay: bool,
+    /// The challenge we sent the relay on the other end of the link, its hello must answer it
+    pub federation_challenge: Option<[u8; 16]>,
+    /// Set for the link we dialed to the relay we're a downstream of, which gets the data of receivers we don't know
+    pub upstream_relay: bool,
+    /// Set for links of relays that are downstreams of us, once they told us the players they reach
+    pub downstream_relay: bool,
+    /// Latencies the player measured to the relays it picks from, in microseconds
+    pub relay_latencies: Vec<(String, u64)>,
+    /// Proves the player is the same one when it greets us (or our standby) again
+    pub token: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PlayerData {
+    /// Data of a player that just connected, and didn't greet us yet
+    pub fn new(address: SocketAddr, stream: SocketWrapper) -> Self {
+        Self {
+            name: "<missing>".to_string(),
+            address,
+            stream,
+            local_port: None,
+            last_known_udp_port: 0,
+            reliable_channel: None,
+            udp_over_link: false,
+            forwarded_ports: vec![],
+            exposed_ports: Some(vec![]),
+            services: vec![],
+            presence: false,
+            observer: false,
+            other_player_name: String::new(),
+            host_migration: false,
+            greeted_at: None,
+            rtt: None,
+            udp_candidates: None,
+            relayed_bytes: 0,
+            peer_relay: None,
+            dialed_relay: false,
+            federation_challenge: None,
+            upstream_relay: false,
+            downstream_relay: false,
+            relay_latencies: vec![],
+            token: 0,
+        }
+    }
+
+    /// Addresses other players may reach the udp socket of the player at directly, the public one first.
+    /// Empty when its udp data doesn't go through our udp socket, or it doesn't want direct paths.
+    pub fn get_udp_candidates(&self) -> Vec<SocketAddr> {
+        let Some(udp_candidates) = self.udp_candidates.as_ref() else {
+            return vec![];
+        };
+        if self.udp_over_link || self.stream.carries_udp() || self.last_known_udp_port == 0 {
+            return vec![];
+        }
+        let mut public_address = self.address;
+        public_address.set_port(self.last_known_udp_port);
+        let mut candidates = vec![public_address];
+        for candidate in udp_candidates.iter() {
+            if !candidates.contains(candidate) {
+                candidates.push(*candidate);
+            }
+        }
+        candidates
+    }
+
+    /// What other players may know about the player
+    pub fn to_public(&self) -> PublicPlayerData {
+        PublicPlayerData {
+            name: self.name.clone(),
+            forwarded_ports: self.forwarded_ports.clone(),
+            exposed_ports: self.exposed_ports.clone(),
+            services: self.services.clone(),
+            other_player_name: self.other_player_name.clone(),
+            relay_latencies: self.relay_latencies.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PublicPlayerData {
     pub name: String,
+    pub forwarded_ports: Vec<u16>,
+    /// None when every port is exposed
+    pub exposed_ports: Option<Vec<u16>>,
+    pub services: Vec<Service>,
+    /// The player this one sends its data to, itself for hosts
+    pub other_player_name: String,
+    /// Latencies the player measured to the relays it picks from, in microseconds
+    pub relay_latencies: Vec<(String, u64)>,
+}
+impl std::fmt::Display for PublicPlayerData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (ports: {:?}, exposed: ",
+            self.name, self.forwarded_ports
+        )?;
+        match self.exposed_ports.as_ref() {
+            Some(ports) => write!(f, "{:?}", ports)?,
+            None => write!(f, "all")?,
+        }
+        for service in self.services.iter() {
+            write!(f, ", {}", service)?;
+        }
+        write!(f, ")")
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct InnerConnections {
     by_tcp_port: HashMap<u16, PlayerData>,
     // pub by_own
+    /// Relays of TURN clients, by the address of the client
+    pub turn_allocations: HashMap<SocketAddr, TurnAllocation>,
+    /// Players greeted on relays we federate with, by name
+    pub remote_players: HashMap<String, RemotePlayer>,
+    /// Set on standby relays until they take over from their primary, turning players away
+    pub passive: bool,
+    /// Players of the primary, as it last told us while we stand by
+    pub replicated_players: Vec<ReplicatedPlayer>,
+    /// Players of the primary that didn't greet us since we took over, by name
+    pub ghosts: HashMap<String, Ghost>,
+    /// Ports of the relay machine forwarding to ports of players
+    pub public_ports: Vec<PublicPort>,
+    /// People that reached players through public ports, by name
+    pub visitors: HashMap<String, Visitor>,
 }
 impl InnerConnections {
     // Breaks encapsulation...
@@ -42,7 +198,7 @@ impl InnerConnections {
         if let Some((port, _)) = self
             .by_tcp_port
             .iter()
-            .find(|(_, player)| &player.name == name)
+            .find(|(_, player)| &player.name == name && !player.observer)
         {
             return Some(*port);
         }
@@ -53,7 +209,7 @@ impl InnerConnections {
         if let Some(port) = self
             .by_tcp_port
             .iter_mut()
-            .find(|(_, player)| &player.name == name)
+            .find(|(_, player)| &player.name == name && !player.observer)
             .map(|player| &mut player.1.last_known_udp_port)
         {
             return Some(port);
@@ -61,7 +217,28 @@ impl InnerConnections {
         None
     }
 
-    pub fn get_target_stream<'a>(&'a mut self, tcp_port: u16) -> Option<&'a mut SocketWrapper> {
+    pub fn get_player_by_name_mut(&mut self, name: &String) -> Option<&mut PlayerData> {
+        self.by_tcp_port
+            .values_mut()
+            .find(|player| &player.name == name && !player.observer)
+    }
+
+    /// The link to the relay a player we don't host is greeted on
+    pub fn get_relay_link_mut(&mut self, player_name: &String) -> Option<&mut PlayerData> {
+        let relay_name = &self.remote_players.get(player_name)?.relay_name;
+        self.by_tcp_port
+            .values_mut()
+            .find(|link| link.peer_relay.as_ref() == Some(relay_name))
+    }
+
+    /// The link to the relay we're a downstream of, once it said hello
+    pub fn get_upstream_link_mut(&mut self) -> Option<&mut PlayerData> {
+        self.by_tcp_port
+            .values_mut()
+            .find(|link| link.upstream_relay && link.peer_relay.is_some())
+    }
+
+    pub fn get_target_stream(&mut self, tcp_port: u16) -> Option<&mut SocketWrapper> {
         if let Some(v) = self.by_tcp_port.get_mut(&tcp_port) {
             return Some(&mut v.stream);
         }
@@ -74,11 +251,12 @@ impl InnerConnections {
         tcp_port: u16,
         greeting: &GreetingPacket,
     ) -> bool {
-        let entry = self
-            .by_tcp_port
-            .iter()
-            .find(|(_, player)| player.name == greeting.player_name);
-        if let Some(_) = entry {
+        // Players may greet us again on their own connection, to update what they told us.
+        // Observers take no name, they're never looked up by it
+        let entry = self.by_tcp_port.iter().find(|(port, player)| {
+            player.name == greeting.player_name && **port != tcp_port && !player.observer
+        });
+        if entry.is_some() && !greeting.observer {
             println!("DUPLICATE PLAYER NAME: {}", greeting.player_name);
             return false;
         }
@@ -87,6 +265,45 @@ impl InnerConnections {
             println!("Updating port from greeting: {}", greeting.local_port);
             player.name = greeting.player_name.clone();
             player.local_port = Some(greeting.local_port);
+            if greeting.tcp_transport == TcpTransport::Kcp {
+                println!("Player {} uses reliable udp for tcp data", player.name);
+                player.reliable_channel = Some(ReliableChannel::new(player.name.clone()));
+            }
+            if greeting.forwarded_ports.len() > 1 {
+                println!(
+                    "Player {} forwards {} ports over its session",
+                    player.name,
+                    greeting.forwarded_ports.len()
+                );
+            }
+            player.forwarded_ports = greeting.forwarded_ports.clone();
+            match greeting.exposed_ports.as_ref() {
+                Some(ports) if !ports.is_empty() => {
+                    println!("Player {} exposes ports {:?}", player.name, ports)
+                }
+                Some(_) => {}
+                None => println!("Player {} exposes every port", player.name),
+            }
+            player.exposed_ports = greeting.exposed_ports.clone();
+            for service in greeting.services.iter() {
+                println!("Player {} publishes {}", player.name, service);
+            }
+            player.services = greeting.services.clone();
+            player.presence = greeting.presence;
+            player.observer = greeting.observer;
+            player.other_player_name = greeting.other_player_name.clone();
+            player.host_migration = greeting.host_migration;
+            player.greeted_at.get_or_insert_with(Instant::now);
+            player.udp_candidates = greeting.udp_candidates.clone();
+            player.relay_latencies = greeting.relay_latencies.clone();
+            // Players keep their token, bringing the one another relay gave them if there's one
+            if player.token == 0 {
+                player.token = greeting.token.unwrap_or_else(new_token);
+            }
+            if greeting.udp_over_link {
+                println!("Player {} receives udp data over its link", player.name);
+                player.udp_over_link = true;
+            }
         }
 
         true
@@ -96,11 +313,11 @@ impl InnerConnections {
         self.by_tcp_port.get(k)
     }
 
-    pub fn iter(&self) -> Iter<u16, PlayerData> {
+    pub fn iter(&self) -> Iter<'_, u16, PlayerData> {
         self.by_tcp_port.iter()
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<u16, PlayerData> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, u16, PlayerData> {
         self.by_tcp_port.iter_mut()
     }
 
@@ -109,13 +326,25 @@ impl InnerConnections {
         self.by_tcp_port.insert(key, value)
     }
 
+    /// Adds a link that just came up, under the key it prefers (the remote port of players) when it's free.
+    /// Links of different machines may come from the same port, and relays we dial have no port of their own:
+    /// those get a free key instead of replacing another link. Returns the key of the link.
+    pub fn insert_link(&mut self, preferred: u16, link: PlayerData) -> u16 {
+        let key = std::iter::once(preferred)
+            .chain((1..=u16::MAX).rev())
+            .find(|key| *key != STDIO_SESSION_PORT && !self.by_tcp_port.contains_key(key))
+            .expect("Every key is taken by a link");
+        self.by_tcp_port.insert(key, link);
+        key
+    }
+
     // If the map did not have this key present, [None] is returned.
     pub fn remove(&mut self, key: &u16) -> Option<PlayerData> {
         self.by_tcp_port.remove(key)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Connections {
     pub data: Arc<Mutex<InnerConnections>>,
 }
@@ -126,8 +355,55 @@ impl Connections {
         }
     }
     pub fn print(&self) {
-        for (_, player_data) in self.data.lock().unwrap().iter() {
-            println!("- {} @ {}", player_data.name, player_data.address);
+        let cons = self.data.lock().unwrap();
+        for (_, player_data) in cons.iter() {
+            println!(
+                "- {} @ {} (ports: {:?}, exposed: {}, relayed: {} bytes)",
+                player_data.name,
+                player_data.address,
+                player_data.forwarded_ports,
+                player_data
+                    .exposed_ports
+                    .as_ref()
+                    .map_or("all".to_string(), |ports| format!("{:?}", ports)),
+                player_data.relayed_bytes
+            );
+        }
+        for (name, remote_player) in cons.remote_players.iter() {
+            println!("- {} on relay {}", name, remote_player.relay_name);
+        }
+        for player in cons.replicated_players.iter() {
+            println!("- {} on the primary", player.data.name);
+        }
+        for name in cons.ghosts.keys() {
+            println!("- {} (not back since the takeover)", name);
+        }
+        for (client, allocation) in cons.turn_allocations.iter() {
+            println!(
+                "- TURN {} @ {} (relayed at {}, peers: {}, relayed: {} bytes)",
+                allocation.username,
+                client,
+                allocation.relayed_address,
+                allocation.permissions.len(),
+                allocation.relayed_bytes
+            );
+        }
+        for public_port in cons.public_ports.iter() {
+            println!(
+                "- PUBLIC PORT {} (forwards to {}:{}{})",
+                public_port.public_port,
+                public_port.player_name,
+                public_port.local_port,
+                public_port
+                    .socket_type
+                    .map_or(String::new(), |socket_type| format!(" {:?}", socket_type))
+            );
+        }
+        for (name, visitor) in cons.visitors.iter() {
+            println!(
+                "- {} visiting {} through {} (relayed: {} bytes)",
+                name, visitor.player_name, visitor.public_port, visitor.relayed_bytes
+            );
         }
     }
 }
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+
+use crate::commands::Discovery;
+
+/// How long answers to discovery traffic a local port sent are let in
+const SENDER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long discovery traffic we captured or sent out is told apart from new traffic.
+/// Shorter than how often games announce themselves, longer than it takes to come back through the relay.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Discovery traffic a player captured on its LAN, sent to the relay over its link,
+/// which passes it on to the players of its room
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BroadcastPacket {
+    /// Set by the relay, players only speak for themselves
+    pub sender_name: String,
+    /// Port the local program sent it from, which answers go back to
+    pub source_port: u16,
+    /// Multicast group it was sent to, None for broadcasts
+    pub group: Option<Ipv4Addr>,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+/// Captures the discovery traffic of local programs, and sends out that of other players
+pub struct LanDiscovery {
+    discoveries: Vec<Discovery>,
+    /// Sockets sharing the ports local programs send discovery traffic to
+    captures: Vec<(Discovery, UdpSocket)>,
+    /// Local ports programs sent discovery traffic from, which answers come back to, with when they last did
+    senders: HashMap<u16, Instant>,
+    /// Hashes of the discovery traffic we captured or sent out lately, with when we did.
+    /// Other players on our LAN capture what we send out and relay it back, as we do with what they send out,
+    /// which would go around forever. Games read the data as is, so it can't be tagged instead.
+    recent: HashMap<u64, Instant>,
+}
+impl LanDiscovery {
+    /// Binds the ports of the discovery traffic to relay, joining the multicast groups.
+    /// Ports a local program is bound to without sharing them can't be captured,
+    /// discovery traffic of other players is still sent out on them.
+    pub fn new(discoveries: Vec<Discovery>) -> Self {
+        let mut captures = vec![];
+        for discovery in discoveries.iter() {
+            let socket = bind_shared(discovery.port).and_then(|socket| {
+                if let Some(group) = discovery.group {
+                    socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+                }
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            });
+            match socket {
+                Ok(socket) => {
+                    println!("DISCOVERY: relaying {}", discovery);
+                    captures.push((*discovery, socket));
+                }
+                Err(e) => println!(
+                    "DISCOVERY: can't capture {} ({}), is a program bound to it without sharing it?",
+                    discovery, e
+                ),
+            }
+        }
+        Self {
+            discoveries,
+            captures,
+            senders: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Reads the discovery traffic local programs sent, leaving out what we or other players sent out
+    /// or captured lately. Forgets the ports that stopped sending any.
+    pub fn capture(
+        &mut self,
+        buffer: &mut [u8],
+        is_own_port: impl Fn(u16) -> bool,
+    ) -> Vec<BroadcastPacket> {
+        let mut broadcasts = vec![];
+        self.senders
+            .retain(|_, last_sent| last_sent.elapsed() < SENDER_TIMEOUT);
+        self.recent.retain(|_, seen| seen.elapsed() < ECHO_TIMEOUT);
+        for (discovery, socket) in self.captures.iter() {
+            while let Ok((size, address)) = socket.recv_from(buffer) {
+                if is_own_port(address.port())
+                    || is_echo(
+                        &mut self.recent,
+                        discovery.group,
+                        discovery.port,
+                        &buffer[..size],
+                    )
+                {
+                    continue;
+                }
+                if self
+                    .senders
+                    .insert(address.port(), Instant::now())
+                    .is_none()
+                {
+                    println!("DISCOVERY: {} sends {}, relaying it", address, discovery);
+                }
+                broadcasts.push(BroadcastPacket {
+                    sender_name: String::new(),
+                    source_port: address.port(),
+                    group: discovery.group,
+                    port: discovery.port,
+                    data: buffer[..size].to_vec(),
+                });
+            }
+        }
+        broadcasts
+    }
+
+    /// Whether we relay this discovery traffic, and send it out when other players relay it to us
+    pub fn is_relayed(&self, broadcast: &BroadcastPacket) -> bool {
+        self.discoveries
+            .iter()
+            .any(|discovery| discovery.group == broadcast.group && discovery.port == broadcast.port)
+    }
+
+    /// Whether a local port sent discovery traffic lately, letting answers to it in
+    pub fn is_answered(&self, port: u16) -> bool {
+        self.senders
+            .get(&port)
+            .is_some_and(|last_sent| last_sent.elapsed() < SENDER_TIMEOUT)
+    }
+
+    /// Sends discovery traffic of another player out on our LAN, from the socket answers go back to it through.
+    /// Traffic we captured or sent out lately isn't, it's already on our LAN.
+    pub fn emit(&mut self, socket: &UdpSocket, broadcast: &BroadcastPacket) {
+        if is_echo(
+            &mut self.recent,
+            broadcast.group,
+            broadcast.port,
+            &broadcast.data,
+        ) {
+            return;
+        }
+        let destination = match broadcast.group {
+            Some(group) => SocketAddr::from((group, broadcast.port)),
+            None => {
+                let _ = socket.set_broadcast(true);
+                SocketAddr::from((Ipv4Addr::BROADCAST, broadcast.port))
+            }
+        };
+        if let Err(e) = socket.send_to(&broadcast.data, destination) {
+            println!(
+                "DISCOVERY: failed to send out discovery traffic of {} to {}: {}",
+                broadcast.sender_name, destination, e
+            );
+        }
+    }
+}
+
+/// Whether we captured or sent out the same discovery traffic lately, remembering it when we didn't
+fn is_echo(
+    recent: &mut HashMap<u64, Instant>,
+    group: Option<Ipv4Addr>,
+    port: u16,
+    data: &[u8],
+) -> bool {
+    let mut hasher = DefaultHasher::new();
+    (group, port, data).hash(&mut hasher);
+    let hash = hasher.finish();
+    if recent
+        .get(&hash)
+        .is_some_and(|seen| seen.elapsed() < ECHO_TIMEOUT)
+    {
+        return true;
+    }
+    recent.insert(hash, Instant::now());
+    false
+}
+
+/// Binds a udp socket to a port local programs may be bound to too, for broadcasts to reach all of us
+fn bind_shared(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Discovery traffic to a port nothing else uses, captured by a new [LanDiscovery]
+    fn discovery() -> (LanDiscovery, u16) {
+        let port = UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let discovery = LanDiscovery::new(vec![Discovery { group: None, port }]);
+        assert_eq!(discovery.captures.len(), 1);
+        (discovery, port)
+    }
+
+    fn capture(discovery: &mut LanDiscovery, own_port: u16) -> Vec<BroadcastPacket> {
+        std::thread::sleep(Duration::from_millis(50));
+        discovery.capture(&mut [0u8; 1024], |port| port == own_port)
+    }
+
+    #[test]
+    fn captures_what_local_programs_send() {
+        let (mut discovery, port) = discovery();
+        let game = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let game_port = game.local_addr().unwrap().port();
+        let own = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let own_port = own.local_addr().unwrap().port();
+
+        game.send_to(b"a game", ("127.0.0.1", port)).unwrap();
+        // What we send out ourselves
+        own.send_to(b"another game", ("127.0.0.1", port)).unwrap();
+        let broadcasts = capture(&mut discovery, own_port);
+        assert_eq!(broadcasts.len(), 1);
+        assert_eq!(broadcasts[0].source_port, game_port);
+        assert_eq!((broadcasts[0].group, broadcasts[0].port), (None, port));
+        assert_eq!(broadcasts[0].data, b"a game");
+        assert!(discovery.is_answered(game_port));
+        assert!(!discovery.is_answered(own_port));
+
+        // Senders that went quiet are forgotten
+        discovery
+            .senders
+            .insert(game_port, Instant::now() - SENDER_TIMEOUT);
+        assert!(!discovery.is_answered(game_port));
+        capture(&mut discovery, own_port);
+        assert!(discovery.senders.is_empty());
+    }
+
+    #[test]
+    fn relays_only_the_discovery_traffic_asked_for() {
+        let (discovery, port) = discovery();
+        let mut broadcast = BroadcastPacket {
+            sender_name: "HOST".to_string(),
+            source_port: 4445,
+            group: None,
+            port,
+            data: vec![],
+        };
+        assert!(discovery.is_relayed(&broadcast));
+        broadcast.group = Some(Ipv4Addr::new(239, 255, 0, 1));
+        assert!(!discovery.is_relayed(&broadcast));
+        broadcast.group = None;
+        broadcast.port = port.wrapping_add(1);
+        assert!(!discovery.is_relayed(&broadcast));
+    }
+
+    #[test]
+    fn doesnt_relay_back_what_it_sent_out() {
+        let (mut discovery, port) = discovery();
+        // Another player on our LAN sending out what we sent out
+        let other_player = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let broadcast = BroadcastPacket {
+            sender_name: "HOST".to_string(),
+            source_port: 4445,
+            group: None,
+            port,
+            data: b"a game".to_vec(),
+        };
+        discovery.emit(&socket, &broadcast);
+        other_player
+            .send_to(&broadcast.data, ("127.0.0.1", port))
+            .unwrap();
+        assert!(capture(&mut discovery, 0).is_empty());
+
+        // What we captured isn't sent out again when relayed back to us
+        let game = UdpSocket::bind("127.0.0.1:0").unwrap();
+        game.send_to(b"another game", ("127.0.0.1", port)).unwrap();
+        assert_eq!(capture(&mut discovery, 0).len(), 1);
+        assert!(is_echo(&mut discovery.recent, None, port, b"another game"));
+
+        // Games announcing themselves again are relayed again
+        for seen in discovery.recent.values_mut() {
+            *seen -= ECHO_TIMEOUT;
+        }
+        game.send_to(b"another game", ("127.0.0.1", port)).unwrap();
+        assert_eq!(capture(&mut discovery, 0).len(), 1);
+    }
+}
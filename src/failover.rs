@@ -0,0 +1,231 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    pipe::PipeLink,
+    probe::RelayMeasurement,
+    proxy::Proxy,
+    socket::{SocketWrapper, QUIC_SCHEME, WEBSOCKET_SCHEME},
+};
+
+/// How long a relay may go without echoing our heartbeats before we move to the next one
+const RELAY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Least time between two failovers, for relays turning us away not to have us loop through them
+const FAILOVER_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two rounds through relays that all failed, the wait doubling after each round
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The relays a player may use, in order of preference, along with the one it's linked to
+pub struct Relays {
+    pub addresses: Vec<String>,
+    /// Index of the relay we're linked to
+    pub current: usize,
+    /// When the relay last echoed one of our heartbeats
+    pub last_echo: Instant,
+    /// Whether our udp socket reaches the relays, straight or through the proxy
+    pub reaches_udp: bool,
+    /// When we last failed over
+    failed_over_at: Option<Instant>,
+    /// How long to wait before going through the relays again, once none of them answered
+    retry_interval: Duration,
+    proxy: Option<Proxy>,
+    proxy_command: Option<String>,
+}
+impl Relays {
+    /// Reads relays given as addresses separated by commas, followed by those of a file listing one address per line.
+    /// Empty lines and lines starting with `#` are skipped.
+    /// Fails when the file can't be read, or when there's no relay at all.
+    pub fn new(
+        list: &str,
+        file: Option<&str>,
+        proxy: Option<Proxy>,
+        proxy_command: Option<String>,
+    ) -> Result<Self, String> {
+        let mut addresses: Vec<String> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(str::to_string)
+            .collect();
+        if let Some(file) = file {
+            let file = std::fs::read_to_string(file)
+                .map_err(|e| format!("Couldn't read the relays of {}: {}", file, e))?;
+            addresses.extend(
+                file.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        if addresses.is_empty() {
+            return Err(format!("No relay to connect to in {}!", list));
+        }
+        Ok(Self {
+            addresses,
+            current: 0,
+            last_echo: Instant::now(),
+            reaches_udp: proxy.is_none(),
+            failed_over_at: None,
+            retry_interval: FAILOVER_BACKOFF,
+            proxy,
+            proxy_command,
+        })
+    }
+
+    pub fn current_address(&self) -> &String {
+        &self.addresses[self.current]
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn receive_echo(&mut self) {
+        self.last_echo = Instant::now();
+    }
+
+    /// Whether the relay echoed our heartbeats recently
+    pub fn is_healthy(&self) -> bool {
+        self.last_echo.elapsed() < RELAY_TIMEOUT
+    }
+
+    /// Opens a link to a relay, through the proxy when there's one
+    pub fn dial(&self, address: &str) -> std::io::Result<SocketWrapper> {
+        match self.proxy_command.as_ref() {
+            Some(proxy_command) => {
+                PipeLink::spawn(proxy_command, address).map(SocketWrapper::from_pipe)
+            }
+            None => SocketWrapper::connect(address, self.proxy.as_ref()),
+        }
+    }
+
+    /// Links to a relay of the list, making it the current one
+    pub fn link_to(&mut self, index: usize) -> std::io::Result<SocketWrapper> {
+        let link = self.dial(&self.addresses[index])?;
+        println!("Linked to relay {}", self.addresses[index]);
+        self.current = index;
+        self.last_echo = Instant::now();
+        self.retry_interval = FAILOVER_BACKOFF;
+        Ok(link)
+    }
+
+    /// Links to the first relay that answers, going through the list from `first` and wrapping around.
+    pub fn link(&mut self, first: usize) -> Option<SocketWrapper> {
+        for offset in 0..self.addresses.len() {
+            let index = (first + offset) % self.addresses.len();
+            match self.link_to(index) {
+                Ok(link) => return Some(link),
+                Err(e) => println!("Relay {} is unreachable: {}", self.addresses[index], e),
+            }
+        }
+        None
+    }
+
+    /// Waits a bit when we failed over a moment ago, ie. when a relay turned us away
+    pub fn back_off(&mut self) {
+        if let Some(failed_over_at) = self.failed_over_at {
+            if failed_over_at.elapsed() < FAILOVER_BACKOFF {
+                std::thread::sleep(FAILOVER_BACKOFF);
+            }
+        }
+        self.failed_over_at = Some(Instant::now());
+    }
+
+    /// Waits before going through the relays again once none of them answered, a bit longer every time.
+    /// Local listeners stay up meanwhile, their programs get through once a relay answers.
+    pub fn wait_to_retry(&mut self) {
+        println!(
+            "None of the relays answers, trying again in {}s",
+            self.retry_interval.as_secs()
+        );
+        std::thread::sleep(self.retry_interval);
+        self.retry_interval = (self.retry_interval * 2).min(MAX_RETRY_INTERVAL);
+    }
+
+    /// Links to the relays after the current one, the current one being tried last
+    pub fn link_next(&mut self) -> Option<SocketWrapper> {
+        self.link(self.current + 1)
+    }
+
+    /// Orders the relays by how fast they answered our probes, the ones that didn't last.
+    /// Returns the latency of each relay that answered, in microseconds.
+    pub fn sort_by_latency(&mut self, measurements: &[RelayMeasurement]) -> Vec<(String, u64)> {
+        let mut latencies: Vec<(String, u64)> = measurements
+            .iter()
+            .filter_map(|measurement| Some((measurement.address.clone(), measurement.latency()?)))
+            .collect();
+        latencies.sort_by_key(|(_, latency)| *latency);
+        self.addresses.sort_by_key(|address| {
+            latencies
+                .iter()
+                .position(|(other, _)| other == address)
+                .unwrap_or(usize::MAX)
+        });
+        latencies
+    }
+}
+
+/// Where the udp socket of a relay listens: on the port of its tcp listener.
+/// Quic and websocket links carry udp data themselves, the udp socket of those relays is on a port we don't know.
+pub fn udp_address(address: &str) -> Option<SocketAddr> {
+    if address.starts_with(QUIC_SCHEME) || address.starts_with(WEBSOCKET_SCHEME) {
+        return None;
+    }
+    address.to_socket_addrs().ok()?.next()
+}
+
+/// Addresses the host of a relay resolves to, for relays given differently by players (names, ips) to be told the same
+pub fn resolve(address: &str) -> Vec<SocketAddr> {
+    let host = address
+        .strip_prefix(QUIC_SCHEME)
+        .or_else(|| address.strip_prefix(WEBSOCKET_SCHEME))
+        .unwrap_or(address);
+    // Without the fingerprint of quic relays, or the path of websocket ones
+    let host = host.split(['#', '/']).next().unwrap_or(host);
+    host.to_socket_addrs()
+        .map(|addresses| addresses.collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_relays_of_lists_and_files() {
+        let relays = Relays::new(" a:8080, ,quic://b:8080#sha256=00 ", None, None, None).unwrap();
+        assert_eq!(relays.addresses, ["a:8080", "quic://b:8080#sha256=00"]);
+
+        let file = std::env::temp_dir().join(format!("rubicon-relays-{}", std::process::id()));
+        std::fs::write(&file, "# backups\nc:8080\n\n  ws://d:8080  \n").unwrap();
+        let relays = Relays::new("a:8080", file.to_str(), None, None).unwrap();
+        assert_eq!(relays.addresses, ["a:8080", "c:8080", "ws://d:8080"]);
+
+        // Nothing to connect to
+        std::fs::write(&file, "# none yet\n").unwrap();
+        assert!(Relays::new(" , ", file.to_str(), None, None).is_err());
+        std::fs::remove_file(&file).unwrap();
+        assert!(Relays::new("a:8080", file.to_str(), None, None).is_err());
+    }
+
+    #[test]
+    fn resolves_relays_however_they_are_given() {
+        let relay: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        for address in [
+            "127.0.0.1:8080",
+            "quic://127.0.0.1:8080#sha256=00",
+            "ws://127.0.0.1:8080/rubicon",
+        ] {
+            assert_eq!(resolve(address), [relay], "{address}");
+        }
+        assert!(resolve("localhost:8080").contains(&relay));
+        assert!(resolve("not an address").is_empty());
+
+        // Only the udp socket of tcp relays is known
+        assert_eq!(udp_address("127.0.0.1:8080"), Some(relay));
+        assert_eq!(udp_address("quic://127.0.0.1:8080#sha256=00"), None);
+        assert_eq!(udp_address("ws://127.0.0.1:8080"), None);
+    }
+}
@@ -0,0 +1,344 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    common::random_bytes,
+    connections::{Connections, PlayerData, PublicPlayerData},
+    packet::Packet,
+    socket::SocketWrapper,
+};
+
+/// How long to wait before dialing a relay again
+const REDIAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sent between relays federating with each other, over a link one of them dialed
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum FederationPacket {
+    /// Answers the challenge of the other relay, proving we know the federation secret
+    Hello(HelloPacket),
+    /// Every player greeted on the relay, sent whenever one joins, leaves or updates what it publishes
+    Players(Vec<PublicPlayerData>),
+    /// Sent by a relay to its upstream instead of its players, with every player reachable through it:
+    /// its own and those of its downstreams
+    Downstream(Vec<PublicPlayerData>),
+    /// Opens the link, sent by both relays: the other one must answer it with its hello.
+    /// Fresh on every link, so a hello recorded on another one is worthless.
+    Challenge([u8; 16]),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct HelloPacket {
+    pub relay_name: String,
+    /// HMAC-SHA1 of the challenge and the name, keyed with the federation secret
+    pub proof: Vec<u8>,
+}
+impl HelloPacket {
+    pub fn new(relay_name: &str, secret: &str, challenge: &[u8; 16]) -> Self {
+        Self {
+            relay_name: relay_name.to_string(),
+            proof: Self::mac(relay_name, secret, challenge)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Whether the relay knows the secret, answering the challenge we sent it
+    pub fn verify(&self, secret: &str, challenge: &[u8; 16]) -> bool {
+        Self::mac(&self.relay_name, secret, challenge)
+            .verify_slice(&self.proof)
+            .is_ok()
+    }
+
+    fn mac(relay_name: &str, secret: &str, challenge: &[u8; 16]) -> Hmac<Sha1> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(challenge);
+        mac.update(relay_name.as_bytes());
+        mac
+    }
+}
+
+/// A challenge for a relay on a link, to be kept until it answers
+pub fn new_challenge() -> [u8; 16] {
+    random_bytes()
+}
+
+/// A player greeted on a relay we federate with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemotePlayer {
+    pub relay_name: String,
+    pub data: PublicPlayerData,
+}
+
+/// What a relay needs to federate with others
+#[derive(Debug)]
+pub struct Federation {
+    /// Our name among the relays
+    pub relay_name: String,
+    pub secret: String,
+    /// The players we last told other relays about, None to tell them again
+    pub last_players: Option<Vec<PublicPlayerData>>,
+    /// The players reachable through us we last told our upstream about, None to tell it again
+    pub last_reachable: Option<Vec<PublicPlayerData>>,
+    /// Every player we know, with its relay, as we last told our downstreams about them. None to tell them again.
+    pub last_known: Option<Vec<RemotePlayer>>,
+}
+impl Federation {
+    pub fn new(relay_name: String, secret: String) -> Self {
+        Self {
+            relay_name,
+            secret,
+            last_players: None,
+            last_reachable: None,
+            last_known: None,
+        }
+    }
+}
+
+/// Dials a relay to federate with on a thread of its own, dialing it again whenever the link goes down.
+/// The link is handled like the links of players once it's up.
+/// Upstream relays get the data of receivers we don't know, and learn the players reachable through us.
+pub fn dial(address: String, connections: Connections, upstream: bool) {
+    std::thread::spawn(move || loop {
+        match SocketWrapper::connect(&address, None) {
+            Ok(stream) => {
+                let challenge = new_challenge();
+                let remote_address = stream.get_tcp_addr().unwrap();
+                let sent =
+                    stream.send_packet(&Packet::Federation(FederationPacket::Challenge(challenge)));
+                if sent.is_ok() && stream.set_nonblocking(true).is_ok() {
+                    println!("FEDERATION: dialed {}, challenging it", address);
+                    let mut link = PlayerData::new(remote_address, stream);
+                    link.dialed_relay = true;
+                    link.upstream_relay = upstream;
+                    link.federation_challenge = Some(challenge);
+                    // Keyed apart from the links of players, we have no remote port of our own to use
+                    let key = connections.data.lock().unwrap().insert_link(0, link);
+                    // Wait for the link to go down
+                    while connections
+                        .data
+                        .lock()
+                        .unwrap()
+                        .get(&key)
+                        .is_some_and(|link| {
+                            link.dialed_relay && link.federation_challenge == Some(challenge)
+                        })
+                    {
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                    println!("FEDERATION: lost the link to {}", address);
+                }
+            }
+            Err(e) => println!("FEDERATION: couldn't reach {}: {}", address, e),
+        }
+        std::thread::sleep(REDIAL_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{framing::next_frame, server::ServerState};
+
+    const SECRET: &str = "secret";
+
+    /// A relay federating as `a`
+    fn relay() -> ServerState {
+        let mut server_state = ServerState::new();
+        server_state.federation = Some(Federation::new("a".to_string(), SECRET.to_string()));
+        server_state
+    }
+
+    /// A link of another relay to ours, with its end of it
+    fn link(server_state: &ServerState) -> (u16, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other_end = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        let link = PlayerData::new(address, SocketWrapper::from_relay_tcp_socket(stream));
+        let key = server_state
+            .connections
+            .data
+            .lock()
+            .unwrap()
+            .insert_link(address.port(), link);
+        (key, other_end)
+    }
+
+    /// What our relay sent over a link
+    fn received(other_end: &mut TcpStream) -> Vec<FederationPacket> {
+        other_end
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut pending = vec![];
+        let mut buffer = [0u8; 1024];
+        while let Ok(size @ 1..) = other_end.read(&mut buffer) {
+            pending.extend_from_slice(&buffer[..size]);
+        }
+        let mut packets = vec![];
+        while let Some(packet) = next_frame(&mut pending).unwrap() {
+            match bincode::deserialize(&packet).unwrap() {
+                Packet::Federation(packet) => packets.push(packet),
+                packet => panic!("Not a federation packet: {:?}", packet),
+            }
+        }
+        packets
+    }
+
+    fn player(name: &str) -> PublicPlayerData {
+        PublicPlayerData {
+            name: name.to_string(),
+            forwarded_ports: vec![],
+            exposed_ports: None,
+            services: vec![],
+            other_player_name: name.to_string(),
+            relay_latencies: vec![],
+        }
+    }
+
+    /// Links a relay named `relay_name` to ours, answering our challenge
+    fn federate(server_state: &mut ServerState, relay_name: &str) -> (u16, TcpStream) {
+        let (key, mut other_end) = link(server_state);
+        let challenge = new_challenge();
+        server_state
+            .receive_federation_packets(vec![(key, FederationPacket::Challenge(challenge))]);
+        let [FederationPacket::Hello(hello), FederationPacket::Challenge(our_challenge)] =
+            &received(&mut other_end)[..]
+        else {
+            panic!("Expected a hello and a challenge");
+        };
+        assert_eq!(hello.relay_name, "a");
+        assert!(hello.verify(SECRET, &challenge));
+
+        let hello = HelloPacket::new(relay_name, SECRET, our_challenge);
+        server_state.receive_federation_packets(vec![(key, FederationPacket::Hello(hello))]);
+        (key, other_end)
+    }
+
+    fn peer_relay(server_state: &ServerState, key: u16) -> Option<Option<String>> {
+        let cons = server_state.connections.data.lock().unwrap();
+        cons.get(&key).map(|link| link.peer_relay.clone())
+    }
+
+    #[test]
+    fn verifies_hellos() {
+        let challenge = new_challenge();
+        let hello = HelloPacket::new("b", SECRET, &challenge);
+        assert!(hello.verify(SECRET, &challenge));
+        assert!(!hello.verify("guess", &challenge));
+        // A hello answers a single challenge, and holds for the name it was made for
+        assert!(!hello.verify(SECRET, &new_challenge()));
+        let renamed = HelloPacket {
+            relay_name: "c".to_string(),
+            ..hello.clone()
+        };
+        assert!(!renamed.verify(SECRET, &challenge));
+        assert_ne!(new_challenge(), challenge);
+    }
+
+    #[test]
+    fn links_relays_answering_challenges() {
+        let mut server_state = relay();
+        let (key, _other_end) = federate(&mut server_state, "b");
+        assert_eq!(peer_relay(&server_state, key), Some(Some("b".to_string())));
+
+        // Knowing the secret isn't enough without our challenge
+        let (key, _other_end) = link(&server_state);
+        let hello = HelloPacket::new("b", SECRET, &new_challenge());
+        server_state.receive_federation_packets(vec![(key, FederationPacket::Hello(hello))]);
+        assert_eq!(peer_relay(&server_state, key), None);
+    }
+
+    #[test]
+    fn drops_relays_not_knowing_the_secret() {
+        let mut server_state = relay();
+        let (key, mut other_end) = link(&server_state);
+        server_state
+            .receive_federation_packets(vec![(key, FederationPacket::Challenge(new_challenge()))]);
+        let Some(FederationPacket::Challenge(challenge)) = received(&mut other_end).pop() else {
+            panic!("Expected a challenge");
+        };
+        let hello = HelloPacket::new("b", "guess", &challenge);
+        server_state.receive_federation_packets(vec![(key, FederationPacket::Hello(hello))]);
+        assert_eq!(peer_relay(&server_state, key), None);
+
+        // Nor ones sending our own hello back to us
+        let (key, mut other_end) = link(&server_state);
+        server_state
+            .receive_federation_packets(vec![(key, FederationPacket::Challenge(new_challenge()))]);
+        let Some(FederationPacket::Challenge(challenge)) = received(&mut other_end).pop() else {
+            panic!("Expected a challenge");
+        };
+        let hello = HelloPacket::new("a", SECRET, &challenge);
+        server_state.receive_federation_packets(vec![(key, FederationPacket::Hello(hello))]);
+        assert_eq!(peer_relay(&server_state, key), None);
+    }
+
+    #[test]
+    fn keys_links_apart() {
+        let server_state = relay();
+        let (player, _player_end) = link(&server_state);
+        let (other, _other_end) = link(&server_state);
+        let (dialed, _dialed_end) = link(&server_state);
+        let mut cons = server_state.connections.data.lock().unwrap();
+        // Another machine connecting from the same port as the player
+        let other = cons.remove(&other).unwrap();
+        assert_ne!(cons.insert_link(player, other), player);
+        // Dialed links take a key no player has, and stay clear of the stdio session
+        let dialed = cons.remove(&dialed).unwrap();
+        let key = cons.insert_link(0, dialed);
+        assert_ne!(key, 0);
+        assert_eq!(cons.iter().count(), 3);
+    }
+
+    #[test]
+    fn tells_downstreams_about_the_rest_of_the_chain() {
+        let mut server_state = relay();
+        let (b, mut b_end) = federate(&mut server_state, "b");
+        let (c, mut c_end) = federate(&mut server_state, "c");
+        let (peer, mut peer_end) = federate(&mut server_state, "peer");
+        server_state.receive_federation_packets(vec![
+            (b, FederationPacket::Downstream(vec![player("bob")])),
+            (c, FederationPacket::Downstream(vec![player("carol")])),
+            (peer, FederationPacket::Players(vec![player("pete")])),
+        ]);
+        server_state.push_players_to_relays();
+        let names = |packets: Vec<FederationPacket>| match packets.last() {
+            Some(FederationPacket::Players(players)) => players
+                .iter()
+                .map(|player| player.name.clone())
+                .collect::<Vec<_>>(),
+            packet => panic!("Expected players, got {:?}", packet),
+        };
+        // Players of relays we merely federate with can't be reached through us
+        assert_eq!(names(received(&mut b_end)), ["carol"]);
+        assert_eq!(names(received(&mut c_end)), ["bob"]);
+        assert!(names(received(&mut peer_end)).is_empty());
+    }
+
+    #[test]
+    fn keeps_names_to_the_first_relay_claiming_them() {
+        let mut server_state = relay();
+        let (b, _b_end) = federate(&mut server_state, "b");
+        let (c, _c_end) = federate(&mut server_state, "c");
+        assert_ne!(b, c);
+        server_state.receive_federation_packets(vec![(
+            b,
+            FederationPacket::Players(vec![player("alice")]),
+        )]);
+        server_state.receive_federation_packets(vec![(
+            c,
+            FederationPacket::Players(vec![player("alice"), player("bob")]),
+        )]);
+        let cons = server_state.connections.data.lock().unwrap();
+        assert_eq!(cons.remote_players["alice"].relay_name, "b");
+        assert_eq!(cons.remote_players["bob"].relay_name, "c");
+    }
+}
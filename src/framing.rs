@@ -0,0 +1,203 @@
+use std::io::ErrorKind;
+
+use crate::{common::BUFFER_SIZE, packet::Packet};
+
+/// Most bytes a single frame may hold. Anything larger means the stream lost track of its frames.
+pub const MAX_FRAME_SIZE: usize = BUFFER_SIZE * 4;
+/// Frames start with the size of the packet they carry, as a little endian u32
+const PREFIX_SIZE: usize = 4;
+
+/// Serializes a packet for a byte stream (tcp links, pipes), prefixed with its size.
+/// Streams merge and split what's written to them, the prefix lets the other side find packets back.
+pub fn frame(packet: &Packet) -> Vec<u8> {
+    let data = bincode::serialize(packet).unwrap();
+    let mut frame = Vec::with_capacity(PREFIX_SIZE + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&data);
+    frame
+}
+
+/// Takes the first frame out of bytes read from a stream, returning the packet it carries once it was received whole.
+/// Fails when the stream doesn't make sense anymore, it should then be closed.
+pub fn next_frame(pending: &mut Vec<u8>) -> std::io::Result<Option<Vec<u8>>> {
+    let Some(prefix) = pending.get(..PREFIX_SIZE) else {
+        return Ok(None);
+    };
+    let size = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+    if size > MAX_FRAME_SIZE {
+        println!(
+            "Dropping a stream announcing a frame of {} bytes, it isn't speaking our protocol",
+            size
+        );
+        pending.clear();
+        return Err(ErrorKind::InvalidData.into());
+    }
+    if pending.len() < PREFIX_SIZE + size {
+        return Ok(None);
+    }
+    let packet = pending[PREFIX_SIZE..PREFIX_SIZE + size].to_vec();
+    pending.drain(..PREFIX_SIZE + size);
+    Ok(Some(packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets() -> Vec<Packet> {
+        vec![
+            Packet::Heartbeat("player".to_string()),
+            Packet::GreetingReply,
+            Packet::RttProbe(42),
+            Packet::PlayerLeft("someone else".to_string()),
+        ]
+    }
+
+    fn decode(data: Vec<u8>) -> Packet {
+        bincode::deserialize(&data).unwrap()
+    }
+
+    #[test]
+    fn finds_packets_split_over_reads() {
+        let mut pending = vec![];
+        let mut received = vec![];
+        for byte in packets().iter().flat_map(frame) {
+            pending.push(byte);
+            while let Some(packet) = next_frame(&mut pending).unwrap() {
+                received.push(decode(packet));
+            }
+        }
+        assert!(pending.is_empty());
+        assert_eq!(format!("{:?}", received), format!("{:?}", packets()));
+    }
+
+    #[test]
+    fn finds_packets_merged_in_a_read() {
+        let mut pending: Vec<u8> = packets().iter().flat_map(frame).collect();
+        // Along with the start of another one
+        let next = frame(&Packet::RttProbe(7));
+        pending.extend_from_slice(&next[..3]);
+
+        let mut received = vec![];
+        while let Some(packet) = next_frame(&mut pending).unwrap() {
+            received.push(decode(packet));
+        }
+        assert_eq!(format!("{:?}", received), format!("{:?}", packets()));
+        assert_eq!(pending, next[..3]);
+
+        pending.extend_from_slice(&next[3..]);
+        assert!(matches!(
+            decode(next_frame(&mut pending).unwrap().unwrap()),
+            Packet::RttProbe(7)
+        ));
+    }
+
+    #[test]
+    fn carries_empty_and_large_frames() {
+        let mut pending = 0u32.to_le_bytes().to_vec();
+        assert_eq!(next_frame(&mut pending).unwrap(), Some(vec![]));
+
+        let mut pending = frame(&Packet::Heartbeat("x".repeat(BUFFER_SIZE)));
+        assert!(next_frame(&mut pending).unwrap().is_some());
+    }
+
+    #[test]
+    fn carries_whole_buffers_read_from_local_programs() {
+        use crate::{
+            commands::SocketType,
+            packet::{DataPacket, HOP_LIMIT},
+            socket::SocketWrapper,
+        };
+        use std::{
+            io::Write,
+            net::{TcpListener, TcpStream},
+        };
+
+        let pair = || {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (stream, listener.accept().unwrap().0)
+        };
+        let (mut program, local) = pair();
+        let (player, relay) = pair();
+        let (player, relay) = (
+            SocketWrapper::from_relay_tcp_socket(player),
+            SocketWrapper::from_relay_tcp_socket(relay),
+        );
+
+        // A local program sending more than a buffer at once
+        let data: Vec<u8> = (0..2 * BUFFER_SIZE).map(|i| i as u8).collect();
+        program.write_all(&data).unwrap();
+        let local = SocketWrapper::from_tcp_socket(local);
+        let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+        let mut read = 0;
+        while read < BUFFER_SIZE {
+            read += local.read(&mut buffer[read..BUFFER_SIZE]).unwrap();
+        }
+        let packet = Packet::Data(DataPacket {
+            socket_type: SocketType::Tcp,
+            sender_name: "a player with a rather long name".repeat(8),
+            sender_port: 22000,
+            receiver_name: "HOST".to_string(),
+            receiver_port: 9999,
+            data: buffer[..read].to_vec(),
+            source_port: 40000,
+            hop_limit: HOP_LIMIT,
+            sequence: Some(u64::MAX),
+            reply: false,
+        });
+        player.send_packet(&packet).unwrap();
+
+        let size = relay.read(&mut buffer).unwrap();
+        assert!(size > BUFFER_SIZE);
+        match decode(buffer[..size].to_vec()) {
+            Packet::Data(received) => assert_eq!(received.data, data[..BUFFER_SIZE]),
+            packet => panic!("Expected data, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn holds_back_frames_links_dont_take_yet() {
+        use crate::socket::SocketWrapper;
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let receiver = SocketWrapper::from_relay_tcp_socket(listener.accept().unwrap().0);
+        let sender = SocketWrapper::from_relay_tcp_socket(sender);
+        sender.set_nonblocking(true).unwrap();
+
+        // More than the stream takes before the other side reads
+        const FRAMES: usize = 32;
+        for i in 0..FRAMES {
+            sender
+                .send_packet(&Packet::Heartbeat(i.to_string().repeat(BUFFER_SIZE)))
+                .unwrap();
+        }
+        let reader = std::thread::spawn(move || {
+            let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+            (0..FRAMES)
+                .map(|_| {
+                    let size = receiver.read(&mut buffer).unwrap();
+                    decode(buffer[..size].to_vec())
+                })
+                .collect::<Vec<_>>()
+        });
+        while !reader.is_finished() {
+            sender.flush().unwrap();
+        }
+        for (i, packet) in reader.join().unwrap().into_iter().enumerate() {
+            assert!(
+                matches!(packet, Packet::Heartbeat(name) if name == i.to_string().repeat(BUFFER_SIZE))
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let mut pending = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes().to_vec();
+        pending.extend_from_slice(b"GET / HTTP/1.1");
+        assert!(next_frame(&mut pending).is_err());
+        assert!(pending.is_empty());
+    }
+}
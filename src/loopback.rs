@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+};
+
+use socket2::{Domain, Socket, Type};
+
+/// Addresses of their own in 127.0.0.0/8 for the players talking to our local programs,
+/// for games telling players apart by address
+#[derive(Default)]
+pub struct VirtualIps {
+    by_name: HashMap<String, Ipv4Addr>,
+}
+impl VirtualIps {
+    /// The address of a player, the same one for as long as we run.
+    /// It's the one its name gives unless another player got it first.
+    pub fn get(&mut self, player_name: &str) -> Ipv4Addr {
+        if let Some(address) = self.by_name.get(player_name) {
+            return *address;
+        }
+        let hash = hash_name(player_name);
+        let address = (0..)
+            .map(|offset| nth_address(hash.wrapping_add(offset)))
+            .find(|address| !self.by_name.values().any(|other| other == address))
+            .unwrap();
+        println!("VIRTUAL IP: {} is {}", player_name, address);
+        self.by_name.insert(player_name.to_string(), address);
+        address
+    }
+}
+
+/// FNV-1a, which unlike the hashers of the standard library stays the same across builds
+fn hash_name(player_name: &str) -> u32 {
+    player_name.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// An address of 127.0.0.0/8 other than 127.0.0.1, not ending in 0 or 255 which some programs refuse
+fn nth_address(n: u32) -> Ipv4Addr {
+    let n = n % (256 * 256 * 254 - 1) + 1;
+    Ipv4Addr::new(
+        127,
+        (n / 254 / 256) as u8,
+        (n / 254 % 256) as u8,
+        (n % 254 + 1) as u8,
+    )
+}
+
+/// Connects to a local port from one of our loopback addresses
+pub fn connect_from(local_ip: Ipv4Addr, port: u16) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.bind(&SocketAddr::from((local_ip, 0)).into())?;
+    socket.connect(&SocketAddr::from((Ipv4Addr::LOCALHOST, port)).into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Addresses there are to give out
+    const ADDRESSES: u32 = 256 * 256 * 254 - 1;
+
+    #[test]
+    fn gives_out_loopback_addresses_but_127_0_0_1() {
+        let mut seen = std::collections::HashSet::new();
+        for n in (0..200_000).chain(ADDRESSES - 100_000..ADDRESSES) {
+            let address = nth_address(n);
+            assert!(address.is_loopback(), "{address}");
+            assert_ne!(address, Ipv4Addr::LOCALHOST);
+            assert!(![0, 255].contains(&address.octets()[3]), "{address}");
+            assert!(seen.insert(address), "{address} given twice");
+        }
+        assert_eq!(
+            nth_address(ADDRESSES - 1),
+            Ipv4Addr::new(127, 255, 255, 254)
+        );
+        // Wrapping around
+        assert_eq!(nth_address(ADDRESSES), nth_address(0));
+        assert_eq!(nth_address(u32::MAX), nth_address(u32::MAX % ADDRESSES));
+    }
+
+    #[test]
+    fn tells_players_apart() {
+        let mut virtual_ips = VirtualIps::default();
+        let alice = virtual_ips.get("ALICE");
+        assert_eq!(alice, nth_address(hash_name("ALICE")));
+        assert_eq!(virtual_ips.get("ALICE"), alice);
+        // Another player got the address of BOB first
+        let bob = nth_address(hash_name("BOB"));
+        virtual_ips.by_name.insert("CAROL".to_string(), bob);
+        assert_eq!(
+            virtual_ips.get("BOB"),
+            nth_address(hash_name("BOB").wrapping_add(1))
+        );
+    }
+}
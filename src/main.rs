@@ -2,34 +2,67 @@ pub mod client;
 pub mod commands;
 pub mod common;
 pub mod connections;
+pub mod discovery;
+pub mod failover;
+pub mod federation;
+pub mod framing;
+pub mod loopback;
+pub mod multipath;
+pub mod natsim;
 pub mod packet;
+pub mod pipe;
+pub mod probe;
+pub mod proxy;
+pub mod punch;
+pub mod quic;
+pub mod reliable;
+pub mod replication;
 pub mod server;
 pub mod socket;
+pub mod stun;
+pub mod tunnel;
+pub mod turn;
+pub mod websocket;
 
 use std::{
+    collections::BTreeMap,
     io::{ErrorKind, Read, Write},
     net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
     sync::{mpsc::channel, Arc, Mutex},
     time::{Duration, Instant},
-    u8,
 };
 
-use clap::Parser;
-use client::ClientState;
-use commands::{Args, Commands, SocketType};
+use client::{ClientState, CATALOG_REQUEST_TIMEOUT, SERVICE_RESOLUTION_TIMEOUT};
+use commands::{
+    Args, Commands, Discovery, Exposure, Forward, MigrationPolicy, MultipathMode, Service,
+    SocketType, TcpTransport, TurnUser,
+};
 use common::{
-    accept_connections, handle_connections, handle_udp_traffic, BUFFER_SIZE,
+    accept_connections, handle_connections, handle_udp_traffic, UdpDestination, BUFFER_SIZE,
     DISABLE_NAGLE_ALGORITHM, MAX_QUEUE_SIZE, MINIMUM_TICK_RATE_IN_MS,
 };
-use connections::{Connections, PlayerData};
+use connections::{Connections, InnerConnections, PlayerData};
+use discovery::LanDiscovery;
+use failover::Relays;
+use federation::Federation;
+use framing::MAX_FRAME_SIZE;
+use multipath::Multipath;
 use packet::{
     print_packet, process_packets, CommandPacket, ConnectionPacket, DataPacket, DataPacketLike,
-    GreetingPacket, Packet,
+    GreetingPacket, Packet, HOP_LIMIT,
 };
-use server::ServerState;
+use pipe::{PipeLink, STDIO_SESSION_PORT};
+use probe::probe_relays;
+use proxy::Proxy;
+use punch::DirectPaths;
+use reliable::{ReliableChannel, ReliableSegment};
+use server::{ServerState, RTT_PROBE_INTERVAL};
+use socket::SocketWrapper;
+use stun::StunResponder;
+use tunnel::Origin;
 
 fn main() {
-    let args = Args::parse();
+    let args = Args::parse_valid();
 
     if let Err(e) = thread_priority::set_current_thread_priority(
         thread_priority::ThreadPriority::Crossplatform(5.try_into().unwrap()),
@@ -39,19 +72,89 @@ fn main() {
 
     // Dispatch from cli
     match args.command {
-        Commands::Host { port } => host(port),
+        Commands::Host {
+            port,
+            quic_port,
+            quic_certificate,
+            websocket_port,
+            stdio,
+            migration_policy,
+            public_ports,
+            stun_port,
+            turn_port,
+            turn_users,
+            turn_realm,
+            turn_private_peers,
+            peers,
+            federation_secret,
+            relay_name,
+            upstream,
+            replication_port,
+            standby_of,
+        } => host(
+            port,
+            HostOptions {
+                quic_port,
+                quic_certificate,
+                websocket_port,
+                stdio,
+                migration_policy,
+                public_ports,
+                stun_port,
+                turn_port,
+                turn_users,
+                turn_realm,
+                turn_private_peers,
+                peers,
+                federation_secret,
+                relay_name,
+                upstream,
+                replication_port,
+                standby_of,
+            },
+        ),
         Commands::Connect {
             player_port: port,
             server_address,
             player_name,
             other_player_name,
             other_player_port,
+            forwards,
+            exposures,
+            services,
+            public_ports,
+            discoveries,
+            virtual_ips,
+            host_migration,
+            relay_only,
+            select_relay,
+            multipath,
+            tcp_transport,
+            proxy,
+            proxy_command,
+            relays_file,
         } => connect(
             port,
             server_address,
             player_name,
             other_player_name,
             other_player_port,
+            ConnectOptions {
+                forwards,
+                exposures,
+                services,
+                public_ports,
+                discoveries,
+                virtual_ips,
+                host_migration,
+                relay_only,
+                select_relay,
+                multipath,
+                tcp_transport,
+                proxy,
+                proxy_command,
+                relays_file,
+            },
         ),
         Commands::Ping {
             port,
@@ -59,6 +162,31 @@ fn main() {
             socket,
             data_size,
         } => ping(port, address, socket, data_size),
+        Commands::Services {
+            server_address,
+            player_name,
+        } => list_services(server_address, player_name),
+        Commands::Players {
+            server_address,
+            watch,
+        } => list_players(server_address, watch),
+        Commands::NatCheck {
+            server_address,
+            proxy,
+        } => stun::check_nat(server_address, proxy),
+        Commands::NatSim {
+            port,
+            behaviour,
+            mapping_lifetime,
+            inside_ports,
+            outside_ports,
+        } => natsim::run(
+            port,
+            behaviour,
+            mapping_lifetime,
+            inside_ports,
+            outside_ports,
+        ),
         Commands::Listen { port, socket } => listen(port, socket),
         Commands::Command { address, command } => send_command(address, command),
         Commands::MultiConnect {
@@ -83,16 +211,124 @@ fn main() {
     }
 }
 
-fn host(port: u16) {
+/// What a relay serves besides the links of players on its port, as given to `host`
+struct HostOptions {
+    quic_port: Option<u16>,
+    quic_certificate: String,
+    websocket_port: Option<u16>,
+    stdio: bool,
+    migration_policy: MigrationPolicy,
+    public_ports: bool,
+    stun_port: Option<u16>,
+    turn_port: Option<u16>,
+    turn_users: Vec<TurnUser>,
+    turn_realm: String,
+    turn_private_peers: bool,
+    peers: Vec<String>,
+    federation_secret: Option<String>,
+    relay_name: Option<String>,
+    upstream: Option<String>,
+    replication_port: Option<u16>,
+    standby_of: Option<String>,
+}
+
+fn host(port: u16, options: HostOptions) {
+    let HostOptions {
+        quic_port,
+        quic_certificate,
+        websocket_port,
+        stdio,
+        migration_policy,
+        public_ports,
+        stun_port,
+        turn_port,
+        turn_users,
+        turn_realm,
+        turn_private_peers,
+        peers,
+        federation_secret,
+        relay_name,
+        upstream,
+        replication_port,
+        standby_of,
+    } = options;
+    // Must come before anything gets printed
+    let stdio_session = stdio.then(|| PipeLink::stdio().unwrap());
     println!("Hosting {}", port);
     // Listener uwu
 
-    let server_state = ServerState::new();
+    let udp_socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).unwrap();
+    let mut server_state = ServerState::new();
+    server_state.migration_policy = migration_policy;
+    server_state.public_ports = public_ports;
+    // Udp data arriving over links of other players is sent out of the udp socket too
+    server_state.udp_socket = Some(udp_socket.try_clone().unwrap());
     let connections = server_state.connections.clone();
+    // Players (and anything speaking STUN) may ask the udp socket where it sees them from
+    let stun_socket =
+        stun_port.map(|stun_port| UdpSocket::bind(format!("0.0.0.0:{}", stun_port)).unwrap());
+    let stun_responder = Arc::new(StunResponder::new(
+        udp_socket.try_clone().unwrap(),
+        stun_socket,
+    ));
+    stun_responder.listen_secondary();
+
+    if let Some(quic_port) = quic_port {
+        quic::listen(quic_port, &quic_certificate, connections.clone());
+    }
+    if let Some(websocket_port) = websocket_port {
+        websocket::listen(websocket_port, connections.clone());
+    }
+    if let Some(turn_port) = turn_port {
+        turn::listen(
+            turn_port,
+            turn_users,
+            turn_realm,
+            turn_private_peers,
+            connections.clone(),
+        );
+    }
+    if let Some(secret) = federation_secret.clone() {
+        let relay_name = relay_name.unwrap_or_else(|| format!("relay-{}", port));
+        println!("Federating as {}", relay_name);
+        for peer in peers {
+            federation::dial(peer, connections.clone(), false);
+        }
+        if let Some(upstream) = upstream {
+            println!("Passing data of receivers we don't know on to {}", upstream);
+            federation::dial(upstream, connections.clone(), true);
+        }
+        server_state.federation = Some(Federation::new(relay_name, secret));
+    }
+    // Clap makes sure there's a secret to replicate with
+    let replication_secret = federation_secret.unwrap_or_default();
+    if let Some(replication_port) = replication_port {
+        replication::serve_standby(
+            replication_port,
+            connections.clone(),
+            replication_secret.clone(),
+        );
+    }
+    if let Some(primary) = standby_of {
+        replication::follow_primary(primary, connections.clone(), replication_secret);
+    }
+    let stdio_session_closed = stdio_session.map(|stdio_session| {
+        println!("Serving a session over stdio");
+        let closed = stdio_session.closed_flag();
+        connections.data.lock().unwrap().insert(
+            STDIO_SESSION_PORT,
+            PlayerData::new(
+                stdio_session.remote_address(),
+                SocketWrapper::from_pipe(stdio_session),
+            ),
+        );
+        closed
+    });
 
     // process existing connections - we need to read the data from them and then pass it to the intended receiver
 
     let _cached = Default::default();
+    let mut last_rtt_probe = Instant::now();
     handle_connections(server_state, move |server_state, buffer, _had_one| {
         // let peers: HashSet<u16> = server_state
         // .connections
@@ -107,6 +343,10 @@ fn host(port: u16) {
         let mut disconnected = vec![]; // Disconnected peers
         let mut commands = vec![];
         let mut greetings = vec![];
+        let mut punch_requests = vec![];
+        let mut federation_packets = vec![];
+        let mut broadcasts = vec![];
+        let mut catalog_requests = vec![];
         let mut rejected_packets = vec![]; // Ignored by servers
 
         let mut connections = server_state.connections.clone();
@@ -118,6 +358,10 @@ fn host(port: u16) {
             &mut disconnected,
             &mut commands,
             &mut greetings,
+            &mut punch_requests,
+            &mut federation_packets,
+            &mut broadcasts,
+            &mut catalog_requests,
             buffer,
             &mut rejected_packets,
             false,
@@ -126,17 +370,79 @@ fn host(port: u16) {
             &_cached,
         );
         relay_packets(server_state, &mut packets, &mut connection_packets);
-        process_disconnection(&mut connections, &mut disconnected);
+        // What visitors of public ports send, which comes over no link
+        {
+            let mut locked_connections = connections.data.lock().unwrap();
+            let (visitor_connection_packets, visitor_packets) =
+                tunnel::poll(&mut locked_connections, buffer);
+            for packet in visitor_connection_packets {
+                relay_connection_packet(&mut locked_connections, packet, None);
+            }
+            let udp_socket = server_state.udp_socket.as_ref().unwrap();
+            for packet in visitor_packets {
+                relay_data_packet(&mut locked_connections, udp_socket, packet, Origin::Unknown);
+            }
+        }
+        // Players whose reliable channel stopped answering are gone, even if their link isn't closed yet
+        disconnected.extend(
+            connections
+                .data
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, player)| {
+                    player
+                        .reliable_channel
+                        .as_ref()
+                        .is_some_and(ReliableChannel::is_dead)
+                })
+                .map(|(port, _)| *port),
+        );
+        for player in process_disconnection(&mut connections, &mut disconnected) {
+            let mut locked_connections = connections.data.lock().unwrap();
+            if let Some(relay_name) = player.peer_relay.as_ref() {
+                ServerState::forget_relay(&mut locked_connections, relay_name);
+                continue;
+            }
+            tunnel::release(&mut locked_connections, &player.name);
+            server_state.migrate_host(&mut locked_connections, &player);
+            ServerState::push_player_left(&locked_connections, &player);
+            if !player.services.is_empty() {
+                ServerState::push_empty_catalog(&locked_connections, &player.name);
+            }
+        }
+
+        replication::expire_ghosts(&mut connections.data.lock().unwrap());
+
+        // Measure the rtt of players every now and then, for picking new hosts
+        if last_rtt_probe.elapsed() >= RTT_PROBE_INTERVAL {
+            last_rtt_probe = Instant::now();
+            ServerState::probe_rtts(&connections.data.lock().unwrap());
+        }
 
         // Process received data
         server_state.receive_greetings(greetings);
+        server_state.receive_punch_requests(punch_requests);
+        server_state.receive_federation_packets(federation_packets);
+        server_state.receive_broadcasts(broadcasts);
+        server_state.receive_catalog_requests(catalog_requests);
+        server_state.push_players_to_relays();
         // server_state.receive_data_packets(packets);
         server_state.receive_commands(commands);
+
+        // Whoever started us over stdio is done with us once the session ends
+        if stdio_session_closed
+            .as_ref()
+            .is_some_and(|closed| closed.load(std::sync::atomic::Ordering::Relaxed))
+        {
+            println!("The stdio session ended, shutting down");
+            std::process::exit(0);
+        }
     });
 
     let mut received_packets_counter = 0;
-    let udp_socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).unwrap();
-    // udp_socket.set_nonblocking(true).unwrap();
+    // Non blocking, as reliable udp channels need to be flushed even when nothing arrives
+    udp_socket.set_nonblocking(true).unwrap();
     {
         let connections = connections.clone();
         std::thread::spawn(move || {
@@ -168,26 +474,25 @@ fn host(port: u16) {
                             Packet::Data(data_packet) => {
                                 data_packet.print(format!("host side udp (received: {}): ", received_packets_counter).as_str());
                                 // print_connections(&connections);
-                                let connections = connections.data.lock().unwrap();
+                                let mut connections = connections.data.lock().unwrap();
+                                if let Some(receiver) = connections.get_player_by_name_mut(&data_packet.receiver_name) {
+                                    receiver.relayed_bytes += data_packet.data.len() as u64;
+                                }
                                 if let Some(receiver_tcp_port) = connections.get_player_tcp_port_by_name(&data_packet.receiver_name){
                                     if let Some(player_data) = connections.get(&receiver_tcp_port) {
-                                        let player_local_port = player_data.last_known_udp_port;
-                                        let mut final_address = player_data.address.clone();
-                                        final_address.set_port(player_local_port);
-
-                                    	// println!(
-                                    	    // "RECEIVED UDP PACKET: {} @ {} FOR {}",
-                                    	    // addr, size, final_address
-                                        // );
-
-										println!("final_address for udp: {}", final_address);
-                                        let _ = udp_socket.send_to(&buffer[..size], final_address);
+                                        relay_udp_data(player_data, &udp_socket, data_packet);
 
                                     	// TODO: CONTINUE HERE - we have the final adress - now we need to deliver it as a data packet with udp, then read it on the other side and relay it to the correct destination uwu
 										// TODO: well, do we? isnt this basically finished? :thinkge:
                                     } else  {
 										println!("Connection for the requested port was not found!");
 									}
+                                } else if tunnel::send_to_visitor(&mut connections, &data_packet, Origin::Udp(addr)) {
+                                    // Answered someone that reached the player through a public port
+                                } else if let Some(link) = next_relay_link(&mut connections, &data_packet.receiver_name, None) {
+                                    // The player is on another relay, or our upstream may know it
+                                    link.relayed_bytes += data_packet.data.len() as u64;
+                                    pass_to_relay(link, Packet::Data(data_packet));
                                 } else {
 									println!("Player with a requested name ({}) was no found!", data_packet.receiver_name);
 								}
@@ -202,8 +507,18 @@ fn host(port: u16) {
 									println!("Received a heartbeat but failed to retrieve the player {s} on: {}. This could be due to it being the very first heartbeat.", addr);
 								}
 							}
+                            Packet::Reliable(segment) => {
+                                let mut connections = connections.data.lock().unwrap();
+                                receive_reliable_segment(&mut connections, &udp_socket, segment, addr);
+                            }
+                            Packet::Probe(sent) => {
+                                // A player measuring how fast we answer over udp
+                                let _ = udp_socket.send_to(&bincode::serialize(&Packet::Probe(sent)).unwrap(), addr);
+                            }
                             _ => println!("Received a non data udp packet on the server from {addr}. This shouldn't happen, we only accept data on the udp socket!")
                         }
+                    } else if stun_responder.answer(&buffer[..size], addr, false) {
+                        // println!("Answered a STUN binding request of {addr}");
                     } else {
                         println!(
                             "Received unstructured udp data from {addr} on the server. Weird!"
@@ -211,6 +526,8 @@ fn host(port: u16) {
                     }
                 }
 
+                flush_reliable_channels(&udp_socket, &mut connections.data.lock().unwrap());
+
                 let remaining_millis =
                     MINIMUM_TICK_RATE_IN_MS as f64 - begin.elapsed().as_millis() as f64;
                 if !had_one && remaining_millis > 0. {
@@ -222,15 +539,167 @@ fn host(port: u16) {
     }
 
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    accept_connections(&listener, connections, None);
+    accept_connections(&[listener], connections, None, true);
+}
+
+/// Feeds a segment received over udp to the reliable channel of its player,
+/// then relays every packet the channel managed to reassemble.
+fn receive_reliable_segment(
+    connections: &mut InnerConnections,
+    udp_socket: &UdpSocket,
+    segment: ReliableSegment,
+    addr: SocketAddr,
+) {
+    let Some(player_data) = connections.get_player_by_name_mut(&segment.player_name) else {
+        println!(
+            "Received a reliable segment from an unknown player {} on: {}",
+            segment.player_name, addr
+        );
+        return;
+    };
+    // Segments keep the NAT mapping alive, same as heartbeats
+    player_data.last_known_udp_port = addr.port();
+    let Some(channel) = player_data.reliable_channel.as_mut() else {
+        println!(
+            "Received a reliable segment from {}, who never asked for reliable udp!",
+            segment.player_name
+        );
+        return;
+    };
+    channel.input(segment);
+
+    let mut packets = vec![];
+    while let Some(message) = channel.receive() {
+        match bincode::deserialize::<Packet>(&message) {
+            Ok(Packet::Data(data)) => packets.push(data),
+            _ => println!("Received a non data packet over a reliable udp channel!"),
+        }
+    }
+    for packet in packets {
+        relay_data_packet(connections, udp_socket, packet, Origin::Udp(addr));
+    }
+}
+
+/// Sends pending segments of every reliable channel to their players.
+fn flush_reliable_channels(udp_socket: &UdpSocket, connections: &mut InnerConnections) {
+    for (_, player_data) in connections.iter_mut() {
+        if player_data.last_known_udp_port == 0 {
+            // We don't know where to send the segments yet
+            continue;
+        }
+        if let Some(channel) = player_data.reliable_channel.as_mut() {
+            let mut address = player_data.address;
+            address.set_port(player_data.last_known_udp_port);
+            for segment in channel.flush() {
+                let _ = udp_socket.send_to(
+                    &bincode::serialize(&Packet::Reliable(segment)).unwrap(),
+                    address,
+                );
+            }
+        }
+    }
+}
+
+/// Relays data to its receiver, through the relay it's on when it isn't ours,
+/// or through our upstream when we don't know it.
+fn relay_data_packet(
+    connections: &mut InnerConnections,
+    udp_socket: &UdpSocket,
+    packet: DataPacket,
+    from: Origin,
+) {
+    if let Some(player_data) = connections.get_player_by_name_mut(&packet.receiver_name) {
+        player_data.relayed_bytes += packet.data.len() as u64;
+        if packet.socket_type == SocketType::Udp {
+            relay_udp_data(player_data, udp_socket, packet);
+        } else {
+            relay_tcp_data(player_data, Packet::Data(packet));
+        }
+    } else if tunnel::send_to_visitor(connections, &packet, from) {
+        // Answered someone that reached the player through a public port
+    } else if let Some(link) = next_relay_link(connections, &packet.receiver_name, from.link_port())
+    {
+        link.relayed_bytes += packet.data.len() as u64;
+        pass_to_relay(link, Packet::Data(packet));
+    } else {
+        // println!("Packet delivery to player {} attempted by player at port {} but the target player was not found!", receiver_name, port);
+    }
+}
+
+/// The link of the relay to pass a packet for a player we don't host on to: the relay the player is on,
+/// or else our upstream. Packets from relays we federate with only go on to chained relays,
+/// and packets from our upstream never go back to it.
+fn next_relay_link<'a>(
+    connections: &'a mut InnerConnections,
+    receiver_name: &String,
+    from_port: Option<u16>,
+) -> Option<&'a mut PlayerData> {
+    let from_relay = from_port.is_some_and(|port| is_relay_link(connections, port));
+    let from_upstream = from_port
+        .and_then(|port| connections.get(&port))
+        .is_some_and(|link| link.upstream_relay);
+    if connections
+        .get_relay_link_mut(receiver_name)
+        .is_some_and(|link| !from_relay || link.upstream_relay || link.downstream_relay)
+    {
+        return connections.get_relay_link_mut(receiver_name);
+    }
+    if from_upstream {
+        return None;
+    }
+    connections.get_upstream_link_mut()
+}
+
+/// Passes a data or connection packet on to another relay,
+/// unless it went through too many of them already - ie. relays are chained in a loop.
+fn pass_to_relay(link: &mut PlayerData, mut packet: Packet) {
+    if !packet.take_hop() {
+        println!(
+            "Dropping a packet that went through too many relays, are relays chained in a loop?"
+        );
+        return;
+    }
+    relay_tcp_data(link, packet);
+}
+
+/// Relays udp data to its receiver.
+/// Goes over the relay link of the receiver when it carries udp, through the udp socket otherwise.
+fn relay_udp_data(player_data: &PlayerData, udp_socket: &UdpSocket, packet: DataPacket) {
+    if player_data.stream.carries_udp() || player_data.udp_over_link {
+        if let Err(e) = player_data.stream.send_packet(&Packet::Data(packet)) {
+            println!(
+                "Failed to relay udp data over the link of {}: {}",
+                player_data.name, e
+            );
+        }
+        return;
+    }
+    let player_local_port = player_data.last_known_udp_port;
+    let mut final_address = player_data.address;
+    final_address.set_port(player_local_port);
+
+    // println!(
+    // "RECEIVED UDP PACKET: {} @ FOR {}",
+    // packet.data.len(), final_address
+    // );
+
+    println!("final_address for udp: {}", final_address);
+    let _ = udp_socket.send_to(
+        &bincode::serialize(&Packet::Data(packet)).unwrap(),
+        final_address,
+    );
 }
 
 fn relay_tcp_data(player_data: &mut PlayerData, packet: Packet) {
+    // Players using reliable udp receive tcp data through their channel instead
+    if let (Some(channel), Packet::Data(data)) = (player_data.reliable_channel.as_mut(), &packet) {
+        if data.socket_type == SocketType::Tcp {
+            channel.send(&bincode::serialize(&packet).unwrap());
+            return;
+        }
+    }
     // We need to construct a new packet!
-    if let Err(e) = player_data
-        .stream
-        .write(&bincode::serialize(&packet).unwrap())
-    {
+    if let Err(e) = player_data.stream.send_packet(&packet) {
         match e.kind() {
             std::io::ErrorKind::WouldBlock => {
                 // println!(
@@ -256,40 +725,65 @@ fn relay_packets(
     connection_packets: &mut Vec<(u16, ConnectionPacket)>,
 ) {
     let mut locked_connections = server.connections.data.lock().unwrap();
-    for (_port, packet) in packets {
-        // We need to find the player to retrieve the data from.
-        let receiver_name = packet.receiver_name.clone();
-        // println!("Relaying a packet to {}", receiver_name);
-        if let Some((_, player_data)) = locked_connections
-            .iter_mut()
-            .find(|element| element.1.name == receiver_name)
-        {
-            relay_tcp_data(player_data, Packet::Data(packet.clone()));
-        } else {
-            // println!("Packet delivery to player {} attempted by player at port {} but the target player was not found!", receiver_name, port);
-        }
+    let udp_socket = server.udp_socket.as_ref().unwrap();
+    for (port, packet) in packets {
+        // println!("Relaying a packet to {}", packet.receiver_name);
+        relay_data_packet(
+            &mut locked_connections,
+            udp_socket,
+            packet.clone(),
+            Origin::Link(*port),
+        );
     }
-    for (_port, connection_packet) in connection_packets {
-        // We need to find the player to retrieve the data from.
-        let receiver_name = connection_packet.receiver_name.clone();
-        // println!("Relaying a packet to {}", receiver_name);
-        if let Some((_, player_data)) = locked_connections
-            .iter_mut()
-            .find(|element| element.1.name == receiver_name)
-        {
-            relay_tcp_data(player_data, Packet::Connection(connection_packet.clone()));
-        } else {
-            // println!("Packet delivery to player {} attempted by player at port {} but the target player was not found!", receiver_name, port);
-        }
+    for (port, connection_packet) in connection_packets {
+        relay_connection_packet(
+            &mut locked_connections,
+            connection_packet.clone(),
+            Some(*port),
+        );
     }
 }
 
-fn process_disconnection(connections: &mut Connections, disconnected: &mut Vec<u16>) {
+/// Passes a connection packet to its receiver, or to the relay it's on
+fn relay_connection_packet(
+    connections: &mut InnerConnections,
+    connection_packet: ConnectionPacket,
+    from_port: Option<u16>,
+) {
+    // We need to find the player to retrieve the data from.
+    let receiver_name = connection_packet.receiver_name.clone();
+    // println!("Relaying a packet to {}", receiver_name);
+    if let Some((_, player_data)) = connections
+        .iter_mut()
+        .find(|element| element.1.name == receiver_name)
+    {
+        relay_tcp_data(player_data, Packet::Connection(connection_packet));
+    } else if let Some(link) = next_relay_link(connections, &receiver_name, from_port) {
+        pass_to_relay(link, Packet::Connection(connection_packet));
+    } else {
+        // println!("Packet delivery to player {} attempted by player at port {} but the target player was not found!", receiver_name, port);
+    }
+}
+
+/// Whether packets from a link came from a relay we federate with
+fn is_relay_link(connections: &InnerConnections, port: u16) -> bool {
+    connections
+        .get(&port)
+        .is_some_and(|link| link.peer_relay.is_some() || link.dialed_relay)
+}
+
+/// Removes disconnected peers, returning their data
+fn process_disconnection(
+    connections: &mut Connections,
+    disconnected: &mut Vec<u16>,
+) -> Vec<PlayerData> {
     let mut locked_connections = connections.data.lock().unwrap();
+    let mut removed = vec![];
     for disconnect in disconnected {
         println!("Disconnecting: {}", disconnect);
-        locked_connections.remove(disconnect);
+        removed.extend(locked_connections.remove(disconnect));
     }
+    removed
 }
 
 fn mass_connect(
@@ -299,54 +793,64 @@ fn mass_connect(
     lower_port: u16,
     upper_port: u16,
 ) {
-    for port in lower_port..upper_port + 1 {
-        let relay_server_address = relay_server_address.clone();
-        let other_player_name = other_player_name.clone();
-        let player_name = player_name.clone();
-        std::thread::spawn(move || {
-            connect(
-                port,
-                relay_server_address,
-                format!("{player_name}_{port}"),
-                other_player_name,
-                port,
-            );
-        });
-        std::thread::sleep(Duration::from_millis(500));
-    }
-
-    println!("Multi connection established!");
-    loop {
-        std::thread::sleep(Duration::from_millis(50));
-    }
+    multi_connect(
+        relay_server_address,
+        other_player_name,
+        player_name,
+        (lower_port..=upper_port).collect(),
+    );
 }
 
+/// Forwards every port to the same port of the other player, all of them sharing a single session.
 fn multi_connect(
     relay_server_address: String,
     other_player_name: String,
     player_name: String,
     player_client_port: Vec<u16>,
 ) {
-    for port in player_client_port {
-        let relay_server_address = relay_server_address.clone();
-        let other_player_name = other_player_name.clone();
-        let player_name = player_name.clone();
-        std::thread::spawn(move || {
-            connect(
-                port,
-                relay_server_address,
-                format!("{player_name}_{port}"),
-                other_player_name,
-                port,
-            );
-        });
-        std::thread::sleep(Duration::from_millis(500));
-    }
+    let (first_port, other_ports) = player_client_port
+        .split_first()
+        .expect("clap asks for at least one port");
+    let forwards = other_ports
+        .iter()
+        .map(|port| Forward {
+            local_port: *port,
+            player_name: None,
+            remote_port: *port,
+            service: None,
+            socket_type: None,
+        })
+        .collect();
+    connect(
+        *first_port,
+        relay_server_address,
+        player_name,
+        other_player_name,
+        Some(*first_port),
+        ConnectOptions {
+            forwards,
+            ..Default::default()
+        },
+    );
+}
 
-    println!("Multi connection established!");
-    loop {
-        std::thread::sleep(Duration::from_millis(50));
-    }
+/// What a player may ask of its session besides the player it sends its data to, as given to `connect`
+#[derive(Default)]
+struct ConnectOptions {
+    forwards: Vec<Forward>,
+    exposures: Vec<Exposure>,
+    services: Vec<Service>,
+    public_ports: Vec<Exposure>,
+    discoveries: Vec<Discovery>,
+    virtual_ips: bool,
+    host_migration: bool,
+    relay_only: bool,
+    select_relay: bool,
+    multipath: Option<MultipathMode>,
+    tcp_transport: TcpTransport,
+    proxy: Option<Proxy>,
+    proxy_command: Option<String>,
+    relays_file: Option<String>,
 }
 
 fn connect(
@@ -354,73 +858,386 @@ fn connect(
     relay_server_address: String,
     player_name: String,
     other_player_name: String,
-    other_player_port: u16,
+    other_player_port: Option<u16>,
+    options: ConnectOptions,
 ) {
+    let ConnectOptions {
+        forwards,
+        exposures,
+        services,
+        public_ports,
+        discoveries,
+        virtual_ips,
+        host_migration,
+        relay_only,
+        select_relay,
+        multipath,
+        tcp_transport,
+        proxy,
+        proxy_command,
+        relays_file,
+    } = options;
     println!("Connecting on {}", player_client_port);
-    // The stream that talks to the server
-    let mut local_outgoing_stream = TcpStream::connect(relay_server_address.clone()).unwrap();
-    local_outgoing_stream
-        .set_nodelay(DISABLE_NAGLE_ALGORITHM)
-        .unwrap();
+    // The other player may be given along with one of its services, in place of a port
+    let (other_player_name, other_service) = match other_player_name.split_once('/') {
+        Some((name, service)) => (name.to_string(), Some(service.to_string())),
+        None => (other_player_name, None),
+    };
+    // Arguments give one or the other. The port of a service is known once the server sends us the catalog of the player
+    let other_player_port = other_player_port.unwrap_or(0);
+    for service in services.iter() {
+        println!("Publishing {}", service);
+    }
+    for public_port in public_ports.iter() {
+        println!("Asking the relay to make {} public", public_port.port);
+    }
+    // Connecting to ourselves makes us the host of the session, letting others reach any of our ports
+    let exposures = if player_name == other_player_name && exposures.is_empty() {
+        println!("Exposing every local port");
+        None
+    } else {
+        for exposure in exposures.iter() {
+            println!(
+                "Exposing {} ({})",
+                exposure.port,
+                match exposure.socket_type {
+                    Some(SocketType::Tcp) => "tcp",
+                    Some(SocketType::Udp) => "udp",
+                    None => "tcp and udp",
+                }
+            );
+        }
+        // Published services and public ports are exposed
+        Some(
+            exposures
+                .into_iter()
+                .chain(services.iter().map(|service| Exposure {
+                    port: service.port,
+                    socket_type: service.socket_type,
+                }))
+                .chain(public_ports.iter().cloned())
+                .collect::<Vec<_>>(),
+        )
+    };
+    // The stream that talks to the server, to the first relay that answers
+    let mut relays = match Relays::new(
+        &relay_server_address,
+        relays_file.as_deref(),
+        proxy,
+        proxy_command,
+    ) {
+        Ok(relays) => relays,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Trying the fastest relays first, when asked to
+    let relay_latencies = if select_relay {
+        let measurements = probe_relays(&relays);
+        let latencies = relays.sort_by_latency(&measurements);
+        for (address, latency) in latencies.iter() {
+            println!(
+                "Relay {} answers in {:.2}ms",
+                address,
+                *latency as f64 / 1000.
+            );
+        }
+        latencies
+    } else {
+        vec![]
+    };
+    let local_outgoing_stream = loop {
+        if let Some(link) = relays.link(0) {
+            break link;
+        }
+        relays.wait_to_retry();
+    };
+    let relay_server_address = relays.current_address().clone();
+    // Links that can't carry udp data go along with a separate udp socket talking to the relay
+    let mut udp_association = None;
+    let relay_udp_address = if local_outgoing_stream.carries_udp() {
+        None
+    } else if let Some(proxy) = relays.proxy() {
+        match proxy.associate_udp() {
+            Ok(association) => {
+                udp_association = Some(association);
+                relays.reaches_udp = true;
+                Some(relay_server_address.clone())
+            }
+            Err(e) => {
+                println!(
+                    "The proxy can't carry udp ({}), sending udp traffic over the tcp link",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        Some(relay_server_address.clone())
+    };
+    // The relay can't tell a plain tcp link apart from one going through a pipe, so let it know
+    let udp_over_link = relay_udp_address.is_none();
+    if tcp_transport == TcpTransport::Kcp && relay_udp_address.is_none() {
+        println!(
+            "Reliable udp can only be used along with a tcp link to the relay, and we can't send udp to {}!",
+            relay_server_address
+        );
+        std::process::exit(1);
+    }
+    // Direct paths need our udp socket to be reachable by others like the relay reaches it
+    let direct = !relay_only && multipath.is_none() && relay_udp_address.is_some();
+    // Behind a proxy, others can only reach us at the address the relay knows us by
+    let udp_candidates = direct.then(|| match local_outgoing_stream.get_local_tcp_addr() {
+        Ok(address) if udp_association.is_none() && !address.ip().is_unspecified() => {
+            vec![SocketAddr::new(address.ip(), player_client_port)]
+        }
+        _ => vec![],
+    });
+    let greeting = GreetingPacket {
+        player_name: player_name.clone(),
+        local_port: player_client_port,
+        tcp_transport,
+        udp_over_link,
+        forwarded_ports: std::iter::once(player_client_port)
+            .chain(forwards.iter().map(|forward| forward.local_port))
+            .collect(),
+        exposed_ports: exposures
+            .as_ref()
+            .map(|exposures| exposures.iter().map(|exposure| exposure.port).collect()),
+        services,
+        presence: true,
+        observer: false,
+        other_player_name: other_player_name.clone(),
+        host_migration,
+        udp_candidates,
+        relay_latencies,
+        token: None,
+        public_ports,
+    };
     // ALWAYS begin by sending our name!
     local_outgoing_stream
-        .write(
-            &bincode::serialize(&Packet::Greeting(GreetingPacket {
-                player_name: player_name.clone(),
-                local_port: player_client_port,
-            }))
-            .unwrap()[..],
-        )
+        .send_packet(&Packet::Greeting(greeting.clone()))
         .unwrap();
     local_outgoing_stream.set_nonblocking(true).unwrap(); // set non blocking AFTER we send the packet
 
     let udp_packet_queue_size = Arc::new(Mutex::new(0u64));
-    let (udp_packet_sender, udp_packet_receiver) = channel::<(u16, Vec<u8>)>();
+    let (udp_packet_sender, udp_packet_receiver) = channel::<(SocketAddr, Vec<u8>)>();
     let relay_queue_size = Arc::new(Mutex::new(0u64));
-    let (relay_packet_sender, relay_packet_receiver) = channel::<(String, Vec<u8>)>();
+    let (relay_packet_sender, relay_packet_receiver) = channel::<(UdpDestination, Vec<u8>)>();
     let udp_packet_queue_size_cloned = udp_packet_queue_size.clone();
     let relay_queue_size_cloned = relay_queue_size.clone();
 
     // Incomming stream
-    let client: ClientState = ClientState::new(
-        player_name.clone(),
-        player_client_port,
-        other_player_name.clone(),
+    let mut client: ClientState = ClientState::new(
+        greeting,
         other_player_port,
+        local_outgoing_stream,
+        relays,
+        relay_udp_address,
+        relay_packet_sender,
     );
+    client.relay_queue_size = relay_queue_size;
+    client.exposures = exposures;
+    client.forwards[0].service = other_service;
+    if direct {
+        client.direct_paths = Some(DirectPaths::new(player_name.clone()));
+    }
+    if let Some(mode) = multipath {
+        if client.relay_udp_address.lock().unwrap().is_none() {
+            println!(
+                "Sending over several relays needs a udp socket reaching them, and we can't send udp to {}!",
+                relay_server_address
+            );
+            std::process::exit(1);
+        }
+        client.multipath = Some(Multipath::new(mode, &client.relays, &client.greeting));
+    }
+    if !discoveries.is_empty() {
+        client.discovery = Some(LanDiscovery::new(discoveries));
+    }
+    if virtual_ips {
+        client.virtual_ips = Some(Default::default());
+    }
+    // The first port is handled by the listener and udp thread below, the others get their own sockets
+    let mut listeners = vec![];
+    for forward in forwards {
+        println!(
+            "Forwarding {} to {} ({})",
+            forward.local_port,
+            match forward.service.as_ref() {
+                Some(service) => format!("{}/{}", client.get_forward_target(&forward), service),
+                None => format!(
+                    "{}:{}",
+                    client.get_forward_target(&forward),
+                    forward.remote_port
+                ),
+            },
+            match forward.socket_type {
+                Some(SocketType::Tcp) => "tcp",
+                Some(SocketType::Udp) => "udp",
+                None => "tcp and udp",
+            }
+        );
+        if forward.carries(SocketType::Tcp) {
+            listeners.push(TcpListener::bind(format!("0.0.0.0:{}", forward.local_port)).unwrap());
+        }
+        if forward.carries(SocketType::Udp) {
+            let udp_socket = UdpSocket::bind(format!("0.0.0.0:{}", forward.local_port)).unwrap();
+            udp_socket.set_nonblocking(true).unwrap();
+            client
+                .forward_udp_sockets
+                .insert(forward.local_port, udp_socket);
+        }
+        client.forwards.push(forward);
+    }
+    // Our ports take any local address, the players we forward to can be reached at theirs
+    // Taking the addresses of the players we forward to, which they're seen from when they connect to us
+    if virtual_ips {
+        let targets: Vec<(String, u16)> = client
+            .forwards
+            .iter()
+            .map(|forward| (client.get_forward_target(forward), forward.local_port))
+            .filter(|(target, _)| *target != player_name)
+            .collect();
+        for (target, local_port) in targets {
+            if let Some(address) = client.virtual_ip(&target) {
+                println!("Reach {} at {}:{}", target, address, local_port);
+            }
+        }
+    }
+    if tcp_transport == TcpTransport::Kcp {
+        println!("Sending tcp data over reliable udp");
+        client.reliable_channel = Some(Arc::new(Mutex::new(ReliableChannel::new(
+            player_name.clone(),
+        ))));
+    }
+    // Nothing can be forwarded to services before we know their ports
+    let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+    let begin = Instant::now();
+    let mut waiting_since = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    while !client.has_resolved_services() {
+        if receive_server_packets(&mut client, &mut buffer) {
+            continue;
+        }
+        if begin.elapsed() > SERVICE_RESOLUTION_TIMEOUT {
+            for forward in client.forwards.iter() {
+                if let (Some(service), 0) = (forward.service.as_ref(), forward.remote_port) {
+                    println!(
+                        "{} doesn't publish {}, is it in the room?",
+                        client.get_forward_target(forward),
+                        service
+                    );
+                }
+            }
+            std::process::exit(1);
+        }
+        if waiting_since.elapsed().as_secs() >= 5 {
+            waiting_since = Instant::now();
+            println!("Waiting for the players we forward to to publish their services...");
+        }
+        if last_heartbeat.elapsed().as_millis() > 500 {
+            last_heartbeat = Instant::now();
+            // Going unanswered, they tell us the relay is gone
+            let _ = client
+                .relay
+                .send_packet(&Packet::Heartbeat(player_name.clone()));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let reliable_channel = client.reliable_channel.clone();
+    let relay_udp_address = client.relay_udp_address.clone();
     let connections = client.connections.clone();
 
     let (connection_sender, connection_receiver) = channel::<SocketAddr>();
 
     let mut last_heartbeat = std::time::Instant::now();
-    // let relay_server_address = relay_server_address.clone();
-    let relay_server_address_cloned = relay_server_address.clone();
     let player_name_cloned = player_name.clone();
     handle_connections(client, move |client, buffer, had_one| {
         // Before anything else, announce new connections to the server
         while let Ok(socket) = connection_receiver.try_recv() {
             println!("RELAYING CONNECTION FROM: {}", socket);
-            local_outgoing_stream
-                .write(
-                    &bincode::serialize(&Packet::Connection(ConnectionPacket {
-                        sender_name: client.player_name.clone(),
-                        sender_port: client.player_port,
-                        receiver_name: client.other_player_name.clone(),
-                        receiver_port: client.other_player_port,
-                        source_port: socket.port(),
-                    }))
-                    .unwrap(),
-                )
-                .unwrap();
+            // Find out which of the forwarded ports it came through
+            let local_port = client
+                .connections
+                .data
+                .lock()
+                .unwrap()
+                .get(&socket.port())
+                .and_then(|player_data| player_data.stream.get_local_tcp_addr().ok())
+                .map(|address| address.port())
+                .unwrap_or(client.player_port);
+            client.tcp_forwards.insert(socket.port(), local_port);
+            let forward = client.get_forward(local_port);
+            let _ = client
+                .relay
+                .send_packet(&Packet::Connection(ConnectionPacket {
+                    sender_name: client.player_name.clone(),
+                    sender_port: client.player_port,
+                    receiver_name: client.get_forward_target(forward),
+                    receiver_port: forward.remote_port,
+                    source_port: socket.port(),
+                    hop_limit: HOP_LIMIT,
+                }));
         }
 
         // Every now and then, send a heartbeat packet over TCP UwU
         // While TCP itself should work, we wanna make sure no NAT shenanigans stops the game from working
         if last_heartbeat.elapsed().as_millis() > 500 {
             last_heartbeat = std::time::Instant::now();
-            local_outgoing_stream
-                .write(&bincode::serialize(&Packet::Heartbeat(player_name_cloned.clone())).unwrap())
-                .unwrap();
+            let _ = client
+                .relay
+                .send_packet(&Packet::Heartbeat(player_name_cloned.clone()));
+        }
+
+        // Try to reach the players we send data to directly, skipping the relay
+        if let Some(direct_paths) = client.direct_paths.as_mut() {
+            let mut targets: Vec<String> = client
+                .forwards
+                .iter()
+                .map(|forward| {
+                    forward
+                        .player_name
+                        .clone()
+                        .unwrap_or(client.other_player_name.clone())
+                })
+                .collect();
+            targets.sort();
+            targets.dedup();
+            for target in targets {
+                if direct_paths.wants_introduction(&target) {
+                    let _ = client.relay.send_packet(&Packet::PunchRequest(target));
+                }
+            }
+            let punches = direct_paths.tick();
+            for (address, punch) in punches {
+                client.send_udp_to(
+                    address.to_string(),
+                    bincode::serialize(&Packet::Punch(punch)).unwrap(),
+                );
+            }
+        }
+
+        // Keep the sessions to the other relays we send over alive, timing every path
+        if let Some(multipath) = client.multipath.as_mut() {
+            let datagrams = multipath.tick(&client.player_name);
+            let packets = multipath.receive_packets(buffer);
+            for (address, packet) in datagrams {
+                client.send_udp_to(address.to_string(), bincode::serialize(&packet).unwrap());
+            }
+            for (index, packet) in packets {
+                *had_one = true;
+                match packet {
+                    // Tcp data of players linked to that relay
+                    // and probes of that relay timing its link
+                    Packet::Data(_) | Packet::Connection(_) | Packet::RttProbe(_) => {
+                        handle_server_packet(client, packet, Some(index))
+                    }
+                    // Presence and catalogs come from the relay we're linked to
+                    _ => {}
+                }
+            }
         }
 
         /*
@@ -438,6 +1255,7 @@ fn connect(
         let mut disconnected = vec![]; // Disconnected peers
         let mut commands = vec![]; // Ignored by clients
         let mut greetings = vec![]; // Ignored by clients
+        let mut punch_requests = vec![]; // Ignored by clients
         let mut rejected_packets = vec![]; // Ignored by servers
 
         let mut connections = client.connections.clone();
@@ -449,18 +1267,32 @@ fn connect(
             &mut disconnected,
             &mut commands,
             &mut greetings,
+            &mut punch_requests,
+            &mut Default::default(),
+            &mut Default::default(),
+            &mut Default::default(),
             buffer,
             &mut rejected_packets,
-            client.is_host(),
+            false, // local connections always come through our forwarded ports
             client.other_player_name.clone(),
             client.other_player_port,
             &client.local_redirection_table,
         );
+        for disconnect in disconnected.iter() {
+            client.tcp_forwards.remove(disconnect);
+        }
         process_disconnection(&mut connections, &mut disconnected);
 
         // These are the packets we received on the listener (should all always be local)
         // We will re-route them to the server.
         for (receiver_name, receiver_port, packet, source_port) in rejected_packets {
+            // Every local connection carries its own target
+            let forward = client.get_tcp_forward(source_port);
+            let (receiver_name, receiver_port) = if client.tcp_forwards.contains_key(&source_port) {
+                (client.get_forward_target(forward), forward.remote_port)
+            } else {
+                (receiver_name, receiver_port)
+            };
             print_packet(
                 "local reject :: ",
                 "self".to_string(),
@@ -475,24 +1307,21 @@ fn connect(
             // "self:{source_port} --(Tcp)--> {receiver_name}:{receiver_port} @ {}",
             // packet.len()
             // );
-            local_outgoing_stream
-                .write(
-                    &bincode::serialize(&Packet::Data(DataPacket {
-                        socket_type: SocketType::Tcp,
-                        sender_name: client.player_name.clone(),
-                        sender_port: client.player_port,
-                        receiver_name,
-                        receiver_port: if client.is_host() {
-                            source_port
-                        } else {
-                            receiver_port
-                        },
-                        data: packet,
-                        source_port,
-                    }))
-                    .unwrap()[..],
-                )
-                .unwrap(); // TODO: verify that this is OK
+            // Fails while the relay is gone, before we move to another one
+            if let Err(e) = client.send_tcp_data(DataPacket {
+                socket_type: SocketType::Tcp,
+                sender_name: client.player_name.clone(),
+                sender_port: client.player_port,
+                receiver_name,
+                receiver_port,
+                data: packet,
+                source_port,
+                hop_limit: HOP_LIMIT,
+                sequence: None,
+                reply: false,
+            }) {
+                println!("Failed to relay local tcp data: {}", e);
+            }
         }
         // Remember to also relay UDP!
         /*
@@ -507,133 +1336,78 @@ fn connect(
             );
         }
         */
-        while let Ok((udp_port, data)) = udp_packet_receiver.try_recv() {
+        while let Ok((udp_address, data)) = udp_packet_receiver.try_recv() {
             *udp_packet_queue_size.lock().unwrap() -= 1;
 
-            //
-            if client.is_host() {
-                // We're the host!
-                // We need to parse the packet to check if it's a data packet.
-                // If it is, we need to ensure we have the udp socket existing
+            // Both data the relay sent us, and data of local programs talking to the first port
+            if *client.relay_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
                 if let Ok(packet) = bincode::deserialize::<Packet>(&data) {
                     // Data packet
                     match packet {
                         Packet::Data(data_packet) => {
-                            client.ensure_udp_socket_on_redirection_table(&data_packet);
-
-                            data_packet.print("RELATING A DATA PACKET TO A LOCAL CONNECTION: ");
-
-                            let player_identifier = data_packet.get_original_player_identifier();
-                            // println!("IDENTIFIER: {}", player_identifier);
-                            // client.ensure_udp_socket_on_redirection_table(data);
-                            if let Some(local_client_connection) =
-                                client.local_redirection_table.get(&player_identifier)
-                            {
-                                // println!("RELAYING TO: 127.0.0.1:{}", data_packet.receiver_port);
-                                let _ = local_client_connection
-                                    .udp_socket
-                                    .as_ref()
-                                    .unwrap()
-                                    .send_to(
-                                        &data_packet.data,
-                                        format!("127.0.0.1:{}", data_packet.receiver_port),
-                                    );
-                                // let (player_name, _player_port, source_port) = (
-                                // local_client_connection.player_name.clone(),
-                                // local_client_connection.port,
-                                // local_client_connection.original_socket_port,
-                                // );
-                                // println!("redirection table contains an entry for the udp port {udp_port}");
-                                // (player_name, source_port.clone(), source_port.clone());
-                            } else {
-                                panic!("redirection table DOESNT contain an entry for the udp port {udp_port}");
+                            if let Some(direct_paths) = client.direct_paths.as_mut() {
+                                direct_paths.receive_data(&data_packet.sender_name, udp_address);
+                            }
+                            if client.is_first_copy(&data_packet, Some(udp_address)) {
+                                client.receive_udp_data(data_packet);
                             }
                         }
                         Packet::Heartbeat(_) => {
-                            // ignore it, the server is just pinging us back
+                            // the server is just pinging us back, telling us it's healthy
                             // println!("heartbeat: {}", udp_port);
+                            // Other relays we send over echo theirs too, only the one we're linked to counts
+                            if client.multipath.as_ref().is_none_or(|multipath| {
+                                multipath.is_from(udp_address, client.relays.current_address())
+                            }) {
+                                client.relays.receive_echo();
+                            }
+                        }
+                        Packet::Probe(sent) => {
+                            // A relay we send over timing its path
+                            if let Some(multipath) = client.multipath.as_mut() {
+                                multipath.receive_probe(udp_address, sent);
+                            }
+                        }
+                        Packet::Punch(punch) => {
+                            // Another player reaching us directly
+                            if let Some((address, answer)) =
+                                client.direct_paths.as_mut().and_then(|direct_paths| {
+                                    direct_paths.receive_punch(punch, udp_address)
+                                })
+                            {
+                                client.send_udp_to(
+                                    address.to_string(),
+                                    bincode::serialize(&Packet::Punch(answer)).unwrap(),
+                                );
+                            }
                         }
                         _ => {
-                            panic!("NOT A DATA PACKET!");
+                            // ignore
+                            println!("RECEIVED WEIRD STRUCTURED DATA!");
                         }
                     }
                 } else {
-                    // Not a data packet, instead, its unstructured information
-                    panic!("UNSTRUCTURED DATA!");
+                    client
+                        .udp_forwards
+                        .insert(udp_address.port(), client.player_port);
+                    let data_packet = DataPacket {
+                        socket_type: SocketType::Udp,
+                        sender_name: client.player_name.clone(),
+                        sender_port: client.player_port,
+                        receiver_name: client.other_player_name.clone(),
+                        receiver_port: client.other_player_port,
+                        data,
+                        source_port: udp_address.port(), // TODO: fix this? If it's even an issue
+                        hop_limit: HOP_LIMIT,
+                        sequence: None,
+                        reply: false,
+                    };
+                    data_packet.print("RELAYING TO SERVER ");
+                    client.send_udp_data(data_packet);
                 }
-
-            /*
-            let player_identifier = data.get_original_player_identifier();
-            println!("IDENTIFIER: {}", player_identifier);
-            // client.ensure_udp_socket_on_redirection_table(data);
-            if let Some(local_client_connection) =
-                client.local_redirection_table.get(&player_identifier)
-            {
-                let (player_name, _player_port, source_port) = (
-                    local_client_connection.player_name.clone(),
-                    local_client_connection.port,
-                    local_client_connection.original_socket_port,
-                );
-                // println!("redirection table contains an entry for the udp port {udp_port}");
-                (player_name, source_port.clone(), source_port.clone())
             } else {
-                panic!("redirection table DOESNT contain an entry for the udp port {udp_port}");
-                // TODO: should we really panic?
-                // (
-                // client.other_player_name.clone(),
-                // client.other_player_port.clone(),
-                // udp_port,
-                // )
+                println!("DROPPING A UDP PACKET! Queue over capacity...");
             }
-            */
-            } else {
-                if *relay_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
-                    if let Ok(packet) = bincode::deserialize::<Packet>(&data) {
-                        // Data packet
-                        match packet {
-                            Packet::Data(data_packet) => {
-                                data_packet.print("RECEIVED FOR RELAY ");
-
-                                relay_packet_sender
-                                    .send((
-                                        format!("127.0.0.1:{}", data_packet.receiver_port),
-                                        data_packet.data,
-                                    ))
-                                    .unwrap();
-                                *relay_queue_size.lock().unwrap() += 1;
-                            }
-                            Packet::Heartbeat(_) => {
-                                // ignore it, the server is just pinging us back
-                                // println!("heartbeat: {}", udp_port);
-                            }
-                            _ => {
-                                // ignore
-                                println!("RECEIVED WEIRD STRUCTURED DATA!");
-                            }
-                        }
-                    } else {
-                        let data_packet = DataPacket {
-                            socket_type: SocketType::Udp,
-                            sender_name: client.player_name.clone(),
-                            sender_port: client.player_port,
-                            receiver_name: client.other_player_name.clone(),
-                            receiver_port: client.other_player_port,
-                            data,
-                            source_port: udp_port, // TODO: fix this? If it's even an issue
-                        };
-                        data_packet.print("RELAYING TO SERVER ");
-                        relay_packet_sender
-                            .send((
-                                relay_server_address.clone(),
-                                bincode::serialize(&Packet::Data(data_packet)).unwrap(),
-                            ))
-                            .unwrap();
-                        *relay_queue_size.lock().unwrap() += 1;
-                    }
-                } else {
-                    println!("DROPPING A UDP PACKET! Queue over capacity...");
-                }
-            };
             // println!("UDP relay through server for {other_player_name}:{other_player_port} ({source_port}) @ {}", data.len());
             // using udp
             //*
@@ -650,6 +1424,8 @@ fn connect(
                         receiver_port: other_player_port,
                         data,
                         source_port, // TODO: fix this? If it's even an issue
+                        hop_limit: HOP_LIMIT,
+                        sequence: None,
                     }))
                     .unwrap()[..],
                 )
@@ -657,13 +1433,8 @@ fn connect(
                            // */
         }
 
-        // After reading packets, we also need to receive packets from the server...
-        if let Ok(data) = local_outgoing_stream.peek(buffer) {
-            if data == 0 {
-                panic!("SERVER TIMEOUT!");
-            }
-        }
         // Receive packets from connected clients and send them to the server...
+        let mut outgoing_packets = vec![];
         for (_, local_connection) in client.local_redirection_table.iter_mut() {
             //
             // println!(
@@ -672,8 +1443,9 @@ fn connect(
             // local_connection.port,
             // local_connection.original_socket_port
             // );
-            if local_connection.stream.is_some() {
-                if let Ok(size) = local_connection.stream.as_ref().unwrap().read(buffer) {
+            if let Some(mut stream) = local_connection.stream.as_ref() {
+                // No more than a buffer, leaving room in frames for the packet wrapping it
+                if let Ok(size) = stream.read(&mut buffer[..BUFFER_SIZE]) {
                     *had_one = true;
 
                     let data = &buffer[..size];
@@ -692,33 +1464,13 @@ fn connect(
                             receiver_name: local_connection.player_name.clone(),
                             receiver_port: local_connection.original_socket_port,
                             data: data.to_vec(),
-                            source_port: local_connection
-                                .stream
-                                .as_ref()
-                                .unwrap()
-                                .peer_addr()
-                                .unwrap()
-                                .port(),
+                            source_port: stream.peer_addr().unwrap().port(),
+                            hop_limit: HOP_LIMIT,
+                            sequence: None,
+                            reply: true,
                         };
                         packet.print("SENDING TO THE SERVER: ");
-                        let data_packet = Packet::Data(packet);
-                        if let Err(e) =
-                            local_outgoing_stream.write(&bincode::serialize(&data_packet).unwrap())
-                        {
-                            match e.kind() {
-                                ErrorKind::WouldBlock => {
-                                    //
-                                }
-                                _ => {
-                                    println!(
-                                        "Failed to relay unstructured data for {}:{} ({})",
-                                        local_connection.player_name,
-                                        local_connection.port,
-                                        local_connection.original_socket_port
-                                    )
-                                }
-                            }
-                        }
+                        outgoing_packets.push(packet);
                     }
                 } else {
                     // Failed to read
@@ -726,13 +1478,8 @@ fn connect(
             } else {
                 // No tcp stream
             }
-            if local_connection.udp_socket.is_some() {
-                if let Ok((size, addr)) = local_connection
-                    .udp_socket
-                    .as_ref()
-                    .unwrap()
-                    .recv_from(buffer)
-                {
+            if let Some(udp_socket) = local_connection.udp_socket.as_ref() {
+                if let Ok((size, addr)) = udp_socket.recv_from(buffer) {
                     *had_one = true;
 
                     local_connection.received_udp_packets_counts += 1;
@@ -748,8 +1495,8 @@ fn connect(
                     if let Ok(_r) = bincode::deserialize::<Packet>(data) {
                         // Structured data, shouldnt happen...
                         println!("Received structured data on a local client udp socket! This should not happen!");
-                    } else if *relay_queue_size.lock().unwrap() < MAX_QUEUE_SIZE {
-                        let data_vec = bincode::serialize(&Packet::Data(DataPacket {
+                    } else {
+                        outgoing_packets.push(DataPacket {
                             socket_type: SocketType::Udp,
                             sender_name: client.player_name.clone(),
                             sender_port: client.player_port,
@@ -757,154 +1504,395 @@ fn connect(
                             receiver_port: local_connection.original_socket_port,
                             data: data.to_vec(),
                             source_port: addr.port(),
-                        }))
-                        .unwrap();
-                        relay_packet_sender
-                            .send((relay_server_address.clone(), data_vec))
-                            .unwrap();
-                        *relay_queue_size.lock().unwrap() += 1;
-                    } else {
-                        println!("Queue over capacity! Dropping the udp thingy...");
+                            hop_limit: HOP_LIMIT,
+                            sequence: None,
+                            reply: true,
+                        });
                     }
                 }
             } else {
                 // No udp stream
             }
         }
+        // Udp data sent to forwarded ports other than the first one
+        for (local_port, udp_socket) in client.forward_udp_sockets.iter() {
+            while let Ok((size, addr)) = udp_socket.recv_from(buffer) {
+                *had_one = true;
+                client.udp_forwards.insert(addr.port(), *local_port);
+                let forward = client.get_forward(*local_port);
+                outgoing_packets.push(DataPacket {
+                    socket_type: SocketType::Udp,
+                    sender_name: client.player_name.clone(),
+                    sender_port: client.player_port,
+                    receiver_name: client.get_forward_target(forward),
+                    receiver_port: forward.remote_port,
+                    data: buffer[..size].to_vec(),
+                    source_port: addr.port(),
+                    hop_limit: HOP_LIMIT,
+                    sequence: None,
+                    reply: false,
+                });
+            }
+        }
+        // Discovery traffic of LAN games, for the players of our room
+        client.relay_discovery(buffer);
+        for packet in outgoing_packets {
+            if packet.socket_type == SocketType::Udp {
+                client.send_udp_data(packet);
+            } else if let Err(e) = client.send_tcp_data(packet.clone()) {
+                match e.kind() {
+                    ErrorKind::WouldBlock => {
+                        //
+                    }
+                    _ => {
+                        println!(
+                            "Failed to relay unstructured data for {}:{} ({})",
+                            packet.receiver_name, packet.receiver_port, packet.source_port
+                        )
+                    }
+                }
+            }
+        }
         // Receive data from the server and relay it to local connections
-        match local_outgoing_stream.read(buffer) {
-            Ok(received_data) => {
-                let _socket_port = local_outgoing_stream.peer_addr().unwrap().port();
+        if receive_server_packets(client, buffer) {
+            *had_one = true;
+        }
+    });
 
-                // This is data received from the server.
-                let received_data = &buffer[..received_data];
-                if let Ok(value) = bincode::deserialize::<Packet>(received_data) {
-                    match value {
-                        Packet::Data(data) => {
-                            // let socket_type = data.socket_type;
-                            data.print("packet received from the server: ");
-                            if client.player_name != data.receiver_name {
-                                println!("Received data meant for another player! Weird!");
-                            } else {
-                                // let receiver_port = data.receiver_port;
-                                // let address = SocketAddr::from_str(
-                                // format!("127.0.0.1:{}", receiver_port).as_str(),
-                                // )
-                                // .unwrap();
-
-                                // Create the socket if it doesn't exist yet
-                                if client.is_host() {
-                                    // Host logic
-                                    client.ensure_tcp_socket_on_redirection_table(&data);
-
-                                    // Send data to the TCP socket
-                                    if let Some(local_connection) = client
-                                        .local_redirection_table
-                                        .get_mut(&data.get_original_player_identifier())
-                                    {
-                                        //
-                                        if data.socket_type == SocketType::Tcp {
-                                            if let Err(e) = local_connection
-                                                .stream
-                                                .as_ref()
-                                                .unwrap()
-                                                .write(&data.data)
-                                            {
-                                                match e.kind() {
-                                                    std::io::ErrorKind::WouldBlock => {
-                                                        // nothing to do...
-                                                    }
-                                                    _ => {
-                                                        println!(
-                                                            "LOCAL TCP SOCKET SEND ERROR: {}",
-                                                            e
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        } else {
-                                            println!("TCP CONNECTIONS CAN ONLY SEND TCP DATA!");
-                                        }
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", player_client_port)).unwrap();
+    listeners.insert(0, listener);
+    handle_udp_traffic(
+        (format!("0.0.0.0:{}", player_client_port), udp_packet_sender),
+        relay_packet_receiver,
+        udp_packet_queue_size_cloned,
+        relay_queue_size_cloned,
+        relay_udp_address,
+        player_name.clone(),
+        reliable_channel,
+        udp_association,
+    );
+    accept_connections(&listeners, connections, Some(connection_sender), false);
+}
+
+/// Handles a single packet the relay server sent us.
+/// It came over our relay link, or over the link of a path when sending over several relays (see [Multipath]).
+fn handle_server_packet(client: &mut ClientState, value: Packet, path: Option<usize>) {
+    match value {
+        Packet::Data(data) if data.socket_type == SocketType::Udp => {
+            // Links carrying udp deliver its data along everything else
+            if client.is_first_copy(&data, None) {
+                client.receive_udp_data(data);
+            }
+        }
+        Packet::Data(data) => {
+            // let socket_type = data.socket_type;
+            data.print("packet received from the server: ");
+            if client.player_name != data.receiver_name {
+                println!("Received data meant for another player! Weird!");
+            } else {
+                // let receiver_port = data.receiver_port;
+                // let address = SocketAddr::from_str(
+                // format!("127.0.0.1:{}", receiver_port).as_str(),
+                // )
+                // .unwrap();
+
+                // Create the socket if it doesn't exist yet
+                if !data.reply {
+                    // Someone is talking to one of our local programs
+                    client.ensure_tcp_socket_on_redirection_table(&data);
+
+                    // Send data to the TCP socket
+                    if let Some(local_connection) = client
+                        .local_redirection_table
+                        .get_mut(&data.get_original_player_identifier())
+                    {
+                        //
+                        if data.socket_type == SocketType::Tcp {
+                            if let Err(e) =
+                                local_connection.stream.as_ref().unwrap().write(&data.data)
+                            {
+                                match e.kind() {
+                                    std::io::ErrorKind::WouldBlock => {
+                                        // nothing to do...
                                     }
-                                } else {
-                                    // Client logic
-                                    // println!("CLIENT");
-                                    // let locked_connections = connections.data.lock().unwrap();
-                                    // print_connections(&connections);
-                                    let locked = connections.data.lock().unwrap();
-                                    if let Some(player) = locked.get(&data.receiver_port) {
-                                        match player.stream.write(&data.data[..]) {
-                                            Ok(_) => {
-                                                // nothing to do, we sent the data!
-                                            }
-                                            Err(e) => {
-                                                match e.kind() {
-                                                    ErrorKind::WouldBlock => {
-                                                        //
-                                                    }
-                                                    _ => {
-                                                        println!("ERROR WHEN SENDING A TCP PACKET!")
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    } else {
-                                        println!("Packed received for a non existing socket!");
+                                    _ => {
+                                        println!("LOCAL TCP SOCKET SEND ERROR: {}", e);
                                     }
                                 }
                             }
+                        } else {
+                            println!("TCP CONNECTIONS CAN ONLY SEND TCP DATA!");
                         }
-                        Packet::GreetingReply => {
-                            println!("Received a greeting reply from the server! TCP connection established!");
-                        }
-                        Packet::Connection(con) => {
-                            if client.is_host() {
-                                println!(
-                                    "New tcp connection established: {}:{} ({}) -> {}:{}",
-                                    con.sender_name,
-                                    con.sender_port,
-                                    con.source_port,
-                                    con.receiver_name,
-                                    con.receiver_port
-                                );
-                                // Host logic
-                                client.ensure_tcp_socket_on_redirection_table(&con);
-                            } else {
-                                println!("Received a connection packet on non host rubicon instance. That's weird! {:?}", con);
+                    }
+                } else {
+                    // Answer to one of our local connections
+                    // println!("CLIENT");
+                    // let locked_connections = connections.data.lock().unwrap();
+                    // print_connections(&connections);
+                    let locked = client.connections.data.lock().unwrap();
+                    if let Some(player) = locked.get(&data.receiver_port) {
+                        match player.stream.write(&data.data[..]) {
+                            Ok(_) => {
+                                // nothing to do, we sent the data!
+                            }
+                            Err(e) => {
+                                match e.kind() {
+                                    ErrorKind::WouldBlock => {
+                                        //
+                                    }
+                                    _ => {
+                                        println!("ERROR WHEN SENDING A TCP PACKET!")
+                                    }
+                                }
                             }
                         }
-                        _ => {
-                            println!(
-                                "Weird packet received from the server. Are we being hacked? {:?}",
-                                value
-                            );
-                        }
+                    } else {
+                        println!("Packed received for a non existing socket!");
                     }
                 }
+            }
+        }
+        Packet::GreetingReply => {
+            println!("Received a greeting reply from the server! TCP connection established!");
+        }
+        Packet::SessionToken(token) => {
+            // Greeting relays with it again, the standby of this one gives us our name back
+            client.greeting.token = Some(token);
+        }
+        Packet::Broadcast(broadcast) => {
+            client.receive_broadcast(broadcast);
+        }
+        Packet::PublicPort(public_port) => {
+            println!(
+                "Port {} is public on relay {}, at port {}{}",
+                public_port.local_port,
+                client.relays.current_address(),
+                public_port.public_port,
+                match public_port.socket_type {
+                    Some(SocketType::Tcp) => " (tcp)",
+                    Some(SocketType::Udp) => " (udp)",
+                    None => "",
+                }
+            );
+        }
+        Packet::Heartbeat(_) => {
+            // Echoed over links carrying udp, as no udp heartbeats go to the relay
+            client.relays.receive_echo();
+        }
+        Packet::Catalog(catalog) => {
+            client.receive_catalog(catalog);
+        }
+        Packet::PlayerJoined(player) => {
+            println!("Player joined: {}", player);
+            client.players.insert(player.name.clone(), player);
+            client.agree_on_relay();
+        }
+        Packet::PlayerUpdated(player) => {
+            println!("Player updated: {}", player);
+            client.players.insert(player.name.clone(), player);
+            client.agree_on_relay();
+        }
+        Packet::PlayerLeft(player_name) => {
+            println!("Player left: {}", player_name);
+            if let Some(multipath) = client.multipath.as_mut() {
+                multipath.forget(&player_name);
+            }
+            client.players.remove(&player_name);
+            client.agree_on_relay();
+        }
+        Packet::RttProbe(sent) => {
+            // Send it right back over the link it came from, for the server to measure our rtt
+            let link = match path {
+                Some(index) => client
+                    .multipath
+                    .as_ref()
+                    .and_then(|multipath| multipath.paths[index].link.as_ref()),
+                None => Some(&client.relay),
+            };
+            if let Some(link) = link {
+                let _ = link.send_packet(&Packet::RttProbe(sent));
+            }
+        }
+        Packet::PeerCandidates(candidates) => {
+            if let Some(direct_paths) = client.direct_paths.as_mut() {
+                direct_paths.receive_candidates(candidates);
+            } else {
+                println!(
+                    "{} wants a direct udp path to us, but we stay on the relay",
+                    candidates.player_name
+                );
+            }
+        }
+        Packet::HostMigration(migration) => {
+            client.migrate_host(migration);
+        }
+        Packet::Connection(con) => {
+            println!(
+                "New tcp connection established: {}:{} ({}) -> {}:{}",
+                con.sender_name,
+                con.sender_port,
+                con.source_port,
+                con.receiver_name,
+                con.receiver_port
+            );
+            // Someone connected to one of our forwarded ports, connect to the local program they want
+            client.ensure_tcp_socket_on_redirection_table(&con);
+        }
+        _ => {
+            println!(
+                "Weird packet received from the server. Are we being hacked? {:?}",
+                value
+            );
+        }
+    }
+}
+
+/// Connects to a host as an observer, which takes no player name and isn't announced to anyone.
+/// `name` only shows in the logs of the host.
+fn observe(server_address: &str, name: &str, presence: bool) -> SocketWrapper {
+    let stream = SocketWrapper::connect(server_address, None).unwrap();
+    stream
+        .send_packet(&Packet::Greeting(GreetingPacket {
+            player_name: name.to_string(),
+            local_port: 0,
+            tcp_transport: TcpTransport::Tcp,
+            udp_over_link: true,
+            forwarded_ports: vec![],
+            exposed_ports: Some(vec![]),
+            services: vec![],
+            presence,
+            observer: true,
+            other_player_name: String::new(),
+            host_migration: false,
+            udp_candidates: None,
+            relay_latencies: vec![],
+            token: None,
+            public_ports: vec![],
+        }))
+        .unwrap();
+    stream.set_nonblocking(true).unwrap();
+    stream
+}
+
+/// Reads what the relay sends us, on its link and over the reliable udp channel,
+/// moving to another relay when this one is gone or stopped echoing our heartbeats.
+/// Returns whether anything was received.
+fn receive_server_packets(client: &mut ClientState, buffer: &mut [u8]) -> bool {
+    if client.relay.is_timed_out()
+        || !client.relays.is_healthy()
+        || client.is_reliable_channel_dead()
+    {
+        client.fail_over();
+    }
+    // What the link held back, before anything else goes over it
+    if let Err(e) = client.relay.flush() {
+        println!("Failed to send data to the relay: {}", e);
+    }
+    let mut had_one = false;
+    loop {
+        match client.relay.read(buffer) {
+            Ok(0) => break,
+            Ok(received_data) => {
+                // This is data received from the server.
+                let received_data = &buffer[..received_data];
+                if let Ok(value) = bincode::deserialize::<Packet>(received_data) {
+                    handle_server_packet(client, value, None);
+                }
 
-                // *had_one = true;
+                had_one = true;
             }
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::WouldBlock => {
-                    // dont print when not debugging - itll flood the console cuz most of the time there's nothing to read...
-                    // println!("A stream ({:?}) would block upon reading: {:?}", peer, e)
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::WouldBlock => {
+                        // dont print when not debugging - itll flood the console cuz most of the time there's nothing to read...
+                        // println!("A stream ({:?}) would block upon reading: {:?}", peer, e)
+                    }
+                    _ => println!("The server stream returned an error upon reading: {:?}", e),
                 }
-                _ => println!("The server stream returned an error upon reading: {:?}", e),
-            },
-            //
+                break;
+            }
         }
-    });
+    }
+    // The server may also send us tcp data over the reliable udp channel
+    for value in client.receive_reliable_packets() {
+        had_one = true;
+        handle_server_packet(client, value, None);
+    }
+    had_one
+}
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", player_client_port)).unwrap();
-    handle_udp_traffic(
-        (format!("0.0.0.0:{}", player_client_port), udp_packet_sender),
-        relay_packet_receiver,
-        udp_packet_queue_size_cloned,
-        relay_queue_size_cloned,
-        relay_server_address_cloned,
-        player_name.clone(),
-    );
-    accept_connections(&listener, connections, Some(connection_sender));
+/// Asks a host for the services every player published, and prints them.
+fn list_services(server_address: String, player_name: Option<String>) {
+    let stream = observe(&server_address, "services", false);
+    stream.send_packet(&Packet::CatalogRequest).unwrap();
+
+    let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+    let begin = Instant::now();
+    let catalogs = loop {
+        if begin.elapsed() > CATALOG_REQUEST_TIMEOUT || stream.is_timed_out() {
+            println!("The host didn't send us the services, is it running an older rubicon?");
+            std::process::exit(1);
+        }
+        match stream.read(&mut buffer) {
+            Ok(size) if size > 0 => {
+                if let Ok(Packet::Catalogs(catalogs)) =
+                    bincode::deserialize::<Packet>(&buffer[..size])
+                {
+                    break catalogs;
+                }
+            }
+            _ => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let catalogs = catalogs
+        .into_iter()
+        .map(|catalog| (catalog.player_name, catalog.services))
+        .collect::<BTreeMap<_, _>>();
+    for (name, services) in catalogs {
+        if player_name
+            .as_ref()
+            .is_some_and(|player_name| *player_name != name)
+        {
+            continue;
+        }
+        println!("{}:", name);
+        for service in services {
+            println!("- {}", service);
+        }
+    }
+}
+
+/// Prints the players on a host, and with `watch`, who joins and leaves it from then on.
+fn list_players(server_address: String, watch: bool) {
+    let stream = observe(&server_address, "players", true);
+
+    // The host tells us who's there right after greeting us
+    let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+    let begin = Instant::now();
+    let mut listed = false;
+    loop {
+        if !listed && begin.elapsed().as_millis() >= 1000 {
+            listed = true;
+            if !watch {
+                break;
+            }
+            println!("Watching players join and leave...");
+        }
+        if stream.is_timed_out() {
+            println!("The host closed the connection");
+            break;
+        }
+        match stream.read(&mut buffer) {
+            Ok(size) if size > 0 => match bincode::deserialize::<Packet>(&buffer[..size]) {
+                Ok(Packet::PlayerJoined(player)) if !listed => println!("- {}", player),
+                Ok(Packet::PlayerJoined(player)) => println!("Player joined: {}", player),
+                Ok(Packet::PlayerUpdated(player)) => println!("Player updated: {}", player),
+                Ok(Packet::PlayerLeft(player_name)) => println!("Player left: {}", player_name),
+                _ => {}
+            },
+            _ => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
 }
 
 /// Connects to an address and starts sending tcp packets to it.
@@ -1026,19 +2014,38 @@ fn listen(port: u16, udp: SocketType) {
             });
 
             let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-            accept_connections(&listener, connections, None);
+            accept_connections(&[listener], connections, None, false);
         }
     }
 }
 
-/// Opens a tcp sockets and starts listening on it
+/// Sends a command to a relay over a link of its own, framed like everything relays read from links
 fn send_command(address: String, command: String) {
     println!("Commanding {} to {}", address, command);
-    // Outgoing stream
-    let mut stream = TcpStream::connect(address).unwrap();
-    stream.set_nonblocking(false).unwrap();
-    stream.set_nodelay(DISABLE_NAGLE_ALGORITHM).unwrap();
+    let result = SocketWrapper::connect(&address, None)
+        .and_then(|stream| stream.send_packet(&Packet::Command(CommandPacket { command })));
+    if let Err(e) = result {
+        println!("Failed to send the command to {}: {}", address, e);
+        std::process::exit(1);
+    }
+}
 
-    let data = bincode::serialize(&Packet::Command(CommandPacket { command })).unwrap();
-    let _ = stream.write(&data[..]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_commands_over_framed_links() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        send_command(address, "show_connections".to_string());
+        let (stream, _) = listener.accept().unwrap();
+        let link = SocketWrapper::from_relay_tcp_socket(stream);
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let size = link.read(&mut buffer).unwrap();
+        match bincode::deserialize(&buffer[..size]).unwrap() {
+            Packet::Command(command) => assert_eq!(command.command, "show_connections"),
+            packet => panic!("Expected a command, got {:?}", packet),
+        }
+    }
 }
@@ -0,0 +1,407 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    commands::MultipathMode,
+    failover::{udp_address, Relays},
+    packet::{monotonic_micros, GreetingPacket, Packet},
+    probe::ProbeStats,
+    socket::SocketWrapper,
+};
+
+/// Time between two probes of every path
+const PATH_PROBE_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a path may go without echoing our probes before we stop sending over it, when sending over the best one
+const PATH_TIMEOUT: Duration = Duration::from_millis(300);
+/// Time between two reports of how the paths are doing
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Sequences behind the highest one received from a player that we still tell copies apart for
+const DEDUP_WINDOW: u64 = 4096;
+
+/// One of the relays we keep a session to
+pub struct Path {
+    pub address: String,
+    /// Where the relay receives udp
+    pub udp_address: SocketAddr,
+    /// Our link to the relay. None for the one we're linked to, its link being the relay link of the client.
+    pub link: Option<SocketWrapper>,
+    /// How the relay answered our probes since the last report
+    pub stats: ProbeStats,
+    /// Smoothed round trip time of our probes
+    pub rtt: Option<Duration>,
+    /// When the relay last echoed one of our probes
+    pub last_echo: Instant,
+    /// Data packets that came over the path since the last report
+    pub received: u64,
+    /// Data packets that came over the path before their copies, since the last report
+    pub first: u64,
+}
+impl Path {
+    fn is_healthy(&self) -> bool {
+        self.last_echo.elapsed() < PATH_TIMEOUT
+    }
+}
+
+/// Sequences received from a player, to drop copies that came over other paths
+#[derive(Default)]
+struct SequenceWindow {
+    highest: u64,
+    seen: HashSet<u64>,
+}
+impl SequenceWindow {
+    /// Whether a sequence wasn't received yet. Too old ones count as received.
+    fn insert(&mut self, sequence: u64) -> bool {
+        let first = sequence + DEDUP_WINDOW > self.highest && self.seen.insert(sequence);
+        if first {
+            self.highest = self.highest.max(sequence);
+            if self.seen.len() as u64 > 2 * DEDUP_WINDOW {
+                let highest = self.highest;
+                self.seen.retain(|seen| seen + DEDUP_WINDOW > highest);
+            }
+        }
+        first
+    }
+}
+
+/// Sessions to several relays at once, udp data going over all of them or over the best one
+pub struct Multipath {
+    pub mode: MultipathMode,
+    pub paths: Vec<Path>,
+    /// Index of the path we send over, when sending over the best one
+    best: usize,
+    /// Sequence of the next udp data packet we send, starting at 0 every session.
+    /// Others forget our sequences when we leave, so a restart doesn't get our data taken for copies.
+    next_sequence: u64,
+    received: HashMap<String, SequenceWindow>,
+    /// Data packets received since the last report, copies excluded
+    unique: u64,
+    last_probe: Instant,
+    last_report: Instant,
+    /// Probes sent before this (see [monotonic_micros]) count for the previous report
+    report_start: u64,
+}
+impl Multipath {
+    /// Greets every relay of the list but the one we're linked to, as the same player.
+    /// Relays that can't be reached are left out.
+    pub fn new(mode: MultipathMode, relays: &Relays, greeting: &GreetingPacket) -> Self {
+        let mut paths = vec![];
+        for address in relays.addresses.iter() {
+            let Some(udp_address) = udp_address(address) else {
+                println!(
+                    "MULTIPATH: can't send udp to relay {}, leaving it out",
+                    address
+                );
+                continue;
+            };
+            let link = if address == relays.current_address() {
+                None
+            } else {
+                let link = relays.dial(address).and_then(|link| {
+                    link.send_packet(&Packet::Greeting(greeting.clone()))?;
+                    link.set_nonblocking(true)?;
+                    Ok(link)
+                });
+                match link {
+                    Ok(link) => Some(link),
+                    Err(e) => {
+                        println!("MULTIPATH: relay {} is unreachable: {}", address, e);
+                        continue;
+                    }
+                }
+            };
+            println!("MULTIPATH: sending over relay {}", address);
+            paths.push(Path {
+                address: address.clone(),
+                udp_address,
+                link,
+                stats: ProbeStats::default(),
+                rtt: None,
+                last_echo: Instant::now(),
+                received: 0,
+                first: 0,
+            });
+        }
+        Self {
+            mode,
+            paths,
+            best: 0,
+            next_sequence: 0,
+            received: Default::default(),
+            unique: 0,
+            last_probe: Instant::now(),
+            last_report: Instant::now(),
+            report_start: monotonic_micros(),
+        }
+    }
+
+    /// Sequence for the next udp data packet we send
+    pub fn next_sequence(&mut self) -> u64 {
+        self.next_sequence += 1;
+        self.next_sequence - 1
+    }
+
+    /// Forgets the sequences of a player that left, who starts over from 0 when coming back
+    pub fn forget(&mut self, player_name: &str) {
+        self.received.remove(player_name);
+    }
+
+    /// Where to send a udp data packet to: every relay, or the one answering fastest.
+    /// We stick to the best one until it's down or another one is clearly faster, not to reorder datagrams.
+    pub fn destinations(&mut self) -> Vec<SocketAddr> {
+        if self.mode == MultipathMode::All {
+            return self.paths.iter().map(|path| path.udp_address).collect();
+        }
+        let fastest = self
+            .paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| path.is_healthy())
+            .min_by_key(|(_, path)| (path.rtt.is_none(), path.rtt))
+            .map(|(index, _)| index);
+        if let (Some(fastest), Some(best)) = (fastest, self.paths.get(self.best)) {
+            let faster = match (self.paths[fastest].rtt, best.rtt) {
+                (Some(rtt), Some(best_rtt)) => rtt * 10 < best_rtt * 9,
+                (Some(_), None) => true,
+                _ => false,
+            };
+            if fastest != self.best && (!best.is_healthy() || faster) {
+                println!(
+                    "MULTIPATH: now sending over relay {}",
+                    self.paths[fastest].address
+                );
+                self.best = fastest;
+            }
+        }
+        self.paths
+            .get(self.best)
+            .map(|path| path.udp_address)
+            .into_iter()
+            .collect()
+    }
+
+    /// Whether a datagram came from the relay at this address
+    pub fn is_from(&self, udp_address: SocketAddr, address: &String) -> bool {
+        self.paths
+            .iter()
+            .any(|path| &path.address == address && path.udp_address == udp_address)
+    }
+
+    /// Counts a data packet that came over a path (None for relay links), returning whether it's the first copy.
+    pub fn receive_data(
+        &mut self,
+        sender_name: &str,
+        sequence: Option<u64>,
+        udp_address: Option<SocketAddr>,
+    ) -> bool {
+        let path = udp_address.and_then(|udp_address| {
+            self.paths
+                .iter_mut()
+                .find(|path| path.udp_address == udp_address)
+        });
+        let Some(sequence) = sequence else {
+            // Not sent over several paths, there's no copy of it
+            return true;
+        };
+        let first = self
+            .received
+            .entry(sender_name.to_string())
+            .or_default()
+            .insert(sequence);
+        if first {
+            self.unique += 1;
+        }
+        if let Some(path) = path {
+            path.received += 1;
+            if first {
+                path.first += 1;
+            }
+        }
+        first
+    }
+
+    /// Times the echo of a probe sent to a path
+    pub fn receive_probe(&mut self, udp_address: SocketAddr, sent: u64) {
+        let report_start = self.report_start;
+        let Some(path) = self
+            .paths
+            .iter_mut()
+            .find(|path| path.udp_address == udp_address)
+        else {
+            return;
+        };
+        let rtt = Duration::from_micros(monotonic_micros().saturating_sub(sent));
+        path.rtt = Some(match path.rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+        path.last_echo = Instant::now();
+        if sent >= report_start {
+            path.stats.receive(sent);
+        }
+    }
+
+    /// Datagrams to send to the paths every now and then: heartbeats, for the relays to know where we are,
+    /// and probes timing them. Reports how the paths are doing every now and then too.
+    pub fn tick(&mut self, player_name: &str) -> Vec<(SocketAddr, Packet)> {
+        let mut datagrams = vec![];
+        if self.last_probe.elapsed() < PATH_PROBE_INTERVAL {
+            return datagrams;
+        }
+        self.last_probe = Instant::now();
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.report();
+        }
+        let sent = monotonic_micros();
+        for path in self.paths.iter_mut() {
+            path.stats.sent += 1;
+            datagrams.push((path.udp_address, Packet::Heartbeat(player_name.to_string())));
+            datagrams.push((path.udp_address, Packet::Probe(sent)));
+        }
+        datagrams
+    }
+
+    /// Prints the latency and loss of every path, and how much of the data came over each of them first
+    fn report(&mut self) {
+        println!(
+            "MULTIPATH: {} data packets received over {} paths",
+            self.unique,
+            self.paths.len()
+        );
+        for path in self.paths.iter_mut() {
+            let carried = if self.unique == 0 {
+                String::new()
+            } else {
+                format!(
+                    ", carried {:.0}% of the data, {:.0}% first",
+                    path.received as f64 / self.unique as f64 * 100.,
+                    path.first as f64 / self.unique as f64 * 100.
+                )
+            };
+            println!(
+                "MULTIPATH: relay {}: {}{}{}",
+                path.address,
+                path.stats,
+                carried,
+                if path.is_healthy() { "" } else { " (down)" }
+            );
+            path.stats = ProbeStats::default();
+            path.received = 0;
+            path.first = 0;
+        }
+        self.unique = 0;
+        self.last_report = Instant::now();
+        self.report_start = monotonic_micros();
+    }
+
+    /// Reads what the relays sent over the links of the paths, dropping links that went down
+    pub fn receive_packets(&mut self, buffer: &mut [u8]) -> Vec<(usize, Packet)> {
+        let mut packets = vec![];
+        for (index, path) in self.paths.iter_mut().enumerate() {
+            let Some(link) = path.link.as_ref() else {
+                continue;
+            };
+            let _ = link.flush();
+            loop {
+                match link.read(buffer) {
+                    Ok(size) if size > 0 => {
+                        if let Ok(packet) = bincode::deserialize::<Packet>(&buffer[..size]) {
+                            packets.push((index, packet));
+                        }
+                    }
+                    Ok(_) => {
+                        println!("MULTIPATH: lost the link to relay {}", path.address);
+                        path.link = None;
+                        break;
+                    }
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                            println!(
+                                "The link to relay {} returned an error upon reading: {:?}",
+                                path.address, e
+                            );
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        packets
+    }
+
+    /// Takes the link of the healthy path answering fastest, for the client to go on with it
+    /// when the relay it's linked to is gone. It greeted the relay already.
+    pub fn take_best_link(&mut self) -> Option<(String, SocketWrapper)> {
+        let path = self
+            .paths
+            .iter_mut()
+            .filter(|path| path.link.is_some() && path.is_healthy())
+            .min_by_key(|path| (path.rtt.is_none(), path.rtt))?;
+        Some((path.address.clone(), path.link.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::TcpTransport;
+
+    #[test]
+    fn drops_copies_of_sequences() {
+        let mut window = SequenceWindow::default();
+        assert!(window.insert(0));
+        assert!(window.insert(2));
+        assert!(!window.insert(2));
+        assert!(window.insert(1));
+        assert!(!window.insert(0));
+        assert!(window.insert(DEDUP_WINDOW + 5));
+        // Too old to tell apart from a copy
+        assert!(!window.insert(3));
+        assert!(window.insert(6));
+        for sequence in DEDUP_WINDOW + 6..4 * DEDUP_WINDOW {
+            assert!(window.insert(sequence));
+        }
+        assert!(window.seen.len() as u64 <= 2 * DEDUP_WINDOW);
+        assert!(!window.insert(4 * DEDUP_WINDOW - 1));
+    }
+
+    #[test]
+    fn tells_players_and_sessions_apart() {
+        let relays = Relays::new("127.0.0.1:8080", None, None, None).unwrap();
+        // Only the relay we're linked to, none to greet
+        let greeting = GreetingPacket {
+            player_name: "JOINER".to_string(),
+            local_port: 0,
+            tcp_transport: TcpTransport::Tcp,
+            udp_over_link: false,
+            forwarded_ports: vec![],
+            exposed_ports: None,
+            services: vec![],
+            presence: true,
+            observer: false,
+            other_player_name: "HOST".to_string(),
+            host_migration: false,
+            udp_candidates: None,
+            relay_latencies: vec![],
+            token: None,
+            public_ports: vec![],
+        };
+        let mut multipath = Multipath::new(MultipathMode::All, &relays, &greeting);
+        assert_eq!(multipath.next_sequence(), 0);
+        assert_eq!(multipath.next_sequence(), 1);
+        let relay = "127.0.0.1:8080".parse().unwrap();
+        assert!(multipath.receive_data("HOST", Some(7), Some(relay)));
+        assert!(!multipath.receive_data("HOST", Some(7), None));
+        assert!(multipath.receive_data("JOINER", Some(7), None));
+        // Not sent over several paths
+        assert!(multipath.receive_data("HOST", None, None));
+        assert!(multipath.receive_data("HOST", None, None));
+        // Back from a restart, starting over
+        multipath.forget("HOST");
+        assert!(multipath.receive_data("HOST", Some(7), None));
+        assert_eq!(multipath.paths[0].received, 1);
+        assert_eq!(multipath.paths[0].first, 1);
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{ErrorKind, Read},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+        UdpSocket,
+    },
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    commands::{NatBehaviour, PortRange},
+    common::{BUFFER_SIZE, DISABLE_NAGLE_ALGORITHM},
+    proxy::{
+        accept_socks_request, decode_socks_datagram, encode_socks_datagram, reply_socks_request,
+        SocksRequest,
+    },
+};
+
+/// How long reads block at most, for closed associations and expired mappings to be noticed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings shared by every association of the NAT
+struct Nat {
+    behaviour: NatBehaviour,
+    mapping_lifetime: Duration,
+    /// None for every port but the outside ones
+    inside_ports: Option<PortRange>,
+    outside_ports: PortRange,
+    /// Next outside port to try, so mappings don't reuse the ports of expired ones right away
+    next_port: AtomicU16,
+}
+
+/// An outside udp port, along with who it was opened towards
+struct Mapping {
+    socket: UdpSocket,
+    /// The destination the mapping was opened towards, its only one for symmetric NATs
+    destination: SocketAddr,
+    /// Destinations the inside sent datagrams to through the mapping
+    contacted: Mutex<HashSet<SocketAddr>>,
+    last_outbound: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+/// Runs a NAT box between the ports of players on this machine and the relay.
+/// A NAT sees every packet leaving the inside, which a program can't do without being handed them:
+/// players hand theirs over by using the NAT as a SOCKS5 proxy (`--proxy socks5://127.0.0.1:PORT`),
+/// the way they'd go through any other proxy.
+/// Udp sockets of players on the inside ports get outside ports mapped and filtered the way the behaviour says,
+/// and mappings expire without outbound traffic like on a router. Tcp connections leave from outside ports too.
+pub fn run(
+    port: u16,
+    behaviour: NatBehaviour,
+    mapping_lifetime: u64,
+    inside_ports: Option<PortRange>,
+    outside_ports: PortRange,
+) {
+    println!(
+        "Simulating a {:?} NAT on {} for inside ports {}, mapping to outside ports {}-{} for {}s",
+        behaviour,
+        port,
+        inside_ports.map_or("all".to_string(), |inside_ports| format!(
+            "{}-{}",
+            inside_ports.first, inside_ports.last
+        )),
+        outside_ports.first,
+        outside_ports.last,
+        mapping_lifetime
+    );
+    let nat = Arc::new(Nat {
+        behaviour,
+        mapping_lifetime: Duration::from_secs(mapping_lifetime),
+        inside_ports,
+        next_port: AtomicU16::new(outside_ports.first),
+        outside_ports,
+    });
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let nat = nat.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = serve(&nat, stream) {
+                println!("NAT: session of {:?} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn serve(nat: &Nat, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nodelay(DISABLE_NAGLE_ALGORITHM)?;
+    match accept_socks_request(&mut stream)? {
+        SocksRequest::Connect(target) => {
+            let outside = match resolve(&target).and_then(|target| nat.connect_outside(target)) {
+                Ok(outside) => outside,
+                Err(e) => {
+                    reply_socks_request(&mut stream, None)?;
+                    return Err(e);
+                }
+            };
+            outside.set_nodelay(DISABLE_NAGLE_ALGORITHM)?;
+            let outside_address = outside.local_addr()?;
+            println!(
+                "NAT: tcp {} -> {} leaves from {}",
+                stream.peer_addr()?,
+                target,
+                outside_address
+            );
+            reply_socks_request(&mut stream, Some(outside_address))?;
+            pass_through(stream, outside)
+        }
+        SocksRequest::UdpAssociate => associate(nat, stream),
+    }
+}
+
+fn resolve(target: &str) -> std::io::Result<SocketAddr> {
+    target
+        .to_socket_addrs()?
+        .next()
+        .ok_or(ErrorKind::NotFound.into())
+}
+
+/// Copies bytes both ways until either side closes
+fn pass_through(inside: TcpStream, outside: TcpStream) -> std::io::Result<()> {
+    let (mut inside_reader, mut outside_writer) = (inside.try_clone()?, outside.try_clone()?);
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut inside_reader, &mut outside_writer);
+        let _ = outside_writer.shutdown(Shutdown::Both);
+    });
+    let (mut outside_reader, mut inside_writer) = (outside, inside);
+    let _ = std::io::copy(&mut outside_reader, &mut inside_writer);
+    let _ = inside_writer.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+/// Relays datagrams of a single inside socket for as long as the tcp connection lives.
+/// Datagrams going out are read here, those coming in are read by a thread per mapping.
+fn associate(nat: &Nat, mut control: TcpStream) -> std::io::Result<()> {
+    let inside_socket = Arc::new(UdpSocket::bind((control.local_addr()?.ip(), 0))?);
+    inside_socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    reply_socks_request(&mut control, Some(inside_socket.local_addr()?))?;
+
+    // The association ends with its tcp connection
+    let ended = Arc::new(AtomicBool::new(false));
+    {
+        let ended = ended.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            while matches!(control.read(&mut buffer), Ok(size) if size > 0) {}
+            ended.store(true, Ordering::Relaxed);
+        });
+    }
+
+    let mut inside_address = None;
+    let mut mappings: Vec<Arc<Mapping>> = vec![];
+    // Destinations are resolved once, rather than for every datagram sent to them
+    let mut destinations: HashMap<String, SocketAddr> = HashMap::new();
+    let mut buffer = [0u8; BUFFER_SIZE];
+    while !ended.load(Ordering::Relaxed) {
+        // Mappings with no outbound traffic for too long are gone
+        mappings.retain(|mapping| {
+            let alive = mapping.last_outbound.lock().unwrap().elapsed() < nat.mapping_lifetime;
+            if !alive {
+                println!(
+                    "NAT: mapping on outside port {} expired",
+                    mapping.socket.local_addr().unwrap().port()
+                );
+                mapping.closed.store(true, Ordering::Relaxed);
+            }
+            alive
+        });
+
+        let (size, address) = match inside_socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                println!("NAT: failed to read datagrams of the inside: {}", e);
+                continue;
+            }
+        };
+        if !nat.is_inside(address.port()) {
+            println!(
+                "NAT: dropping a datagram from {}, its port isn't on the inside",
+                address
+            );
+            continue;
+        }
+        inside_address = Some(address);
+        let Some((destination, data)) = decode_socks_datagram(&buffer[..size]) else {
+            continue;
+        };
+        let destination = match destinations.get(&destination) {
+            Some(resolved) => *resolved,
+            None => match resolve(&destination) {
+                Ok(resolved) => *destinations.entry(destination).or_insert(resolved),
+                Err(e) => {
+                    println!("NAT: failed to resolve {}: {}", destination, e);
+                    continue;
+                }
+            },
+        };
+        let mapping = match mappings.iter().find(|mapping| {
+            nat.behaviour != NatBehaviour::Symmetric || mapping.destination == destination
+        }) {
+            Some(mapping) => mapping.clone(),
+            None => {
+                let socket = nat.bind_outside_port(|port| UdpSocket::bind(("0.0.0.0", port)))?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                println!(
+                    "NAT: {} gets outside port {}{}",
+                    address,
+                    socket.local_addr()?.port(),
+                    if nat.behaviour == NatBehaviour::Symmetric {
+                        format!(" towards {}", destination)
+                    } else {
+                        String::new()
+                    }
+                );
+                let mapping = Arc::new(Mapping {
+                    socket,
+                    destination,
+                    contacted: Default::default(),
+                    last_outbound: Mutex::new(Instant::now()),
+                    closed: AtomicBool::new(false),
+                });
+                {
+                    let (mapping, inside_socket) = (mapping.clone(), inside_socket.clone());
+                    let behaviour = nat.behaviour;
+                    std::thread::spawn(move || {
+                        receive_inbound(&mapping, &inside_socket, address, behaviour)
+                    });
+                }
+                mappings.push(mapping.clone());
+                mapping
+            }
+        };
+        mapping.contacted.lock().unwrap().insert(destination);
+        *mapping.last_outbound.lock().unwrap() = Instant::now();
+        let _ = mapping.socket.send_to(data, destination);
+    }
+    for mapping in mappings {
+        mapping.closed.store(true, Ordering::Relaxed);
+    }
+    println!("NAT: association of {:?} closed", inside_address);
+    Ok(())
+}
+
+/// Passes datagrams reaching an outside port to the inside, as long as the behaviour of the NAT lets them in
+fn receive_inbound(
+    mapping: &Mapping,
+    inside_socket: &UdpSocket,
+    inside_address: SocketAddr,
+    behaviour: NatBehaviour,
+) {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    while !mapping.closed.load(Ordering::Relaxed) {
+        let Ok((size, source)) = mapping.socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        if !mapping.lets_in(behaviour, source) {
+            println!(
+                "NAT: filtered a datagram from {} to outside port {}",
+                source,
+                mapping.socket.local_addr().unwrap().port()
+            );
+            continue;
+        }
+        match encode_socks_datagram(&source.to_string(), &buffer[..size]) {
+            Ok(datagram) => {
+                let _ = inside_socket.send_to(&datagram, inside_address);
+            }
+            Err(e) => println!("NAT: failed to pass a datagram from {}: {}", source, e),
+        }
+    }
+}
+
+impl Mapping {
+    /// Whether a datagram reaching the outside port from a source is passed to the inside
+    fn lets_in(&self, behaviour: NatBehaviour, source: SocketAddr) -> bool {
+        match behaviour {
+            NatBehaviour::FullCone => true,
+            NatBehaviour::AddressRestricted => self
+                .contacted
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|contacted| contacted.ip() == source.ip()),
+            NatBehaviour::PortRestricted => self.contacted.lock().unwrap().contains(&source),
+            NatBehaviour::Symmetric => self.destination == source,
+        }
+    }
+}
+
+impl Nat {
+    fn is_inside(&self, port: u16) -> bool {
+        self.inside_ports.map_or_else(
+            || !self.outside_ports.contains(port),
+            |inside_ports| inside_ports.contains(port),
+        )
+    }
+
+    /// Binds the next free port of the outside range
+    fn bind_outside_port<T>(&self, bind: impl Fn(u16) -> std::io::Result<T>) -> std::io::Result<T> {
+        let size = self.outside_ports.last - self.outside_ports.first + 1;
+        for _ in 0..size {
+            let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+            if port >= self.outside_ports.last {
+                self.next_port
+                    .store(self.outside_ports.first, Ordering::Relaxed);
+            }
+            match bind(port) {
+                Ok(bound) => return Ok(bound),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::AddrInUse,
+            "No outside port left for a new mapping",
+        ))
+    }
+
+    /// Opens a tcp connection to the outside from a port of the outside range
+    fn connect_outside(&self, target: SocketAddr) -> std::io::Result<TcpStream> {
+        let unspecified = match target.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = self.bind_outside_port(|port| {
+            let socket = Socket::new(Domain::for_address(target), Type::STREAM, None)?;
+            socket.bind(&SocketAddr::new(unspecified, port).into())?;
+            Ok(socket)
+        })?;
+        socket.connect(&target.into())?;
+        Ok(socket.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::proxy::Proxy;
+
+    #[test]
+    fn filters_inbound_datagrams_by_behaviour() {
+        let destination: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let mapping = Mapping {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            destination,
+            contacted: Mutex::new(HashSet::from([
+                destination,
+                "198.51.100.2:5000".parse().unwrap(),
+            ])),
+            last_outbound: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        };
+        // The destination the mapping was opened towards, another destination it sent to,
+        // another port of that destination, and a stranger
+        let sources = [
+            "198.51.100.1:4000",
+            "198.51.100.2:5000",
+            "198.51.100.1:4001",
+            "203.0.113.9:4000",
+        ]
+        .map(|source| source.parse::<SocketAddr>().unwrap());
+        let let_in = |behaviour| sources.map(|source| mapping.lets_in(behaviour, source));
+        assert_eq!(let_in(NatBehaviour::FullCone), [true, true, true, true]);
+        assert_eq!(
+            let_in(NatBehaviour::AddressRestricted),
+            [true, true, true, false]
+        );
+        assert_eq!(
+            let_in(NatBehaviour::PortRestricted),
+            [true, true, false, false]
+        );
+        assert_eq!(let_in(NatBehaviour::Symmetric), [true, false, false, false]);
+    }
+
+    #[test]
+    fn passes_only_what_the_behaviour_lets_in() {
+        let inside = UdpSocket::bind("127.0.0.1:0").unwrap();
+        inside
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let (contacted, stranger) = (
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        );
+        let mapping = Arc::new(Mapping {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            destination: contacted.local_addr().unwrap(),
+            contacted: Mutex::new(HashSet::from([contacted.local_addr().unwrap()])),
+            last_outbound: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
+        mapping
+            .socket
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .unwrap();
+        let outside = mapping.socket.local_addr().unwrap();
+        let receiver = {
+            let (mapping, inside_socket) =
+                (mapping.clone(), UdpSocket::bind("127.0.0.1:0").unwrap());
+            let inside_address = inside.local_addr().unwrap();
+            std::thread::spawn(move || {
+                receive_inbound(
+                    &mapping,
+                    &inside_socket,
+                    inside_address,
+                    NatBehaviour::PortRestricted,
+                )
+            })
+        };
+        stranger.send_to(b"stranger", outside).unwrap();
+        contacted.send_to(b"contacted", outside).unwrap();
+        let mut buffer = [0u8; 128];
+        let size = inside.recv(&mut buffer).unwrap();
+        let (source, data) = decode_socks_datagram(&buffer[..size]).unwrap();
+        assert_eq!(source, contacted.local_addr().unwrap().to_string());
+        assert_eq!(data, b"contacted");
+        assert!(inside.recv(&mut buffer).is_err());
+
+        mapping.closed.store(true, Ordering::Relaxed);
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn expires_mappings_without_outbound_traffic() {
+        let nat = Arc::new(Nat {
+            behaviour: NatBehaviour::FullCone,
+            mapping_lifetime: Duration::from_millis(300),
+            inside_ports: None,
+            outside_ports: PortRange {
+                first: 47000,
+                last: 47999,
+            },
+            next_port: AtomicU16::new(47000),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy =
+            Proxy::from_str(&format!("socks5://{}", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = serve(&nat, stream);
+        });
+        let association = proxy.associate_udp().unwrap();
+        let inside = UdpSocket::bind("127.0.0.1:0").unwrap();
+        inside
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let send_out = |data: &[u8]| {
+            let datagram = association
+                .wrap(&peer.local_addr().unwrap().to_string(), data)
+                .unwrap();
+            inside
+                .send_to(&datagram, association.relay_address())
+                .unwrap();
+        };
+        let mut buffer = [0u8; 128];
+
+        // The peer answers through the mapping while it's alive
+        send_out(b"out");
+        let (_, first_outside) = peer.recv_from(&mut buffer).unwrap();
+        peer.send_to(b"in", first_outside).unwrap();
+        let size = inside.recv(&mut buffer).unwrap();
+        assert_eq!(association.unwrap(&buffer[..size]).unwrap().1, b"in");
+
+        // And no more once it went without outbound traffic for its lifetime
+        std::thread::sleep(Duration::from_millis(300) + POLL_INTERVAL * 3);
+        peer.send_to(b"late", first_outside).unwrap();
+        assert!(inside.recv(&mut buffer).is_err());
+
+        // Sending out again opens a new mapping
+        send_out(b"again");
+        let (_, second_outside) = peer.recv_from(&mut buffer).unwrap();
+        assert_ne!(second_outside, first_outside);
+    }
+}
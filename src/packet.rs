@@ -1,8 +1,26 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{client::ClientLocalConnection, commands::SocketType, connections::Connections};
+use crate::{
+    client::ClientLocalConnection,
+    commands::{Exposure, Service, SocketType, TcpTransport},
+    connections::{Connections, PublicPlayerData},
+    discovery::BroadcastPacket,
+    federation::FederationPacket,
+    punch::{PeerCandidatesPacket, PunchPacket},
+    reliable::ReliableSegment,
+    replication::ReplicationPacket,
+    tunnel::PublicPortPacket,
+};
+
+/// Relays a packet may go through, so relays chained in a loop drop it instead of passing it around forever
+pub const HOP_LIMIT: u8 = 8;
 
 pub trait DataPacketLike {
     fn get_sender_name(&self) -> String;
@@ -28,6 +46,59 @@ pub enum Packet {
     GreetingReply,
     Heartbeat(String), // Needed by the UDP to avoid issues with NAT (possibly also needed for TCP?)
     Connection(ConnectionPacket),
+    /// A segment of the reliable udp channel, only ever sent over udp
+    Reliable(ReliableSegment),
+    /// The services a player published, pushed by the server whenever they change
+    Catalog(CatalogPacket),
+    /// Pushed by the server to players watching presence, as someone greets it
+    PlayerJoined(PublicPlayerData),
+    /// Pushed by the server to players watching presence, with the name of a player that disconnected
+    PlayerLeft(String),
+    /// Pushed by the server to players watching presence, as someone greets it again
+    PlayerUpdated(PublicPlayerData),
+    /// Sent by the server over links with a timestamp of its own (see [monotonic_micros]), and sent right back by players
+    RttProbe(u64),
+    /// Pushed by the server to players of a host that left, telling them who replaces it
+    HostMigration(HostMigrationPacket),
+    /// Asks the server to introduce us to a player, with the name of the player, for a direct udp path
+    PunchRequest(String),
+    /// Sent by the server to both players of a punch request
+    PeerCandidates(PeerCandidatesPacket),
+    /// Sent by players straight to each other, only ever over udp
+    Punch(PunchPacket),
+    /// Sent between federated relays
+    Federation(FederationPacket),
+    /// Sent by players probing relays before picking one, with a timestamp (microseconds since the unix epoch).
+    /// Relays send it right back, over links and over udp.
+    Probe(u64),
+    /// Sent by a primary relay to its standby
+    Replication(ReplicationPacket),
+    /// Sent by the server after a greeting, with the token proving who we are when we greet a relay again
+    SessionToken(u64),
+    /// Sent by the server for each port we asked to make public, once it allocated a port of its own for it
+    PublicPort(PublicPortPacket),
+    /// Discovery traffic of LAN games, sent by players to the relay and by the relay to the players of their room
+    Broadcast(BroadcastPacket),
+    /// Asks the server for the services every player published, answered with [Packet::Catalogs]
+    CatalogRequest,
+    /// The services every player published, answering a catalog request
+    Catalogs(Vec<CatalogPacket>),
+}
+impl Packet {
+    /// Counts one more relay a data or connection packet goes through.
+    /// False when it went through too many of them already, or isn't a packet relays pass on.
+    pub fn take_hop(&mut self) -> bool {
+        let hop_limit = match self {
+            Packet::Data(data) => &mut data.hop_limit,
+            Packet::Connection(connection) => &mut connection.hop_limit,
+            _ => return false,
+        };
+        if *hop_limit == 0 {
+            return false;
+        }
+        *hop_limit -= 1;
+        true
+    }
 }
 
 /// For announcting TCP connections
@@ -38,6 +109,8 @@ pub struct ConnectionPacket {
     pub receiver_name: String,
     pub receiver_port: u16,
     pub source_port: u16,
+    /// Relays it may still be passed on to
+    pub hop_limit: u8,
 }
 impl DataPacketLike for ConnectionPacket {
     fn get_sender_name(&self) -> String {
@@ -61,6 +134,20 @@ impl DataPacketLike for ConnectionPacket {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CatalogPacket {
+    pub player_name: String,
+    pub services: Vec<Service>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct HostMigrationPacket {
+    pub old_host_name: String,
+    pub new_host_name: String,
+    /// Ports the old host exposed, None when it exposed every port
+    pub exposed_ports: Option<Vec<u16>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CommandPacket {
     pub command: String,
@@ -75,6 +162,13 @@ pub struct DataPacket {
     pub receiver_port: u16,
     pub data: Vec<u8>,
     pub source_port: u16,
+    /// Relays it may still be passed on to
+    pub hop_limit: u8,
+    /// Set on udp data sent over several relays at once, for the receiver to drop the copies
+    pub sequence: Option<u64>,
+    /// Set on data a local program sends back to a player that reached it, for the player to pass it on
+    /// to the forward it came through rather than to one of its own local programs
+    pub reply: bool,
 }
 impl DataPacket {
     pub fn print(&self, prefix: &str) {
@@ -112,6 +206,7 @@ impl DataPacketLike for DataPacket {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn print_packet(
     prefix: &str,
     sender_name: String,
@@ -139,10 +234,47 @@ pub fn print_packet(
 pub struct GreetingPacket {
     pub player_name: String,
     pub local_port: u16,
+    /// How the player wants to exchange data of tcp connections with the relay
+    pub tcp_transport: TcpTransport,
+    /// Whether udp data should be sent to the player over its tcp link, as it can't reach our udp socket
+    pub udp_over_link: bool,
+    /// Local ports the player forwards over this session
+    pub forwarded_ports: Vec<u16>,
+    /// Local ports other players may connect to, None when every port is
+    pub exposed_ports: Option<Vec<u16>>,
+    /// Local services the player publishes to the others
+    pub services: Vec<Service>,
+    /// Whether the player wants to be told who joins and leaves the server
+    pub presence: bool,
+    /// Observers only watch the server - they aren't announced to other players
+    pub observer: bool,
+    /// The player we send our data to, ourselves for hosts
+    pub other_player_name: String,
+    /// Whether we follow host migration, and may become a host ourselves
+    pub host_migration: bool,
+    /// Local addresses of our udp socket other players may reach us at directly, None when we don't want them to.
+    /// Behind NATs and proxies there are none, others reach us at the address the relay knows us by.
+    pub udp_candidates: Option<Vec<SocketAddr>>,
+    /// Latencies we measured to the relays we pick from, in microseconds, for the players of our session
+    /// to agree on one. Empty when we didn't probe them.
+    pub relay_latencies: Vec<(String, u64)>,
+    /// The token a relay gave us, when we greet one again. Lets the standby of a relay that went down
+    /// give us back our name.
+    pub token: Option<u64>,
+    /// Exposed ports we want the relay to forward a port of its own to, for people that don't run rubicon
+    pub public_ports: Vec<Exposure>,
+}
+
+/// Microseconds since this process first asked, on a clock that never goes back.
+/// Probes carry it and are sent right back with it, so only we ever read it.
+pub fn monotonic_micros() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
 }
 
 /// Processes incomming packets
 /// TODO: replace these args with a single structure
+#[allow(clippy::too_many_arguments)]
 pub fn process_packets(
     connections: &mut Connections,
     // peers: &HashSet<u16>,
@@ -151,6 +283,10 @@ pub fn process_packets(
     disconnected: &mut Vec<u16>,
     commands: &mut Vec<String>,
     greetings: &mut Vec<(u16, GreetingPacket)>,
+    punch_requests: &mut Vec<(u16, String)>, // ignored by clients
+    federation_packets: &mut Vec<(u16, FederationPacket)>, // ignored by clients
+    broadcasts: &mut Vec<(u16, BroadcastPacket)>, // ignored by clients
+    catalog_requests: &mut Vec<u16>,         // ignored by clients
     buffer: &mut [u8],
     rejected_packets_buffers: &mut Vec<(String, u16, Vec<u8>, u16)>, // ignored by servers
     is_host: bool,                                                   // ignored by servers
@@ -166,8 +302,16 @@ pub fn process_packets(
             disconnected.push(*port);
             continue;
         }
+        // What the link held back, before anything else goes over it
+        if let Err(e) = player_data.stream.flush() {
+            println!("Failed to send data to {}: {}", player_data.name, e);
+        }
 
-        if player_data.stream.has_tcp() {
+        if player_data.stream.has_tcp()
+            || player_data.stream.has_quic()
+            || player_data.stream.has_websocket()
+            || player_data.stream.has_pipe()
+        {
             // The amount of packets to drain in a single iteration. We need more than one cuz some programs could be FLOODING our connection,
             // We can't set it too high, though, as that'd fuck up OTHER connections.
             const MAX_PACKETS_TO_GO_THROUGH: usize = usize::MAX;
@@ -181,7 +325,8 @@ pub fn process_packets(
                         // Address of the socket we're receiving data from.
 
                         if value == 0 {
-                            continue; // skip size 0 packets, lol
+                            // The stream closed while we were reading it, the next timeout check drops it
+                            break;
                         }
                         let sliced_data = &buffer[..value];
                         let deserialize = bincode::deserialize::<Packet>(sliced_data);
@@ -195,7 +340,10 @@ pub fn process_packets(
                                     // "Tcp sockets should only receive tcp data!"
                                     // );
                                     data.print("received data packet on a tcp socket :: ");
-                                    if data.socket_type == SocketType::Udp {
+                                    // Federated relays pass udp data on over their links
+                                    if data.socket_type == SocketType::Udp
+                                        && player_data.peer_relay.is_none()
+                                    {
                                         println!("Received a udp packet on a tcp relay!");
                                     }
 
@@ -215,7 +363,7 @@ pub fn process_packets(
                                     // Ping back with a reply
                                     player_data
                                         .stream
-                                        .write(&bincode::serialize(&Packet::GreetingReply).unwrap())
+                                        .send_packet(&Packet::GreetingReply)
                                         .unwrap();
                                 }
                                 Packet::Heartbeat(player_name) => {
@@ -223,19 +371,19 @@ pub fn process_packets(
                                         // We received a packet on a tcp socket from a client! Time to send it back!
                                         player_data
                                             .stream
-                                            .write(
-                                                &bincode::serialize(&Packet::Heartbeat(
-                                                    "".to_string(),
-                                                ))
-                                                .unwrap(),
-                                            )
+                                            .send_packet(&Packet::Heartbeat("".to_string()))
                                             .unwrap();
                                         println!(
                                             "Received a tcp heartbeat from player: {}",
                                             player_name
                                         );
-                                    } else {
-                                        //
+                                    } else if player_data.stream.carries_udp()
+                                        || player_data.udp_over_link
+                                    {
+                                        // No udp heartbeats come from players sending udp over their link, echo these for them to tell we're up
+                                        let _ = player_data
+                                            .stream
+                                            .send_packet(&Packet::Heartbeat("".to_string()));
                                     }
                                 }
                                 Packet::GreetingReply => {
@@ -252,6 +400,53 @@ pub fn process_packets(
                                     );
                                     connection_packets.push((*port, con));
                                 }
+                                Packet::Reliable(_) => {
+                                    println!("Received a reliable udp segment on a tcp socket!");
+                                }
+                                Packet::Catalog(_) | Packet::Catalogs(_) => {
+                                    println!("Received a catalog from a player, services are published with the greeting!");
+                                }
+                                Packet::CatalogRequest => {
+                                    catalog_requests.push(*port);
+                                }
+                                Packet::PlayerJoined(_)
+                                | Packet::PlayerLeft(_)
+                                | Packet::PlayerUpdated(_)
+                                | Packet::HostMigration(_) => {
+                                    println!("Received an event from a player, only the server sends those!");
+                                }
+                                Packet::PunchRequest(player_name) => {
+                                    punch_requests.push((*port, player_name));
+                                }
+                                Packet::PeerCandidates(_) | Packet::Punch(_) => {
+                                    println!("Received a punching packet on a tcp socket!");
+                                }
+                                Packet::Federation(federation_packet) => {
+                                    federation_packets.push((*port, federation_packet));
+                                }
+                                Packet::Broadcast(broadcast) => {
+                                    broadcasts.push((*port, broadcast));
+                                }
+                                Packet::Replication(_) => {
+                                    println!("Received replicated state on a player link!");
+                                }
+                                Packet::SessionToken(_) => {
+                                    println!("Received a session token from a player!");
+                                }
+                                Packet::PublicPort(_) => {
+                                    println!("Received a public port from a player, only the server allocates those!");
+                                }
+                                Packet::Probe(sent) => {
+                                    // A player measuring how fast we answer
+                                    let _ = player_data.stream.send_packet(&Packet::Probe(sent));
+                                }
+                                Packet::RttProbe(sent) => {
+                                    // A player sent back a probe we sent
+                                    let rtt = Duration::from_micros(
+                                        monotonic_micros().saturating_sub(sent),
+                                    );
+                                    player_data.rtt = Some(rtt);
+                                }
                             }
                         } else {
                             println!(
@@ -317,3 +512,61 @@ pub fn process_packets(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(hop_limit: u8) -> Packet {
+        Packet::Connection(ConnectionPacket {
+            sender_name: "JOINER".to_string(),
+            sender_port: 22000,
+            receiver_name: "HOST".to_string(),
+            receiver_port: 9999,
+            source_port: 40000,
+            hop_limit,
+        })
+    }
+
+    fn hop_limit(packet: &Packet) -> u8 {
+        match packet {
+            Packet::Data(data) => data.hop_limit,
+            Packet::Connection(connection) => connection.hop_limit,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn counts_relays_packets_go_through() {
+        let mut data = Packet::Data(DataPacket {
+            socket_type: SocketType::Udp,
+            sender_name: "JOINER".to_string(),
+            sender_port: 22000,
+            receiver_name: "HOST".to_string(),
+            receiver_port: 9999,
+            data: b"ping".to_vec(),
+            source_port: 40000,
+            hop_limit: HOP_LIMIT,
+            sequence: None,
+            reply: false,
+        });
+        for remaining in (0..HOP_LIMIT).rev() {
+            assert!(data.take_hop());
+            assert_eq!(hop_limit(&data), remaining);
+        }
+        // Relays chained in a loop stop passing it around
+        assert!(!data.take_hop());
+        assert_eq!(hop_limit(&data), 0);
+
+        let mut connection = connection(1);
+        assert!(connection.take_hop());
+        assert!(!connection.take_hop());
+    }
+
+    #[test]
+    fn passes_only_data_and_connections_on() {
+        assert!(!Packet::Heartbeat("HOST".to_string()).take_hop());
+        assert!(!Packet::GreetingReply.take_hop());
+        assert!(connection(HOP_LIMIT).take_hop());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    common::BUFFER_SIZE,
+    framing::{frame, next_frame},
+    packet::Packet,
+};
+
+/// Key of the stdio session among the connections of the host, tcp peers never come from port 0
+pub const STDIO_SESSION_PORT: u16 = 0;
+
+/// A relay link running over a pair of byte pipes - the stdio of a command (ie. `ssh -W relay:8080 jumphost`)
+/// on the client side, or our own stdio on a host serving a single session.
+/// Bytes on the pipes are exactly what a tcp relay link would carry, so a command forwarding
+/// to the tcp port of a regular host works as well.
+/// Udp data is carried too, as there's no telling where the other end of the pipe is.
+pub struct PipeLink {
+    description: String,
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Mutex<Box<dyn Write + Send>>,
+    closed: Arc<AtomicBool>,
+    child: Option<Mutex<Child>>,
+}
+impl PipeLink {
+    /// Runs a command through the shell and talks to the relay over its stdin and stdout.
+    /// `%h` and `%p` in the command are replaced with the host and port of the relay, the way ssh does it.
+    pub fn spawn(command: &str, relay_address: &str) -> std::io::Result<Self> {
+        let (host, port) = relay_address
+            .rsplit_once(':')
+            .unwrap_or((relay_address, ""));
+        let command = command.replace("%h", host).replace("%p", port);
+        println!("Reaching the relay through: {}", command);
+
+        #[cfg(unix)]
+        let mut shell = Command::new("sh");
+        #[cfg(unix)]
+        shell.arg("-c");
+        #[cfg(windows)]
+        let mut shell = Command::new("cmd");
+        #[cfg(windows)]
+        shell.arg("/C");
+        let mut child = shell
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let mut link = Self::new(command, Box::new(stdout), Box::new(stdin));
+        link.child = Some(Mutex::new(child));
+        Ok(link)
+    }
+
+    /// Serves a session over our own stdin and stdout.
+    /// Everything printed from now on goes to stderr, so it doesn't end up in the session.
+    pub fn stdio() -> std::io::Result<Self> {
+        let stdout = take_stdout()?;
+        Ok(Self::new(
+            "stdio".to_string(),
+            Box::new(std::io::stdin()),
+            stdout,
+        ))
+    }
+
+    fn new(
+        description: String,
+        mut reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+    ) -> Self {
+        let (sender, incoming) = channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_cloned = closed.clone();
+        let description_cloned = description.clone();
+        std::thread::spawn(move || {
+            let mut pending = vec![];
+            let mut chunk = vec![0u8; BUFFER_SIZE];
+            loop {
+                loop {
+                    match next_frame(&mut pending) {
+                        Ok(Some(packet)) => {
+                            if sender.send(packet).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(_) => {
+                            println!("The pipe ({}) lost track of its frames", description_cloned);
+                            closed_cloned.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                }
+                match reader.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => pending.extend_from_slice(&chunk[..size]),
+                }
+            }
+            println!("The pipe ({}) was closed", description_cloned);
+            closed_cloned.store(true, Ordering::Relaxed);
+        });
+        Self {
+            description,
+            incoming,
+            outgoing: Mutex::new(writer),
+            closed,
+            child: None,
+        }
+    }
+
+    /// Pipes have no address, but players need one
+    pub fn remote_address(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// A flag raised once the other side closes the pipe
+    pub fn closed_flag(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
+    }
+
+    /// Reads a single message (serialized packet), returns [ErrorKind::WouldBlock] if there's none.
+    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.incoming.try_recv() {
+            Ok(message) => {
+                if message.len() > buf.len() {
+                    println!(
+                        "Dropping a message of size {} from the pipe ({}) that doesn't fit the buffer",
+                        message.len(),
+                        self.description
+                    );
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                buf[..message.len()].copy_from_slice(&message);
+                Ok(message.len())
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => Ok(0),
+        }
+    }
+
+    pub fn send_packet(&self, packet: &Packet) -> std::io::Result<()> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        outgoing.write_all(&frame(packet))?;
+        outgoing.flush()
+    }
+}
+
+impl std::fmt::Debug for PipeLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PipeLink")
+            .field("description", &self.description)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Drop for PipeLink {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_ref() {
+            let _ = child.lock().unwrap().kill();
+        }
+    }
+}
+
+/// Moves stdout aside for the session, pointing the old one at stderr.
+#[cfg(unix)]
+fn take_stdout() -> std::io::Result<Box<dyn Write + Send>> {
+    use std::os::fd::FromRawFd;
+
+    std::io::stdout().flush()?;
+    // SAFETY: plain fd juggling, the duplicated fd is owned by the returned file
+    unsafe {
+        let session = libc::dup(libc::STDOUT_FILENO);
+        if session < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Box::new(std::fs::File::from_raw_fd(session)))
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> std::io::Result<Box<dyn Write + Send>> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "Serving a session over stdio is only supported on unix",
+    ))
+}
@@ -0,0 +1,342 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::BUFFER_SIZE,
+    failover::{udp_address, Relays},
+    packet::{monotonic_micros, Packet},
+    socket::SocketWrapper,
+};
+
+/// Probes sent to each relay, over its link and over udp
+const PROBES: u32 = 8;
+/// How long to wait for the echo of a probe
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time between two udp probes, so they don't queue up behind each other
+const UDP_PROBE_INTERVAL: Duration = Duration::from_millis(20);
+/// Time between two checks for an echo over a link, short as it adds to the measured round trip times
+const PROBE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// How a relay answered our probes over one protocol
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProbeStats {
+    pub sent: u32,
+    pub received: u32,
+    /// Sum of the round trip times of the probes that came back
+    pub total_rtt: Duration,
+}
+impl ProbeStats {
+    pub fn loss(&self) -> f64 {
+        1. - self.received as f64 / self.sent.max(1) as f64
+    }
+
+    /// Average round trip time, inflated by the loss as lost data has to be sent again.
+    /// None when nothing came back.
+    pub fn expected_rtt(&self) -> Option<Duration> {
+        if self.received == 0 {
+            return None;
+        }
+        Some((self.total_rtt / self.received).div_f64(1. - self.loss()))
+    }
+
+    pub fn receive(&mut self, sent: u64) {
+        self.received += 1;
+        self.total_rtt += Duration::from_micros(monotonic_micros().saturating_sub(sent));
+    }
+}
+impl std::fmt::Display for ProbeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.received == 0 {
+            return write!(f, "no answer");
+        }
+        write!(
+            f,
+            "{:.2}ms, {:.0}% loss",
+            (self.total_rtt / self.received).as_secs_f64() * 1000.,
+            self.loss() * 100.
+        )
+    }
+}
+
+/// How a relay answered our probes
+pub struct RelayMeasurement {
+    pub address: String,
+    pub link: ProbeStats,
+    /// None when the relay has no udp socket we can reach to probe, ie. `quic://` and `ws://` relays carrying udp over their link
+    pub udp: Option<ProbeStats>,
+}
+impl RelayMeasurement {
+    /// Expected round trip time to the relay in microseconds, the slower of its link and udp.
+    /// Relays whose udp doesn't answer (ie. behind firewalls only letting tcp through) are measured by their link.
+    /// None when its link doesn't answer.
+    pub fn latency(&self) -> Option<u64> {
+        let link = self.link.expected_rtt()?;
+        let udp = self
+            .udp
+            .as_ref()
+            .and_then(ProbeStats::expected_rtt)
+            .unwrap_or_default();
+        Some(link.max(udp).as_micros() as u64)
+    }
+}
+impl std::fmt::Display for RelayMeasurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: link {}", self.address, self.link)?;
+        match self.udp.as_ref() {
+            Some(udp) => write!(f, ", udp {}", udp),
+            None => write!(f, ", can't be probed over udp"),
+        }
+    }
+}
+
+/// Times probe exchanges with every relay over a link and with its udp socket, before we link to one of them.
+/// The probing links never greet, relays don't see a player until we pick one.
+pub fn probe_relays(relays: &Relays) -> Vec<RelayMeasurement> {
+    relays
+        .addresses
+        .iter()
+        .map(|address| {
+            let link = match relays.dial(address) {
+                Ok(link) => probe_link(&link),
+                Err(e) => {
+                    println!("Relay {} is unreachable: {}", address, e);
+                    ProbeStats::default()
+                }
+            };
+            let measurement = RelayMeasurement {
+                address: address.clone(),
+                link,
+                udp: udp_address(address)
+                    .filter(|_| relays.reaches_udp)
+                    .map(probe_udp),
+            };
+            println!("Probed relay {}", measurement);
+            measurement
+        })
+        .collect()
+}
+
+/// Sends probes over the link one after the other, each waiting for the echo of the one before
+fn probe_link(link: &SocketWrapper) -> ProbeStats {
+    let mut stats = ProbeStats::default();
+    if link.set_nonblocking(true).is_err() {
+        return stats;
+    }
+    let mut buffer = [0u8; BUFFER_SIZE];
+    for _ in 0..PROBES {
+        let sent = monotonic_micros();
+        stats.sent += 1;
+        if link.send_packet(&Packet::Probe(sent)).is_err() {
+            continue;
+        }
+        let begin = Instant::now();
+        while begin.elapsed() < PROBE_TIMEOUT {
+            match link.read(&mut buffer) {
+                Ok(0) => return stats,
+                Ok(size) => {
+                    if let Ok(Packet::Probe(echoed)) = bincode::deserialize(&buffer[..size]) {
+                        if echoed == sent {
+                            stats.receive(sent);
+                            break;
+                        }
+                    }
+                }
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return stats,
+                Err(_) => std::thread::sleep(PROBE_POLL_INTERVAL),
+            }
+        }
+    }
+    stats
+}
+
+/// Sends probes to the udp socket of the relay, spaced out, and gathers the echoes
+fn probe_udp(address: SocketAddr) -> ProbeStats {
+    let mut stats = ProbeStats::default();
+    let Ok(socket) = UdpSocket::bind(if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    }) else {
+        return stats;
+    };
+    if socket.connect(address).is_err() {
+        return stats;
+    }
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let mut last_probe = Instant::now() - UDP_PROBE_INTERVAL;
+    while stats.received < PROBES {
+        if stats.sent < PROBES && last_probe.elapsed() >= UDP_PROBE_INTERVAL {
+            last_probe = Instant::now();
+            stats.sent += 1;
+            let _ = socket.send(&bincode::serialize(&Packet::Probe(monotonic_micros())).unwrap());
+        }
+        // Waits for echoes until the next probe is due, or until the last one timed out
+        let wait_for = if stats.sent < PROBES {
+            UDP_PROBE_INTERVAL
+        } else {
+            PROBE_TIMEOUT
+        };
+        let Some(wait) = wait_for
+            .checked_sub(last_probe.elapsed())
+            .filter(|wait| !wait.is_zero())
+        else {
+            if stats.sent < PROBES {
+                continue;
+            }
+            break;
+        };
+        if socket.set_read_timeout(Some(wait)).is_err() {
+            break;
+        }
+        match socket.recv(&mut buffer) {
+            Ok(size) => {
+                if let Ok(Packet::Probe(sent)) = bincode::deserialize(&buffer[..size]) {
+                    stats.receive(sent);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            // Ie. the relay refusing udp, the next probe may still get through
+            Err(_) => std::thread::sleep(wait),
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Echoes probes over links like relays do, or reads them without answering
+    fn relay_link(listener: TcpListener, answers: bool) {
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let link = SocketWrapper::from_relay_tcp_socket(stream);
+                    let mut buffer = [0u8; BUFFER_SIZE];
+                    while let Ok(size) = link.read(&mut buffer) {
+                        match bincode::deserialize(&buffer[..size]) {
+                            Ok(Packet::Probe(sent)) if answers => {
+                                let _ = link.send_packet(&Packet::Probe(sent));
+                            }
+                            Ok(_) if size > 0 => continue,
+                            _ => break,
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Echoes probes sent to a udp socket like relays do
+    fn relay_udp(socket: UdpSocket) {
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; BUFFER_SIZE];
+            while let Ok((size, source)) = socket.recv_from(&mut buffer) {
+                let _ = socket.send_to(&buffer[..size], source);
+            }
+        });
+    }
+
+    #[test]
+    fn inflates_round_trip_times_by_the_loss() {
+        let stats = ProbeStats {
+            sent: 4,
+            received: 2,
+            total_rtt: Duration::from_millis(20),
+        };
+        assert_eq!(stats.loss(), 0.5);
+        assert_eq!(stats.expected_rtt(), Some(Duration::from_millis(20)));
+        assert_eq!(ProbeStats::default().expected_rtt(), None);
+    }
+
+    #[test]
+    fn measures_relays_by_their_link_when_udp_doesnt_answer() {
+        let answered = ProbeStats {
+            sent: 2,
+            received: 2,
+            total_rtt: Duration::from_millis(20),
+        };
+        let mut measurement = RelayMeasurement {
+            address: "relay".to_string(),
+            link: answered,
+            udp: None,
+        };
+        assert_eq!(measurement.latency(), Some(10_000));
+        measurement.udp = Some(ProbeStats {
+            sent: 2,
+            ..Default::default()
+        });
+        assert_eq!(measurement.latency(), Some(10_000));
+        measurement.udp = Some(ProbeStats {
+            total_rtt: Duration::from_millis(40),
+            ..answered
+        });
+        assert_eq!(measurement.latency(), Some(20_000));
+        measurement.link = ProbeStats::default();
+        assert_eq!(measurement.latency(), None);
+    }
+
+    #[test]
+    fn probes_links_of_relays() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        relay_link(listener, true);
+        let link = SocketWrapper::from_relay_tcp_socket(TcpStream::connect(address).unwrap());
+        let stats = probe_link(&link);
+        assert_eq!((stats.sent, stats.received), (PROBES, PROBES));
+        assert!(stats.expected_rtt().unwrap() < PROBE_TIMEOUT);
+
+        // Each probe of a link that doesn't answer times out
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        relay_link(listener, false);
+        let link = SocketWrapper::from_relay_tcp_socket(TcpStream::connect(address).unwrap());
+        let stats = probe_link(&link);
+        assert_eq!((stats.sent, stats.received), (PROBES, 0));
+    }
+
+    #[test]
+    fn probes_relays_over_their_link_and_udp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let udp_socket = UdpSocket::bind(address).unwrap();
+        relay_link(listener, true);
+        relay_udp(udp_socket);
+        // Tcp only, its udp socket being on another port
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_only = listener.local_addr().unwrap();
+        relay_link(listener, true);
+
+        let relays = Relays::new(&format!("{},{}", address, tcp_only), None, None, None).unwrap();
+        let measurements = probe_relays(&relays);
+        assert_eq!(measurements[0].link.received, PROBES);
+        assert_eq!(measurements[0].udp.unwrap().received, PROBES);
+        assert!(measurements[0].latency().is_some());
+        assert_eq!(measurements[1].link.received, PROBES);
+        assert_eq!(measurements[1].udp.unwrap().received, 0);
+        assert!(measurements[1].latency().is_some());
+    }
+
+    #[test]
+    fn probes_udp_sockets_of_relays() {
+        // The udp socket of a relay echoes probes
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = relay.local_addr().unwrap();
+        relay_udp(relay);
+        let stats = probe_udp(address);
+        assert_eq!((stats.sent, stats.received), (PROBES, PROBES));
+        assert!(stats.expected_rtt().unwrap() < PROBE_TIMEOUT);
+
+        // One that doesn't answer is given up on once the last probe timed out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let begin = Instant::now();
+        let stats = probe_udp(silent.local_addr().unwrap());
+        assert_eq!((stats.sent, stats.received), (PROBES, 0));
+        assert!(begin.elapsed() < UDP_PROBE_INTERVAL * PROBES + PROBE_TIMEOUT * 2);
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often to punch while trying to reach a player directly
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to keep punching before giving up on reaching a player directly
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to check a direct path is still up
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a direct path may stay silent before falling back to the relay
+const PATH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before asking for introductions, for the relay to learn where our udp socket is
+const INTRODUCTION_DELAY: Duration = Duration::from_secs(1);
+/// How long to stay on the relay before trying to reach a player directly again
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Addresses a player may be reached at directly, sent by the server to both players wanting a direct path
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PeerCandidatesPacket {
+    pub player_name: String,
+    /// The public address the server knows the player by comes first, then its local ones
+    pub candidates: Vec<SocketAddr>,
+}
+
+/// Sent straight to the udp socket of another player, to open our NAT towards it and check it reaches us
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PunchPacket {
+    pub player_name: String,
+    /// Set when answering a punch of the other player
+    pub ack: bool,
+}
+
+struct DirectPath {
+    candidates: Vec<SocketAddr>,
+    /// The address the player punched us from, once it did
+    address: Option<SocketAddr>,
+    /// Whether the player answered our punches, so udp goes both ways
+    confirmed: bool,
+    started: Instant,
+    last_punch: Instant,
+    last_seen: Instant,
+}
+
+/// Direct udp paths to other players, skipping the relay.
+/// The server introduces players to each other, then both punch holes in their NATs by sending
+/// to each other at the same time. Udp data goes over the direct path once punches go both ways,
+/// and back over the relay when the path goes silent.
+pub struct DirectPaths {
+    player_name: String,
+    paths: HashMap<String, DirectPath>,
+    /// When we may ask the server to introduce us to a player again
+    retry_at: HashMap<String, Instant>,
+    created: Instant,
+}
+impl DirectPaths {
+    pub fn new(player_name: String) -> Self {
+        Self {
+            player_name,
+            paths: Default::default(),
+            retry_at: Default::default(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Whether we should ask the server to introduce us to a player now
+    pub fn wants_introduction(&mut self, player_name: &str) -> bool {
+        if player_name == self.player_name
+            || self.paths.contains_key(player_name)
+            || self.created.elapsed() < INTRODUCTION_DELAY
+        {
+            return false;
+        }
+        if self
+            .retry_at
+            .get(player_name)
+            .is_some_and(|retry_at| Instant::now() < *retry_at)
+        {
+            return false;
+        }
+        // Don't ask again while the server is at it
+        self.retry_at
+            .insert(player_name.to_string(), Instant::now() + PUNCH_TIMEOUT);
+        true
+    }
+
+    /// Starts punching towards a player the server introduced us to
+    pub fn receive_candidates(&mut self, candidates: PeerCandidatesPacket) {
+        if self
+            .paths
+            .get(&candidates.player_name)
+            .is_some_and(|path| path.confirmed)
+        {
+            return;
+        }
+        println!(
+            "Trying to reach {} directly at {:?}",
+            candidates.player_name, candidates.candidates
+        );
+        let now = Instant::now();
+        self.paths.insert(
+            candidates.player_name,
+            DirectPath {
+                candidates: candidates.candidates,
+                address: None,
+                confirmed: false,
+                started: now,
+                last_punch: now - PUNCH_INTERVAL,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Handles a punch the other player sent us from an address, returning an answer to send back
+    pub fn receive_punch(
+        &mut self,
+        punch: PunchPacket,
+        address: SocketAddr,
+    ) -> Option<(SocketAddr, PunchPacket)> {
+        let Some(path) = self.paths.get_mut(&punch.player_name) else {
+            // We weren't introduced, or gave up already
+            return None;
+        };
+        // Anyone can claim a name, only the addresses the server vouched for are the player
+        if !path.candidates.contains(&address) {
+            println!(
+                "Dropping a punch of {} from {}, which isn't one of its addresses",
+                punch.player_name, address
+            );
+            return None;
+        }
+        if path.confirmed && path.address != Some(address) {
+            println!(
+                "Dropping a punch of {} from {}, its direct path goes to {:?}",
+                punch.player_name, address, path.address
+            );
+            return None;
+        }
+        path.last_seen = Instant::now();
+        path.address = Some(address);
+        if punch.ack && !path.confirmed {
+            path.confirmed = true;
+            println!(
+                "DIRECT PATH: udp to {} now goes straight to {}",
+                punch.player_name, address
+            );
+        }
+        (!punch.ack).then(|| {
+            (
+                address,
+                PunchPacket {
+                    player_name: self.player_name.clone(),
+                    ack: true,
+                },
+            )
+        })
+    }
+
+    /// Notes data arriving from an address, keeping the direct path it belongs to alive
+    pub fn receive_data(&mut self, player_name: &str, address: SocketAddr) {
+        if let Some(path) = self.paths.get_mut(player_name) {
+            if path.address == Some(address) {
+                path.last_seen = Instant::now();
+            }
+        }
+    }
+
+    /// Where to send udp data meant for a player, when it can skip the relay
+    pub fn get_address(&self, player_name: &str) -> Option<SocketAddr> {
+        self.paths
+            .get(player_name)
+            .filter(|path| path.confirmed)
+            .and_then(|path| path.address)
+    }
+
+    /// Gives up on paths that went silent, and returns the punches to send now
+    pub fn tick(&mut self) -> Vec<(SocketAddr, PunchPacket)> {
+        let now = Instant::now();
+        let mut punches = vec![];
+        let mut failed = vec![];
+        for (player_name, path) in self.paths.iter_mut() {
+            if path.confirmed {
+                if now.duration_since(path.last_seen) > PATH_TIMEOUT {
+                    println!(
+                        "RELAY PATH: lost the direct path to {}, falling back to the relay",
+                        player_name
+                    );
+                    failed.push(player_name.clone());
+                } else if now.duration_since(path.last_punch) >= KEEPALIVE_INTERVAL {
+                    path.last_punch = now;
+                    punches.extend(path.address);
+                }
+            } else if now.duration_since(path.started) > PUNCH_TIMEOUT {
+                println!(
+                    "RELAY PATH: couldn't reach {} directly, staying on the relay",
+                    player_name
+                );
+                failed.push(player_name.clone());
+            } else if now.duration_since(path.last_punch) >= PUNCH_INTERVAL {
+                path.last_punch = now;
+                // Answering the address it punched us from is our best bet, keep trying the others too
+                for address in path.address.iter().chain(path.candidates.iter()) {
+                    punches.push(*address);
+                }
+            }
+        }
+        for player_name in failed {
+            self.paths.remove(&player_name);
+            self.retry_at.insert(player_name, now + RETRY_INTERVAL);
+        }
+        punches
+            .into_iter()
+            .map(|address| {
+                (
+                    address,
+                    PunchPacket {
+                        player_name: self.player_name.clone(),
+                        ack: false,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn introduced(candidates: &[SocketAddr]) -> DirectPaths {
+        let mut paths = DirectPaths::new("A".to_string());
+        paths.receive_candidates(PeerCandidatesPacket {
+            player_name: "B".to_string(),
+            candidates: candidates.to_vec(),
+        });
+        paths
+    }
+
+    fn punch(ack: bool) -> PunchPacket {
+        PunchPacket {
+            player_name: "B".to_string(),
+            ack,
+        }
+    }
+
+    #[test]
+    fn answers_punches_from_candidates() {
+        let public = "203.0.113.7:40000".parse().unwrap();
+        let local = "192.168.1.20:22000".parse().unwrap();
+        let mut paths = introduced(&[public, local]);
+
+        let (answer_to, answer) = paths.receive_punch(punch(false), local).unwrap();
+        assert_eq!(answer_to, local);
+        assert!(answer.ack && answer.player_name == "A");
+        assert_eq!(paths.get_address("B"), None);
+
+        assert!(paths.receive_punch(punch(true), local).is_none());
+        assert_eq!(paths.get_address("B"), Some(local));
+    }
+
+    #[test]
+    fn drops_punches_from_elsewhere() {
+        let public = "203.0.113.7:40000".parse().unwrap();
+        let spoofed = "198.51.100.1:1234".parse().unwrap();
+        let mut paths = introduced(&[public]);
+
+        assert!(paths.receive_punch(punch(false), spoofed).is_none());
+        assert!(paths.receive_punch(punch(true), spoofed).is_none());
+        assert_eq!(paths.get_address("B"), None);
+
+        // Never introduced
+        let mut strangers = DirectPaths::new("A".to_string());
+        assert!(strangers.receive_punch(punch(false), public).is_none());
+    }
+
+    #[test]
+    fn keeps_confirmed_paths_where_they_are() {
+        let public = "203.0.113.7:40000".parse().unwrap();
+        let local = "192.168.1.20:22000".parse().unwrap();
+        let mut paths = introduced(&[public, local]);
+        paths.receive_punch(punch(true), public);
+        assert_eq!(paths.get_address("B"), Some(public));
+
+        assert!(paths.receive_punch(punch(false), local).is_none());
+        assert!(paths.receive_punch(punch(true), local).is_none());
+        assert_eq!(paths.get_address("B"), Some(public));
+        assert!(paths.receive_punch(punch(false), public).is_some());
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use quinn::{
+    crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint, IdleTimeout, RecvStream,
+    SendDatagramError, SendStream, ServerConfig, TransportConfig, VarInt,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{
+    commands::SocketType,
+    connections::{Connections, PlayerData},
+    framing::MAX_FRAME_SIZE,
+    packet::Packet,
+    socket::SocketWrapper,
+};
+
+/// Server name used in the tls handshake. Certificates are pinned rather than checked against it.
+const SERVER_NAME: &str = "rubicon";
+/// Separates the address of a relay from the fingerprint of its certificate, ie. `quic://address:port#sha256=...`
+const FINGERPRINT_PREFIX: &str = "#sha256=";
+/// Streams of flows that didn't carry anything for this long get closed.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Every local tcp connection uses its own stream, so we need plenty of them
+const MAX_CONCURRENT_STREAMS: u32 = 4096;
+/// Name of the flow carrying everything that isn't tied to a single connection (greetings, heartbeats...)
+const CONTROL_FLOW: &str = "control";
+/// Name of the flow carrying udp data too large to fit in a datagram
+const OVERSIZED_DATAGRAMS_FLOW: &str = "datagrams";
+
+/// Quic is asynchronous, while the rest of rubicon is not.
+/// All quic connections are driven by a single runtime running on its own threads.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("rubicon-quic")
+            .enable_all()
+            .build()
+            .unwrap()
+    })
+}
+
+#[derive(Debug)]
+struct OutgoingFrame {
+    flow: String,
+    data: Vec<u8>,
+}
+
+/// A quic connection to the relay (or, on the server side, to a player).
+/// Every local tcp connection gets its own stream, so flows of different players don't block
+/// each other, while udp data rides as quic datagrams.
+/// Every message received, regardless of the stream or datagram it came from, is a serialized [Packet].
+#[derive(Debug)]
+pub struct QuicLink {
+    connection: Connection,
+    incoming: Receiver<Vec<u8>>,
+    outgoing: UnboundedSender<OutgoingFrame>,
+    // Client side links own their endpoint
+    _endpoint: Option<Endpoint>,
+}
+impl QuicLink {
+    fn new(connection: Connection, endpoint: Option<Endpoint>) -> Self {
+        let (incoming_sender, incoming) = std::sync::mpsc::channel();
+        let (outgoing, outgoing_receiver) = unbounded_channel();
+        let runtime = runtime();
+        runtime.spawn(receive_streams(connection.clone(), incoming_sender.clone()));
+        runtime.spawn(receive_datagrams(connection.clone(), incoming_sender));
+        runtime.spawn(send_streams(connection.clone(), outgoing_receiver));
+        Self {
+            connection,
+            incoming,
+            outgoing,
+            _endpoint: endpoint,
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+
+    /// Reads a single message (serialized packet), returns [ErrorKind::WouldBlock] if there's none.
+    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.incoming.try_recv() {
+            Ok(message) => {
+                if message.len() > buf.len() {
+                    println!(
+                        "Dropping a quic message of size {} that doesn't fit the buffer",
+                        message.len()
+                    );
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                buf[..message.len()].copy_from_slice(&message);
+                Ok(message.len())
+            }
+            Err(std::sync::mpsc::TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => Ok(0),
+        }
+    }
+
+    /// Sends a packet over the stream of its flow, or as a datagram for udp data.
+    pub fn send_packet(&self, packet: &Packet) -> std::io::Result<()> {
+        let data = bincode::serialize(packet).unwrap();
+        let flow = match packet {
+            Packet::Data(data_packet) if data_packet.socket_type == SocketType::Udp => {
+                match self.connection.send_datagram(data.clone().into()) {
+                    Ok(()) => return Ok(()),
+                    Err(SendDatagramError::TooLarge) => OVERSIZED_DATAGRAMS_FLOW.to_string(),
+                    Err(e) => return Err(std::io::Error::other(e)),
+                }
+            }
+            Packet::Data(data_packet) => format!(
+                "{}:{}>{}:{}",
+                data_packet.sender_name,
+                data_packet.source_port,
+                data_packet.receiver_name,
+                data_packet.receiver_port
+            ),
+            // Connection packets need to go along the data of their connection, to keep the order
+            Packet::Connection(con) => format!(
+                "{}:{}>{}:{}",
+                con.sender_name, con.source_port, con.receiver_name, con.receiver_port
+            ),
+            _ => CONTROL_FLOW.to_string(),
+        };
+        self.outgoing
+            .send(OutgoingFrame { flow, data })
+            .map_err(|_| ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// Connects to a relay server listening for quic connections.
+/// The address carries the fingerprint of the certificate of the relay, as `address:port#sha256=...`.
+pub fn connect(address: &str) -> std::io::Result<QuicLink> {
+    let Some((address, fingerprint)) = address.split_once(FINGERPRINT_PREFIX) else {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "The certificate of quic relays is pinned, connect with quic://{}{}<fingerprint the relay prints>",
+                address, FINGERPRINT_PREFIX
+            ),
+        ));
+    };
+    let fingerprint = parse_fingerprint(fingerprint).ok_or(std::io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} is not a sha256 fingerprint", fingerprint),
+    ))?;
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or(ErrorKind::AddrNotAvailable)?;
+    runtime().block_on(async move {
+        let local_address: SocketAddr = if address.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let mut endpoint = Endpoint::client(local_address)?;
+        endpoint.set_default_client_config(client_config(fingerprint));
+        let connection = endpoint
+            .connect(address, SERVER_NAME)
+            .map_err(std::io::Error::other)?
+            .await
+            .map_err(std::io::Error::other)?;
+        println!(
+            "Established a quic connection with the relay at {}",
+            connection.remote_address()
+        );
+        Ok(QuicLink::new(connection, Some(endpoint)))
+    })
+}
+
+/// Accepts quic connections on a given port, adding them to the connections of the server.
+/// Connections migrate when the ip address of a client changes, without the player being dropped.
+/// The certificate is kept in a file, for its fingerprint to stay the one clients pinned.
+pub fn listen(port: u16, certificate_file: &str, connections: Connections) {
+    let (certificate, key) = load_or_create_certificate(certificate_file).unwrap();
+    let fingerprint = fingerprint(&certificate);
+    let endpoint = runtime().block_on(async move {
+        Endpoint::server(
+            server_config(certificate, key),
+            SocketAddr::from(([0, 0, 0, 0], port)),
+        )
+        .unwrap()
+    });
+    println!("Accepting quic connections on {}", port);
+    println!(
+        "QUIC CERTIFICATE: clients connect with quic://<address>:{}{}{}",
+        port, FINGERPRINT_PREFIX, fingerprint
+    );
+    runtime().spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                match incoming.await {
+                    Ok(connection) => {
+                        let address = connection.remote_address();
+                        println!("Received quic connection from: {}", address);
+                        connections.data.lock().unwrap().insert_link(
+                            address.port(),
+                            PlayerData::new(
+                                address,
+                                SocketWrapper::from_quic(QuicLink::new(connection, None)),
+                            ),
+                        );
+                    }
+                    Err(e) => println!("Failed to accept a quic connection: {}", e),
+                }
+            });
+        }
+    });
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .max_concurrent_uni_streams(VarInt::from_u32(MAX_CONCURRENT_STREAMS))
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(Some(IdleTimeout::try_from(MAX_IDLE_TIMEOUT).unwrap()));
+    Arc::new(transport)
+}
+
+/// Reads the certificate and key of a relay from a file, creating them the first time
+fn load_or_create_certificate(
+    file: &str,
+) -> std::io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let invalid = |e| std::io::Error::new(ErrorKind::InvalidData, format!("{}: {:?}", file, e));
+    match std::fs::read(file) {
+        Ok(pem) => Ok((
+            CertificateDer::from_pem_slice(&pem).map_err(invalid)?,
+            PrivateKeyDer::from_pem_slice(&pem).map_err(invalid)?,
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let certificate =
+                rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+            let pem = certificate.cert.pem() + &certificate.key_pair.serialize_pem();
+            write_private(file, pem.as_bytes())?;
+            println!("QUIC CERTIFICATE: created {}", file);
+            Ok((
+                certificate.cert.der().clone(),
+                PrivatePkcs8KeyDer::from(certificate.key_pair.serialize_der()).into(),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a file only we may read, it holds a private key
+fn write_private(file: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(file)?, data)
+}
+
+/// The sha256 digest of a certificate, in hex
+pub fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    ring::digest::digest(&ring::digest::SHA256, certificate)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_fingerprint(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut fingerprint = [0; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+fn server_config(
+    certificate: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+) -> ServerConfig {
+    let mut config = ServerConfig::with_single_cert(vec![certificate], key).unwrap();
+    config.transport_config(transport_config()).migration(true);
+    config
+}
+
+fn client_config(fingerprint: [u8; 32]) -> ClientConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            fingerprint,
+            provider,
+        }))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()));
+    config.transport_config(transport_config());
+    config
+}
+
+/// Relays use self signed certificates, so we can't verify them against any authority.
+/// Instead, the certificate has to be the one whose fingerprint the player was given.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+        if digest.as_ref() != self.fingerprint {
+            println!(
+                "The quic relay presented a certificate of fingerprint {}, not the pinned one!",
+                fingerprint(end_entity)
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+async fn receive_streams(connection: Connection, sender: Sender<Vec<u8>>) {
+    while let Ok(stream) = connection.accept_uni().await {
+        tokio::spawn(receive_frames(stream, sender.clone()));
+    }
+}
+
+/// Streams carry length prefixed messages
+async fn receive_frames(mut stream: RecvStream, sender: Sender<Vec<u8>>) {
+    let mut length = [0u8; 4];
+    while stream.read_exact(&mut length).await.is_ok() {
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_FRAME_SIZE {
+            println!(
+                "Dropping a quic stream announcing a frame of {} bytes, it isn't speaking our protocol",
+                length
+            );
+            let _ = stream.stop(VarInt::from_u32(0));
+            break;
+        }
+        let mut frame = vec![0u8; length];
+        if stream.read_exact(&mut frame).await.is_err() || sender.send(frame).is_err() {
+            break;
+        }
+    }
+}
+
+async fn receive_datagrams(connection: Connection, sender: Sender<Vec<u8>>) {
+    while let Ok(datagram) = connection.read_datagram().await {
+        if sender.send(datagram.to_vec()).is_err() {
+            break;
+        }
+    }
+}
+
+/// Dispatches outgoing frames to the streams of their flows, opening new streams as needed.
+async fn send_streams(connection: Connection, mut receiver: UnboundedReceiver<OutgoingFrame>) {
+    let mut flows: HashMap<String, UnboundedSender<Vec<u8>>> = HashMap::new();
+    while let Some(OutgoingFrame { flow, data }) = receiver.recv().await {
+        let data = match flows.get(&flow) {
+            Some(sender) => match sender.send(data) {
+                Ok(()) => continue,
+                // The stream of the flow was closed after being idle
+                Err(e) => e.0,
+            },
+            None => data,
+        };
+        let Ok(stream) = connection.open_uni().await else {
+            break;
+        };
+        let (sender, flow_receiver) = unbounded_channel();
+        sender.send(data).unwrap();
+        tokio::spawn(send_frames(stream, flow_receiver));
+        flows.retain(|_, sender| !sender.is_closed());
+        flows.insert(flow, sender);
+    }
+}
+
+async fn send_frames(mut stream: SendStream, mut receiver: UnboundedReceiver<Vec<u8>>) {
+    loop {
+        match tokio::time::timeout(FLOW_IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Some(frame)) => {
+                if write_frame(&mut stream, &frame).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {
+                // Idle for too long. Stop accepting frames, but send out whatever came in meanwhile.
+                receiver.close();
+                while let Ok(frame) = receiver.try_recv() {
+                    if write_frame(&mut stream, &frame).await.is_err() {
+                        return;
+                    }
+                }
+                break;
+            }
+        }
+    }
+    let _ = stream.finish();
+}
+
+async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> Result<(), quinn::WriteError> {
+    stream
+        .write_all(&(frame.len() as u32).to_le_bytes())
+        .await?;
+    stream.write_all(frame).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_fingerprints_it_prints() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+        let printed = fingerprint(certificate.cert.der());
+        let parsed = parse_fingerprint(&printed).unwrap();
+        assert_eq!(
+            parsed,
+            ring::digest::digest(&ring::digest::SHA256, certificate.cert.der()).as_ref()
+        );
+        assert_eq!(parse_fingerprint(&printed[1..]), None);
+        assert_eq!(
+            parse_fingerprint(&printed.replace(&printed[..2], "zz")),
+            None
+        );
+        assert_eq!(parse_fingerprint(&"é".repeat(32)), None);
+    }
+
+    #[test]
+    fn stops_streams_announcing_oversized_frames() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+        let fingerprint = parse_fingerprint(&fingerprint(certificate.cert.der())).unwrap();
+        let key = PrivatePkcs8KeyDer::from(certificate.key_pair.serialize_der()).into();
+        runtime().block_on(async move {
+            let relay = Endpoint::server(
+                server_config(certificate.cert.der().clone(), key),
+                "127.0.0.1:0".parse().unwrap(),
+            )
+            .unwrap();
+            let mut player = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            player.set_default_client_config(client_config(fingerprint));
+            let connecting = player
+                .connect(relay.local_addr().unwrap(), SERVER_NAME)
+                .unwrap();
+            let (connection, accepted) =
+                tokio::join!(connecting, async { relay.accept().await.unwrap().await });
+            let connection = connection.unwrap();
+            let link = QuicLink::new(accepted.unwrap(), None);
+
+            let mut stream = connection.open_uni().await.unwrap();
+            // In one write, the relay stopping the stream as soon as it read the length
+            let junk = [&u32::MAX.to_le_bytes()[..], b"GET / HTTP/1.1"].concat();
+            stream.write_all(&junk).await.unwrap();
+            let stopped = tokio::time::timeout(Duration::from_secs(5), stream.stopped()).await;
+            assert_eq!(stopped.unwrap().unwrap(), Some(VarInt::from_u32(0)));
+            assert_eq!(
+                link.read(&mut [0u8; 64]).unwrap_err().kind(),
+                ErrorKind::WouldBlock
+            );
+        });
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum amount of payload bytes carried by a single fragment.
+/// Chosen to keep datagrams under the usual internet MTU.
+pub const RELIABLE_MSS: usize = 1200;
+/// How many fragments can be in flight (or waiting for reassembly) at once.
+pub const RELIABLE_WINDOW: u32 = 512;
+/// How many later fragments need to be acknowledged before a missing one gets resent early.
+pub const FAST_RESEND_THRESHOLD: u32 = 2;
+/// After this many retransmissions of the same fragment, the channel is considered dead.
+pub const MAX_TRANSMISSIONS: u32 = 30;
+
+const MIN_RTO: Duration = Duration::from_millis(30);
+const MAX_RTO: Duration = Duration::from_millis(5_000);
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+/// Maximum amount of selective acks carried by a single segment
+const MAX_SELECTIVE_ACKS: usize = 128;
+/// How often to tell the other side about our window, even when there's nothing else to say.
+const WINDOW_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A single datagram of the reliable udp protocol.
+/// Every segment carries acknowledgements, and (optionally) some data fragments.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReliableSegment {
+    /// Name of the player on the client side of the channel.
+    /// Lets the server find the channel a segment belongs to.
+    pub player_name: String,
+    /// All fragments with a sequence number lower than this one were received.
+    pub una: u32,
+    /// Fragments above `una` that were received out of order (selective acks).
+    pub acks: Vec<u32>,
+    /// Amount of fragments the sender is still willing to receive.
+    pub window: u32,
+    pub fragments: Vec<Fragment>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Fragment {
+    pub sequence: u32,
+    /// Amount of fragments that follow this one in the same message (0 for the last one).
+    pub remaining: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct InFlightFragment {
+    fragment: Fragment,
+    sent_at: Instant,
+    resend_at: Instant,
+    transmissions: u32,
+    skips: u32,
+}
+
+/// Returns true if sequence number `a` comes before `b`, taking wrapping into account.
+fn sequence_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A reliable, ordered message channel in the style of KCP, meant to be driven over a udp socket.
+/// Messages are split into fragments, every fragment is acknowledged both cumulatively (`una`)
+/// and selectively (`acks`), lost fragments are resent on timeout or as soon as enough
+/// later fragments were acknowledged (fast retransmit).
+///
+/// The channel doesn't own a socket - segments to send are produced by [ReliableChannel::flush]
+/// and received segments are passed in with [ReliableChannel::input].
+#[derive(Debug)]
+pub struct ReliableChannel {
+    player_name: String,
+
+    // Sending side
+    send_queue: VecDeque<Fragment>,
+    in_flight: BTreeMap<u32, InFlightFragment>,
+    next_sequence: u32,
+    remote_window: u32,
+
+    // Receiving side
+    receive_buffer: BTreeMap<u32, Fragment>,
+    next_expected: u32,
+    assembling: Vec<u8>,
+    received_messages: VecDeque<Vec<u8>>,
+    ack_pending: bool,
+    last_window_probe: Instant,
+
+    // Round trip estimation
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    rto: Duration,
+
+    dead: bool,
+}
+impl ReliableChannel {
+    pub fn new(player_name: String) -> Self {
+        Self {
+            player_name,
+            send_queue: Default::default(),
+            in_flight: Default::default(),
+            next_sequence: 0,
+            remote_window: RELIABLE_WINDOW,
+            receive_buffer: Default::default(),
+            next_expected: 0,
+            assembling: vec![],
+            received_messages: Default::default(),
+            ack_pending: false,
+            last_window_probe: Instant::now(),
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            rto: INITIAL_RTO,
+            dead: false,
+        }
+    }
+
+    /// Queues a message for delivery.
+    pub fn send(&mut self, message: &[u8]) {
+        let chunks: Vec<&[u8]> = if message.is_empty() {
+            vec![&[]]
+        } else {
+            message.chunks(RELIABLE_MSS).collect()
+        };
+        let count = chunks.len();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            self.send_queue.push_back(Fragment {
+                sequence: 0, // assigned once the fragment enters the window
+                remaining: (count - i - 1) as u16,
+                data: chunk.to_vec(),
+            });
+        }
+    }
+
+    /// Returns the next message received in order, if there's one.
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        self.received_messages.pop_front()
+    }
+
+    /// Amount of fragments that are queued or waiting for an acknowledgement.
+    pub fn pending(&self) -> usize {
+        self.send_queue.len() + self.in_flight.len()
+    }
+
+    /// Whether the other side stopped acknowledging our fragments.
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    /// Processes a segment received from the other side of the channel.
+    pub fn input(&mut self, segment: ReliableSegment) {
+        self.input_at(segment, Instant::now());
+    }
+
+    fn input_at(&mut self, segment: ReliableSegment, now: Instant) {
+        self.remote_window = segment.window;
+
+        // Cumulative acknowledgement
+        let acknowledged: Vec<u32> = self
+            .in_flight
+            .keys()
+            .copied()
+            .filter(|sequence| sequence_before(*sequence, segment.una))
+            .collect();
+        for sequence in acknowledged {
+            self.acknowledge(sequence, now);
+        }
+        // Selective acknowledgements
+        let mut highest_acknowledged = None;
+        for sequence in segment.acks.iter() {
+            if self.in_flight.contains_key(sequence) {
+                self.acknowledge(*sequence, now);
+                highest_acknowledged = Some(*sequence);
+            }
+        }
+        // Fast retransmit - every fragment sent before a newly acknowledged one was "skipped"
+        if let Some(highest) = highest_acknowledged {
+            for (sequence, in_flight) in self.in_flight.iter_mut() {
+                if sequence_before(*sequence, highest) {
+                    in_flight.skips += 1;
+                }
+            }
+        }
+
+        // Data
+        for fragment in segment.fragments {
+            self.ack_pending = true;
+            if sequence_before(fragment.sequence, self.next_expected)
+                || !sequence_before(
+                    fragment.sequence,
+                    self.next_expected.wrapping_add(RELIABLE_WINDOW),
+                )
+            {
+                // Duplicate, or out of the window
+                continue;
+            }
+            self.receive_buffer.insert(fragment.sequence, fragment);
+        }
+        while let Some(fragment) = self.receive_buffer.remove(&self.next_expected) {
+            self.next_expected = self.next_expected.wrapping_add(1);
+            self.assembling.extend_from_slice(&fragment.data);
+            if fragment.remaining == 0 {
+                self.received_messages
+                    .push_back(std::mem::take(&mut self.assembling));
+            }
+        }
+    }
+
+    /// Produces the segments that need to be sent to the other side right now.
+    /// Should be called often (every tick), as it also handles retransmissions.
+    /// Produces nothing once the channel is dead, it's up to the owner to tear it down.
+    pub fn flush(&mut self) -> Vec<ReliableSegment> {
+        self.flush_at(Instant::now())
+    }
+
+    fn flush_at(&mut self, now: Instant) -> Vec<ReliableSegment> {
+        if self.dead {
+            return vec![];
+        }
+        let mut fragments = vec![];
+
+        // Move queued fragments into the window
+        let window = RELIABLE_WINDOW.min(self.remote_window.max(1));
+        while (self.in_flight.len() as u32) < window {
+            let Some(mut fragment) = self.send_queue.pop_front() else {
+                break;
+            };
+            fragment.sequence = self.next_sequence;
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+            fragments.push(fragment.clone());
+            self.in_flight.insert(
+                fragment.sequence,
+                InFlightFragment {
+                    fragment,
+                    sent_at: now,
+                    resend_at: now + self.rto,
+                    transmissions: 1,
+                    skips: 0,
+                },
+            );
+        }
+
+        // Retransmissions
+        let mut timed_out = false;
+        for in_flight in self.in_flight.values_mut() {
+            let fast_resend = in_flight.skips >= FAST_RESEND_THRESHOLD;
+            let timeout = now >= in_flight.resend_at;
+            if !fast_resend && !timeout {
+                continue;
+            }
+            if in_flight.transmissions >= MAX_TRANSMISSIONS {
+                if !self.dead {
+                    println!(
+                        "Reliable channel of {} is dead, fragment {} was never acknowledged",
+                        self.player_name, in_flight.fragment.sequence
+                    );
+                }
+                self.dead = true;
+                continue;
+            }
+            timed_out |= timeout;
+            in_flight.transmissions += 1;
+            in_flight.skips = 0;
+            in_flight.sent_at = now;
+            in_flight.resend_at = now + self.rto;
+            fragments.push(in_flight.fragment.clone());
+        }
+        if timed_out {
+            // Back off, the same way tcp would
+            self.rto = (self.rto * 3 / 2).min(MAX_RTO);
+        }
+
+        let probe_window = now.saturating_duration_since(self.last_window_probe)
+            >= WINDOW_PROBE_INTERVAL
+            && (self.pending() > 0 || !self.receive_buffer.is_empty());
+        if fragments.is_empty() && !self.ack_pending && !probe_window {
+            return vec![];
+        }
+        self.ack_pending = false;
+        self.last_window_probe = now;
+
+        // Pack fragments into segments that fit the mss
+        let acks: Vec<u32> = self
+            .receive_buffer
+            .keys()
+            .copied()
+            .take(MAX_SELECTIVE_ACKS)
+            .collect();
+        let window = RELIABLE_WINDOW.saturating_sub(self.receive_buffer.len() as u32);
+        let mut segments = vec![];
+        let mut current = vec![];
+        let mut current_size = 0;
+        for fragment in fragments {
+            if current_size + fragment.data.len() > RELIABLE_MSS && !current.is_empty() {
+                segments.push(std::mem::take(&mut current));
+                current_size = 0;
+            }
+            current_size += fragment.data.len();
+            current.push(fragment);
+        }
+        segments.push(current);
+
+        segments
+            .into_iter()
+            .map(|fragments| ReliableSegment {
+                player_name: self.player_name.clone(),
+                una: self.next_expected,
+                acks: acks.clone(),
+                window,
+                fragments,
+            })
+            .collect()
+    }
+
+    fn acknowledge(&mut self, sequence: u32, now: Instant) {
+        if let Some(in_flight) = self.in_flight.remove(&sequence) {
+            // Karn's algorithm - only sample fragments that were sent once
+            if in_flight.transmissions == 1 {
+                self.sample_rtt(now - in_flight.sent_at);
+            }
+        }
+    }
+
+    fn sample_rtt(&mut self, rtt: Duration) {
+        if let Some(smoothed) = self.smoothed_rtt {
+            let delta = smoothed.abs_diff(rtt);
+            self.rtt_variance = (self.rtt_variance * 3 + delta) / 4;
+            self.smoothed_rtt = Some((smoothed * 7 + rtt) / 8);
+        } else {
+            self.smoothed_rtt = Some(rtt);
+            self.rtt_variance = rtt / 2;
+        }
+        self.rto = (self.smoothed_rtt.unwrap() + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Passes every segment one side flushes to the other, dropping those `lose` says to
+    fn shuttle(
+        from: &mut ReliableChannel,
+        to: &mut ReliableChannel,
+        now: Instant,
+        mut lose: impl FnMut(&ReliableSegment) -> bool,
+    ) -> usize {
+        let segments = from.flush_at(now);
+        let count = segments.len();
+        for segment in segments {
+            if !lose(&segment) {
+                to.input_at(segment, now);
+            }
+        }
+        count
+    }
+
+    fn pair() -> (ReliableChannel, ReliableChannel) {
+        (
+            ReliableChannel::new("client".to_string()),
+            ReliableChannel::new("client".to_string()),
+        )
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(sequence_before(1, 2));
+        assert!(!sequence_before(2, 1));
+        assert!(!sequence_before(5, 5));
+        assert!(sequence_before(u32::MAX, 0));
+        assert!(sequence_before(u32::MAX - 10, 3));
+        assert!(!sequence_before(3, u32::MAX - 10));
+    }
+
+    #[test]
+    fn delivers_fragmented_messages_in_order() {
+        let (mut a, mut b) = pair();
+        let now = Instant::now();
+        let big: Vec<u8> = (0..RELIABLE_MSS * 3 + 17).map(|i| i as u8).collect();
+        a.send(b"hello");
+        a.send(&big);
+        a.send(b"");
+        shuttle(&mut a, &mut b, now, |_| false);
+        assert_eq!(b.receive().unwrap(), b"hello");
+        assert_eq!(b.receive().unwrap(), big);
+        assert_eq!(b.receive().unwrap(), b"");
+        assert_eq!(b.receive(), None);
+
+        // Acks flow back, emptying the window
+        shuttle(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.pending(), 0);
+        assert!(a.rtt().is_some());
+    }
+
+    #[test]
+    fn delivers_across_sequence_wrap() {
+        let (mut a, mut b) = pair();
+        a.next_sequence = u32::MAX - 1;
+        b.next_expected = u32::MAX - 1;
+        let now = Instant::now();
+        for i in 0..5u8 {
+            a.send(&[i]);
+        }
+        shuttle(&mut a, &mut b, now, |_| false);
+        for i in 0..5u8 {
+            assert_eq!(b.receive().unwrap(), vec![i]);
+        }
+        shuttle(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.pending(), 0);
+        assert_eq!(b.next_expected, 3);
+    }
+
+    #[test]
+    fn retransmits_lost_fragments_on_timeout() {
+        let (mut a, mut b) = pair();
+        let now = Instant::now();
+        a.send(b"lost");
+        shuttle(&mut a, &mut b, now, |_| true);
+        assert_eq!(b.receive(), None);
+
+        // Nothing is resent before the timeout
+        assert_eq!(a.flush_at(now + INITIAL_RTO / 2).len(), 0);
+        shuttle(&mut a, &mut b, now + INITIAL_RTO, |_| false);
+        assert_eq!(b.receive().unwrap(), b"lost");
+        // The timeout backed off
+        assert!(a.rto > INITIAL_RTO);
+    }
+
+    #[test]
+    fn selective_acks_trigger_fast_resend() {
+        let (mut a, mut b) = pair();
+        let now = Instant::now();
+        // One fragment per segment, so that a single one can be lost
+        for i in 0..4u8 {
+            a.send(&vec![i; RELIABLE_MSS]);
+        }
+        let mut segments = a.flush_at(now).into_iter();
+        segments.next(); // Lost
+                         // Every later fragment acknowledged counts as a skip of the lost one
+        for segment in segments.take(FAST_RESEND_THRESHOLD as usize) {
+            b.input_at(segment, now);
+            shuttle(&mut b, &mut a, now, |_| false);
+        }
+        assert_eq!(b.receive(), None);
+        assert_eq!(a.in_flight[&0].skips, FAST_RESEND_THRESHOLD);
+
+        // Resent well before its timeout
+        let resent = a.flush_at(now);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].fragments[0].sequence, 0);
+        b.input_at(resent[0].clone(), now);
+        assert_eq!(b.receive().unwrap(), vec![0; RELIABLE_MSS]);
+    }
+
+    #[test]
+    fn respects_the_remote_window() {
+        let (mut a, _) = pair();
+        let now = Instant::now();
+        a.remote_window = 3;
+        for i in 0..10u8 {
+            a.send(&[i]);
+        }
+        a.flush_at(now);
+        assert_eq!(a.in_flight.len(), 3);
+        assert_eq!(a.send_queue.len(), 7);
+
+        // A closed window still lets a single fragment through
+        a.input_at(
+            ReliableSegment {
+                player_name: "client".to_string(),
+                una: 3,
+                acks: vec![],
+                window: 0,
+                fragments: vec![],
+            },
+            now,
+        );
+        assert_eq!(a.in_flight.len(), 0);
+        a.flush_at(now);
+        assert_eq!(a.in_flight.len(), 1);
+        assert_eq!(a.send_queue.len(), 6);
+    }
+
+    #[test]
+    fn drops_duplicates_and_fragments_out_of_the_window() {
+        let (mut a, mut b) = pair();
+        let now = Instant::now();
+        a.send(b"once");
+        let segments = a.flush_at(now);
+        b.input_at(segments[0].clone(), now);
+        b.input_at(segments[0].clone(), now);
+        assert_eq!(b.receive().unwrap(), b"once");
+        assert_eq!(b.receive(), None);
+
+        b.input_at(
+            ReliableSegment {
+                player_name: "client".to_string(),
+                una: 0,
+                acks: vec![],
+                window: RELIABLE_WINDOW,
+                fragments: vec![Fragment {
+                    sequence: 1 + RELIABLE_WINDOW,
+                    remaining: 0,
+                    data: vec![],
+                }],
+            },
+            now,
+        );
+        assert!(b.receive_buffer.is_empty());
+    }
+
+    #[test]
+    fn dies_after_too_many_transmissions() {
+        let (mut a, _) = pair();
+        let mut now = Instant::now();
+        a.send(b"never acknowledged");
+        a.flush_at(now);
+        for _ in 1..MAX_TRANSMISSIONS {
+            now += MAX_RTO;
+            assert!(!a.flush_at(now).is_empty());
+        }
+        assert!(!a.is_dead());
+        now += MAX_RTO;
+        a.flush_at(now);
+        assert!(a.is_dead());
+        // A dead channel doesn't retransmit anymore
+        now += MAX_RTO;
+        assert!(a.flush_at(now).is_empty());
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{random_bytes, BUFFER_SIZE, DISABLE_NAGLE_ALGORITHM},
+    connections::{Connections, InnerConnections, PublicPlayerData},
+    federation::{new_challenge, HelloPacket},
+    framing::MAX_FRAME_SIZE,
+    packet::Packet,
+    socket::SocketWrapper,
+};
+
+/// How often the primary sends its state, even when nothing changed, for the standby to know it's up
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the standby waits without hearing from the primary before taking over
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long names of the players of the primary are kept for them after taking over
+const GHOST_LIFETIME: Duration = Duration::from_secs(30);
+/// How long each side has to answer the challenge of the other one
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the primary waits for a standby to take its state, before giving up on it
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Names the primary and the standby answer challenges with, so neither can pass the answer of the other one as its own
+const PRIMARY: &str = "primary";
+const STANDBY: &str = "standby";
+
+/// Sent between a primary relay and its standby, over the replication link
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ReplicationPacket {
+    /// Every player greeted on the primary, in the order they greeted it.
+    /// Follows the [ReplicationPacket::MorePlayers] coming before them, when there are too many for a single packet.
+    Players(Vec<ReplicatedPlayer>),
+    /// Opens the link, sent by both relays: the other one answers it with a hello, proving it knows the federation secret
+    Challenge([u8; 16]),
+    /// Answers the challenge of the other relay, named after its role
+    Hello(HelloPacket),
+    /// Players coming before those of the next packet, the state of the primary being split over several packets
+    MorePlayers(Vec<ReplicatedPlayer>),
+}
+
+/// What a standby needs to know about a player of the primary, to take it back after taking over
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct ReplicatedPlayer {
+    /// Ports, exposures, services and the room (the host it sends its data to) of the player
+    pub data: PublicPlayerData,
+    pub host_migration: bool,
+    /// Proves a player greeting us is this one
+    pub token: u64,
+}
+
+/// A player of the primary that didn't greet us since we took over, its name kept for it for a while
+#[derive(Debug)]
+pub struct Ghost {
+    pub player: ReplicatedPlayer,
+    /// When it greeted the primary, roughly, so rooms keep the order players joined them in
+    pub greeted_at: Instant,
+    pub expires_at: Instant,
+}
+
+/// A fresh token for a player greeting us without one
+pub fn new_token() -> u64 {
+    u64::from_le_bytes(random_bytes())
+}
+
+/// Every player greeted on the relay, in the order they greeted it
+fn replicated_players(cons: &InnerConnections) -> Vec<ReplicatedPlayer> {
+    let mut players: Vec<_> = cons
+        .iter()
+        .map(|(_, player)| player)
+        .filter(|player| {
+            player.local_port.is_some() && !player.observer && player.peer_relay.is_none()
+        })
+        .collect();
+    players.sort_by_key(|player| player.greeted_at);
+    players
+        .into_iter()
+        .map(|player| ReplicatedPlayer {
+            data: player.to_public(),
+            host_migration: player.host_migration,
+            token: player.token,
+        })
+        .collect()
+}
+
+/// Splits the players of the primary over packets fitting a buffer, for any number of them to fit in frames.
+/// The last packet is a [ReplicationPacket::Players].
+fn snapshot(players: Vec<ReplicatedPlayer>) -> Vec<ReplicationPacket> {
+    let mut chunks = vec![vec![]];
+    let mut size = 0;
+    for player in players {
+        let player_size = bincode::serialized_size(&player).unwrap_or_default() as usize;
+        if size + player_size > BUFFER_SIZE && !chunks.last().unwrap().is_empty() {
+            chunks.push(vec![]);
+            size = 0;
+        }
+        size += player_size;
+        chunks.last_mut().unwrap().push(player);
+    }
+    let last = chunks.pop().unwrap();
+    chunks
+        .into_iter()
+        .map(ReplicationPacket::MorePlayers)
+        .chain(std::iter::once(ReplicationPacket::Players(last)))
+        .collect()
+}
+
+/// Waits for the next packet of a blocking replication link, None when it's down or didn't send one in time
+fn receive(link: &SocketWrapper, buffer: &mut [u8]) -> Option<ReplicationPacket> {
+    let size = link.read(buffer).ok().filter(|size| *size > 0)?;
+    match bincode::deserialize(&buffer[..size]) {
+        Ok(Packet::Replication(packet)) => Some(packet),
+        _ => None,
+    }
+}
+
+fn send(link: &SocketWrapper, packet: ReplicationPacket) -> std::io::Result<()> {
+    link.send_packet(&Packet::Replication(packet))
+}
+
+/// Waits for the hello of the other relay, answering our challenge as the role it should have
+fn receive_hello(
+    link: &SocketWrapper,
+    buffer: &mut [u8],
+    secret: &str,
+    challenge: &[u8; 16],
+    role: &str,
+) -> bool {
+    matches!(
+        receive(link, buffer),
+        Some(ReplicationPacket::Hello(hello)) if hello.relay_name == role && hello.verify(secret, challenge)
+    )
+}
+
+/// Checks that a standby knows the secret, answering its challenge in return.
+/// The standby speaks first, it has to know we're its primary before trusting our state.
+fn accept_standby(link: &SocketWrapper, buffer: &mut [u8], secret: &str) -> bool {
+    let Some(ReplicationPacket::Challenge(standby_challenge)) = receive(link, buffer) else {
+        return false;
+    };
+    let challenge = new_challenge();
+    let hello = HelloPacket::new(PRIMARY, secret, &standby_challenge);
+    send(link, ReplicationPacket::Hello(hello)).is_ok()
+        && send(link, ReplicationPacket::Challenge(challenge)).is_ok()
+        && receive_hello(link, buffer, secret, &challenge, STANDBY)
+}
+
+/// Lets standby relays follow our state on a port of our own, sending it whenever it changes.
+/// Standbys prove they know the federation secret first.
+pub fn serve_standby(port: u16, connections: Connections, secret: String) {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
+    println!("REPLICATION: a standby may follow us on {}", port);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            // Each standby on its own, for one that doesn't read not to hold the others up
+            let connections = connections.clone();
+            let secret = secret.clone();
+            std::thread::spawn(move || serve(stream, connections, &secret));
+        }
+    });
+}
+
+/// Sends our state to a standby until it's gone, or stops taking it
+fn serve(stream: TcpStream, connections: Connections, secret: &str) {
+    let _ = stream.set_nodelay(DISABLE_NAGLE_ALGORITHM);
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(SEND_TIMEOUT));
+    let address = stream.peer_addr();
+    let link = SocketWrapper::from_relay_tcp_socket(stream);
+    if !accept_standby(&link, &mut [0u8; BUFFER_SIZE], secret) {
+        println!(
+            "REPLICATION: {:?} doesn't know the secret, turning it away",
+            address
+        );
+        return;
+    }
+    println!("REPLICATION: standby {:?} follows us", address);
+    let mut last_players = None;
+    let mut last_sent = Instant::now();
+    loop {
+        let players = replicated_players(&connections.data.lock().unwrap());
+        if last_players.as_ref() != Some(&players) || last_sent.elapsed() >= KEEPALIVE_INTERVAL {
+            let sent = snapshot(players.clone())
+                .into_iter()
+                .try_for_each(|packet| send(&link, packet));
+            if let Err(e) = sent {
+                println!("REPLICATION: lost standby {:?}: {}", address, e);
+                return;
+            }
+            last_players = Some(players);
+            last_sent = Instant::now();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Links to the primary, checking it knows the secret and proving we do.
+/// Fails with [ErrorKind::PermissionDenied] when it doesn't answer as a primary knowing the secret.
+fn dial_primary(address: &str, buffer: &mut [u8], secret: &str) -> std::io::Result<SocketWrapper> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(DISABLE_NAGLE_ALGORITHM)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let link = SocketWrapper::from_relay_tcp_socket(stream);
+    let challenge = new_challenge();
+    send(&link, ReplicationPacket::Challenge(challenge))?;
+    if !receive_hello(&link, buffer, secret, &challenge, PRIMARY) {
+        return Err(ErrorKind::PermissionDenied.into());
+    }
+    let Some(ReplicationPacket::Challenge(primary_challenge)) = receive(&link, buffer) else {
+        return Err(ErrorKind::PermissionDenied.into());
+    };
+    let hello = HelloPacket::new(STANDBY, secret, &primary_challenge);
+    send(&link, ReplicationPacket::Hello(hello))?;
+    link.set_nonblocking(true)?;
+    Ok(link)
+}
+
+/// Keeps the state of the primary until it goes down, or stops sending it.
+/// Packets we can't make sense of don't mean it's down, taking over while it's up would leave two relays in charge.
+fn follow(link: &SocketWrapper, buffer: &mut [u8], connections: &Connections) {
+    let mut last_heard = Instant::now();
+    // Players of the state being received, when it's split over several packets
+    let mut incoming = vec![];
+    while last_heard.elapsed() < PRIMARY_TIMEOUT {
+        match link.read(buffer) {
+            Ok(0) => break,
+            Ok(size) => match bincode::deserialize(&buffer[..size]) {
+                Ok(Packet::Replication(ReplicationPacket::MorePlayers(players))) => {
+                    incoming.extend(players);
+                }
+                Ok(Packet::Replication(ReplicationPacket::Players(players))) => {
+                    last_heard = Instant::now();
+                    incoming.extend(players);
+                    connections.data.lock().unwrap().replicated_players =
+                        std::mem::take(&mut incoming);
+                }
+                _ => {}
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => {
+                if link.is_timed_out() {
+                    break;
+                }
+                println!(
+                    "REPLICATION: failed to read the state of the primary: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Follows the state of a primary relay, keeping the relay passive until the primary goes down.
+/// We take over when it can't be reached, or stops sending us its state.
+/// The primary is in charge whenever it's up: once it comes back, we hand its players back to it and stand by again.
+pub fn follow_primary(address: String, connections: Connections, secret: String) {
+    connections.data.lock().unwrap().passive = true;
+    println!("REPLICATION: standing by for {}", address);
+    std::thread::spawn(move || {
+        let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+        let mut link = match dial_primary(&address, &mut buffer, &secret) {
+            Ok(link) => Some(link),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                // Taking over would leave two relays in charge
+                println!(
+                    "REPLICATION: {} isn't a primary knowing our secret, exiting",
+                    address
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                println!("REPLICATION: couldn't reach the primary {}: {}", address, e);
+                None
+            }
+        };
+        loop {
+            if let Some(link) = link.as_ref() {
+                follow(link, &mut buffer, &connections);
+                println!("REPLICATION: lost the primary {}", address);
+            }
+            take_over(&mut connections.data.lock().unwrap());
+            // Relays that can't prove they know the secret don't get the players
+            link = loop {
+                std::thread::sleep(PRIMARY_TIMEOUT);
+                if let Ok(link) = dial_primary(&address, &mut buffer, &secret) {
+                    break Some(link);
+                }
+            };
+            println!("REPLICATION: the primary {} is back", address);
+            step_down(&mut connections.data.lock().unwrap());
+        }
+    });
+}
+
+/// Starts accepting players, keeping the names of those of the primary for them for a while
+fn take_over(cons: &mut InnerConnections) {
+    cons.passive = false;
+    let players = std::mem::take(&mut cons.replicated_players);
+    println!(
+        "REPLICATION: taking over, waiting for the {} players of the primary",
+        players.len()
+    );
+    let now = Instant::now();
+    let count = players.len() as u32;
+    for (index, player) in players.into_iter().enumerate() {
+        cons.ghosts.insert(
+            player.data.name.clone(),
+            Ghost {
+                player,
+                greeted_at: now - Duration::from_millis((count - index as u32) as u64),
+                expires_at: now + GHOST_LIFETIME,
+            },
+        );
+    }
+}
+
+/// Stands by again once the primary is back, dropping our players for them to fail over to it
+fn step_down(cons: &mut InnerConnections) {
+    cons.passive = true;
+    cons.ghosts.clear();
+    let players: Vec<u16> = cons
+        .iter()
+        .filter(|(_, link)| link.peer_relay.is_none() && !link.dialed_relay)
+        .map(|(port, _)| *port)
+        .collect();
+    println!(
+        "REPLICATION: standing by again, handing {} links back to the primary",
+        players.len()
+    );
+    for port in players {
+        cons.remove(&port);
+    }
+}
+
+/// Forgets players of the primary that didn't come back in time
+pub fn expire_ghosts(cons: &mut InnerConnections) {
+    cons.ghosts.retain(|name, ghost| {
+        let alive = ghost.expires_at > Instant::now();
+        if !alive {
+            println!(
+                "REPLICATION: {} didn't come back after the takeover, freeing its name",
+                name
+            );
+        }
+        alive
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Links a standby knowing `standby_secret` to a primary knowing `primary_secret`.
+    /// Returns whether the primary accepted it, and whether it trusted the primary.
+    fn replicate(primary_secret: &str, standby_secret: &str) -> (bool, std::io::Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let primary_secret = primary_secret.to_string();
+        let primary = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+            let link = SocketWrapper::from_relay_tcp_socket(stream);
+            accept_standby(&link, &mut [0u8; BUFFER_SIZE], &primary_secret)
+        });
+        let standby = dial_primary(&address, &mut [0u8; BUFFER_SIZE], standby_secret).map(drop);
+        (primary.join().unwrap(), standby)
+    }
+
+    #[test]
+    fn replicates_between_relays_knowing_the_secret() {
+        let (accepted, standby) = replicate("secret", "secret");
+        assert!(accepted);
+        assert!(standby.is_ok());
+
+        let (accepted, standby) = replicate("secret", "guess");
+        assert!(!accepted);
+        assert_eq!(standby.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    fn player(index: usize) -> ReplicatedPlayer {
+        ReplicatedPlayer {
+            data: PublicPlayerData {
+                name: format!("player-{}", index),
+                forwarded_ports: (0..64).collect(),
+                exposed_ports: None,
+                services: vec![],
+                other_player_name: "HOST".to_string(),
+                relay_latencies: vec![],
+            },
+            host_migration: true,
+            token: index as u64,
+        }
+    }
+
+    #[test]
+    fn splits_states_too_large_for_a_packet() {
+        let players: Vec<_> = (0..4000).map(player).collect();
+        let packets = snapshot(players.clone());
+        assert!(packets.len() > 1);
+        assert!(matches!(
+            packets.last(),
+            Some(ReplicationPacket::Players(_))
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let standby = SocketWrapper::from_relay_tcp_socket(listener.accept().unwrap().0);
+        let primary = SocketWrapper::from_relay_tcp_socket(primary);
+        let sender = std::thread::spawn(move || {
+            for packet in packets {
+                send(&primary, packet).unwrap();
+            }
+        });
+        let connections = Connections::new();
+        follow(&standby, &mut vec![0u8; MAX_FRAME_SIZE], &connections);
+        sender.join().unwrap();
+        assert_eq!(connections.data.lock().unwrap().replicated_players, players);
+
+        assert_eq!(snapshot(vec![]).len(), 1);
+    }
+
+    #[test]
+    fn serves_standbys_side_by_side() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        serve_standby(port, Connections::new(), "secret".to_string());
+        let address = format!("127.0.0.1:{}", port);
+        // One that never says anything
+        let _silent = TcpStream::connect(&address).unwrap();
+        let begin = Instant::now();
+        assert!(dial_primary(&address, &mut [0u8; BUFFER_SIZE], "secret").is_ok());
+        assert!(begin.elapsed() < HANDSHAKE_TIMEOUT);
+    }
+
+    #[test]
+    fn draws_tokens_at_random() {
+        assert_ne!(new_token(), new_token());
+    }
+}
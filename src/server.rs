@@ -1,16 +1,43 @@
-use crate::{common::ToConnections, connections::Connections, packet::GreetingPacket};
+use std::{net::UdpSocket, time::Duration};
+
+use crate::{
+    commands::MigrationPolicy,
+    common::ToConnections,
+    connections::{Connections, InnerConnections, PlayerData, PublicPlayerData},
+    discovery::BroadcastPacket,
+    federation::{new_challenge, Federation, FederationPacket, HelloPacket, RemotePlayer},
+    packet::{monotonic_micros, CatalogPacket, GreetingPacket, HostMigrationPacket, Packet},
+    punch::PeerCandidatesPacket,
+    tunnel,
+};
+
+/// How often the rtt of players is measured, for picking new hosts
+pub const RTT_PROBE_INTERVAL: Duration = Duration::from_secs(5);
 
 /// The server is responsible for the following operations:
 /// - accepting new TCP connections from clients
 /// - receiving packets from clients, along the lines of "connected a socket" / "closed a socket" / "transferred data"
 /// - relaying all the relevant information to clients as it comes in
+#[derive(Default)]
 pub struct ServerState {
     pub connections: Connections,
+    /// The udp socket players send their udp data to
+    pub udp_socket: Option<UdpSocket>,
+    /// How to pick a new host when one leaves
+    pub migration_policy: MigrationPolicy,
+    /// Whether players may get ports of the relay machine forwarding to theirs (see [tunnel])
+    pub public_ports: bool,
+    /// Set when we federate with other relays
+    pub federation: Option<Federation>,
 }
 impl ServerState {
     pub fn new() -> Self {
         Self {
             connections: Connections::new(),
+            udp_socket: None,
+            migration_policy: MigrationPolicy::default(),
+            public_ports: false,
+            federation: None,
         }
     }
 
@@ -34,15 +61,631 @@ impl ServerState {
     pub fn receive_greetings(&mut self, greetings: Vec<(u16, GreetingPacket)>) {
         let mut cons = self.connections.data.lock().unwrap();
         for (port, greeting) in greetings {
-            if let Some(_) = cons.get(&port) {
+            if cons.passive {
+                println!(
+                    "STANDBY: turning {} away, the primary is up",
+                    greeting.player_name
+                );
+                cons.remove(&port);
+                continue;
+            }
+            // Players of the primary we took over from get their name back with their token
+            if let Some(ghost) = cons.ghosts.get(&greeting.player_name) {
+                if greeting.token != Some(ghost.player.token) {
+                    println!(
+                        "{} is kept for the player of the primary, removing the impostor player...",
+                        greeting.player_name
+                    );
+                    cons.remove(&port);
+                    continue;
+                }
+                let ghost = cons.ghosts.remove(&greeting.player_name).unwrap();
+                println!("REPLICATION: {} is back", greeting.player_name);
+                if let Some((_, player)) = cons.iter_mut().find(|(other, _)| **other == port) {
+                    player.greeted_at = Some(ghost.greeted_at);
+                }
+            }
+            if let Some(player) = cons.get(&port) {
+                // Players that greeted us before are updating what they told us
+                let greeted_before = player.local_port.is_some();
                 println!("NEW PLAYER: {}:{}", greeting.player_name, port);
                 if !cons.update_player_from_greeting(port, &greeting) {
                     println!("Removing the impostor player...");
                     cons.remove(&port);
+                } else {
+                    if let Some(player) = cons.get(&port) {
+                        if !greeted_before && !player.observer {
+                            let _ = player
+                                .stream
+                                .send_packet(&Packet::SessionToken(player.token));
+                        }
+                    }
+                    let public_ports = if self.public_ports {
+                        tunnel::allocate(&mut cons, &greeting.player_name, &greeting.public_ports)
+                    } else {
+                        if !greeting.public_ports.is_empty() {
+                            println!(
+                                "PUBLIC PORT: {} asked for public ports, we don't give any out",
+                                greeting.player_name
+                            );
+                        }
+                        vec![]
+                    };
+                    if let Some(player) = cons.get(&port) {
+                        for public_port in public_ports {
+                            let _ = player.stream.send_packet(&Packet::PublicPort(public_port));
+                        }
+                    }
+                    Self::push_catalogs(&cons, port);
+                    Self::push_presence(&cons, port, greeted_before);
                 }
             }
         }
     }
+
+    /// Sends the catalog of a player that just greeted us to everyone else,
+    /// and the catalogs of everyone else to that player.
+    fn push_catalogs(cons: &InnerConnections, port: u16) {
+        let Some(player) = cons.get(&port) else {
+            return;
+        };
+        let catalog = Packet::Catalog(CatalogPacket {
+            player_name: player.name.clone(),
+            services: player.services.clone(),
+        });
+        for (other_port, other_player) in cons.iter() {
+            if *other_port == port {
+                continue;
+            }
+            if !player.services.is_empty() {
+                if let Err(e) = other_player.stream.send_packet(&catalog) {
+                    println!("Failed to send a catalog to {}: {}", other_player.name, e);
+                }
+            }
+            if !other_player.services.is_empty() {
+                let other_catalog = Packet::Catalog(CatalogPacket {
+                    player_name: other_player.name.clone(),
+                    services: other_player.services.clone(),
+                });
+                if let Err(e) = player.stream.send_packet(&other_catalog) {
+                    println!("Failed to send a catalog to {}: {}", player.name, e);
+                }
+            }
+        }
+        for (name, remote_player) in cons.remote_players.iter() {
+            if !remote_player.data.services.is_empty() {
+                let _ = player.stream.send_packet(&Packet::Catalog(CatalogPacket {
+                    player_name: name.clone(),
+                    services: remote_player.data.services.clone(),
+                }));
+            }
+        }
+    }
+
+    /// Answers players asking for the services everyone published, here and on the relays we federate with
+    pub fn receive_catalog_requests(&mut self, requests: Vec<u16>) {
+        if requests.is_empty() {
+            return;
+        }
+        let cons = self.connections.data.lock().unwrap();
+        let catalogs = cons
+            .iter()
+            .map(|(_, player)| player)
+            .filter(|player| player.local_port.is_some() && !player.services.is_empty())
+            .map(|player| CatalogPacket {
+                player_name: player.name.clone(),
+                services: player.services.clone(),
+            })
+            .chain(
+                cons.remote_players
+                    .iter()
+                    .filter(|(_, remote_player)| !remote_player.data.services.is_empty())
+                    .map(|(name, remote_player)| CatalogPacket {
+                        player_name: name.clone(),
+                        services: remote_player.data.services.clone(),
+                    }),
+            )
+            .collect::<Vec<_>>();
+        let answer = Packet::Catalogs(catalogs);
+        for port in requests {
+            if let Some(player) = cons.get(&port) {
+                if let Err(e) = player.stream.send_packet(&answer) {
+                    println!("Failed to send the catalogs to {}: {}", player.name, e);
+                }
+            }
+        }
+    }
+
+    /// Announces a player that just greeted us to everyone watching presence,
+    /// and tells the player who's already there if it's watching too.
+    fn push_presence(cons: &InnerConnections, port: u16, greeted_before: bool) {
+        let Some(player) = cons.get(&port) else {
+            return;
+        };
+        let event = if greeted_before {
+            Packet::PlayerUpdated(player.to_public())
+        } else {
+            Packet::PlayerJoined(player.to_public())
+        };
+        for (other_port, other_player) in cons.iter() {
+            if *other_port == port || other_player.local_port.is_none() {
+                continue;
+            }
+            if other_player.presence && !player.observer {
+                if let Err(e) = other_player.stream.send_packet(&event) {
+                    println!(
+                        "Failed to send a presence event to {}: {}",
+                        other_player.name, e
+                    );
+                }
+            }
+            if player.presence && !greeted_before && !other_player.observer {
+                if let Err(e) = player
+                    .stream
+                    .send_packet(&Packet::PlayerJoined(other_player.to_public()))
+                {
+                    println!("Failed to send a presence event to {}: {}", player.name, e);
+                }
+            }
+        }
+        if player.presence && !greeted_before {
+            for (_, remote_player) in cons.remote_players.iter() {
+                let _ = player
+                    .stream
+                    .send_packet(&Packet::PlayerJoined(remote_player.data.clone()));
+            }
+        }
+    }
+
+    /// Passes the discovery traffic players captured on their LAN to the players of their room,
+    /// those sending their data to the same host.
+    pub fn receive_broadcasts(&mut self, broadcasts: Vec<(u16, BroadcastPacket)>) {
+        let cons = self.connections.data.lock().unwrap();
+        for (port, mut broadcast) in broadcasts {
+            let Some(player) = cons.get(&port) else {
+                continue;
+            };
+            if player.local_port.is_none() || player.observer {
+                continue;
+            }
+            broadcast.sender_name = player.name.clone();
+            let broadcast = Packet::Broadcast(broadcast);
+            for (other_port, other_player) in cons.iter() {
+                if *other_port == port
+                    || other_player.local_port.is_none()
+                    || other_player.observer
+                    || other_player.other_player_name != player.other_player_name
+                {
+                    continue;
+                }
+                if let Err(e) = other_player.stream.send_packet(&broadcast) {
+                    println!(
+                        "Failed to send discovery traffic of {} to {}: {}",
+                        player.name, other_player.name, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Introduces players asking for direct udp paths to the players they asked for,
+    /// sending both the addresses the other one may be reached at.
+    pub fn receive_punch_requests(&mut self, punch_requests: Vec<(u16, String)>) {
+        let cons = self.connections.data.lock().unwrap();
+        for (port, player_name) in punch_requests {
+            let Some(player) = cons.get(&port) else {
+                continue;
+            };
+            if cons.remote_players.contains_key(&player_name) {
+                println!(
+                    "{} wants a direct path to {}, who is on another relay",
+                    player.name, player_name
+                );
+                continue;
+            }
+            let Some(other_player) = cons
+                .get_player_tcp_port_by_name(&player_name)
+                .and_then(|other_port| cons.get(&other_port))
+            else {
+                println!(
+                    "{} wants a direct path to {}, who isn't there",
+                    player.name, player_name
+                );
+                continue;
+            };
+            let (candidates, other_candidates) = (
+                player.get_udp_candidates(),
+                other_player.get_udp_candidates(),
+            );
+            if candidates.is_empty() || other_candidates.is_empty() {
+                println!(
+                    "{} wants a direct path to {}, but udp of one of them can't go direct",
+                    player.name, other_player.name
+                );
+                continue;
+            }
+            println!(
+                "Introducing {} ({:?}) and {} ({:?}) for a direct udp path",
+                player.name, candidates, other_player.name, other_candidates
+            );
+            let _ = player
+                .stream
+                .send_packet(&Packet::PeerCandidates(PeerCandidatesPacket {
+                    player_name: other_player.name.clone(),
+                    candidates: other_candidates,
+                }));
+            let _ =
+                other_player
+                    .stream
+                    .send_packet(&Packet::PeerCandidates(PeerCandidatesPacket {
+                        player_name: player.name.clone(),
+                        candidates,
+                    }));
+        }
+    }
+
+    /// Lets everyone watching presence know a player left
+    pub fn push_player_left(cons: &InnerConnections, player: &PlayerData) {
+        // Never greeted, or never announced
+        if player.local_port.is_none() || player.observer {
+            return;
+        }
+        for (_, other_player) in cons.iter() {
+            if other_player.presence {
+                let _ = other_player
+                    .stream
+                    .send_packet(&Packet::PlayerLeft(player.name.clone()));
+            }
+        }
+    }
+
+    /// Sends a probe over the link of every player, which they send right back to measure their rtt
+    pub fn probe_rtts(cons: &InnerConnections) {
+        let probe = Packet::RttProbe(monotonic_micros());
+        for (_, player) in cons.iter() {
+            if player.local_port.is_some() && !player.observer {
+                let _ = player.stream.send_packet(&probe);
+            }
+        }
+    }
+
+    /// Picks a new host for the players of a host that left, among those following host migration,
+    /// and tells them all who it is.
+    pub fn migrate_host(&self, cons: &mut InnerConnections, old_host: &PlayerData) {
+        if old_host.local_port.is_none() || old_host.other_player_name != old_host.name {
+            // Not a host
+            return;
+        }
+        let room = cons
+            .iter()
+            .map(|(_, player)| player)
+            .filter(|player| player.other_player_name == old_host.name && player.host_migration);
+        let new_host = match self.migration_policy {
+            MigrationPolicy::FirstJoined => room.min_by_key(|player| player.greeted_at),
+            MigrationPolicy::LowestRtt => {
+                room.min_by_key(|player| player.rtt.unwrap_or(Duration::MAX))
+            }
+        }
+        .map(|player| player.name.clone());
+        let Some(new_host_name) = new_host else {
+            println!(
+                "Host {} left, and none of its players follow host migration",
+                old_host.name
+            );
+            return;
+        };
+        println!(
+            "Host {} left, {} is the new host ({:?})",
+            old_host.name, new_host_name, self.migration_policy
+        );
+        let migration = Packet::HostMigration(HostMigrationPacket {
+            old_host_name: old_host.name.clone(),
+            new_host_name: new_host_name.clone(),
+            exposed_ports: old_host.exposed_ports.clone(),
+        });
+        for (_, player) in cons.iter_mut() {
+            if player.other_player_name != old_host.name || !player.host_migration {
+                continue;
+            }
+            player.other_player_name = new_host_name.clone();
+            if let Err(e) = player.stream.send_packet(&migration) {
+                println!("Failed to tell {} about the new host: {}", player.name, e);
+            }
+        }
+    }
+
+    /// Opens links of relays proving they know the federation secret,
+    /// and keeps track of the players greeted on the relays we federate with.
+    pub fn receive_federation_packets(&mut self, federation_packets: Vec<(u16, FederationPacket)>) {
+        let mut cons = self.connections.data.lock().unwrap();
+        for (port, federation_packet) in federation_packets {
+            let Some(federation) = self.federation.as_mut() else {
+                println!("A relay wants to federate with us, but we don't federate. Dropping its link...");
+                cons.remove(&port);
+                continue;
+            };
+            match federation_packet {
+                FederationPacket::Challenge(challenge) => {
+                    let Some((_, link)) =
+                        cons.iter_mut().find(|(link_port, _)| **link_port == port)
+                    else {
+                        continue;
+                    };
+                    let hello =
+                        HelloPacket::new(&federation.relay_name, &federation.secret, &challenge);
+                    let _ = link
+                        .stream
+                        .send_packet(&Packet::Federation(FederationPacket::Hello(hello)));
+                    // Relays that dialed us challenged us first, and wait for our challenge too
+                    if link.federation_challenge.is_none() {
+                        let challenge = new_challenge();
+                        let _ = link.stream.send_packet(&Packet::Federation(
+                            FederationPacket::Challenge(challenge),
+                        ));
+                        link.federation_challenge = Some(challenge);
+                    }
+                }
+                FederationPacket::Hello(hello) => {
+                    let Some(challenge) =
+                        cons.get(&port).and_then(|link| link.federation_challenge)
+                    else {
+                        println!(
+                            "FEDERATION: relay {} says hello before we challenged it, dropping its link",
+                            hello.relay_name
+                        );
+                        cons.remove(&port);
+                        continue;
+                    };
+                    if !hello.verify(&federation.secret, &challenge) {
+                        println!(
+                            "FEDERATION: relay {} doesn't know the secret, dropping its link",
+                            hello.relay_name
+                        );
+                        cons.remove(&port);
+                        continue;
+                    }
+                    // Our own answer, sent back to us by someone that doesn't know the secret
+                    if hello.relay_name == federation.relay_name {
+                        println!(
+                            "FEDERATION: a relay claims our name {}, dropping its link",
+                            hello.relay_name
+                        );
+                        cons.remove(&port);
+                        continue;
+                    }
+                    let Some((_, link)) =
+                        cons.iter_mut().find(|(link_port, _)| **link_port == port)
+                    else {
+                        continue;
+                    };
+                    println!("FEDERATION: linked with relay {}", hello.relay_name);
+                    link.name = format!("@{}", hello.relay_name);
+                    link.peer_relay = Some(hello.relay_name);
+                    // Tell every relay about our players again, this one included
+                    federation.last_players = None;
+                    federation.last_reachable = None;
+                    federation.last_known = None;
+                }
+                FederationPacket::Players(players) => {
+                    let Some(relay_name) = cons.get(&port).and_then(|link| link.peer_relay.clone())
+                    else {
+                        println!("Received players from a relay that didn't say hello!");
+                        continue;
+                    };
+                    Self::receive_remote_players(&mut cons, &relay_name, players);
+                }
+                FederationPacket::Downstream(players) => {
+                    let Some((_, link)) = cons
+                        .iter_mut()
+                        .find(|(link_port, link)| **link_port == port && link.peer_relay.is_some())
+                    else {
+                        println!("Received players from a relay that didn't say hello!");
+                        continue;
+                    };
+                    if !link.downstream_relay {
+                        println!("FEDERATION: {} is a downstream of us", link.name);
+                        link.downstream_relay = true;
+                        // It hears about the whole chain from now on
+                        federation.last_known = None;
+                    }
+                    let relay_name = link.peer_relay.clone().unwrap();
+                    Self::receive_remote_players(&mut cons, &relay_name, players);
+                }
+            }
+        }
+    }
+
+    /// Updates the players of a relay we federate with, telling our players who joined, updated or left
+    fn receive_remote_players(
+        cons: &mut InnerConnections,
+        relay_name: &String,
+        players: Vec<PublicPlayerData>,
+    ) {
+        let mut events = vec![];
+        let mut catalogs = vec![];
+        for player in players.iter() {
+            if cons.get_player_tcp_port_by_name(&player.name).is_some() {
+                println!(
+                    "FEDERATION: {} is on relay {} and on us too, ours wins",
+                    player.name, relay_name
+                );
+                continue;
+            }
+            if let Some(remote_player) = cons
+                .remote_players
+                .get(&player.name)
+                .filter(|remote_player| remote_player.relay_name != *relay_name)
+            {
+                println!(
+                    "FEDERATION: {} is on relay {} and on {} too, the first one wins",
+                    player.name, relay_name, remote_player.relay_name
+                );
+                continue;
+            }
+            let previous = cons.remote_players.insert(
+                player.name.clone(),
+                RemotePlayer {
+                    relay_name: relay_name.clone(),
+                    data: player.clone(),
+                },
+            );
+            match previous {
+                None => {
+                    println!("FEDERATION: {} joined relay {}", player.name, relay_name);
+                    events.push(Packet::PlayerJoined(player.clone()));
+                }
+                Some(previous) if previous.data != *player => {
+                    events.push(Packet::PlayerUpdated(player.clone()))
+                }
+                Some(_) => continue,
+            }
+            if !player.services.is_empty() {
+                catalogs.push(Packet::Catalog(CatalogPacket {
+                    player_name: player.name.clone(),
+                    services: player.services.clone(),
+                }));
+            }
+        }
+        let gone = cons
+            .remote_players
+            .iter()
+            .filter(|(name, remote_player)| {
+                remote_player.relay_name == *relay_name
+                    && !players.iter().any(|player| player.name == **name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in gone {
+            let remote_player = cons.remote_players.remove(&name).unwrap();
+            println!("FEDERATION: {} left relay {}", name, relay_name);
+            events.push(Packet::PlayerLeft(name.clone()));
+            if !remote_player.data.services.is_empty() {
+                catalogs.push(Packet::Catalog(CatalogPacket {
+                    player_name: name,
+                    services: vec![],
+                }));
+            }
+        }
+        for (_, player) in cons.iter() {
+            if player.local_port.is_none() {
+                continue;
+            }
+            if player.presence {
+                for event in events.iter() {
+                    let _ = player.stream.send_packet(event);
+                }
+            }
+            for catalog in catalogs.iter() {
+                let _ = player.stream.send_packet(catalog);
+            }
+        }
+    }
+
+    /// Tells the relays we federate with about our players, when they changed since we last did,
+    /// our upstream about every player reachable through us, and our downstreams about the rest of the chain
+    pub fn push_players_to_relays(&mut self) {
+        let Some(federation) = self.federation.as_mut() else {
+            return;
+        };
+        let cons = self.connections.data.lock().unwrap();
+        let mut players = cons
+            .iter()
+            .map(|(_, player)| player)
+            .filter(|player| player.local_port.is_some() && !player.observer)
+            .map(|player| player.to_public())
+            .collect::<Vec<_>>();
+        players.sort_by(|a, b| a.name.cmp(&b.name));
+        // Our upstream reaches the players of our downstreams through us too
+        let downstreams = cons
+            .iter()
+            .filter(|(_, link)| link.downstream_relay)
+            .filter_map(|(_, link)| link.peer_relay.as_ref())
+            .collect::<Vec<_>>();
+        let mut reachable = players.clone();
+        reachable.extend(
+            cons.remote_players
+                .values()
+                .filter(|remote_player| downstreams.contains(&&remote_player.relay_name))
+                .map(|remote_player| remote_player.data.clone()),
+        );
+        reachable.sort_by(|a, b| a.name.cmp(&b.name));
+
+        // Downstreams reach the rest of the chain through us only, they hear about every player of it but theirs.
+        // Relays we federate with don't pass data of chained relays on, their players are left out.
+        let chained = cons
+            .iter()
+            .filter(|(_, link)| link.upstream_relay || link.downstream_relay)
+            .filter_map(|(_, link)| link.peer_relay.as_ref())
+            .collect::<Vec<_>>();
+        let mut known = players
+            .iter()
+            .map(|player| RemotePlayer {
+                relay_name: federation.relay_name.clone(),
+                data: player.clone(),
+            })
+            .chain(
+                cons.remote_players
+                    .values()
+                    .filter(|remote_player| chained.contains(&&remote_player.relay_name))
+                    .cloned(),
+            )
+            .collect::<Vec<_>>();
+        known.sort_by(|a, b| a.data.name.cmp(&b.data.name));
+
+        if federation.last_players.as_ref() != Some(&players) {
+            let packet = Packet::Federation(FederationPacket::Players(players.clone()));
+            for (_, link) in cons.iter() {
+                if link.peer_relay.is_some() && !link.upstream_relay && !link.downstream_relay {
+                    if let Err(e) = link.stream.send_packet(&packet) {
+                        println!("Failed to send our players to {}: {}", link.name, e);
+                    }
+                }
+            }
+            federation.last_players = Some(players);
+        }
+        if federation.last_reachable.as_ref() != Some(&reachable) {
+            let packet = Packet::Federation(FederationPacket::Downstream(reachable.clone()));
+            for (_, link) in cons.iter() {
+                if link.peer_relay.is_some() && link.upstream_relay {
+                    if let Err(e) = link.stream.send_packet(&packet) {
+                        println!("Failed to send our players to {}: {}", link.name, e);
+                    }
+                }
+            }
+            federation.last_reachable = Some(reachable);
+        }
+        if federation.last_known.as_ref() != Some(&known) {
+            for (_, link) in cons.iter().filter(|(_, link)| link.downstream_relay) {
+                let players = known
+                    .iter()
+                    .filter(|player| link.peer_relay.as_ref() != Some(&player.relay_name))
+                    .map(|player| player.data.clone())
+                    .collect();
+                let packet = Packet::Federation(FederationPacket::Players(players));
+                if let Err(e) = link.stream.send_packet(&packet) {
+                    println!("Failed to send the players we know to {}: {}", link.name, e);
+                }
+            }
+            federation.last_known = Some(known);
+        }
+    }
+
+    /// Forgets the players of a relay we lost the link to
+    pub fn forget_relay(cons: &mut InnerConnections, relay_name: &String) {
+        println!("FEDERATION: lost relay {}", relay_name);
+        Self::receive_remote_players(cons, relay_name, vec![]);
+    }
+
+    /// Lets everyone know the services of a player that left are gone
+    pub fn push_empty_catalog(cons: &InnerConnections, player_name: &str) {
+        let catalog = Packet::Catalog(CatalogPacket {
+            player_name: player_name.to_string(),
+            services: vec![],
+        });
+        for (_, player) in cons.iter() {
+            let _ = player.stream.send_packet(&catalog);
+        }
+    }
 }
 impl ToConnections for ServerState {
     fn to_connections(&mut self) -> &mut Connections {
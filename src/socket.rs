@@ -1,27 +1,144 @@
 use std::{
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
     net::TcpStream,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-use crate::common::BUFFER_SIZE;
+use crate::{
+    common::{BUFFER_SIZE, DISABLE_NAGLE_ALGORITHM},
+    framing::{self, next_frame, MAX_FRAME_SIZE},
+    packet::Packet,
+    pipe::PipeLink,
+    proxy::Proxy,
+    quic::{self, QuicLink},
+    websocket::{self, WebSocketLink},
+};
+
+/// Prefix of relay addresses that should be reached over quic, instead of tcp.
+pub const QUIC_SCHEME: &str = "quic://";
+/// Prefix of relay addresses that should be reached over websockets.
+pub const WEBSOCKET_SCHEME: &str = "ws://";
+/// Bytes of frames a relay link may hold back while the other side doesn't read, before we wait for it
+const MAX_UNSENT_SIZE: usize = MAX_FRAME_SIZE * 16;
+/// How long we wait for the other side of a relay link to read what it holds back, before giving up on it
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// A "merged" socket type that combines tcp and udp in a one easier to use API.
 #[derive(Debug)]
 pub struct SocketWrapper {
     tcp: Option<TcpStream>,
+    /// Bytes read from a tcp relay link that don't make up a whole frame yet.
+    /// None for tcp streams of local programs, which carry raw data.
+    tcp_pending: Option<Mutex<Vec<u8>>>,
+    /// Bytes of frames a non blocking tcp relay link didn't take yet, sent before anything else.
+    /// Frames are never cut short, which would leave the other side unable to find the next ones.
+    tcp_unsent: Option<Mutex<Vec<u8>>>,
+    quic: Option<QuicLink>,
+    websocket: Option<WebSocketLink>,
+    pipe: Option<PipeLink>,
 }
 impl SocketWrapper {
     pub fn from_tcp_socket(tcp: TcpStream) -> Self {
-        Self { tcp: Some(tcp) }
+        Self {
+            tcp: Some(tcp),
+            tcp_pending: None,
+            tcp_unsent: None,
+            quic: None,
+            websocket: None,
+            pipe: None,
+        }
+    }
+
+    /// A tcp link between a player and the relay, carrying packets
+    pub fn from_relay_tcp_socket(tcp: TcpStream) -> Self {
+        Self {
+            tcp_pending: Some(Default::default()),
+            tcp_unsent: Some(Default::default()),
+            ..Self::from_tcp_socket(tcp)
+        }
+    }
+
+    pub fn from_quic(quic: QuicLink) -> Self {
+        Self {
+            tcp: None,
+            tcp_pending: None,
+            tcp_unsent: None,
+            quic: Some(quic),
+            websocket: None,
+            pipe: None,
+        }
+    }
+
+    pub fn from_websocket(websocket: WebSocketLink) -> Self {
+        Self {
+            tcp: None,
+            tcp_pending: None,
+            tcp_unsent: None,
+            quic: None,
+            websocket: Some(websocket),
+            pipe: None,
+        }
+    }
+
+    pub fn from_pipe(pipe: PipeLink) -> Self {
+        Self {
+            tcp: None,
+            tcp_pending: None,
+            tcp_unsent: None,
+            quic: None,
+            websocket: None,
+            pipe: Some(pipe),
+        }
+    }
+
+    /// Connects to a relay server. Addresses starting with `quic://` use quic, `ws://` urls use websockets,
+    /// everything else uses tcp.
+    /// Tcp streams (websockets included) are left in blocking mode, and can be tunneled through a proxy.
+    pub fn connect(address: &str, proxy: Option<&Proxy>) -> std::io::Result<Self> {
+        if let Some(address) = address.strip_prefix(QUIC_SCHEME) {
+            if proxy.is_some() {
+                return Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    "Quic can't be used through a proxy",
+                ));
+            }
+            Ok(Self::from_quic(quic::connect(address)?))
+        } else if address.starts_with(WEBSOCKET_SCHEME) {
+            Ok(Self::from_websocket(websocket::connect(address, proxy)?))
+        } else {
+            let tcp = match proxy {
+                Some(proxy) => proxy.connect(address)?,
+                None => TcpStream::connect(address)?,
+            };
+            tcp.set_nodelay(DISABLE_NAGLE_ALGORITHM)?;
+            Ok(Self::from_relay_tcp_socket(tcp))
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        if let Some(tcp) = self.tcp.as_ref() {
+            tcp.set_nonblocking(nonblocking)?;
+        }
+        if let Some(websocket) = self.websocket.as_ref() {
+            websocket.set_nonblocking(nonblocking)?;
+        }
+        Ok(())
     }
 
     pub fn is_timed_out(&self) -> bool {
         let mut buffer = [0u8; BUFFER_SIZE];
-        if self.has_tcp() {
-            if let Ok(size) = self.tcp.as_ref().unwrap().peek(&mut buffer) {
-                size == 0
-            } else {
-                false
+        if let Some(quic) = self.quic.as_ref() {
+            quic.is_closed()
+        } else if let Some(websocket) = self.websocket.as_ref() {
+            websocket.is_closed()
+        } else if let Some(pipe) = self.pipe.as_ref() {
+            pipe.is_closed()
+        } else if self.has_tcp() {
+            match self.tcp.as_ref().unwrap().peek(&mut buffer) {
+                Ok(size) => size == 0,
+                // Reset or aborted links are as dead as closed ones
+                Err(e) => e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::Interrupted,
             }
         } else {
             true
@@ -33,21 +150,152 @@ impl SocketWrapper {
         self.tcp.as_ref().unwrap().peek(buf)
     }
 
-    /// Reads from the tcp stream
+    /// Reads from the tcp stream. Relay links return a single packet per read instead.
+    /// Packets sent over tcp get merged and split by the stream, so they're put back together here.
     pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.tcp.as_ref().unwrap().read(buf)
+        if let Some(quic) = self.quic.as_ref() {
+            return quic.read(buf);
+        }
+        if let Some(websocket) = self.websocket.as_ref() {
+            return websocket.read(buf);
+        }
+        if let Some(pipe) = self.pipe.as_ref() {
+            return pipe.read(buf);
+        }
+        let Some(pending) = self.tcp_pending.as_ref() else {
+            // No more than a buffer of raw data, leaving room in frames for the packet wrapping it
+            let size = buf.len().min(BUFFER_SIZE);
+            return self.tcp.as_ref().unwrap().read(&mut buf[..size]);
+        };
+        let mut pending = pending.lock().unwrap();
+        loop {
+            let tcp = self.tcp.as_ref().unwrap();
+            let frame = next_frame(&mut pending).inspect_err(|_| {
+                // Lost track of the frames, the next timeout check drops the link
+                let _ = tcp.shutdown(std::net::Shutdown::Both);
+            })?;
+            if let Some(packet) = frame {
+                if packet.len() > buf.len() {
+                    println!(
+                        "Dropping a packet of size {} that doesn't fit the buffer",
+                        packet.len()
+                    );
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                buf[..packet.len()].copy_from_slice(&packet);
+                return Ok(packet.len());
+            }
+            let mut chunk = [0u8; BUFFER_SIZE];
+            let size = (&*tcp).read(&mut chunk)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            pending.extend_from_slice(&chunk[..size]);
+        }
     }
 
     pub fn get_tcp_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        if let Some(quic) = self.quic.as_ref() {
+            return Ok(quic.remote_address());
+        }
+        if let Some(websocket) = self.websocket.as_ref() {
+            return Ok(websocket.remote_address());
+        }
+        if let Some(pipe) = self.pipe.as_ref() {
+            return Ok(pipe.remote_address());
+        }
         self.tcp.as_ref().unwrap().peer_addr()
     }
 
+    /// Address of our own end of the tcp stream
+    pub fn get_local_tcp_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        match self.tcp.as_ref() {
+            Some(tcp) => tcp.local_addr(),
+            None => Err(ErrorKind::Unsupported.into()),
+        }
+    }
+
     /// Writes the tcp stream
     pub fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.quic.is_some() || self.websocket.is_some() || self.pipe.is_some() {
+            // Quic, websocket and pipe links only carry whole packets
+            return Err(ErrorKind::Unsupported.into());
+        }
         self.tcp.as_ref().unwrap().write(buf)
     }
 
+    /// Sends a whole packet to the other side of a relay link
+    pub fn send_packet(&self, packet: &Packet) -> std::io::Result<()> {
+        if let Some(quic) = self.quic.as_ref() {
+            return quic.send_packet(packet);
+        }
+        if let Some(websocket) = self.websocket.as_ref() {
+            return websocket.send_packet(packet);
+        }
+        if let Some(pipe) = self.pipe.as_ref() {
+            return pipe.send_packet(packet);
+        }
+        let tcp = self.tcp.as_ref().unwrap();
+        let Some(unsent) = self.tcp_unsent.as_ref() else {
+            return (&*tcp).write_all(&framing::frame(packet));
+        };
+        let mut unsent = unsent.lock().unwrap();
+        // Holding back too much, wait for the other side to read some
+        let begin = Instant::now();
+        while unsent.len() > MAX_UNSENT_SIZE {
+            write_unsent(tcp, &mut unsent)?;
+            if begin.elapsed() > SEND_TIMEOUT {
+                let _ = tcp.shutdown(std::net::Shutdown::Both);
+                return Err(ErrorKind::TimedOut.into());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        unsent.extend_from_slice(&framing::frame(packet));
+        write_unsent(tcp, &mut unsent)
+    }
+
+    /// Sends what a relay link held back, as much as it takes
+    pub fn flush(&self) -> std::io::Result<()> {
+        match (self.tcp.as_ref(), self.tcp_unsent.as_ref()) {
+            (Some(tcp), Some(unsent)) => write_unsent(tcp, &mut unsent.lock().unwrap()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn has_tcp(&self) -> bool {
-        return self.tcp.is_some();
+        self.tcp.is_some()
+    }
+
+    pub fn has_quic(&self) -> bool {
+        self.quic.is_some()
+    }
+
+    pub fn has_websocket(&self) -> bool {
+        self.websocket.is_some()
+    }
+
+    pub fn has_pipe(&self) -> bool {
+        self.pipe.is_some()
+    }
+
+    /// Whether udp data should be sent over this link, rather than over a separate udp socket
+    pub fn carries_udp(&self) -> bool {
+        self.has_quic() || self.has_websocket() || self.has_pipe()
+    }
+}
+
+/// Writes as much of the bytes held back for a tcp relay link as it takes
+fn write_unsent(mut tcp: &TcpStream, unsent: &mut Vec<u8>) -> std::io::Result<()> {
+    while !unsent.is_empty() {
+        match tcp.write(unsent) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(size) => {
+                unsent.drain(..size);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,493 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::random_bytes,
+    proxy::{Proxy, UdpAssociation},
+};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub const HEADER_SIZE: usize = 20;
+pub const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const SOFTWARE: u16 = 0x8022;
+const OTHER_ADDRESS: u16 = 0x802c;
+/// Flag of CHANGE-REQUEST asking to be answered from the other port (RFC 5780)
+const CHANGE_PORT: u32 = 0x2;
+/// How long nat-check waits for each answer, and how many times it asks
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+const REQUEST_ATTEMPTS: usize = 3;
+
+/// Answers STUN binding requests (RFC 5389) arriving on the udp socket of the relay,
+/// and on a second port when there's one.
+/// With a second port, answers carry OTHER-ADDRESS, and requests asking for it are answered
+/// from the other port (RFC 5780) - enough for clients to tell how their NAT maps and filters.
+pub struct StunResponder {
+    primary: UdpSocket,
+    secondary: Option<UdpSocket>,
+}
+impl StunResponder {
+    pub fn new(primary: UdpSocket, secondary: Option<UdpSocket>) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// Answers a datagram if it's a binding request, returning whether it was one
+    pub fn answer(&self, datagram: &[u8], source: SocketAddr, on_secondary: bool) -> bool {
+        let Some(request) = BindingRequest::parse(datagram) else {
+            return false;
+        };
+        let (socket, other_socket) = match (on_secondary, self.secondary.as_ref()) {
+            (true, Some(secondary)) => (secondary, Some(&self.primary)),
+            _ => (&self.primary, self.secondary.as_ref()),
+        };
+        let other_address = other_socket.and_then(|other_socket| other_socket.local_addr().ok());
+        let response = binding_response(request.transaction_id, source, other_address);
+        let socket = match other_socket {
+            Some(other_socket) if request.change_port => other_socket,
+            _ => socket,
+        };
+        if let Err(e) = socket.send_to(&response, source) {
+            println!(
+                "Failed to answer a STUN binding request of {}: {}",
+                source, e
+            );
+        }
+        true
+    }
+
+    /// Answers binding requests arriving on the second port, on a thread of its own
+    pub fn listen_secondary(self: &Arc<Self>) {
+        let Some(secondary) = self.secondary.as_ref() else {
+            return;
+        };
+        println!(
+            "Answering STUN binding requests on {} too",
+            secondary.local_addr().unwrap()
+        );
+        let responder = self.clone();
+        std::thread::spawn(move || {
+            let secondary = responder.secondary.as_ref().unwrap();
+            let mut buffer = [0u8; 1500];
+            loop {
+                match secondary.recv_from(&mut buffer) {
+                    Ok((size, source)) => {
+                        if !responder.answer(&buffer[..size], source, true) {
+                            println!("Received a non STUN datagram on the STUN port from {source}");
+                        }
+                    }
+                    Err(e) => println!("Failed to receive on the STUN port: {}", e),
+                }
+            }
+        });
+    }
+}
+
+struct BindingRequest {
+    transaction_id: [u8; 12],
+    change_port: bool,
+}
+impl BindingRequest {
+    fn parse(datagram: &[u8]) -> Option<Self> {
+        let (message_type, transaction_id, attributes) = parse_message(datagram)?;
+        if message_type != BINDING_REQUEST {
+            return None;
+        }
+        let change_port = attributes
+            .iter()
+            .find(|(attribute_type, _)| *attribute_type == CHANGE_REQUEST)
+            .and_then(|(_, value)| value.get(..4)?.try_into().ok())
+            .is_some_and(|flags| u32::from_be_bytes(flags) & CHANGE_PORT != 0);
+        Some(Self {
+            transaction_id,
+            change_port,
+        })
+    }
+}
+
+/// What a binding response tells us
+struct BindingResponse {
+    /// Where the server saw our request come from
+    mapped_address: SocketAddr,
+    /// The second port of the server, if it has one
+    other_address: Option<SocketAddr>,
+}
+impl BindingResponse {
+    fn parse(datagram: &[u8], transaction_id: [u8; 12]) -> Option<Self> {
+        let (message_type, response_id, attributes) = parse_message(datagram)?;
+        if message_type != BINDING_SUCCESS || response_id != transaction_id {
+            return None;
+        }
+        let attribute = |wanted: u16| {
+            attributes
+                .iter()
+                .find(|(attribute_type, _)| *attribute_type == wanted)
+                .map(|(_, value)| *value)
+        };
+        let mapped_address = attribute(XOR_MAPPED_ADDRESS)
+            .and_then(|value| decode_address(value, Some(transaction_id)))
+            .or_else(|| attribute(MAPPED_ADDRESS).and_then(|value| decode_address(value, None)))?;
+        let other_address = attribute(OTHER_ADDRESS).and_then(|value| decode_address(value, None));
+        Some(Self {
+            mapped_address,
+            other_address,
+        })
+    }
+}
+
+/// Types and values of the attributes of a STUN message
+pub type Attributes<'a> = Vec<(u16, &'a [u8])>;
+
+/// Splits a STUN message into its type, transaction id and attributes
+pub fn parse_message(datagram: &[u8]) -> Option<(u16, [u8; 12], Attributes<'_>)> {
+    if datagram.len() < HEADER_SIZE
+        || datagram[0] & 0xc0 != 0
+        || u32::from_be_bytes(datagram[4..8].try_into().ok()?) != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let message_type = u16::from_be_bytes(datagram[0..2].try_into().ok()?);
+    let length = u16::from_be_bytes(datagram[2..4].try_into().ok()?) as usize;
+    if datagram.len() != HEADER_SIZE + length {
+        return None;
+    }
+    let transaction_id = datagram[8..20].try_into().ok()?;
+    let mut attributes = vec![];
+    let mut rest = &datagram[HEADER_SIZE..];
+    while rest.len() >= 4 {
+        let attribute_type = u16::from_be_bytes(rest[0..2].try_into().ok()?);
+        let length = u16::from_be_bytes(rest[2..4].try_into().ok()?) as usize;
+        let value = rest.get(4..4 + length)?;
+        attributes.push((attribute_type, value));
+        // Attributes are padded to 4 bytes
+        rest = rest
+            .get((4 + length).next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    Some((message_type, transaction_id, attributes))
+}
+
+pub fn encode_message(
+    message_type: u16,
+    transaction_id: [u8; 12],
+    attributes: &[(u16, Vec<u8>)],
+) -> Vec<u8> {
+    let mut body = vec![];
+    for (attribute_type, value) in attributes {
+        body.extend_from_slice(&attribute_type.to_be_bytes());
+        body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        body.extend_from_slice(value);
+        body.resize(body.len().next_multiple_of(4), 0);
+    }
+    let mut message = vec![];
+    message.extend_from_slice(&message_type.to_be_bytes());
+    message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(&transaction_id);
+    message.extend_from_slice(&body);
+    message
+}
+
+/// Encodes an address attribute, xored with the cookie and transaction id for XOR-MAPPED-ADDRESS
+pub fn encode_address(address: SocketAddr, xor: Option<[u8; 12]>) -> Vec<u8> {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(&xor.unwrap_or_default());
+    if xor.is_none() {
+        mask.fill(0);
+    }
+    let (family, ip) = match address.ip() {
+        IpAddr::V4(ip) => (1u8, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2u8, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(
+        &(address.port() ^ u16::from_be_bytes([mask[0], mask[1]])).to_be_bytes(),
+    );
+    value.extend(ip.iter().zip(mask.iter()).map(|(byte, mask)| byte ^ mask));
+    value
+}
+
+pub fn decode_address(value: &[u8], xor: Option<[u8; 12]>) -> Option<SocketAddr> {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(&xor.unwrap_or_default());
+    if xor.is_none() {
+        mask.fill(0);
+    }
+    let port = u16::from_be_bytes(value.get(2..4)?.try_into().ok()?)
+        ^ u16::from_be_bytes([mask[0], mask[1]]);
+    let unmask = |octets: &[u8]| -> Vec<u8> {
+        octets
+            .iter()
+            .zip(mask.iter())
+            .map(|(byte, mask)| byte ^ mask)
+            .collect()
+    };
+    let ip: IpAddr = match value.get(1)? {
+        1 => <[u8; 4]>::try_from(unmask(value.get(4..8)?)).ok()?.into(),
+        2 => <[u8; 16]>::try_from(unmask(value.get(4..20)?)).ok()?.into(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn binding_response(
+    transaction_id: [u8; 12],
+    mapped_address: SocketAddr,
+    other_address: Option<SocketAddr>,
+) -> Vec<u8> {
+    let mut attributes = vec![
+        (
+            XOR_MAPPED_ADDRESS,
+            encode_address(mapped_address, Some(transaction_id)),
+        ),
+        (MAPPED_ADDRESS, encode_address(mapped_address, None)),
+    ];
+    if let Some(other_address) = other_address {
+        attributes.push((OTHER_ADDRESS, encode_address(other_address, None)));
+    }
+    attributes.push((SOFTWARE, b"rubicon".to_vec()));
+    encode_message(BINDING_SUCCESS, transaction_id, &attributes)
+}
+
+/// Transaction ids must not be guessed, or anyone could answer our requests in place of the server
+pub fn new_transaction_id() -> [u8; 12] {
+    random_bytes()
+}
+
+fn binding_request(change_port: bool) -> ([u8; 12], Vec<u8>) {
+    let transaction_id = new_transaction_id();
+    let attributes = if change_port {
+        vec![(CHANGE_REQUEST, CHANGE_PORT.to_be_bytes().to_vec())]
+    } else {
+        vec![]
+    };
+    (
+        transaction_id,
+        encode_message(BINDING_REQUEST, transaction_id, &attributes),
+    )
+}
+
+/// The udp socket nat-check asks from, possibly through a SOCKS5 proxy
+struct Prober {
+    socket: UdpSocket,
+    association: Option<UdpAssociation>,
+}
+impl Prober {
+    /// Binds the socket to ask from, through the proxy when there's one.
+    /// Also returns the address our datagrams leave from, were there no NAT.
+    fn new(server: SocketAddr, proxy: Option<Proxy>) -> std::io::Result<(Self, SocketAddr)> {
+        let association = proxy.map(|proxy| proxy.associate_udp()).transpose()?;
+        let bind_address = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let prober = Prober {
+            socket: UdpSocket::bind(bind_address)?,
+            association,
+        };
+        let probe = UdpSocket::bind(bind_address)?;
+        probe.connect(server)?;
+        let local_address =
+            SocketAddr::new(probe.local_addr()?.ip(), prober.socket.local_addr()?.port());
+        Ok((prober, local_address))
+    }
+
+    /// Sends a binding request to an address until it's answered
+    fn ask(&self, server: SocketAddr, change_port: bool) -> Option<BindingResponse> {
+        let (transaction_id, request) = binding_request(change_port);
+        let request = match self.association.as_ref() {
+            Some(association) => association.wrap(&server.to_string(), &request).ok()?,
+            None => request,
+        };
+        let destination = self
+            .association
+            .as_ref()
+            .map(|association| association.relay_address())
+            .unwrap_or(server);
+        let mut buffer = [0u8; 1500];
+        for _ in 0..REQUEST_ATTEMPTS {
+            self.socket.send_to(&request, destination).ok()?;
+            let deadline = Instant::now() + REQUEST_TIMEOUT;
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                self.socket
+                    .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+                    .ok()?;
+                let Ok((size, _)) = self.socket.recv_from(&mut buffer) else {
+                    break;
+                };
+                let data = match self.association.as_ref() {
+                    Some(association) => association.unwrap(&buffer[..size])?.1,
+                    None => &buffer[..size],
+                };
+                if let Some(response) = BindingResponse::parse(data, transaction_id) {
+                    return Some(response);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Classifies the NAT in front of us by asking the STUN responder of a relay where it sees us,
+/// from both of its ports, and prints whether players are likely to reach us directly.
+pub fn check_nat(server_address: String, proxy: Option<Proxy>) {
+    let Some(server) = server_address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+    else {
+        println!("Couldn't resolve {}", server_address);
+        std::process::exit(1);
+    };
+    let (prober, local_address) = match Prober::new(server, proxy) {
+        Ok(prober) => prober,
+        Err(e) => {
+            println!("Couldn't get a udp socket to ask {} from: {}", server, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Asking {} where it sees us from {}", server, local_address);
+
+    let Some(first) = prober.ask(server, false) else {
+        println!(
+            "NAT CHECK: no answer from {}, udp doesn't get through",
+            server
+        );
+        println!("Players will send udp over their tcp link to the relay, and can't be reached directly.");
+        return;
+    };
+    println!("The relay sees us at {}", first.mapped_address);
+    if prober.association.is_none() && first.mapped_address == local_address {
+        println!("NAT CHECK: no NAT, players should reach us directly.");
+        return;
+    }
+    let Some(mut other_address) = first.other_address else {
+        println!("NAT CHECK: we're behind a NAT, but the relay has no second STUN port to tell which kind.");
+        println!("Run the host with --stun-port for a full check.");
+        return;
+    };
+    if other_address.ip().is_unspecified() {
+        // The relay listens on every interface, and reaches us on the one we asked
+        other_address.set_ip(server.ip());
+    }
+    // Check filtering first, the second port must not have heard from us yet
+    let filtered = prober.ask(server, true).is_none();
+    let Some(second) = prober.ask(other_address, false) else {
+        println!(
+            "NAT CHECK: no answer from the second port {}, udp is filtered",
+            other_address
+        );
+        return;
+    };
+    println!("The second port sees us at {}", second.mapped_address);
+    if second.mapped_address != first.mapped_address {
+        println!("NAT CHECK: symmetric NAT, it maps us to a new port for every destination.");
+        println!("Direct paths only work with players behind cone NATs, expect udp to go through the relay.");
+    } else if filtered {
+        println!("NAT CHECK: port restricted cone NAT, only addresses and ports we sent to may reach us.");
+        println!("Direct paths should work with players not behind symmetric NATs.");
+    } else {
+        // Telling it apart from an address restricted one would take a relay with a second address
+        println!("NAT CHECK: full cone or address restricted NAT, it doesn't filter on ports.");
+        println!("Direct paths should work with every player.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_parses_messages() {
+        let transaction_id = new_transaction_id();
+        let message = encode_message(
+            BINDING_REQUEST,
+            transaction_id,
+            &[
+                (SOFTWARE, b"rubicon".to_vec()),
+                (CHANGE_REQUEST, vec![0, 0, 0, 2]),
+            ],
+        );
+        // Attributes are padded to 4 bytes
+        assert_eq!(message.len(), HEADER_SIZE + 4 + 8 + 4 + 4);
+        let (message_type, parsed_id, attributes) = parse_message(&message).unwrap();
+        assert_eq!(message_type, BINDING_REQUEST);
+        assert_eq!(parsed_id, transaction_id);
+        assert_eq!(
+            attributes,
+            [
+                (SOFTWARE, &b"rubicon"[..]),
+                (CHANGE_REQUEST, &[0, 0, 0, 2][..])
+            ]
+        );
+        assert!(BindingRequest::parse(&message).unwrap().change_port);
+        assert!(
+            !BindingRequest::parse(&binding_request(false).1)
+                .unwrap()
+                .change_port
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_messages() {
+        let (_, message) = binding_request(true);
+        assert!(parse_message(&message[..HEADER_SIZE - 1]).is_none());
+        // Wrong cookie, length not matching the datagram, not a STUN message type
+        let mut wrong_cookie = message.clone();
+        wrong_cookie[4] ^= 0xff;
+        assert!(parse_message(&wrong_cookie).is_none());
+        assert!(parse_message(&message[..message.len() - 4]).is_none());
+        let mut not_stun = message.clone();
+        not_stun[0] |= 0x80;
+        assert!(parse_message(&not_stun).is_none());
+        // An attribute running past the end of the message
+        let mut truncated = message.clone();
+        truncated[HEADER_SIZE + 3] = 40;
+        assert!(parse_message(&truncated).is_none());
+        assert!(BindingRequest::parse(&binding_response(
+            new_transaction_id(),
+            "10.0.0.1:1".parse().unwrap(),
+            None
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn encodes_and_decodes_addresses() {
+        let transaction_id = new_transaction_id();
+        for address in ["203.0.113.7:40000", "[2001:db8::7]:40000"] {
+            let address: SocketAddr = address.parse().unwrap();
+            let plain = encode_address(address, None);
+            let xored = encode_address(address, Some(transaction_id));
+            assert_ne!(plain, xored);
+            assert_eq!(decode_address(&plain, None), Some(address));
+            assert_eq!(decode_address(&xored, Some(transaction_id)), Some(address));
+        }
+        // XOR-MAPPED-ADDRESS of RFC 5769, 192.0.2.1:32853
+        assert_eq!(
+            encode_address("192.0.2.1:32853".parse().unwrap(), Some([0; 12])),
+            [0, 1, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]
+        );
+        assert_eq!(decode_address(&[0, 3, 0, 80, 1, 2, 3, 4], None), None);
+        assert_eq!(decode_address(&[0, 1, 0, 80, 1, 2], None), None);
+    }
+
+    #[test]
+    fn reads_binding_responses() {
+        let transaction_id = new_transaction_id();
+        let mapped_address = "203.0.113.7:40000".parse().unwrap();
+        let other_address = "203.0.113.1:3479".parse().unwrap();
+        let response = binding_response(transaction_id, mapped_address, Some(other_address));
+        let parsed = BindingResponse::parse(&response, transaction_id).unwrap();
+        assert_eq!(parsed.mapped_address, mapped_address);
+        assert_eq!(parsed.other_address, Some(other_address));
+        // Answers to someone else's request
+        assert!(BindingResponse::parse(&response, new_transaction_id()).is_none());
+    }
+}
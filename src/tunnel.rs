@@ -0,0 +1,638 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{Exposure, SocketType},
+    common::{BUFFER_SIZE, DISABLE_NAGLE_ALGORITHM},
+    connections::InnerConnections,
+    packet::{ConnectionPacket, DataPacket, HOP_LIMIT},
+};
+
+/// How long a visitor may go without sending or receiving udp data before we forget it
+const UDP_VISITOR_TIMEOUT: Duration = Duration::from_secs(60);
+/// Tries at finding a port free for both tcp and udp
+const BIND_ATTEMPTS: u32 = 16;
+/// Public ports a player may have at once
+pub const MAX_PUBLIC_PORTS_PER_PLAYER: usize = 8;
+/// Public ports the relay gives out at once, to all players
+pub const MAX_PUBLIC_PORTS: usize = 256;
+/// Udp visitors a public port keeps track of at once, others being ignored until some are forgotten
+const MAX_UDP_VISITORS_PER_PORT: usize = 64;
+/// Data for a tcp visitor we hold on to while it doesn't read it, before closing its connection
+const MAX_UNSENT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Sent by the relay to a player for each local port it asked to make public, once it got a port of the relay
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PublicPortPacket {
+    pub local_port: u16,
+    /// Port of the relay machine forwarding to the local port
+    pub public_port: u16,
+    /// None when both tcp and udp are forwarded
+    pub socket_type: Option<SocketType>,
+}
+
+/// A port of the relay machine forwarding to an exposed port of a player, for people that don't run rubicon
+#[derive(Debug)]
+pub struct PublicPort {
+    pub player_name: String,
+    pub local_port: u16,
+    pub public_port: u16,
+    pub socket_type: Option<SocketType>,
+    pub tcp_listener: Option<TcpListener>,
+    pub udp_socket: Option<UdpSocket>,
+}
+
+/// Where data the relay passes on came from
+#[derive(Clone, Copy, Debug)]
+pub enum Origin {
+    /// The link on this tcp port of the relay
+    Link(u16),
+    /// This udp address
+    Udp(SocketAddr),
+    /// Someone that isn't a player, ie. a visitor of a public port
+    Unknown,
+}
+
+impl Origin {
+    pub fn link_port(self) -> Option<u16> {
+        match self {
+            Origin::Link(port) => Some(port),
+            _ => None,
+        }
+    }
+}
+
+/// Someone that reached a player through one of its public ports.
+/// Players answer it by name like they answer other players, the name being its address and protocol.
+#[derive(Debug)]
+pub struct Visitor {
+    /// The player the public port forwards to
+    pub player_name: String,
+    pub public_port: u16,
+    /// The port of the player the public port forwards to
+    pub local_port: u16,
+    pub address: SocketAddr,
+    /// Its connection, for tcp visitors
+    pub stream: Option<TcpStream>,
+    /// The socket of the public port, for udp visitors
+    pub udp_socket: Option<UdpSocket>,
+    pub last_active: Instant,
+    /// Bytes relayed both ways
+    pub relayed_bytes: u64,
+    /// Data for tcp visitors their connection didn't take yet
+    pub unsent: Vec<u8>,
+}
+
+impl Visitor {
+    fn new(
+        public_port: &PublicPort,
+        address: SocketAddr,
+        stream: Option<TcpStream>,
+        udp_socket: Option<UdpSocket>,
+    ) -> Self {
+        Self {
+            player_name: public_port.player_name.clone(),
+            public_port: public_port.public_port,
+            local_port: public_port.local_port,
+            address,
+            stream,
+            udp_socket,
+            last_active: Instant::now(),
+            relayed_bytes: 0,
+            unsent: vec![],
+        }
+    }
+
+    /// Writes as much of the data waiting for a tcp visitor as its connection takes
+    fn flush(&mut self) -> std::io::Result<()> {
+        let Some(mut stream) = self.stream.as_ref() else {
+            return Ok(());
+        };
+        while !self.unsent.is_empty() {
+            match stream.write(&self.unsent) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    self.unsent.drain(..size);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wraps what the visitor sent for the player, as if it came from a player of that name
+    fn wrap(&mut self, name: String, socket_type: SocketType, data: &[u8]) -> DataPacket {
+        self.relayed_bytes += data.len() as u64;
+        self.last_active = Instant::now();
+        DataPacket {
+            socket_type,
+            sender_name: name,
+            sender_port: self.public_port,
+            receiver_name: self.player_name.clone(),
+            receiver_port: self.local_port,
+            data: data.to_vec(),
+            source_port: self.address.port(),
+            hop_limit: HOP_LIMIT,
+            sequence: None,
+            reply: false,
+        }
+    }
+}
+
+fn visitor_name(address: SocketAddr, socket_type: SocketType) -> String {
+    match socket_type {
+        SocketType::Tcp => format!("{}/tcp", address),
+        SocketType::Udp => format!("{}/udp", address),
+    }
+}
+
+/// Binds a tcp listener and/or a udp socket on a port picked by the system, the same one for both
+fn bind(socket_type: Option<SocketType>) -> std::io::Result<PublicPort> {
+    let mut last_error = ErrorKind::AddrInUse.into();
+    for _ in 0..BIND_ATTEMPTS {
+        let tcp_listener = match socket_type {
+            Some(SocketType::Udp) => None,
+            _ => Some(TcpListener::bind("0.0.0.0:0")?),
+        };
+        let port = match tcp_listener.as_ref() {
+            Some(listener) => listener.local_addr()?.port(),
+            None => 0,
+        };
+        let udp_socket = match socket_type {
+            Some(SocketType::Tcp) => None,
+            _ => match UdpSocket::bind(format!("0.0.0.0:{}", port)) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    // Taken for udp, try another port
+                    last_error = e;
+                    continue;
+                }
+            },
+        };
+        if let Some(listener) = tcp_listener.as_ref() {
+            listener.set_nonblocking(true)?;
+        }
+        let public_port = match udp_socket.as_ref() {
+            Some(socket) => {
+                socket.set_nonblocking(true)?;
+                socket.local_addr()?.port()
+            }
+            None => port,
+        };
+        return Ok(PublicPort {
+            player_name: String::new(),
+            local_port: 0,
+            public_port,
+            socket_type,
+            tcp_listener,
+            udp_socket,
+        });
+    }
+    Err(last_error)
+}
+
+/// Allocates the public ports a player asked for that it doesn't have yet, as many as it may have.
+/// Returns where they are, for the player to be told.
+pub fn allocate(
+    cons: &mut InnerConnections,
+    player_name: &str,
+    requested: &[Exposure],
+) -> Vec<PublicPortPacket> {
+    let mut allocated = vec![];
+    for exposure in requested {
+        if cons.public_ports.iter().any(|public_port| {
+            public_port.player_name == player_name
+                && public_port.local_port == exposure.port
+                && public_port.socket_type == exposure.socket_type
+        }) {
+            continue;
+        }
+        let owned = cons
+            .public_ports
+            .iter()
+            .filter(|public_port| public_port.player_name == player_name)
+            .count();
+        if owned >= MAX_PUBLIC_PORTS_PER_PLAYER || cons.public_ports.len() >= MAX_PUBLIC_PORTS {
+            println!(
+                "PUBLIC PORT: not allocating one for {}:{}, {} has {} and we gave out {}",
+                player_name,
+                exposure.port,
+                player_name,
+                owned,
+                cons.public_ports.len()
+            );
+            continue;
+        }
+        match bind(exposure.socket_type) {
+            Ok(mut public_port) => {
+                println!(
+                    "PUBLIC PORT: {} forwards to {}:{}",
+                    public_port.public_port, player_name, exposure.port
+                );
+                public_port.player_name = player_name.to_string();
+                public_port.local_port = exposure.port;
+                allocated.push(PublicPortPacket {
+                    local_port: exposure.port,
+                    public_port: public_port.public_port,
+                    socket_type: exposure.socket_type,
+                });
+                cons.public_ports.push(public_port);
+            }
+            Err(e) => println!(
+                "PUBLIC PORT: failed to allocate one for {}:{}: {}",
+                player_name, exposure.port, e
+            ),
+        }
+    }
+    allocated
+}
+
+/// Releases the public ports of a player that left, closing the connections of their visitors
+pub fn release(cons: &mut InnerConnections, player_name: &str) {
+    cons.public_ports.retain(|public_port| {
+        let released = public_port.player_name == player_name;
+        if released {
+            println!(
+                "PUBLIC PORT: releasing {}, {} left",
+                public_port.public_port, player_name
+            );
+        }
+        !released
+    });
+    cons.visitors.retain(|_, visitor| {
+        if visitor.player_name != player_name {
+            return true;
+        }
+        if let Some(stream) = visitor.stream.as_ref() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        false
+    });
+}
+
+/// Accepts visitors of public ports and reads what they send, wrapping it for the players the ports forward to.
+/// Forgets visitors that left.
+pub fn poll(
+    cons: &mut InnerConnections,
+    buffer: &mut [u8],
+) -> (Vec<ConnectionPacket>, Vec<DataPacket>) {
+    let mut connection_packets = vec![];
+    let mut data_packets = vec![];
+    for public_port in cons.public_ports.iter() {
+        if let Some(listener) = public_port.tcp_listener.as_ref() {
+            while let Ok((stream, address)) = listener.accept() {
+                if stream.set_nonblocking(true).is_err() {
+                    continue;
+                }
+                let _ = stream.set_nodelay(DISABLE_NAGLE_ALGORITHM);
+                let name = visitor_name(address, SocketType::Tcp);
+                println!(
+                    "PUBLIC PORT: {} connected to {}, forwarding to {}:{}",
+                    name, public_port.public_port, public_port.player_name, public_port.local_port
+                );
+                connection_packets.push(ConnectionPacket {
+                    sender_name: name.clone(),
+                    sender_port: public_port.public_port,
+                    receiver_name: public_port.player_name.clone(),
+                    receiver_port: public_port.local_port,
+                    source_port: address.port(),
+                    hop_limit: HOP_LIMIT,
+                });
+                cons.visitors
+                    .insert(name, Visitor::new(public_port, address, Some(stream), None));
+            }
+        }
+        if let Some(socket) = public_port.udp_socket.as_ref() {
+            while let Ok((size, address)) = socket.recv_from(buffer) {
+                let name = visitor_name(address, SocketType::Udp);
+                if !cons.visitors.contains_key(&name) {
+                    let visitors = cons
+                        .visitors
+                        .values()
+                        .filter(|visitor| {
+                            visitor.udp_socket.is_some()
+                                && visitor.public_port == public_port.public_port
+                        })
+                        .count();
+                    if visitors >= MAX_UDP_VISITORS_PER_PORT {
+                        // Not printed, as anyone may flood us with datagrams from many addresses
+                        continue;
+                    }
+                    let Ok(udp_socket) = socket.try_clone() else {
+                        continue;
+                    };
+                    println!(
+                        "PUBLIC PORT: {} sends to {}, forwarding to {}:{}",
+                        name,
+                        public_port.public_port,
+                        public_port.player_name,
+                        public_port.local_port
+                    );
+                    cons.visitors.insert(
+                        name.clone(),
+                        Visitor::new(public_port, address, None, Some(udp_socket)),
+                    );
+                }
+                let visitor = cons.visitors.get_mut(&name).unwrap();
+                data_packets.push(visitor.wrap(name, SocketType::Udp, &buffer[..size]));
+            }
+        }
+    }
+
+    // What tcp visitors send, and who left
+    let mut left = vec![];
+    for (name, visitor) in cons.visitors.iter_mut() {
+        if visitor.stream.is_none() {
+            if visitor.last_active.elapsed() > UDP_VISITOR_TIMEOUT {
+                left.push(name.clone());
+            }
+            continue;
+        }
+        if let Err(e) = visitor.flush() {
+            println!("PUBLIC PORT: failed to send data to {}: {}", name, e);
+            left.push(name.clone());
+            continue;
+        }
+        while let Some(mut stream) = visitor.stream.as_ref() {
+            // No more than a buffer, leaving room in frames for the packet wrapping it
+            match stream.read(&mut buffer[..BUFFER_SIZE]) {
+                Ok(0) => {
+                    left.push(name.clone());
+                    break;
+                }
+                Ok(size) => {
+                    data_packets.push(visitor.wrap(name.clone(), SocketType::Tcp, &buffer[..size]));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    left.push(name.clone());
+                    break;
+                }
+            }
+        }
+    }
+    for name in left {
+        println!("PUBLIC PORT: {} left", name);
+        cons.visitors.remove(&name);
+    }
+    (connection_packets, data_packets)
+}
+
+/// Passes data a player sends to a visitor of one of its public ports on.
+/// What the connection of a tcp visitor doesn't take right away is sent as it takes it, when polling.
+/// Returns false when it isn't meant for a visitor of the player, or didn't come from the player:
+/// only its link or its udp address may answer its visitors, whatever name the packet carries.
+pub fn send_to_visitor(cons: &mut InnerConnections, packet: &DataPacket, from: Origin) -> bool {
+    let Some(player_name) = cons
+        .visitors
+        .get(&packet.receiver_name)
+        .filter(|visitor| visitor.player_name == packet.sender_name)
+        .map(|visitor| visitor.player_name.clone())
+    else {
+        return false;
+    };
+    if !is_from_player(cons, &player_name, from) {
+        println!(
+            "PUBLIC PORT: dropping data for {} claiming to come from {}, it came from {:?}",
+            packet.receiver_name, player_name, from
+        );
+        return false;
+    }
+    let visitor = cons.visitors.get_mut(&packet.receiver_name).unwrap();
+    visitor.relayed_bytes += packet.data.len() as u64;
+    visitor.last_active = Instant::now();
+    let result = match (visitor.stream.as_ref(), visitor.udp_socket.as_ref()) {
+        (Some(_), _) if visitor.unsent.len() + packet.data.len() > MAX_UNSENT_BYTES => Err(
+            std::io::Error::new(ErrorKind::OutOfMemory, "it doesn't read what we send"),
+        ),
+        (Some(_), _) => {
+            visitor.unsent.extend_from_slice(&packet.data);
+            visitor.flush()
+        }
+        (None, Some(socket)) => socket.send_to(&packet.data, visitor.address).map(|_| ()),
+        (None, None) => Ok(()),
+    };
+    if let Err(e) = result {
+        println!(
+            "PUBLIC PORT: failed to send data to {}: {}",
+            packet.receiver_name, e
+        );
+        // Polling tells it left
+        if let Some(stream) = visitor.stream.as_ref() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+    true
+}
+
+/// Whether data came over the link of a player or from its udp address
+fn is_from_player(cons: &InnerConnections, player_name: &String, from: Origin) -> bool {
+    match from {
+        Origin::Link(port) => cons
+            .get(&port)
+            .is_some_and(|player| &player.name == player_name && !player.observer),
+        Origin::Udp(address) => cons.iter().any(|(_, player)| {
+            &player.name == player_name
+                && !player.observer
+                && player.address.ip() == address.ip()
+                && player.last_known_udp_port == address.port()
+        }),
+        Origin::Unknown => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connections::PlayerData, socket::SocketWrapper};
+
+    /// Gives a player a link to the relay, returning the port it's on
+    fn link(cons: &mut InnerConnections, name: &str, udp_port: u16) -> (u16, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let player = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        let mut player_data =
+            PlayerData::new(address, SocketWrapper::from_relay_tcp_socket(stream));
+        player_data.name = name.to_string();
+        player_data.last_known_udp_port = udp_port;
+        cons.insert(address.port(), player_data);
+        (address.port(), player)
+    }
+
+    fn exposures(count: u16) -> Vec<Exposure> {
+        (1..=count)
+            .map(|port| Exposure {
+                port,
+                socket_type: Some(SocketType::Tcp),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limits_public_ports_of_players() {
+        let mut cons = InnerConnections::default();
+        let allocated = allocate(&mut cons, "HOST", &exposures(10));
+        assert_eq!(allocated.len(), MAX_PUBLIC_PORTS_PER_PLAYER);
+        // Asking again gives nothing new
+        assert!(allocate(&mut cons, "HOST", &exposures(10)).is_empty());
+        assert_eq!(allocate(&mut cons, "JOINER", &exposures(2)).len(), 2);
+        release(&mut cons, "HOST");
+        assert_eq!(cons.public_ports.len(), 2);
+    }
+
+    #[test]
+    fn queues_what_visitors_dont_read_yet() {
+        let mut cons = InnerConnections::default();
+        let (host_port, _host) = link(&mut cons, "HOST", 0);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let public_port = PublicPort {
+            player_name: "HOST".to_string(),
+            local_port: 25565,
+            public_port: 1,
+            socket_type: Some(SocketType::Tcp),
+            tcp_listener: None,
+            udp_socket: None,
+        };
+        let name = visitor_name(address, SocketType::Tcp);
+        cons.visitors.insert(
+            name.clone(),
+            Visitor::new(&public_port, address, Some(stream), None),
+        );
+
+        // More than the connection takes before the visitor reads
+        let data: Vec<u8> = (0..16 * 1024 * 1024).map(|i| i as u8).collect();
+        let mut packet = DataPacket {
+            socket_type: SocketType::Tcp,
+            sender_name: "HOST".to_string(),
+            sender_port: 25565,
+            receiver_name: name.clone(),
+            receiver_port: 1,
+            data: data[..MAX_UNSENT_BYTES].to_vec(),
+            source_port: 25565,
+            hop_limit: HOP_LIMIT,
+            sequence: None,
+            reply: false,
+        };
+        assert!(send_to_visitor(&mut cons, &packet, Origin::Link(host_port)));
+        assert!(!cons.visitors[&name].unsent.is_empty());
+
+        let mut received = vec![0; MAX_UNSENT_BYTES];
+        let mut buffer = vec![0; 64 * 1024];
+        // Kept connected once done reading
+        let reader = std::thread::spawn(move || {
+            client.read_exact(&mut received).unwrap();
+            (client, received)
+        });
+        while !cons.visitors[&name].unsent.is_empty() {
+            poll(&mut cons, &mut buffer);
+        }
+        let (_client, received) = reader.join().unwrap();
+        assert_eq!(received, data[..MAX_UNSENT_BYTES]);
+
+        // A visitor not reading at all is let go
+        packet.data = data;
+        send_to_visitor(&mut cons, &packet, Origin::Link(host_port));
+        poll(&mut cons, &mut buffer);
+        assert!(cons.visitors.is_empty());
+    }
+
+    #[test]
+    fn answers_visitors_only_from_their_player() {
+        let mut cons = InnerConnections::default();
+        let (host_port, _host) = link(&mut cons, "HOST", 40000);
+        let (other_port, _other) = link(&mut cons, "OTHER", 40001);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let visitor = UdpSocket::bind("127.0.0.1:0").unwrap();
+        visitor
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let address = visitor.local_addr().unwrap();
+        let public_port = PublicPort {
+            player_name: "HOST".to_string(),
+            local_port: 9999,
+            public_port: 1,
+            socket_type: Some(SocketType::Udp),
+            tcp_listener: None,
+            udp_socket: None,
+        };
+        let name = visitor_name(address, SocketType::Udp);
+        cons.visitors.insert(
+            name.clone(),
+            Visitor::new(&public_port, address, None, Some(socket)),
+        );
+        let packet = DataPacket {
+            socket_type: SocketType::Udp,
+            sender_name: "HOST".to_string(),
+            sender_port: 9999,
+            receiver_name: name,
+            receiver_port: 1,
+            data: b"hi".to_vec(),
+            source_port: 9999,
+            hop_limit: HOP_LIMIT,
+            sequence: None,
+            reply: false,
+        };
+
+        // Another player naming itself after the player
+        assert!(!send_to_visitor(
+            &mut cons,
+            &packet,
+            Origin::Link(other_port)
+        ));
+        assert!(!send_to_visitor(
+            &mut cons,
+            &packet,
+            Origin::Udp("127.0.0.1:40001".parse().unwrap())
+        ));
+        assert!(!send_to_visitor(&mut cons, &packet, Origin::Unknown));
+        let mut buffer = [0u8; 16];
+        assert!(visitor.recv_from(&mut buffer).is_err());
+
+        // The player itself, over its link and from its udp address
+        assert!(send_to_visitor(&mut cons, &packet, Origin::Link(host_port)));
+        assert!(send_to_visitor(
+            &mut cons,
+            &packet,
+            Origin::Udp("127.0.0.1:40000".parse().unwrap())
+        ));
+        for _ in 0..2 {
+            let (size, _) = visitor.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..size], b"hi");
+        }
+    }
+
+    #[test]
+    fn limits_udp_visitors_of_public_ports() {
+        let mut cons = InnerConnections::default();
+        let allocated = allocate(
+            &mut cons,
+            "HOST",
+            &[Exposure {
+                port: 9999,
+                socket_type: Some(SocketType::Udp),
+            }],
+        );
+        let public_port = allocated[0].public_port;
+        let sockets: Vec<UdpSocket> = (0..MAX_UDP_VISITORS_PER_PORT + 8)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for socket in sockets.iter() {
+            socket.send_to(b"hi", ("127.0.0.1", public_port)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        let mut buffer = vec![0; 1024];
+        let (_, data_packets) = poll(&mut cons, &mut buffer);
+        assert_eq!(data_packets.len(), MAX_UDP_VISITORS_PER_PORT);
+        assert_eq!(cons.visitors.len(), MAX_UDP_VISITORS_PER_PORT);
+    }
+}
@@ -0,0 +1,1012 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::{
+    commands::TurnUser,
+    common::{random_bytes, BUFFER_SIZE, MINIMUM_TICK_RATE_IN_MS},
+    connections::Connections,
+    stun::{
+        decode_address, encode_address, encode_message, new_transaction_id, parse_message,
+        Attributes, StunResponder, BINDING_REQUEST, HEADER_SIZE, SOFTWARE, XOR_MAPPED_ADDRESS,
+    },
+};
+
+const ALLOCATE: u16 = 0x0003;
+const REFRESH: u16 = 0x0004;
+const SEND_INDICATION: u16 = 0x0016;
+const DATA_INDICATION: u16 = 0x0017;
+const CREATE_PERMISSION: u16 = 0x0008;
+const CHANNEL_BIND: u16 = 0x0009;
+/// Added to the method of a request for its success and error responses
+const SUCCESS_CLASS: u16 = 0x0100;
+const ERROR_CLASS: u16 = 0x0110;
+
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const ERROR_CODE: u16 = 0x0009;
+const CHANNEL_NUMBER: u16 = 0x000c;
+const LIFETIME: u16 = 0x000d;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA: u16 = 0x0013;
+const REALM: u16 = 0x0014;
+const NONCE: u16 = 0x0015;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+const FINGERPRINT: u16 = 0x8028;
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+/// Protocol number of udp, the only transport we relay
+const UDP_TRANSPORT: u8 = 17;
+/// Channel numbers clients may bind
+const CHANNELS: std::ops::RangeInclusive<u16> = 0x4000..=0x7fff;
+
+const DEFAULT_ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_ALLOCATION_LIFETIME: Duration = Duration::from_secs(3600);
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+/// How long clients may authenticate with a nonce, before being told it's stale and given another one
+const NONCE_LIFETIME: Duration = Duration::from_secs(600);
+/// Relayed addresses a single user may hold at once
+const MAX_ALLOCATIONS_PER_USER: usize = 8;
+/// Relayed addresses all users may hold at once, each being a socket of ours
+const MAX_ALLOCATIONS: usize = 256;
+
+/// A relayed udp address a TURN client got from us, keyed by the address of the client in [crate::connections::InnerConnections]
+#[derive(Debug)]
+pub struct TurnAllocation {
+    pub username: String,
+    /// The socket peers send to, to reach the client
+    pub relay_socket: UdpSocket,
+    pub relayed_address: SocketAddr,
+    pub expires_at: Instant,
+    /// Peers allowed to send to the relayed address, by ip
+    pub permissions: HashMap<IpAddr, Instant>,
+    /// Peers bound to channels, for smaller headers
+    pub channels: HashMap<u16, (SocketAddr, Instant)>,
+    /// Bytes relayed both ways
+    pub relayed_bytes: u64,
+}
+impl TurnAllocation {
+    fn is_permitted(&self, peer: SocketAddr) -> bool {
+        self.permissions
+            .get(&peer.ip())
+            .is_some_and(|expires_at| Instant::now() < *expires_at)
+    }
+
+    fn get_channel(&self, peer: SocketAddr) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (address, _))| *address == peer)
+            .map(|(channel, _)| *channel)
+    }
+}
+
+/// An error response, with its code and reason
+type TurnError = (u16, &'static str);
+
+/// A request that passed the long-term credential check
+struct Authenticated {
+    username: String,
+    key: [u8; 16],
+}
+
+/// A TURN (RFC 5766/8656) front end to the relay, for clients that aren't players, ie. WebRTC ones.
+/// Relays udp only, over udp, with long-term credentials.
+/// Allocations are kept with the players in [Connections], so listing connections shows them too.
+struct TurnServer {
+    socket: UdpSocket,
+    realm: String,
+    users: HashMap<String, String>,
+    /// The nonce we last handed to each client, with when it goes stale
+    nonces: HashMap<SocketAddr, (String, Instant)>,
+    /// Whether clients may relay to loopback, private and link-local addresses
+    private_peers: bool,
+    stun: StunResponder,
+    connections: Connections,
+}
+
+/// Serves TURN on a udp port, on a thread of its own
+pub fn listen(
+    port: u16,
+    users: Vec<TurnUser>,
+    realm: String,
+    private_peers: bool,
+    connections: Connections,
+) {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    println!(
+        "Serving TURN on {} for {} user(s) of realm {}",
+        port,
+        users.len(),
+        realm
+    );
+    let mut server = TurnServer::new(socket, users, realm, private_peers, connections);
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        loop {
+            let begin = Instant::now();
+            let mut had_one = false;
+            while let Ok((size, source)) = server.socket.recv_from(&mut buffer) {
+                had_one = true;
+                server.receive(&buffer[..size], source);
+            }
+            had_one |= server.relay_from_peers(&mut buffer);
+            server.forget_stale_nonces();
+
+            let remaining_millis =
+                MINIMUM_TICK_RATE_IN_MS as f64 - begin.elapsed().as_millis() as f64;
+            if !had_one && remaining_millis > 0. {
+                std::thread::sleep(Duration::from_millis(remaining_millis as u64));
+            }
+        }
+    });
+}
+
+impl TurnServer {
+    fn new(
+        socket: UdpSocket,
+        users: Vec<TurnUser>,
+        realm: String,
+        private_peers: bool,
+        connections: Connections,
+    ) -> Self {
+        Self {
+            stun: StunResponder::new(socket.try_clone().unwrap(), None),
+            socket,
+            realm,
+            users: users
+                .into_iter()
+                .map(|user| (user.username, user.password))
+                .collect(),
+            nonces: Default::default(),
+            private_peers,
+            connections,
+        }
+    }
+
+    fn receive(&mut self, datagram: &[u8], source: SocketAddr) {
+        // ChannelData messages start with a channel number, which has 01 as its first bits
+        if datagram.first().is_some_and(|byte| byte & 0xc0 == 0x40) {
+            self.receive_channel_data(datagram, source);
+            return;
+        }
+        let Some((message_type, transaction_id, attributes)) = parse_message(datagram) else {
+            println!("TURN: received a non STUN datagram from {}", source);
+            return;
+        };
+        match message_type {
+            BINDING_REQUEST => {
+                self.stun.answer(datagram, source, false);
+            }
+            SEND_INDICATION => self.receive_send_indication(transaction_id, &attributes, source),
+            ALLOCATE | REFRESH | CREATE_PERMISSION | CHANNEL_BIND => {
+                let authenticated = match self.authenticate(datagram, &attributes, source) {
+                    Ok(authenticated) => authenticated,
+                    Err(error) => {
+                        // Tell the client how to authenticate
+                        let nonce = self.new_nonce(source);
+                        let attributes = vec![
+                            error_code(error),
+                            (REALM, self.realm.as_bytes().to_vec()),
+                            (NONCE, nonce.into_bytes()),
+                        ];
+                        self.respond(
+                            message_type | ERROR_CLASS,
+                            transaction_id,
+                            attributes,
+                            None,
+                            source,
+                        );
+                        return;
+                    }
+                };
+                let result = match message_type {
+                    ALLOCATE => self.allocate(&authenticated, transaction_id, &attributes, source),
+                    REFRESH => self.refresh(&authenticated, &attributes, source),
+                    CREATE_PERMISSION => {
+                        self.create_permission(&authenticated, transaction_id, &attributes, source)
+                    }
+                    _ => self.bind_channel(&authenticated, transaction_id, &attributes, source),
+                };
+                let (response_type, attributes) = match result {
+                    Ok(attributes) => (message_type | SUCCESS_CLASS, attributes),
+                    Err(error) => {
+                        println!(
+                            "TURN: {} of {} failed: {} {}",
+                            method_name(message_type),
+                            source,
+                            error.0,
+                            error.1
+                        );
+                        (message_type | ERROR_CLASS, vec![error_code(error)])
+                    }
+                };
+                self.respond(
+                    response_type,
+                    transaction_id,
+                    attributes,
+                    Some(authenticated.key),
+                    source,
+                );
+            }
+            _ => println!(
+                "TURN: ignoring a message of type {:#06x} from {}",
+                message_type, source
+            ),
+        }
+    }
+
+    /// Checks the long-term credentials of a request (RFC 5389 section 10.2)
+    fn authenticate(
+        &self,
+        datagram: &[u8],
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) -> Result<Authenticated, TurnError> {
+        let attribute = |wanted: u16| get_attribute(attributes, wanted);
+        let (Some(username), Some(integrity)) = (attribute(USERNAME), attribute(MESSAGE_INTEGRITY))
+        else {
+            return Err((401, "Unauthorized"));
+        };
+        let (Some(realm), Some(nonce)) = (attribute(REALM), attribute(NONCE)) else {
+            return Err((400, "Bad Request"));
+        };
+        let username = String::from_utf8_lossy(username).to_string();
+        let Some(password) = self.users.get(&username) else {
+            println!("TURN: unknown user {} at {}", username, source);
+            return Err((401, "Unauthorized"));
+        };
+        if realm != self.realm.as_bytes() {
+            println!(
+                "TURN: {} at {} authenticates for realm {} instead of {}",
+                username,
+                source,
+                String::from_utf8_lossy(realm),
+                self.realm
+            );
+            return Err((401, "Unauthorized"));
+        }
+        match self.nonces.get(&source) {
+            Some((handed, expires_at)) if handed.as_bytes() == nonce => {
+                if Instant::now() >= *expires_at {
+                    return Err((438, "Stale Nonce"));
+                }
+            }
+            // Not the nonce we gave, ie. one from before we restarted
+            _ => return Err((438, "Stale Nonce")),
+        }
+        let key = long_term_key(&username, &self.realm, password);
+        let Some(offset) = integrity_offset(datagram) else {
+            return Err((400, "Bad Request"));
+        };
+        if !has_message_integrity(&datagram[..offset], &key, integrity) {
+            println!("TURN: wrong password of {} at {}", username, source);
+            return Err((401, "Unauthorized"));
+        }
+        Ok(Authenticated { username, key })
+    }
+
+    fn allocate(
+        &mut self,
+        authenticated: &Authenticated,
+        transaction_id: [u8; 12],
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) -> Result<Vec<(u16, Vec<u8>)>, TurnError> {
+        let mut cons = self.connections.data.lock().unwrap();
+        if cons.turn_allocations.contains_key(&source) {
+            return Err((437, "Allocation Mismatch"));
+        }
+        let allocations_of_user = cons
+            .turn_allocations
+            .values()
+            .filter(|allocation| allocation.username == authenticated.username)
+            .count();
+        if allocations_of_user >= MAX_ALLOCATIONS_PER_USER {
+            println!(
+                "TURN: {} already holds {} allocations, refusing another one",
+                authenticated.username, allocations_of_user
+            );
+            return Err((486, "Allocation Quota Reached"));
+        }
+        if cons.turn_allocations.len() >= MAX_ALLOCATIONS {
+            println!(
+                "TURN: {} allocations are held already, refusing another one",
+                cons.turn_allocations.len()
+            );
+            return Err((508, "Insufficient Capacity"));
+        }
+        match get_attribute(attributes, REQUESTED_TRANSPORT).and_then(|value| value.first()) {
+            Some(&UDP_TRANSPORT) => {}
+            Some(_) => return Err((442, "Unsupported Transport Protocol")),
+            None => return Err((400, "Bad Request")),
+        }
+        let lifetime = requested_lifetime(attributes).unwrap_or(DEFAULT_ALLOCATION_LIFETIME);
+        let relay_socket =
+            UdpSocket::bind("0.0.0.0:0").map_err(|_| (508, "Insufficient Capacity"))?;
+        relay_socket
+            .set_nonblocking(true)
+            .map_err(|_| (500, "Server Error"))?;
+        // The relay socket listens everywhere, advertise the address the client reaches us at
+        let relayed_address = SocketAddr::new(
+            local_ip_towards(source).unwrap_or(IpAddr::from([0, 0, 0, 0])),
+            relay_socket
+                .local_addr()
+                .map_err(|_| (500, "Server Error"))?
+                .port(),
+        );
+        println!(
+            "TURN: {} at {} got {} for {}s",
+            authenticated.username,
+            source,
+            relayed_address,
+            lifetime.as_secs()
+        );
+        cons.turn_allocations.insert(
+            source,
+            TurnAllocation {
+                username: authenticated.username.clone(),
+                relay_socket,
+                relayed_address,
+                expires_at: Instant::now() + lifetime,
+                permissions: Default::default(),
+                channels: Default::default(),
+                relayed_bytes: 0,
+            },
+        );
+        Ok(vec![
+            (
+                XOR_RELAYED_ADDRESS,
+                encode_address(relayed_address, Some(transaction_id)),
+            ),
+            (LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec()),
+            (
+                XOR_MAPPED_ADDRESS,
+                encode_address(source, Some(transaction_id)),
+            ),
+        ])
+    }
+
+    fn refresh(
+        &mut self,
+        authenticated: &Authenticated,
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) -> Result<Vec<(u16, Vec<u8>)>, TurnError> {
+        let mut cons = self.connections.data.lock().unwrap();
+        let allocation = get_allocation(&mut cons.turn_allocations, authenticated, source)?;
+        let lifetime = requested_lifetime(attributes).unwrap_or(DEFAULT_ALLOCATION_LIFETIME);
+        if lifetime.is_zero() {
+            println!(
+                "TURN: {} released {} after relaying {} bytes",
+                allocation.username, allocation.relayed_address, allocation.relayed_bytes
+            );
+            cons.turn_allocations.remove(&source);
+        } else {
+            allocation.expires_at = Instant::now() + lifetime;
+        }
+        Ok(vec![(
+            LIFETIME,
+            (lifetime.as_secs() as u32).to_be_bytes().to_vec(),
+        )])
+    }
+
+    fn create_permission(
+        &mut self,
+        authenticated: &Authenticated,
+        transaction_id: [u8; 12],
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) -> Result<Vec<(u16, Vec<u8>)>, TurnError> {
+        let mut cons = self.connections.data.lock().unwrap();
+        let allocation = get_allocation(&mut cons.turn_allocations, authenticated, source)?;
+        let peers = attributes
+            .iter()
+            .filter(|(attribute_type, _)| *attribute_type == XOR_PEER_ADDRESS)
+            .map(|(_, value)| decode_address(value, Some(transaction_id)))
+            .collect::<Option<Vec<_>>>()
+            .filter(|peers| !peers.is_empty())
+            .ok_or((400, "Bad Request"))?;
+        if let Some(peer) = peers
+            .iter()
+            .find(|peer| !self.private_peers && is_private(peer.ip()))
+        {
+            println!(
+                "TURN: {} asked to let {} in, which is private",
+                allocation.username, peer
+            );
+            return Err((403, "Forbidden"));
+        }
+        for peer in peers {
+            if !allocation.permissions.contains_key(&peer.ip()) {
+                println!("TURN: {} lets {} in", allocation.username, peer.ip());
+            }
+            allocation
+                .permissions
+                .insert(peer.ip(), Instant::now() + PERMISSION_LIFETIME);
+        }
+        Ok(vec![])
+    }
+
+    fn bind_channel(
+        &mut self,
+        authenticated: &Authenticated,
+        transaction_id: [u8; 12],
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) -> Result<Vec<(u16, Vec<u8>)>, TurnError> {
+        let mut cons = self.connections.data.lock().unwrap();
+        let allocation = get_allocation(&mut cons.turn_allocations, authenticated, source)?;
+        let channel = get_attribute(attributes, CHANNEL_NUMBER)
+            .and_then(|value| Some(u16::from_be_bytes(value.get(..2)?.try_into().ok()?)))
+            .filter(|channel| CHANNELS.contains(channel))
+            .ok_or((400, "Bad Request"))?;
+        let peer = get_attribute(attributes, XOR_PEER_ADDRESS)
+            .and_then(|value| decode_address(value, Some(transaction_id)))
+            .ok_or((400, "Bad Request"))?;
+        if !self.private_peers && is_private(peer.ip()) {
+            println!(
+                "TURN: {} asked to bind a channel to {}, which is private",
+                allocation.username, peer
+            );
+            return Err((403, "Forbidden"));
+        }
+        // A channel is bound to a single peer for good, and the other way around
+        let bound_peer = allocation.channels.get(&channel).map(|(peer, _)| *peer);
+        let bound_channel = allocation.get_channel(peer);
+        if bound_peer.is_some_and(|bound_peer| bound_peer != peer)
+            || bound_channel.is_some_and(|bound_channel| bound_channel != channel)
+        {
+            return Err((400, "Bad Request"));
+        }
+        if bound_peer.is_none() {
+            println!(
+                "TURN: {} binds channel {:#06x} to {}",
+                allocation.username, channel, peer
+            );
+        }
+        let now = Instant::now();
+        allocation
+            .channels
+            .insert(channel, (peer, now + CHANNEL_LIFETIME));
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+        Ok(vec![])
+    }
+
+    /// Data a client sends to a peer, with the address of the peer
+    fn receive_send_indication(
+        &mut self,
+        transaction_id: [u8; 12],
+        attributes: &Attributes,
+        source: SocketAddr,
+    ) {
+        let mut cons = self.connections.data.lock().unwrap();
+        let Some(allocation) = cons.turn_allocations.get_mut(&source) else {
+            return;
+        };
+        let (Some(peer), Some(data)) = (
+            get_attribute(attributes, XOR_PEER_ADDRESS)
+                .and_then(|value| decode_address(value, Some(transaction_id))),
+            get_attribute(attributes, DATA),
+        ) else {
+            return;
+        };
+        if !allocation.is_permitted(peer) {
+            println!(
+                "TURN: {} has no permission for {}, dropping its data",
+                allocation.username, peer
+            );
+            return;
+        }
+        allocation.relayed_bytes += data.len() as u64;
+        let _ = allocation.relay_socket.send_to(data, peer);
+    }
+
+    /// Data a client sends to a peer over a channel
+    fn receive_channel_data(&mut self, datagram: &[u8], source: SocketAddr) {
+        let mut cons = self.connections.data.lock().unwrap();
+        let Some(allocation) = cons.turn_allocations.get_mut(&source) else {
+            return;
+        };
+        let (Some(channel), Some(length)) = (
+            datagram
+                .get(..2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])),
+            datagram
+                .get(2..4)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize),
+        ) else {
+            return;
+        };
+        let (Some(data), Some((peer, _))) = (
+            datagram.get(4..4 + length),
+            allocation.channels.get(&channel),
+        ) else {
+            return;
+        };
+        allocation.relayed_bytes += data.len() as u64;
+        let _ = allocation.relay_socket.send_to(data, *peer);
+    }
+
+    /// Passes data peers sent to relayed addresses on to their clients, and drops what expired.
+    /// Returns whether there was any.
+    fn relay_from_peers(&mut self, buffer: &mut [u8]) -> bool {
+        let mut had_one = false;
+        let now = Instant::now();
+        let mut cons = self.connections.data.lock().unwrap();
+        cons.turn_allocations.retain(|client, allocation| {
+            if now >= allocation.expires_at {
+                println!(
+                    "TURN: allocation {} of {} at {} expired",
+                    allocation.relayed_address, allocation.username, client
+                );
+                return false;
+            }
+            allocation
+                .permissions
+                .retain(|_, expires_at| now < *expires_at);
+            allocation
+                .channels
+                .retain(|_, (_, expires_at)| now < *expires_at);
+            true
+        });
+        for (client, allocation) in cons.turn_allocations.iter_mut() {
+            while let Ok((size, peer)) = allocation.relay_socket.recv_from(buffer) {
+                had_one = true;
+                if !allocation.is_permitted(peer) {
+                    continue;
+                }
+                allocation.relayed_bytes += size as u64;
+                let message = match allocation.get_channel(peer) {
+                    Some(channel) => {
+                        let mut message = channel.to_be_bytes().to_vec();
+                        message.extend_from_slice(&(size as u16).to_be_bytes());
+                        message.extend_from_slice(&buffer[..size]);
+                        message
+                    }
+                    None => {
+                        let transaction_id = new_transaction_id();
+                        encode_message(
+                            DATA_INDICATION,
+                            transaction_id,
+                            &[
+                                (XOR_PEER_ADDRESS, encode_address(peer, Some(transaction_id))),
+                                (DATA, buffer[..size].to_vec()),
+                            ],
+                        )
+                    }
+                };
+                let _ = self.socket.send_to(&message, client);
+            }
+        }
+        had_one
+    }
+
+    fn respond(
+        &self,
+        message_type: u16,
+        transaction_id: [u8; 12],
+        mut attributes: Vec<(u16, Vec<u8>)>,
+        key: Option<[u8; 16]>,
+        destination: SocketAddr,
+    ) {
+        attributes.push((SOFTWARE, b"rubicon".to_vec()));
+        let mut message = encode_message(message_type, transaction_id, &attributes);
+        if let Some(key) = key {
+            append_attribute(&mut message, MESSAGE_INTEGRITY, 20, |message| {
+                integrity_mac(message, &key)
+                    .finalize()
+                    .into_bytes()
+                    .to_vec()
+            });
+        }
+        append_attribute(&mut message, FINGERPRINT, 4, |message| {
+            (crc32(message) ^ FINGERPRINT_XOR).to_be_bytes().to_vec()
+        });
+        let _ = self.socket.send_to(&message, destination);
+    }
+
+    fn new_nonce(&mut self, source: SocketAddr) -> String {
+        let nonce: String = random_bytes::<16>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.nonces
+            .insert(source, (nonce.clone(), Instant::now() + NONCE_LIFETIME));
+        nonce
+    }
+
+    /// Drops the nonces nobody can authenticate with anymore, so addresses that asked once don't pile up
+    fn forget_stale_nonces(&mut self) {
+        let now = Instant::now();
+        self.nonces.retain(|_, (_, expires_at)| now < *expires_at);
+    }
+}
+
+/// Addresses of the relay itself or of the network it's on, which clients could reach through us otherwise
+fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
+
+fn get_attribute<'a>(attributes: &Attributes<'a>, wanted: u16) -> Option<&'a [u8]> {
+    attributes
+        .iter()
+        .find(|(attribute_type, _)| *attribute_type == wanted)
+        .map(|(_, value)| *value)
+}
+
+/// The allocation of a client, as long as the same user asks for it
+fn get_allocation<'a>(
+    allocations: &'a mut HashMap<SocketAddr, TurnAllocation>,
+    authenticated: &Authenticated,
+    source: SocketAddr,
+) -> Result<&'a mut TurnAllocation, TurnError> {
+    let allocation = allocations
+        .get_mut(&source)
+        .ok_or((437, "Allocation Mismatch"))?;
+    if allocation.username != authenticated.username {
+        return Err((441, "Wrong Credentials"));
+    }
+    Ok(allocation)
+}
+
+fn requested_lifetime(attributes: &Attributes) -> Option<Duration> {
+    let seconds = u32::from_be_bytes(
+        get_attribute(attributes, LIFETIME)?
+            .get(..4)?
+            .try_into()
+            .ok()?,
+    );
+    Some(Duration::from_secs(seconds as u64).min(MAX_ALLOCATION_LIFETIME))
+}
+
+fn error_code((code, reason): TurnError) -> (u16, Vec<u8>) {
+    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+    value.extend_from_slice(reason.as_bytes());
+    (ERROR_CODE, value)
+}
+
+fn method_name(message_type: u16) -> &'static str {
+    match message_type {
+        ALLOCATE => "Allocate",
+        REFRESH => "Refresh",
+        CREATE_PERMISSION => "CreatePermission",
+        _ => "ChannelBind",
+    }
+}
+
+/// Where the MESSAGE-INTEGRITY attribute of a message starts
+fn integrity_offset(datagram: &[u8]) -> Option<usize> {
+    let mut offset = HEADER_SIZE;
+    while offset + 4 <= datagram.len() {
+        let attribute_type = u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+        let length = u16::from_be_bytes([datagram[offset + 2], datagram[offset + 3]]) as usize;
+        if attribute_type == MESSAGE_INTEGRITY {
+            return Some(offset);
+        }
+        offset += (4 + length).next_multiple_of(4);
+    }
+    None
+}
+
+/// The key of long-term credentials, MD5 of `username:realm:password`
+fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    Md5::digest(format!("{}:{}:{}", username, realm, password)).into()
+}
+
+/// HMAC-SHA1 of a message up to the MESSAGE-INTEGRITY attribute,
+/// with a length in its header as if the message ended right after it
+fn integrity_mac(message: &[u8], key: &[u8]) -> Hmac<Sha1> {
+    let mut message = message.to_vec();
+    let length = (message.len() - HEADER_SIZE + 24) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&message);
+    mac
+}
+
+/// Whether the MESSAGE-INTEGRITY a client sent matches the message, compared in constant time
+fn has_message_integrity(message: &[u8], key: &[u8], integrity: &[u8]) -> bool {
+    integrity_mac(message, key).verify_slice(integrity).is_ok()
+}
+
+/// Appends an attribute computed over the message before it, like MESSAGE-INTEGRITY and FINGERPRINT.
+/// The length in the header already counts the attribute when it's computed.
+fn append_attribute(
+    message: &mut Vec<u8>,
+    attribute_type: u16,
+    size: usize,
+    value: impl Fn(&[u8]) -> Vec<u8>,
+) {
+    let length = (message.len() - HEADER_SIZE + 4 + size) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    let value = value(message);
+    message.extend_from_slice(&attribute_type.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(&value);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// The ip of the interface we reach an address from
+fn local_ip_towards(address: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .ok()?;
+    socket.connect(address).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = "user";
+    const PASSWORD: &str = "secret";
+
+    fn server() -> TurnServer {
+        TurnServer::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            vec![TurnUser {
+                username: USER.to_string(),
+                password: PASSWORD.to_string(),
+            }],
+            "rubicon".to_string(),
+            false,
+            Connections::new(),
+        )
+    }
+
+    /// An authenticated request, as a TURN client would send it
+    fn request(
+        message_type: u16,
+        realm: &str,
+        nonce: &str,
+        password: &str,
+        mut attributes: Vec<(u16, Vec<u8>)>,
+    ) -> Vec<u8> {
+        attributes.extend([
+            (USERNAME, USER.as_bytes().to_vec()),
+            (REALM, realm.as_bytes().to_vec()),
+            (NONCE, nonce.as_bytes().to_vec()),
+        ]);
+        let mut message = encode_message(message_type, new_transaction_id(), &attributes);
+        let key = long_term_key(USER, realm, password);
+        append_attribute(&mut message, MESSAGE_INTEGRITY, 20, |message| {
+            integrity_mac(message, &key)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        });
+        message
+    }
+
+    fn authenticate(server: &TurnServer, message: &[u8], source: SocketAddr) -> Option<TurnError> {
+        let (_, _, attributes) = parse_message(message).unwrap();
+        server.authenticate(message, &attributes, source).err()
+    }
+
+    #[test]
+    fn checks_message_integrity_of_rfc_5769() {
+        // Sample request with long-term authentication of RFC 5769 section 2.4
+        let message = [
+            0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33, 0xc6, 0xad,
+            0x72, 0xc0, 0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3,
+            0x83, 0x88, 0xe3, 0x83, 0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9,
+            0x00, 0x00, 0x00, 0x15, 0x00, 0x1c, 0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39,
+            0x35, 0x34, 0x64, 0x36, 0x4f, 0x4c, 0x33, 0x34, 0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54,
+            0x76, 0x79, 0x36, 0x34, 0x73, 0x41, 0x00, 0x14, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d,
+            0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x00, 0x00, 0x08, 0x00, 0x14, 0xf6, 0x70,
+            0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02, 0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2,
+            0x8c, 0xa8, 0x96, 0x66,
+        ];
+        let (_, _, attributes) = parse_message(&message).unwrap();
+        let username = std::str::from_utf8(get_attribute(&attributes, USERNAME).unwrap()).unwrap();
+        assert_eq!(username, "マトリックス");
+        assert_eq!(get_attribute(&attributes, REALM).unwrap(), b"example.org");
+
+        let offset = integrity_offset(&message).unwrap();
+        let integrity = get_attribute(&attributes, MESSAGE_INTEGRITY).unwrap();
+        let key = long_term_key(username, "example.org", "TheMatrIX");
+        assert!(has_message_integrity(&message[..offset], &key, integrity));
+        let key = long_term_key(username, "example.org", "TheMatrix");
+        assert!(!has_message_integrity(&message[..offset], &key, integrity));
+    }
+
+    #[test]
+    fn encodes_attributes() {
+        assert_eq!(
+            error_code((438, "Stale Nonce")),
+            (ERROR_CODE, [&[0, 0, 4, 38][..], b"Stale Nonce"].concat())
+        );
+        // The check value of CRC-32
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let lifetime =
+            |seconds: u32| requested_lifetime(&vec![(LIFETIME, &seconds.to_be_bytes()[..])]);
+        assert_eq!(lifetime(60), Some(Duration::from_secs(60)));
+        assert_eq!(lifetime(u32::MAX), Some(MAX_ALLOCATION_LIFETIME));
+        assert_eq!(requested_lifetime(&vec![(LIFETIME, &[0, 1][..])]), None);
+    }
+
+    #[test]
+    fn authenticates_long_term_credentials() {
+        let mut server = server();
+        let source: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        // Clients ask without credentials first, to get a nonce
+        let unauthenticated = encode_message(ALLOCATE, new_transaction_id(), &[]);
+        assert_eq!(
+            authenticate(&server, &unauthenticated, source),
+            Some((401, "Unauthorized"))
+        );
+        let nonce = server.new_nonce(source);
+        assert_eq!(nonce.len(), 32);
+        assert_ne!(nonce, server.new_nonce("127.0.0.1:5001".parse().unwrap()));
+
+        let allocate = |realm, nonce, password| request(ALLOCATE, realm, nonce, password, vec![]);
+        assert_eq!(
+            authenticate(&server, &allocate("rubicon", &nonce, PASSWORD), source),
+            None
+        );
+        assert_eq!(
+            authenticate(&server, &allocate("rubicon", &nonce, "guess"), source),
+            Some((401, "Unauthorized"))
+        );
+        // The password of the user holds for our realm only
+        assert_eq!(
+            authenticate(&server, &allocate("elsewhere", &nonce, PASSWORD), source),
+            Some((401, "Unauthorized"))
+        );
+        // Nonces are for the address they were handed to, until they expire
+        assert_eq!(
+            authenticate(
+                &server,
+                &allocate("rubicon", &nonce, PASSWORD),
+                "127.0.0.1:5002".parse().unwrap()
+            ),
+            Some((438, "Stale Nonce"))
+        );
+        server.nonces.get_mut(&source).unwrap().1 = Instant::now();
+        assert_eq!(
+            authenticate(&server, &allocate("rubicon", &nonce, PASSWORD), source),
+            Some((438, "Stale Nonce"))
+        );
+        server.forget_stale_nonces();
+        assert!(!server.nonces.contains_key(&source));
+    }
+
+    #[test]
+    fn limits_allocations_of_users() {
+        let mut server = server();
+        let authenticated = Authenticated {
+            username: USER.to_string(),
+            key: long_term_key(USER, "rubicon", PASSWORD),
+        };
+        let transport = [(REQUESTED_TRANSPORT, &[UDP_TRANSPORT, 0, 0, 0][..])];
+        let allocate = |server: &mut TurnServer, port: u16| {
+            let source = SocketAddr::from(([127, 0, 0, 1], port));
+            server.allocate(
+                &authenticated,
+                new_transaction_id(),
+                &transport.to_vec(),
+                source,
+            )
+        };
+        for port in 0..MAX_ALLOCATIONS_PER_USER as u16 {
+            assert!(allocate(&mut server, 5000 + port).is_ok());
+        }
+        assert_eq!(
+            allocate(&mut server, 5000).unwrap_err(),
+            (437, "Allocation Mismatch")
+        );
+        assert_eq!(
+            allocate(&mut server, 6000).unwrap_err(),
+            (486, "Allocation Quota Reached")
+        );
+    }
+
+    #[test]
+    fn forbids_private_peers() {
+        let mut server = server();
+        let authenticated = Authenticated {
+            username: USER.to_string(),
+            key: long_term_key(USER, "rubicon", PASSWORD),
+        };
+        let source: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let transport = vec![(REQUESTED_TRANSPORT, &[UDP_TRANSPORT, 0, 0, 0][..])];
+        server
+            .allocate(&authenticated, new_transaction_id(), &transport, source)
+            .unwrap();
+        let permit = |server: &mut TurnServer, peer: &str| {
+            let transaction_id = new_transaction_id();
+            let peer = encode_address(peer.parse().unwrap(), Some(transaction_id));
+            server.create_permission(
+                &authenticated,
+                transaction_id,
+                &vec![(XOR_PEER_ADDRESS, &peer[..])],
+                source,
+            )
+        };
+        let bind = |server: &mut TurnServer, peer: &str| {
+            let transaction_id = new_transaction_id();
+            let peer = encode_address(peer.parse().unwrap(), Some(transaction_id));
+            server.bind_channel(
+                &authenticated,
+                transaction_id,
+                &vec![
+                    (CHANNEL_NUMBER, &[0x40, 0, 0, 0][..]),
+                    (XOR_PEER_ADDRESS, &peer[..]),
+                ],
+                source,
+            )
+        };
+        for peer in [
+            "127.0.0.1:9",
+            "0.0.0.0:9",
+            "10.1.2.3:9",
+            "172.16.0.1:9",
+            "192.168.1.1:9",
+            "169.254.169.254:80",
+            "[::1]:9",
+            "[::ffff:10.1.2.3]:9",
+            "[fd00::1]:9",
+        ] {
+            assert_eq!(
+                permit(&mut server, peer).unwrap_err(),
+                (403, "Forbidden"),
+                "{peer}"
+            );
+            assert_eq!(
+                bind(&mut server, peer).unwrap_err(),
+                (403, "Forbidden"),
+                "{peer}"
+            );
+        }
+        assert!(
+            server.connections.data.lock().unwrap().turn_allocations[&source]
+                .permissions
+                .is_empty()
+        );
+        assert!(permit(&mut server, "203.0.113.7:9").is_ok());
+        assert!(bind(&mut server, "203.0.113.7:9").is_ok());
+
+        // Unless the relay lets them in, ie. on a LAN
+        server.private_peers = true;
+        assert!(permit(&mut server, "192.168.1.1:9").is_ok());
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::{
+    common::DISABLE_NAGLE_ALGORITHM,
+    connections::{Connections, PlayerData},
+    packet::Packet,
+    proxy::Proxy,
+    socket::SocketWrapper,
+};
+
+/// How long a client may take to send its upgrade request, for silent ones not to hold a thread forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A websocket connection to the relay (or, on the server side, to a player).
+/// Every binary message is a single serialized [Packet] (bincode), so anything that can open
+/// a websocket - browsers included - can speak the relay protocol.
+/// Both tcp and udp data are carried, as udp is often blocked where websockets are the only way out.
+#[derive(Debug)]
+pub struct WebSocketLink {
+    socket: Mutex<WebSocket<TcpStream>>,
+    remote_address: SocketAddr,
+    closed: AtomicBool,
+}
+impl WebSocketLink {
+    fn new(socket: WebSocket<TcpStream>) -> std::io::Result<Self> {
+        let remote_address = socket.get_ref().peer_addr()?;
+        socket.get_ref().set_nodelay(DISABLE_NAGLE_ALGORITHM)?;
+        Ok(Self {
+            socket: Mutex::new(socket),
+            remote_address,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote_address
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.socket
+            .lock()
+            .unwrap()
+            .get_ref()
+            .set_nonblocking(nonblocking)
+    }
+
+    /// Reads a single message (serialized packet), returns [ErrorKind::WouldBlock] if there's none.
+    /// Also pushes out data that couldn't be written earlier.
+    pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut socket = self.socket.lock().unwrap();
+        loop {
+            match socket.read() {
+                Ok(Message::Binary(message)) => {
+                    if message.len() > buf.len() {
+                        println!(
+                            "Dropping a websocket message of size {} that doesn't fit the buffer",
+                            message.len()
+                        );
+                        return Err(ErrorKind::InvalidData.into());
+                    }
+                    buf[..message.len()].copy_from_slice(&message);
+                    return Ok(message.len());
+                }
+                Ok(Message::Close(_)) => {
+                    self.closed.store(true, Ordering::Relaxed);
+                    return Ok(0);
+                }
+                // Pings are answered by tungstenite itself, text isn't part of the protocol
+                Ok(_) => continue,
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => return Err(e),
+                Err(e) => return Err(self.close_on_error(e)),
+            }
+        }
+    }
+
+    /// Sends a packet as a single binary message.
+    /// When the socket would block the message stays buffered, and goes out with the next read or send.
+    pub fn send_packet(&self, packet: &Packet) -> std::io::Result<()> {
+        let data = bincode::serialize(packet).unwrap();
+        match self.socket.lock().unwrap().send(Message::binary(data)) {
+            Ok(()) => Ok(()),
+            Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(self.close_on_error(e)),
+        }
+    }
+
+    fn close_on_error(&self, e: Error) -> std::io::Error {
+        match e {
+            Error::Io(e) => {
+                if e.kind() != ErrorKind::Interrupted {
+                    self.closed.store(true, Ordering::Relaxed);
+                }
+                e
+            }
+            e => {
+                self.closed.store(true, Ordering::Relaxed);
+                std::io::Error::new(ErrorKind::ConnectionAborted, e)
+            }
+        }
+    }
+}
+
+/// Connects to a relay server accepting websockets, `url` being a `ws://host:port/path` url.
+pub fn connect(url: &str, proxy: Option<&Proxy>) -> std::io::Result<WebSocketLink> {
+    let authority = url
+        .strip_prefix(crate::socket::WEBSOCKET_SCHEME)
+        .unwrap_or(url)
+        .split('/')
+        .next()
+        .unwrap();
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(authority)?,
+        None => {
+            let address = authority
+                .to_socket_addrs()?
+                .next()
+                .ok_or(ErrorKind::AddrNotAvailable)?;
+            TcpStream::connect(address)?
+        }
+    };
+    let (socket, _response) = tungstenite::client(url, stream)
+        .map_err(|e| std::io::Error::new(ErrorKind::ConnectionRefused, e.to_string()))?;
+    WebSocketLink::new(socket)
+}
+
+/// Answers the upgrade request of a client that connected to us
+fn accept(tcp_stream: TcpStream) -> std::io::Result<WebSocketLink> {
+    tcp_stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let socket = tungstenite::accept(tcp_stream)
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    socket.get_ref().set_read_timeout(None)?;
+    WebSocketLink::new(socket)
+}
+
+/// Accepts websocket upgrades on a given port, adding them to the connections of the server.
+/// The path of the upgrade request is ignored.
+pub fn listen(port: u16, connections: Connections) {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
+    println!("Accepting websocket connections on {}", port);
+    std::thread::spawn(move || {
+        for (tcp_stream, peer) in listener.incoming().flatten().filter_map(|tcp_stream| {
+            let peer = tcp_stream.peer_addr().ok()?;
+            Some((tcp_stream, peer))
+        }) {
+            let connections = connections.clone();
+            // Handshakes of slow clients shouldn't hold up everyone else
+            std::thread::spawn(move || match accept(tcp_stream) {
+                Ok(link) => {
+                    println!("Received websocket connection from: {}", peer);
+                    link.set_nonblocking(true).unwrap();
+                    connections.data.lock().unwrap().insert_link(
+                        peer.port(),
+                        PlayerData::new(peer, SocketWrapper::from_websocket(link)),
+                    );
+                }
+                Err(e) => println!("Failed to accept a websocket connection: {}", e),
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Instant};
+
+    use super::*;
+
+    /// A relay accepting a single websocket upgrade on a local port, as an HTTP server would
+    fn stand_in() -> (u16, std::thread::JoinHandle<std::io::Result<WebSocketLink>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || accept(listener.accept()?.0));
+        (port, server)
+    }
+
+    fn read_blocking(link: &WebSocketLink) -> Packet {
+        let mut buffer = [0; 1024];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match link.read(&mut buffer) {
+                Ok(size) => return bincode::deserialize(&buffer[..size]).unwrap(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn carries_packets_both_ways() {
+        let (port, server) = stand_in();
+        let client = connect(&format!("ws://127.0.0.1:{}/relay", port), None).unwrap();
+        let server = server.join().unwrap().unwrap();
+        server.set_nonblocking(true).unwrap();
+        client.set_nonblocking(true).unwrap();
+
+        client
+            .send_packet(&Packet::Heartbeat("player".to_string()))
+            .unwrap();
+        assert!(matches!(read_blocking(&server), Packet::Heartbeat(name) if name == "player"));
+        server.send_packet(&Packet::GreetingReply).unwrap();
+        assert!(matches!(read_blocking(&client), Packet::GreetingReply));
+
+        drop(client);
+        let mut buffer = [0; 64];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.is_closed() && Instant::now() < deadline {
+            let _ = server.read(&mut buffer);
+        }
+        assert!(server.is_closed());
+    }
+
+    #[test]
+    fn refuses_plain_http() {
+        let (port, server) = stand_in();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: relay\r\n\r\n")
+            .unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+}